@@ -58,83 +58,145 @@ impl Display for Bencode {
     }
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    InvalidString,
+    InvalidInteger,
+    NonStringKey,
+    UnexpectedByte(u8),
+    TooDeep,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => f.write_str("unexpected end of input"),
+            DecodeError::InvalidString => f.write_str("invalid string length"),
+            DecodeError::InvalidInteger => f.write_str("invalid integer"),
+            DecodeError::NonStringKey => f.write_str("dictionary key is not a string"),
+            DecodeError::UnexpectedByte(byte) => write!(f, "unexpected byte 0x{byte:02x}"),
+            DecodeError::TooDeep => f.write_str("lists and dictionaries nested too deeply"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Deepest nesting of lists and dictionaries that is decoded, so hostile
+/// input cannot overflow the stack.
+pub const MAX_DEPTH: usize = 256;
+
 impl Bencode {
     #[allow(dead_code)]
     pub fn decode_value(encoded_value: Vec<u8>) -> (Self, Vec<u8>) {
-        // If encoded_value starts with a digit, it's a number
-        match encoded_value.first().unwrap() {
-            b'0'..=b'9' => {
-                if let Some(index) = encoded_value.iter().position(|&c| c == b':') {
-                    let (len_bytes, rest) = encoded_value.split_at(index);
-                    let len_string = String::from_utf8(len_bytes.to_vec()).unwrap();
-
-                    if let Ok(len) = len_string.parse::<usize>() {
-                        return (
-                            Bencode::String(rest[1..len + 1].to_vec()),
-                            rest[len + 1..].to_vec(),
-                        );
-                    }
-                }
+        Self::try_decode_value(encoded_value)
+            .unwrap_or_else(|error| panic!("Error decoding Bencode: {error}"))
+    }
 
-                panic!("Error decoding Bencode string")
-            }
-            b'i' => {
-                let mut split = encoded_value.split_at(1).1.splitn(2, |&c| c == b'e');
+    /// Same as `decode_value`, but returns an error instead of panicking, for
+    /// data received from the network.
+    pub fn try_decode_value(encoded_value: Vec<u8>) -> Result<(Self, Vec<u8>), DecodeError> {
+        let (value, used) = Self::try_decode_slice(&encoded_value)?;
 
-                let number_bytes = split.next().unwrap();
-                let rest = split.next().unwrap();
+        Ok((value, encoded_value[used..].to_vec()))
+    }
 
-                if number_bytes.first().unwrap() == &b'0' && number_bytes.len() > 1 {
-                    panic!("All encodings with a leading zero are invalid, other than i0e")
-                }
+    /// Decodes the value at the start of `encoded_value`, returning it with
+    /// the number of bytes it took.
+    pub fn try_decode_slice(encoded_value: &[u8]) -> Result<(Self, usize), DecodeError> {
+        Self::decode_slice(encoded_value, 0)
+    }
 
-                if number_bytes == b"-0" {
-                    panic!("i-0e is invalid")
+    fn decode_slice(encoded_value: &[u8], depth: usize) -> Result<(Self, usize), DecodeError> {
+        // If encoded_value starts with a digit, it's a number
+        match *encoded_value.first().ok_or(DecodeError::UnexpectedEnd)? {
+            b'0'..=b'9' => {
+                let index = encoded_value
+                    .iter()
+                    .position(|&c| c == b':')
+                    .ok_or(DecodeError::InvalidString)?;
+
+                let len = std::str::from_utf8(&encoded_value[..index])
+                    .ok()
+                    .and_then(|len_string| len_string.parse::<usize>().ok())
+                    .ok_or(DecodeError::InvalidString)?;
+
+                let end = len
+                    .checked_add(1)
+                    .and_then(|len| index.checked_add(len))
+                    .filter(|&end| end <= encoded_value.len())
+                    .ok_or(DecodeError::UnexpectedEnd)?;
+
+                Ok((Bencode::String(encoded_value[index + 1..end].to_vec()), end))
+            }
+            b'i' => {
+                let end = encoded_value
+                    .iter()
+                    .position(|&c| c == b'e')
+                    .ok_or(DecodeError::UnexpectedEnd)?;
+                let number_bytes = &encoded_value[1..end];
+
+                // All encodings with a leading zero are invalid, other than
+                // i0e, and so is i-0e. Only a minus sign may come before the
+                // digits
+                if (number_bytes.first() == Some(&b'0') && number_bytes.len() > 1)
+                    || number_bytes.starts_with(b"-0")
+                    || !matches!(number_bytes.first(), Some(b'-' | b'0'..=b'9'))
+                {
+                    return Err(DecodeError::InvalidInteger);
                 }
 
-                let number_string = String::from_utf8(number_bytes.to_vec()).unwrap();
-                let number = number_string.parse::<i64>().unwrap();
+                let number = std::str::from_utf8(number_bytes)
+                    .ok()
+                    .and_then(|number_string| number_string.parse::<i64>().ok())
+                    .ok_or(DecodeError::InvalidInteger)?;
 
-                return (Bencode::Integer(number), rest.to_vec());
+                Ok((Bencode::Integer(number), end + 1))
             }
+            b'l' | b'd' if depth == MAX_DEPTH => Err(DecodeError::TooDeep),
             b'l' => {
-                let mut list_string = encoded_value.split_at(1).1.to_vec();
-
+                let mut position = 1;
                 let mut list = Vec::new();
 
                 loop {
-                    let (decoded_value, rest) = Self::decode_value(list_string.to_vec());
-                    list.push(decoded_value);
-                    if rest.first().unwrap() == &b'e' {
-                        return (Bencode::List(list), rest.split_at(1).1.to_vec());
-                    };
-
-                    list_string = rest;
+                    match encoded_value.get(position) {
+                        None => return Err(DecodeError::UnexpectedEnd),
+                        Some(b'e') => return Ok((Bencode::List(list), position + 1)),
+                        Some(_) => {
+                            let (decoded_value, used) =
+                                Self::decode_slice(&encoded_value[position..], depth + 1)?;
+                            list.push(decoded_value);
+                            position += used;
+                        }
+                    }
                 }
             }
             b'd' => {
-                let mut dict_string = encoded_value.split_at(1).1.to_vec();
-
+                let mut position = 1;
                 let mut dict = IndexMap::new();
 
-                while let (Bencode::String(key_bytes), rest) =
-                    Self::decode_value(dict_string.to_vec())
-                {
-                    let (value, rest) = Self::decode_value(rest);
-                    dict.insert(key_bytes, value);
-                    if rest.first().unwrap() == &b'e' {
-                        return (Bencode::Dictionary(dict), rest.split_at(1).1.to_vec());
+                loop {
+                    match encoded_value.get(position) {
+                        None => return Err(DecodeError::UnexpectedEnd),
+                        Some(b'e') => return Ok((Bencode::Dictionary(dict), position + 1)),
+                        Some(_) => {
+                            let (key, used) =
+                                Self::decode_slice(&encoded_value[position..], depth + 1)?;
+                            let Bencode::String(key_bytes) = key else {
+                                return Err(DecodeError::NonStringKey);
+                            };
+                            position += used;
+
+                            let (value, used) =
+                                Self::decode_slice(&encoded_value[position..], depth + 1)?;
+                            dict.insert(key_bytes, value);
+                            position += used;
+                        }
                     }
-
-                    dict_string = rest;
                 }
-
-                panic!("Error decoding Bencode dictionary")
             }
-            _ => panic!(
-                "Unhandled encoded value: {}",
-                String::from_utf8_lossy(&encoded_value)
-            ),
+            byte => Err(DecodeError::UnexpectedByte(byte)),
         }
     }
 
@@ -146,9 +208,9 @@ impl Bencode {
                 len.push(b':');
                 len.append(s);
 
-                return len;
+                len
             }
-            Bencode::Integer(i) => return format!("i{i}e").into_bytes(),
+            Bencode::Integer(i) => format!("i{i}e").into_bytes(),
             Bencode::List(l) => {
                 let mut out = vec![b'l'];
 
@@ -158,7 +220,7 @@ impl Bencode {
 
                 out.push(b'e');
 
-                return out;
+                out
             }
             Bencode::Dictionary(d) => {
                 let mut out = vec![b'd'];
@@ -170,7 +232,7 @@ impl Bencode {
 
                 out.push(b'e');
 
-                return out;
+                out
             }
         }
     }
@@ -270,6 +332,79 @@ mod tests {
         )
     }
 
+    #[test]
+    fn decode_bencode_empty_containers() {
+        assert_eq!(
+            Bencode::decode_value(b"d5:peerslee".to_vec()),
+            (
+                Bencode::Dictionary(IndexMap::from([(b"peers".to_vec(), Bencode::List(vec![]))])),
+                vec![]
+            )
+        );
+        assert_eq!(
+            Bencode::decode_value(b"dei1e".to_vec()),
+            (Bencode::Dictionary(IndexMap::new()), b"i1e".to_vec())
+        );
+    }
+
+    #[test]
+    fn try_decode_bencode_errors() {
+        assert_eq!(
+            Bencode::try_decode_value(b"5:abc".to_vec()),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(b"i03e".to_vec()),
+            Err(DecodeError::InvalidInteger)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(b"i-0e".to_vec()),
+            Err(DecodeError::InvalidInteger)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(b"i+5e".to_vec()),
+            Err(DecodeError::InvalidInteger)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(b"i 5e".to_vec()),
+            Err(DecodeError::InvalidInteger)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(b"di1ei2ee".to_vec()),
+            Err(DecodeError::NonStringKey)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(b"l4:spam".to_vec()),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(b"x".to_vec()),
+            Err(DecodeError::UnexpectedByte(b'x'))
+        );
+        assert_eq!(
+            Bencode::try_decode_value(vec![]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(format!("{}:abc", usize::MAX).into_bytes()),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn try_decode_bencode_depth() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(Bencode::try_decode_value(nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Bencode::try_decode_value(nested(MAX_DEPTH + 1)),
+            Err(DecodeError::TooDeep)
+        );
+        assert_eq!(
+            Bencode::try_decode_value(vec![b'l'; 100_000]),
+            Err(DecodeError::TooDeep)
+        );
+    }
+
     #[test]
     fn encode_bencode_string() {
         assert_eq!(
@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use indexmap::IndexMap;
+
+use crate::{
+    geometry::BLOCK_SIZE,
+    merkle,
+    torrent::{File, FileTree, Info, Torrent},
+};
+
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+
+#[derive(Default)]
+pub struct CreateOptions {
+    /// Defaults to a power of two giving roughly a thousand pieces.
+    pub piece_length: Option<u32>,
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub web_seeds: Vec<String>,
+    pub private: bool,
+    pub source: Option<String>,
+}
+
+/// Builds a v2 torrent for a file or a directory, hashing its content.
+pub fn create(path: &Path, options: &CreateOptions) -> io::Result<Torrent> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+    let piece_length = match options.piece_length {
+        Some(piece_length) => piece_length,
+        None => default_piece_length(total_size(path)?),
+    };
+
+    if piece_length < BLOCK_SIZE || !piece_length.is_power_of_two() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "piece length must be a power of two of at least 16 KiB",
+        ));
+    }
+
+    let mut piece_layers = HashMap::new();
+
+    let contents = if path.is_dir() {
+        hash_directory(path, piece_length, &mut piece_layers)?
+    } else {
+        vec![FileTree::File(
+            name.clone(),
+            hash_file(path, piece_length, &mut piece_layers)?,
+        )]
+    };
+
+    let announce = options
+        .trackers
+        .first()
+        .and_then(|tier| tier.first())
+        .cloned()
+        .unwrap_or_default();
+
+    let announce_list = if options.trackers.iter().flatten().count() > 1 {
+        options.trackers.clone()
+    } else {
+        Vec::new()
+    };
+
+    Ok(Torrent {
+        announce,
+        announce_list,
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        url_list: options.web_seeds.clone(),
+        info: Info {
+            name,
+            piece_length,
+            meta_version: 2,
+            file_tree: FileTree::Directory(String::new(), contents),
+            private: options.private.then_some(true),
+            source: options.source.clone(),
+            extra: IndexMap::new(),
+            original: None,
+        },
+        piece_layers,
+        extra: IndexMap::new(),
+        original: None,
+    })
+}
+
+fn default_piece_length(total_size: u64) -> u32 {
+    (total_size / 1024)
+        .next_power_of_two()
+        .clamp(BLOCK_SIZE as u64, MAX_PIECE_LENGTH as u64) as u32
+}
+
+fn total_size(path: &Path) -> io::Result<u64> {
+    if !path.is_dir() {
+        return Ok(fs::metadata(path)?.len());
+    }
+
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += total_size(&entry?.path())?;
+    }
+
+    Ok(size)
+}
+
+fn hash_directory(
+    path: &Path,
+    piece_length: u32,
+    piece_layers: &mut HashMap<Vec<u8>, Vec<u8>>,
+) -> io::Result<Vec<FileTree>> {
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    // The file tree is a bencoded dictionary, so its keys must be sorted
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut contents = Vec::new();
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let entry_path = entry.path();
+
+        if entry_path.is_dir() {
+            contents.push(FileTree::Directory(
+                name,
+                hash_directory(&entry_path, piece_length, piece_layers)?,
+            ));
+        } else {
+            contents.push(FileTree::File(
+                name,
+                hash_file(&entry_path, piece_length, piece_layers)?,
+            ));
+        }
+    }
+
+    Ok(contents)
+}
+
+fn hash_file(
+    path: &Path,
+    piece_length: u32,
+    piece_layers: &mut HashMap<Vec<u8>, Vec<u8>>,
+) -> io::Result<File> {
+    let mut file = fs::File::open(path)?;
+    let mut leaves = Vec::new();
+    let mut length = 0;
+    let mut block = vec![0; BLOCK_SIZE as usize];
+
+    loop {
+        let read = read_block(&mut file, &mut block)?;
+        if read == 0 {
+            break;
+        }
+
+        leaves.push(merkle::hash_block(&block[..read]));
+        length += read as u64;
+    }
+
+    if length == 0 {
+        return Ok(File {
+            length,
+            pieces_root: Vec::new(),
+        });
+    }
+
+    let pieces_root = merkle::pieces_root(&leaves, piece_length).to_vec();
+
+    if length > piece_length as u64 {
+        let layer = merkle::piece_layer(&leaves, piece_length).concat();
+        piece_layers.insert(pieces_root.clone(), layer);
+    }
+
+    Ok(File {
+        length,
+        pieces_root,
+    })
+}
+
+/// Fills `block` as much as possible, returning less than its size only at
+/// the end of the file.
+fn read_block(file: &mut fs::File, block: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < block.len() {
+        match file.read(&mut block[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::bencode_decoder::Bencode;
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("bittorent-create-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("data/sub")).unwrap();
+        fs::write(dir.join("data/b.txt"), b"hello").unwrap();
+        fs::write(dir.join("data/a.bin"), vec![3; 100_000]).unwrap();
+        fs::write(dir.join("data/sub/empty"), b"").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_create_directory() {
+        let dir = test_dir("directory");
+
+        let torrent = create(
+            &dir.join("data"),
+            &CreateOptions {
+                piece_length: Some(32768),
+                trackers: vec![vec!["http://example.com/announce".to_string()]],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut files = Vec::new();
+        torrent.info.file_tree.get_files_with_paths(&[], &mut files);
+        let paths: Vec<String> = files.iter().map(|(path, _)| path.join("/")).collect();
+
+        assert_eq!(paths, vec!["a.bin", "b.txt", "sub/empty"]);
+        assert_eq!(
+            hex::encode(&files[1].1.pieces_root),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(torrent.piece_layers.len(), 1);
+        assert_eq!(torrent.piece_layers[&files[0].1.pieces_root].len(), 4 * 32);
+        assert!(torrent.validate().is_valid());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_round_trip() {
+        let dir = test_dir("round-trip");
+
+        let torrent = create(
+            &dir.join("data/a.bin"),
+            &CreateOptions {
+                private: true,
+                comment: Some("test".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let encoded = torrent.to_bencode().encode_value();
+        let (metainfo, _) = Bencode::decode_value(encoded);
+        let parsed = Torrent::parse(&metainfo);
+
+        assert_eq!(parsed.info.name, "a.bin");
+        assert_eq!(parsed.info.piece_length, BLOCK_SIZE);
+        assert_eq!(parsed.info.private, Some(true));
+        assert_eq!(parsed.info.get_infohash(), torrent.info.get_infohash());
+        assert!(parsed.validate().is_valid());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::bencode_decoder::Bencode;
+
+use super::{krpc::KrpcError, NodeId};
+
+/// Largest bencoded value that may be stored.
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+
+pub const ERROR_VALUE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+/// Bencodes a value with the keys of its dictionaries sorted, as signatures
+/// and hashes are computed over the canonical encoding.
+pub fn encode_canonical(value: &Bencode) -> Vec<u8> {
+    fn sort(value: &mut Bencode) {
+        match value {
+            Bencode::List(list) => list.iter_mut().for_each(sort),
+            Bencode::Dictionary(dict) => {
+                dict.sort_keys();
+                dict.values_mut().for_each(sort);
+            }
+            _ => {}
+        }
+    }
+
+    let mut value = value.clone();
+    sort(&mut value);
+    value.encode_value()
+}
+
+fn check_size(value: &Bencode) -> Result<(), KrpcError> {
+    if encode_canonical(value).len() > MAX_VALUE_SIZE {
+        return Err(KrpcError::new(
+            ERROR_VALUE_TOO_BIG,
+            "message (v field) too big",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Target of an immutable item: the SHA-1 of its bencoded value.
+pub fn immutable_target(value: &Bencode) -> NodeId {
+    NodeId(
+        sha1_smol::Sha1::from(encode_canonical(value))
+            .digest()
+            .bytes(),
+    )
+}
+
+/// Target of a mutable item: the SHA-1 of its public key and salt.
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(key);
+    hasher.update(salt);
+    NodeId(hasher.digest().bytes())
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MutableItem {
+    /// ed25519 public key of the publisher.
+    pub key: [u8; 32],
+    /// Lets one key publish several items.
+    pub salt: Vec<u8>,
+    /// Version of the item; only higher ones replace it.
+    pub seq: i64,
+    pub value: Bencode,
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    /// The signed bytes: the bencoded `salt`, `seq` and `v` entries, without
+    /// the surrounding dictionary.
+    pub fn signable(salt: &[u8], seq: i64, value: &Bencode) -> Vec<u8> {
+        let mut signable = Vec::new();
+        if !salt.is_empty() {
+            signable.extend(format!("4:salt{}:", salt.len()).into_bytes());
+            signable.extend_from_slice(salt);
+        }
+        signable.extend(format!("3:seqi{seq}e1:v").into_bytes());
+        signable.extend(encode_canonical(value));
+        signable
+    }
+
+    pub fn sign(signing_key: &SigningKey, salt: &[u8], seq: i64, value: Bencode) -> Self {
+        let signature = signing_key.sign(&MutableItem::signable(salt, seq, &value));
+
+        MutableItem {
+            key: signing_key.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature.to_bytes(),
+        }
+    }
+
+    pub fn target(&self) -> NodeId {
+        mutable_target(&self.key, &self.salt)
+    }
+
+    pub fn verify(&self) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.key) else {
+            return false;
+        };
+
+        key.verify(
+            &MutableItem::signable(&self.salt, self.seq, &self.value),
+            &Signature::from_bytes(&self.signature),
+        )
+        .is_ok()
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Item {
+    Immutable(Bencode),
+    Mutable(MutableItem),
+}
+
+impl Item {
+    pub fn target(&self) -> NodeId {
+        match self {
+            Item::Immutable(value) => immutable_target(value),
+            Item::Mutable(item) => item.target(),
+        }
+    }
+
+    pub fn value(&self) -> &Bencode {
+        match self {
+            Item::Immutable(value) => value,
+            Item::Mutable(item) => &item.value,
+        }
+    }
+
+    /// Checks the limits and the signature an item must pass to be stored.
+    pub fn validate(&self) -> Result<(), KrpcError> {
+        check_size(self.value())?;
+
+        if let Item::Mutable(item) = self {
+            if item.salt.len() > MAX_SALT_SIZE {
+                return Err(KrpcError::new(
+                    ERROR_SALT_TOO_BIG,
+                    "salt (salt field) too big",
+                ));
+            }
+            if !item.verify() {
+                return Err(KrpcError::new(ERROR_INVALID_SIGNATURE, "invalid signature"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether this item may replace `stored`, with `cas` the
+    /// sequence number the writer expects to replace.
+    pub fn replaces(&self, stored: Option<&Item>, cas: Option<i64>) -> Result<(), KrpcError> {
+        let (Item::Mutable(item), Some(Item::Mutable(stored))) = (self, stored) else {
+            return Ok(());
+        };
+
+        if cas.is_some_and(|cas| cas != stored.seq) {
+            return Err(KrpcError::new(ERROR_CAS_MISMATCH, "CAS mismatch"));
+        }
+        if item.seq < stored.seq || (item.seq == stored.seq && item.value != stored.value) {
+            return Err(KrpcError::new(
+                ERROR_SEQ_TOO_LOW,
+                "sequence number less than current",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use super::*;
+
+    fn test_key() -> SigningKey {
+        SigningKey::from_bytes(&rand::random())
+    }
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_bep44_vectors() {
+        let value = Bencode::String(b"Hello World!".to_vec());
+        assert_eq!(
+            hex::encode(immutable_target(&value).0),
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+        );
+        assert_eq!(
+            MutableItem::signable(b"", 1, &value),
+            b"3:seqi1e1:v12:Hello World!"
+        );
+
+        let key = bytes("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let item = MutableItem {
+            key,
+            salt: Vec::new(),
+            seq: 1,
+            value: value.clone(),
+            signature: bytes("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"),
+        };
+        assert_eq!(
+            hex::encode(item.target().0),
+            "4a533d47ec9c7d95b1ad75f576cffc641853b750"
+        );
+        assert!(item.verify());
+
+        let salted = MutableItem {
+            key,
+            salt: b"foobar".to_vec(),
+            seq: 1,
+            value,
+            signature: bytes("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"),
+        };
+        assert_eq!(
+            hex::encode(salted.target().0),
+            "411eba73b6f087ca51a3795d9c8c938d365e32c1"
+        );
+        assert!(salted.verify());
+        assert!(!MutableItem { seq: 2, ..salted }.verify());
+    }
+
+    #[test]
+    fn test_sign() {
+        let key = test_key();
+        let item = MutableItem::sign(&key, b"salt", 3, Bencode::Integer(7));
+        assert_eq!(item.key, key.verifying_key().to_bytes());
+        assert!(item.verify());
+    }
+
+    #[test]
+    fn test_validation() {
+        let key = test_key();
+        let mut item = MutableItem::sign(&key, b"salt", 5, Bencode::Integer(1));
+        assert_eq!(Item::Mutable(item.clone()).validate(), Ok(()));
+
+        item.seq = 6;
+        let error = Item::Mutable(item.clone()).validate().unwrap_err();
+        assert_eq!(error.code, ERROR_INVALID_SIGNATURE);
+
+        let big = Item::Immutable(Bencode::String(vec![0; MAX_VALUE_SIZE]));
+        assert_eq!(big.validate().unwrap_err().code, ERROR_VALUE_TOO_BIG);
+
+        let salty = MutableItem::sign(&key, &[0; 65], 1, Bencode::Integer(1));
+        assert_eq!(
+            Item::Mutable(salty).validate().unwrap_err().code,
+            ERROR_SALT_TOO_BIG
+        );
+    }
+
+    #[test]
+    fn test_replaces() {
+        let key = test_key();
+        let stored = Item::Mutable(MutableItem::sign(&key, b"", 5, Bencode::Integer(1)));
+        let newer = Item::Mutable(MutableItem::sign(&key, b"", 6, Bencode::Integer(2)));
+        let older = Item::Mutable(MutableItem::sign(&key, b"", 4, Bencode::Integer(2)));
+
+        assert_eq!(newer.replaces(Some(&stored), None), Ok(()));
+        assert_eq!(newer.replaces(Some(&stored), Some(5)), Ok(()));
+        assert_eq!(
+            newer.replaces(Some(&stored), Some(4)).unwrap_err().code,
+            ERROR_CAS_MISMATCH
+        );
+        assert_eq!(
+            older.replaces(Some(&stored), None).unwrap_err().code,
+            ERROR_SEQ_TOO_LOW
+        );
+        assert_eq!(stored.replaces(Some(&stored), None), Ok(()));
+    }
+
+    #[test]
+    fn test_canonical_encoding() {
+        let mut dict = IndexMap::new();
+        dict.insert(b"b".to_vec(), Bencode::Integer(2));
+        dict.insert(b"a".to_vec(), Bencode::Integer(1));
+        let value = Bencode::List(vec![Bencode::Dictionary(dict)]);
+
+        assert_eq!(encode_canonical(&value), b"ld1:ai1e1:bi2eee");
+    }
+}
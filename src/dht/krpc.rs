@@ -0,0 +1,708 @@
+use std::{fmt::Display, net::SocketAddr};
+
+use indexmap::IndexMap;
+
+use crate::{
+    bencode_decoder::Bencode,
+    peers::{decode_compact_peer, encode_compact_peer},
+};
+
+use super::{
+    decode_compact_nodes, encode_compact_nodes,
+    item::{Item, MutableItem},
+    NodeId, NodeInfo,
+};
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// The `e` list of a KRPC error message.
+#[derive(PartialEq, Debug, Clone)]
+pub struct KrpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl KrpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        KrpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn protocol(message: &str) -> Self {
+        KrpcError::new(ERROR_PROTOCOL, message)
+    }
+}
+
+impl Display for KrpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for KrpcError {}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+    },
+    GetPeers {
+        id: NodeId,
+        info_hash: NodeId,
+    },
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: NodeId,
+        port: u16,
+        /// Use the port the query came from instead of `port`.
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    /// Fetches a stored item (BEP 44). With `seq`, mutable items are only
+    /// returned if they are newer.
+    Get {
+        id: NodeId,
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    /// Stores an item (BEP 44). With `cas`, a mutable item is only replaced
+    /// if its sequence number is still `cas`.
+    Put {
+        id: NodeId,
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
+}
+
+type Dict = IndexMap<Vec<u8>, Bencode>;
+
+fn get_bytes<'a>(dict: &'a Dict, key: &str) -> Option<&'a [u8]> {
+    match dict.get(key.as_bytes()) {
+        Some(Bencode::String(value)) => Some(value),
+        _ => None,
+    }
+}
+
+fn get_integer(dict: &Dict, key: &str) -> Option<i64> {
+    match dict.get(key.as_bytes()) {
+        Some(Bencode::Integer(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+fn get_id(dict: &Dict, key: &str) -> Result<NodeId, KrpcError> {
+    get_bytes(dict, key)
+        .and_then(|id| id.try_into().ok())
+        .map(NodeId)
+        .ok_or_else(|| KrpcError::protocol(&format!("invalid {key}")))
+}
+
+fn insert_bytes(dict: &mut Dict, key: &str, value: &[u8]) {
+    dict.insert(key.as_bytes().to_vec(), Bencode::String(value.to_vec()));
+}
+
+impl Query {
+    pub fn id(&self) -> NodeId {
+        match self {
+            Query::Ping { id }
+            | Query::FindNode { id, .. }
+            | Query::GetPeers { id, .. }
+            | Query::AnnouncePeer { id, .. }
+            | Query::Get { id, .. }
+            | Query::Put { id, .. } => *id,
+        }
+    }
+
+    pub fn method(&self) -> &'static str {
+        match self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+        }
+    }
+
+    fn to_args(&self) -> Dict {
+        let mut args = IndexMap::new();
+        insert_bytes(&mut args, "id", &self.id().0);
+
+        match self {
+            Query::Ping { .. } => {}
+            Query::FindNode { target, .. } => insert_bytes(&mut args, "target", &target.0),
+            Query::GetPeers { info_hash, .. } => insert_bytes(&mut args, "info_hash", &info_hash.0),
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                ..
+            } => {
+                insert_bytes(&mut args, "info_hash", &info_hash.0);
+                if *implied_port {
+                    args.insert(b"implied_port".to_vec(), Bencode::Integer(1));
+                }
+                args.insert(b"port".to_vec(), Bencode::Integer((*port).into()));
+                insert_bytes(&mut args, "token", token);
+            }
+            Query::Get { target, seq, .. } => {
+                insert_bytes(&mut args, "target", &target.0);
+                if let Some(seq) = seq {
+                    args.insert(b"seq".to_vec(), Bencode::Integer(*seq));
+                }
+            }
+            Query::Put {
+                token, item, cas, ..
+            } => {
+                insert_bytes(&mut args, "token", token);
+                args.insert(b"v".to_vec(), item.value().clone());
+
+                if let Item::Mutable(item) = item {
+                    insert_bytes(&mut args, "k", &item.key);
+                    insert_bytes(&mut args, "sig", &item.signature);
+                    args.insert(b"seq".to_vec(), Bencode::Integer(item.seq));
+                    if !item.salt.is_empty() {
+                        insert_bytes(&mut args, "salt", &item.salt);
+                    }
+                }
+                if let Some(cas) = cas {
+                    args.insert(b"cas".to_vec(), Bencode::Integer(*cas));
+                }
+            }
+        }
+
+        args.sort_keys();
+        args
+    }
+
+    fn from_args(method: &[u8], args: &Dict) -> Result<Self, KrpcError> {
+        let id = get_id(args, "id")?;
+
+        match method {
+            b"ping" => Ok(Query::Ping { id }),
+            b"find_node" => Ok(Query::FindNode {
+                id,
+                target: get_id(args, "target")?,
+            }),
+            b"get_peers" => Ok(Query::GetPeers {
+                id,
+                info_hash: get_id(args, "info_hash")?,
+            }),
+            b"announce_peer" => {
+                let implied_port =
+                    get_integer(args, "implied_port").is_some_and(|value| value != 0);
+                // The port is ignored, and may be missing, with implied_port
+                let port = get_integer(args, "port").and_then(|port| u16::try_from(port).ok());
+
+                Ok(Query::AnnouncePeer {
+                    id,
+                    info_hash: get_id(args, "info_hash")?,
+                    port: match port {
+                        Some(port) => port,
+                        None if implied_port => 0,
+                        None => return Err(KrpcError::protocol("invalid port")),
+                    },
+                    implied_port,
+                    token: get_bytes(args, "token")
+                        .ok_or_else(|| KrpcError::protocol("missing token"))?
+                        .to_vec(),
+                })
+            }
+            b"get" => Ok(Query::Get {
+                id,
+                target: get_id(args, "target")?,
+                seq: get_integer(args, "seq"),
+            }),
+            b"put" => {
+                let value = args
+                    .get(b"v".as_slice())
+                    .ok_or_else(|| KrpcError::protocol("missing v"))?
+                    .clone();
+
+                let item = match get_bytes(args, "k") {
+                    None => Item::Immutable(value),
+                    Some(key) => Item::Mutable(MutableItem {
+                        key: key
+                            .try_into()
+                            .map_err(|_| KrpcError::protocol("invalid k"))?,
+                        salt: get_bytes(args, "salt").unwrap_or_default().to_vec(),
+                        seq: get_integer(args, "seq")
+                            .ok_or_else(|| KrpcError::protocol("missing seq"))?,
+                        value,
+                        signature: get_bytes(args, "sig")
+                            .and_then(|sig| sig.try_into().ok())
+                            .ok_or_else(|| KrpcError::protocol("invalid sig"))?,
+                    }),
+                };
+
+                Ok(Query::Put {
+                    id,
+                    token: get_bytes(args, "token")
+                        .ok_or_else(|| KrpcError::protocol("missing token"))?
+                        .to_vec(),
+                    item,
+                    cas: get_integer(args, "cas"),
+                })
+            }
+            _ => Err(KrpcError::new(ERROR_METHOD_UNKNOWN, "Method Unknown")),
+        }
+    }
+}
+
+/// The `r` dictionary of a response. Which fields are set depends on the
+/// query it answers.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Response {
+    pub id: NodeId,
+    /// Closest nodes of both address families, sent as `nodes` and `nodes6`.
+    pub nodes: Vec<NodeInfo>,
+    /// Peers of a `get_peers` response.
+    pub values: Vec<SocketAddr>,
+    /// Token to announce or put with, from a `get_peers` or `get` response.
+    pub token: Option<Vec<u8>>,
+    /// Stored value of a `get` response.
+    pub value: Option<Bencode>,
+    /// Public key, signature and sequence number of a mutable item. The
+    /// sequence number comes alone when the requester has the item already.
+    pub key: Option<[u8; 32]>,
+    pub signature: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Response {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+            value: None,
+            key: None,
+            signature: None,
+            seq: None,
+        }
+    }
+
+    /// The item a `get` response carries, checked against the target it was
+    /// asked for. Mutable items need the salt the target was made with.
+    pub fn item(&self, target: &NodeId, salt: &[u8]) -> Option<Item> {
+        let value = self.value.clone()?;
+
+        let item = match (self.key, self.signature, self.seq) {
+            (Some(key), Some(signature), Some(seq)) => {
+                let item = MutableItem {
+                    key,
+                    salt: salt.to_vec(),
+                    seq,
+                    value,
+                    signature,
+                };
+                if !item.verify() {
+                    return None;
+                }
+                Item::Mutable(item)
+            }
+            _ => Item::Immutable(value),
+        };
+
+        (item.target() == *target).then_some(item)
+    }
+
+    fn to_dict(&self) -> Dict {
+        let mut dict = IndexMap::new();
+        insert_bytes(&mut dict, "id", &self.id.0);
+
+        for (key, v6) in [("nodes", false), ("nodes6", true)] {
+            let nodes = encode_compact_nodes(&self.nodes, v6);
+            if !nodes.is_empty() {
+                insert_bytes(&mut dict, key, &nodes);
+            }
+        }
+        if let Some(token) = &self.token {
+            insert_bytes(&mut dict, "token", token);
+        }
+        if let Some(value) = &self.value {
+            dict.insert(b"v".to_vec(), value.clone());
+        }
+        if let Some(key) = &self.key {
+            insert_bytes(&mut dict, "k", key);
+        }
+        if let Some(signature) = &self.signature {
+            insert_bytes(&mut dict, "sig", signature);
+        }
+        if let Some(seq) = self.seq {
+            dict.insert(b"seq".to_vec(), Bencode::Integer(seq));
+        }
+        if !self.values.is_empty() {
+            dict.insert(
+                b"values".to_vec(),
+                Bencode::List(
+                    self.values
+                        .iter()
+                        .map(|peer| Bencode::String(encode_compact_peer(peer)))
+                        .collect(),
+                ),
+            );
+        }
+
+        dict.sort_keys();
+        dict
+    }
+
+    fn from_dict(dict: &Dict) -> Result<Self, KrpcError> {
+        let mut response = Response::new(get_id(dict, "id")?);
+
+        for (key, v6) in [("nodes", false), ("nodes6", true)] {
+            if let Some(nodes) = get_bytes(dict, key) {
+                response.nodes.extend(
+                    decode_compact_nodes(nodes, v6)
+                        .ok_or_else(|| KrpcError::protocol(&format!("invalid {key}")))?,
+                );
+            }
+        }
+
+        response.token = get_bytes(dict, "token").map(<[u8]>::to_vec);
+        response.value = dict.get(b"v".as_slice()).cloned();
+        response.key = get_bytes(dict, "k").and_then(|key| key.try_into().ok());
+        response.signature = get_bytes(dict, "sig").and_then(|sig| sig.try_into().ok());
+        response.seq = get_integer(dict, "seq");
+
+        if let Some(Bencode::List(values)) = dict.get(b"values".as_slice()) {
+            response.values = values
+                .iter()
+                .map(|value| match value {
+                    Bencode::String(peer) => decode_compact_peer(peer),
+                    _ => None,
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(|| KrpcError::protocol("invalid values"))?;
+        }
+
+        Ok(response)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error(KrpcError),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Message {
+    /// Transaction id chosen by the querying node and echoed in the reply.
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+/// A message that could not be decoded. Queries get `error` as reply when
+/// their transaction id is known.
+#[derive(PartialEq, Debug, Clone)]
+pub struct InvalidMessage {
+    pub transaction: Option<Vec<u8>>,
+    pub query: bool,
+    pub error: KrpcError,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = IndexMap::new();
+        insert_bytes(&mut dict, "t", &self.transaction);
+
+        match &self.body {
+            Body::Query(query) => {
+                dict.insert(b"a".to_vec(), Bencode::Dictionary(query.to_args()));
+                insert_bytes(&mut dict, "q", query.method().as_bytes());
+                insert_bytes(&mut dict, "y", b"q");
+            }
+            Body::Response(response) => {
+                dict.insert(b"r".to_vec(), Bencode::Dictionary(response.to_dict()));
+                insert_bytes(&mut dict, "y", b"r");
+            }
+            Body::Error(error) => {
+                dict.insert(
+                    b"e".to_vec(),
+                    Bencode::List(vec![
+                        Bencode::Integer(error.code),
+                        Bencode::String(error.message.as_bytes().to_vec()),
+                    ]),
+                );
+                insert_bytes(&mut dict, "y", b"e");
+            }
+        }
+
+        dict.sort_keys();
+        Bencode::Dictionary(dict).encode_value()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, InvalidMessage> {
+        let invalid = |transaction: Option<&[u8]>, query: bool, error: KrpcError| InvalidMessage {
+            transaction: transaction.map(<[u8]>::to_vec),
+            query,
+            error,
+        };
+
+        let Ok((Bencode::Dictionary(dict), _)) = Bencode::try_decode_value(bytes.to_vec()) else {
+            return Err(invalid(None, false, KrpcError::protocol("invalid message")));
+        };
+
+        let transaction = get_bytes(&dict, "t");
+        let kind = get_bytes(&dict, "y");
+        let query = kind == Some(b"q");
+
+        let Some(transaction) = transaction else {
+            return Err(invalid(None, query, KrpcError::protocol("missing t")));
+        };
+
+        let body = match kind {
+            Some(b"q") => {
+                let method = get_bytes(&dict, "q").unwrap_or_default();
+                let args = match dict.get(b"a".as_slice()) {
+                    Some(Bencode::Dictionary(args)) => args,
+                    _ => {
+                        return Err(invalid(
+                            Some(transaction),
+                            true,
+                            KrpcError::protocol("missing a"),
+                        ))
+                    }
+                };
+
+                Body::Query(
+                    Query::from_args(method, args)
+                        .map_err(|error| invalid(Some(transaction), true, error))?,
+                )
+            }
+            Some(b"r") => match dict.get(b"r".as_slice()) {
+                Some(Bencode::Dictionary(response)) => Body::Response(
+                    Response::from_dict(response)
+                        .map_err(|error| invalid(Some(transaction), false, error))?,
+                ),
+                _ => {
+                    return Err(invalid(
+                        Some(transaction),
+                        false,
+                        KrpcError::protocol("missing r"),
+                    ))
+                }
+            },
+            Some(b"e") => match dict.get(b"e".as_slice()) {
+                Some(Bencode::List(error)) => match error.as_slice() {
+                    [Bencode::Integer(code), Bencode::String(message), ..] => {
+                        Body::Error(KrpcError {
+                            code: *code,
+                            message: String::from_utf8_lossy(message).into_owned(),
+                        })
+                    }
+                    _ => {
+                        return Err(invalid(
+                            Some(transaction),
+                            false,
+                            KrpcError::protocol("invalid e"),
+                        ))
+                    }
+                },
+                _ => {
+                    return Err(invalid(
+                        Some(transaction),
+                        false,
+                        KrpcError::protocol("missing e"),
+                    ))
+                }
+            },
+            _ => {
+                return Err(invalid(
+                    Some(transaction),
+                    false,
+                    KrpcError::protocol("invalid y"),
+                ))
+            }
+        };
+
+        Ok(Message {
+            transaction: transaction.to_vec(),
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) {
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_bep5_examples() {
+        let ping = Message {
+            transaction: b"aa".to_vec(),
+            body: Body::Query(Query::Ping {
+                id: NodeId(*b"abcdefghij0123456789"),
+            }),
+        };
+        assert_eq!(
+            ping.encode(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+
+        let pong = Message::decode(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re").unwrap();
+        assert_eq!(
+            pong.body,
+            Body::Response(Response::new(NodeId(*b"mnopqrstuvwxyz123456")))
+        );
+
+        let error =
+            Message::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            error.body,
+            Body::Error(KrpcError::new(ERROR_GENERIC, "A Generic Error Ocurred"))
+        );
+        round_trip(error);
+
+        let announce = Message::decode(b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(
+            announce.body,
+            Body::Query(Query::AnnouncePeer {
+                id: NodeId(*b"abcdefghij0123456789"),
+                info_hash: NodeId(*b"mnopqrstuvwxyz123456"),
+                port: 6881,
+                implied_port: false,
+                token: b"aoeusnth".to_vec(),
+            })
+        );
+        round_trip(announce);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let id = NodeId([7; 20]);
+
+        round_trip(Message {
+            transaction: vec![0, 1],
+            body: Body::Query(Query::FindNode {
+                id,
+                target: NodeId([9; 20]),
+            }),
+        });
+        round_trip(Message {
+            transaction: vec![0, 2],
+            body: Body::Query(Query::AnnouncePeer {
+                id,
+                info_hash: NodeId([9; 20]),
+                port: 0,
+                implied_port: true,
+                token: vec![1, 2, 3],
+            }),
+        });
+        round_trip(Message {
+            transaction: vec![0, 3],
+            body: Body::Response(Response {
+                id,
+                nodes: vec![
+                    NodeInfo {
+                        id: NodeId([1; 20]),
+                        address: "10.0.0.1:1".parse().unwrap(),
+                    },
+                    NodeInfo {
+                        id: NodeId([2; 20]),
+                        address: "[::2]:2".parse().unwrap(),
+                    },
+                ],
+                values: vec!["10.0.0.3:3".parse().unwrap(), "[::4]:4".parse().unwrap()],
+                token: Some(b"token".to_vec()),
+                ..Response::new(id)
+            }),
+        });
+    }
+
+    #[test]
+    fn test_items() {
+        let id = NodeId([7; 20]);
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let item = MutableItem::sign(&key, b"salt", 4, Bencode::String(b"value".to_vec()));
+        let target = item.target();
+
+        round_trip(Message {
+            transaction: vec![1],
+            body: Body::Query(Query::Put {
+                id,
+                token: vec![9],
+                item: Item::Mutable(item.clone()),
+                cas: Some(3),
+            }),
+        });
+        round_trip(Message {
+            transaction: vec![2],
+            body: Body::Query(Query::Put {
+                id,
+                token: vec![9],
+                item: Item::Immutable(Bencode::Integer(5)),
+                cas: None,
+            }),
+        });
+        round_trip(Message {
+            transaction: vec![3],
+            body: Body::Query(Query::Get {
+                id,
+                target,
+                seq: Some(2),
+            }),
+        });
+
+        let response = Response {
+            value: Some(item.value.clone()),
+            key: Some(item.key),
+            signature: Some(item.signature),
+            seq: Some(item.seq),
+            ..Response::new(id)
+        };
+        round_trip(Message {
+            transaction: vec![4],
+            body: Body::Response(response.clone()),
+        });
+        assert_eq!(
+            response.item(&target, b"salt"),
+            Some(Item::Mutable(item.clone()))
+        );
+        // Wrong salt means a wrong signature and target
+        assert_eq!(response.item(&target, b"other"), None);
+
+        let immutable = Response {
+            value: Some(Bencode::Integer(5)),
+            ..Response::new(id)
+        };
+        let target = Item::Immutable(Bencode::Integer(5)).target();
+        assert!(immutable.item(&target, b"").is_some());
+        assert!(immutable.item(&NodeId([0; 20]), b"").is_none());
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let unknown = Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe")
+            .unwrap_err();
+        assert_eq!(unknown.transaction, Some(b"aa".to_vec()));
+        assert!(unknown.query);
+        assert_eq!(unknown.error.code, ERROR_METHOD_UNKNOWN);
+
+        let short_id = Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").unwrap_err();
+        assert_eq!(short_id.error.code, ERROR_PROTOCOL);
+
+        let garbage = Message::decode(b"i42e").unwrap_err();
+        assert_eq!(garbage.transaction, None);
+        assert!(!garbage.query);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{fmt::Display, io, net::SocketAddr};
+
+use crate::peers::{decode_compact_peer, encode_compact_peer, COMPACT_V4_SIZE, COMPACT_V6_SIZE};
+
+pub mod item;
+pub mod krpc;
+pub mod node;
+pub mod routing;
+
+/// Size of a compact IPv4 node: the node id followed by a compact peer.
+pub const COMPACT_NODE_V4_SIZE: usize = 20 + COMPACT_V4_SIZE;
+/// Size of a compact IPv6 node, as found in `nodes6`.
+pub const COMPACT_NODE_V6_SIZE: usize = 20 + COMPACT_V6_SIZE;
+
+/// A 160-bit node id, also used for the info hashes looked up in the DHT.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        NodeId(rand::random())
+    }
+
+    /// XOR distance, which compares like a big-endian number.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|index| self.0[index] ^ other.0[index])
+    }
+
+    /// Number of leading bits both ids have in common.
+    pub fn shared_prefix(&self, other: &NodeId) -> u32 {
+        let distance = self.distance(other);
+
+        match distance.iter().position(|byte| *byte != 0) {
+            Some(index) => index as u32 * 8 + distance[index].leading_zeros(),
+            None => 160,
+        }
+    }
+
+    /// A random id sharing exactly `bits` leading bits with this one.
+    pub fn random_at_distance(&self, bits: u32) -> Self {
+        let mut id = NodeId::random();
+        let bits = bits.min(159) as usize;
+
+        for bit in 0..=bits {
+            let mask = 0x80 >> (bit % 8);
+            let own = self.0[bit / 8] & mask;
+            // The bit after the shared prefix differs
+            let own = if bit == bits { own ^ mask } else { own };
+            id.0[bit / 8] = (id.0[bit / 8] & !mask) | own;
+        }
+
+        id
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+impl NodeInfo {
+    pub fn encode_compact(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend(encode_compact_peer(&self.address));
+        bytes
+    }
+
+    /// Decodes a single compact node of either 26 or 38 bytes.
+    pub fn decode_compact(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 20 {
+            return None;
+        }
+
+        Some(NodeInfo {
+            id: NodeId(bytes[..20].try_into().unwrap()),
+            address: decode_compact_peer(&bytes[20..])?,
+        })
+    }
+}
+
+/// Encodes the nodes of one address family as a `nodes` or `nodes6` string.
+pub fn encode_compact_nodes(nodes: &[NodeInfo], v6: bool) -> Vec<u8> {
+    nodes
+        .iter()
+        .filter(|node| node.address.is_ipv6() == v6)
+        .flat_map(NodeInfo::encode_compact)
+        .collect()
+}
+
+pub fn decode_compact_nodes(bytes: &[u8], v6: bool) -> Option<Vec<NodeInfo>> {
+    let entry_size = if v6 {
+        COMPACT_NODE_V6_SIZE
+    } else {
+        COMPACT_NODE_V4_SIZE
+    };
+
+    if !bytes.len().is_multiple_of(entry_size) {
+        return None;
+    }
+
+    bytes
+        .chunks(entry_size)
+        .map(NodeInfo::decode_compact)
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum DhtError {
+    Io(io::Error),
+    /// The node did not answer in time.
+    Timeout,
+    /// The node answered with a KRPC error.
+    Remote(krpc::KrpcError),
+    /// The node answered with something other than what was asked for.
+    InvalidResponse(String),
+    /// An item nodes would refuse to store.
+    InvalidItem(krpc::KrpcError),
+}
+
+impl Display for DhtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DhtError::Io(error) => write!(f, "{error}"),
+            DhtError::Timeout => write!(f, "query timed out"),
+            DhtError::Remote(error) => write!(f, "node replied with an error: {error}"),
+            DhtError::InvalidResponse(reason) => write!(f, "invalid response: {reason}"),
+            DhtError::InvalidItem(error) => write!(f, "invalid item: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for DhtError {}
+
+impl From<io::Error> for DhtError {
+    fn from(error: io::Error) -> Self {
+        DhtError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        let zero = NodeId([0; 20]);
+        let mut other = [0; 20];
+        other[2] = 0x10;
+
+        assert_eq!(zero.shared_prefix(&NodeId(other)), 19);
+        assert_eq!(zero.shared_prefix(&zero), 160);
+        assert_eq!(zero.distance(&NodeId(other)), other);
+
+        let id = NodeId::random();
+        for bits in [0, 7, 8, 100, 159] {
+            assert_eq!(id.shared_prefix(&id.random_at_distance(bits)), bits);
+        }
+    }
+
+    #[test]
+    fn test_compact_nodes() {
+        let nodes = vec![
+            NodeInfo {
+                id: NodeId([1; 20]),
+                address: "10.0.0.1:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: NodeId([2; 20]),
+                address: "[::1]:6881".parse().unwrap(),
+            },
+        ];
+
+        let v4 = encode_compact_nodes(&nodes, false);
+        assert_eq!(v4.len(), COMPACT_NODE_V4_SIZE);
+        assert_eq!(decode_compact_nodes(&v4, false).unwrap(), nodes[..1]);
+
+        let v6 = encode_compact_nodes(&nodes, true);
+        assert_eq!(decode_compact_nodes(&v6, true).unwrap(), nodes[1..]);
+        assert_eq!(decode_compact_nodes(&v6, false), None);
+    }
+}
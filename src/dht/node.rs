@@ -0,0 +1,846 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::bencode_decoder::Bencode;
+
+use super::{
+    item::{immutable_target, mutable_target, Item, MutableItem},
+    krpc::{Body, KrpcError, Message, Query, Response, ERROR_PROTOCOL},
+    routing::{RoutingTable, K},
+    DhtError, NodeId, NodeInfo,
+};
+
+/// Queries a lookup has in flight at once.
+pub const ALPHA: usize = 3;
+/// Tokens are handed out with the current secret, and accepted with the
+/// previous one too, so they stay valid for up to twice this long.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are dropped after this long.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Most peers returned in one `get_peers` response.
+const MAX_VALUES: usize = 50;
+/// Stored items are dropped after this long unless put again.
+const ITEM_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_LOOKUP_ROUNDS: usize = 32;
+/// How often the receiving thread checks whether the node was dropped.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct DhtOptions {
+    /// How long to wait for a reply before counting a query as failed.
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtOptions {
+    fn default() -> Self {
+        DhtOptions {
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+struct Tokens {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new(now: Instant) -> Self {
+        Tokens {
+            current: rand::random(),
+            previous: rand::random(),
+            rotated: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = now;
+        }
+    }
+
+    fn token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+        let mut hasher = sha1_smol::Sha1::new();
+        hasher.update(secret);
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => hasher.update(&ip.octets()),
+            IpAddr::V6(ip) => hasher.update(&ip.octets()),
+        }
+        hasher.digest().bytes()[..8].to_vec()
+    }
+
+    fn generate(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        Tokens::token(&self.current, ip)
+    }
+
+    fn validate(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        token == Tokens::token(&self.current, ip) || token == Tokens::token(&self.previous, ip)
+    }
+}
+
+type Reply = (SocketAddr, Result<Response, DhtError>);
+
+struct Pending {
+    address: SocketAddr,
+    reply: mpsc::Sender<Reply>,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    id: NodeId,
+    options: DhtOptions,
+    table: Mutex<RoutingTable>,
+    tokens: Mutex<Tokens>,
+    peers: Mutex<HashMap<NodeId, HashMap<SocketAddr, Instant>>>,
+    items: Mutex<HashMap<NodeId, (Item, Instant)>>,
+    pending: Mutex<HashMap<Vec<u8>, Pending>>,
+    next_transaction: AtomicU16,
+    stopped: AtomicBool,
+}
+
+/// A DHT node. A background thread answers queries from other nodes and
+/// routes replies to the queries this node makes, which block the calling
+/// thread until they are answered or time out. The thread stops once the
+/// node is dropped.
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+impl Dht {
+    /// Starts a node with a random id and an empty routing table.
+    pub fn bind(address: impl ToSocketAddrs, options: DhtOptions) -> io::Result<Self> {
+        let table = RoutingTable::new(NodeId::random(), Instant::now());
+        Dht::new(UdpSocket::bind(address)?, table, options)
+    }
+
+    /// Starts a node on `socket`, taking its id from `table`, which may have
+    /// been loaded from an earlier session.
+    pub fn new(socket: UdpSocket, table: RoutingTable, options: DhtOptions) -> io::Result<Self> {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let inner = Arc::new(Inner {
+            socket,
+            id: table.id(),
+            options,
+            table: Mutex::new(table),
+            tokens: Mutex::new(Tokens::new(Instant::now())),
+            peers: Mutex::new(HashMap::new()),
+            items: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            stopped: AtomicBool::new(false),
+        });
+
+        let receiver = inner.clone();
+        thread::spawn(move || receiver.run());
+
+        Ok(Dht { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    pub fn routing_table(&self) -> RoutingTable {
+        self.inner.table.lock().unwrap().clone()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.routing_table().save(path)
+    }
+
+    /// Sends a single query and waits for its reply.
+    pub fn query(&self, address: SocketAddr, query: Query) -> Result<Response, DhtError> {
+        self.inner
+            .query_all(vec![(address, query)])
+            .pop()
+            .map(|(_, result)| result)
+            .unwrap_or(Err(DhtError::Timeout))
+    }
+
+    pub fn ping(&self, address: SocketAddr) -> Result<NodeId, DhtError> {
+        let response = self.query(address, Query::Ping { id: self.id() })?;
+        Ok(response.id)
+    }
+
+    pub fn find_node(
+        &self,
+        address: SocketAddr,
+        target: NodeId,
+    ) -> Result<Vec<NodeInfo>, DhtError> {
+        let response = self.query(
+            address,
+            Query::FindNode {
+                id: self.id(),
+                target,
+            },
+        )?;
+        Ok(response.nodes)
+    }
+
+    /// Joins the DHT through nodes of known address, then looks up our own
+    /// id to fill the routing table. Returns the size of the table.
+    pub fn bootstrap(&self, nodes: &[SocketAddr]) -> usize {
+        let target = self.id();
+        self.inner.query_all(
+            nodes
+                .iter()
+                .map(|address| (*address, Query::FindNode { id: target, target }))
+                .collect(),
+        );
+
+        self.lookup(target);
+        self.inner.table.lock().unwrap().len()
+    }
+
+    /// The nodes closest to `target` that answered an iterative lookup,
+    /// closest first.
+    pub fn lookup(&self, target: NodeId) -> Vec<NodeInfo> {
+        let id = self.id();
+        self.inner
+            .iterate(target, || Query::FindNode { id, target })
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect()
+    }
+
+    /// Peers of `info_hash` announced to the nodes closest to it.
+    pub fn get_peers(&self, info_hash: NodeId) -> Vec<SocketAddr> {
+        let id = self.id();
+        let responses = self
+            .inner
+            .iterate(info_hash, || Query::GetPeers { id, info_hash });
+
+        values(&responses)
+    }
+
+    /// Announces that we are a peer of `info_hash` to the nodes closest to
+    /// it, on `port` or on the DHT port if `None`. Returns the peers found
+    /// along the way.
+    pub fn announce(&self, info_hash: NodeId, port: Option<u16>) -> Vec<SocketAddr> {
+        let id = self.id();
+        let responses = self
+            .inner
+            .iterate(info_hash, || Query::GetPeers { id, info_hash });
+
+        self.inner.query_all(
+            responses
+                .iter()
+                .filter_map(|(node, response)| {
+                    let query = Query::AnnouncePeer {
+                        id,
+                        info_hash,
+                        port: port.unwrap_or_default(),
+                        implied_port: port.is_none(),
+                        token: response.token.clone()?,
+                    };
+                    Some((node.address, query))
+                })
+                .collect(),
+        );
+
+        values(&responses)
+    }
+
+    /// Periodic upkeep: pings nodes not heard from in a while, looks up
+    /// targets in stale buckets and forgets expired peers.
+    pub fn refresh(&self) {
+        let now = Instant::now();
+        let (questionable, targets) = {
+            let table = self.inner.table.lock().unwrap();
+            (table.questionable(now), table.refresh_targets(now))
+        };
+
+        let id = self.id();
+        self.inner.query_all(
+            questionable
+                .into_iter()
+                .map(|node| (node.address, Query::Ping { id }))
+                .collect(),
+        );
+
+        for target in targets {
+            self.lookup(target);
+        }
+
+        let mut peers = self.inner.peers.lock().unwrap();
+        for swarm in peers.values_mut() {
+            swarm.retain(|_, announced| now.duration_since(*announced) < PEER_TIMEOUT);
+        }
+        peers.retain(|_, swarm| !swarm.is_empty());
+
+        self.inner
+            .items
+            .lock()
+            .unwrap()
+            .retain(|_, (_, stored)| now.duration_since(*stored) < ITEM_TIMEOUT);
+    }
+
+    /// Fetches an immutable item by the hash of its value.
+    pub fn get_immutable(&self, target: NodeId) -> Option<Bencode> {
+        self.get(target, b"")
+            .into_iter()
+            .find_map(|item| match item {
+                Item::Immutable(value) => Some(value),
+                Item::Mutable(_) => None,
+            })
+    }
+
+    /// Fetches the newest version of a mutable item.
+    pub fn get_mutable(&self, key: &[u8; 32], salt: &[u8]) -> Option<MutableItem> {
+        self.get(mutable_target(key, salt), salt)
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::Mutable(item) => Some(item),
+                Item::Immutable(_) => None,
+            })
+            .max_by_key(|item| item.seq)
+    }
+
+    /// Stores an immutable item on the nodes closest to its hash, which is
+    /// returned along with the number of nodes that stored it.
+    pub fn put_immutable(&self, value: Bencode) -> Result<(NodeId, usize), DhtError> {
+        let target = immutable_target(&value);
+        Ok((target, self.put(Item::Immutable(value), None)?))
+    }
+
+    /// Stores a signed mutable item on the nodes closest to its target. With
+    /// `cas`, nodes only replace the version with that sequence number.
+    /// Returns the number of nodes that stored it.
+    pub fn put_mutable(&self, item: MutableItem, cas: Option<i64>) -> Result<usize, DhtError> {
+        self.put(Item::Mutable(item), cas)
+    }
+
+    fn get(&self, target: NodeId, salt: &[u8]) -> Vec<Item> {
+        let id = self.id();
+        self.inner
+            .iterate(target, || Query::Get {
+                id,
+                target,
+                seq: None,
+            })
+            .into_iter()
+            .filter_map(|(_, response)| response.item(&target, salt))
+            .collect()
+    }
+
+    /// Puts to every close node that handed out a token. Fails with the
+    /// first error when no node stored the item.
+    fn put(&self, item: Item, cas: Option<i64>) -> Result<usize, DhtError> {
+        item.validate().map_err(DhtError::InvalidItem)?;
+
+        let id = self.id();
+        let target = item.target();
+        let responses = self.inner.iterate(target, || Query::Get {
+            id,
+            target,
+            seq: None,
+        });
+
+        let results = self.inner.query_all(
+            responses
+                .into_iter()
+                .filter_map(|(node, response)| {
+                    let query = Query::Put {
+                        id,
+                        token: response.token?,
+                        item: item.clone(),
+                        cas,
+                    };
+                    Some((node.address, query))
+                })
+                .collect(),
+        );
+
+        let stored = results.iter().filter(|(_, result)| result.is_ok()).count();
+        if stored > 0 {
+            return Ok(stored);
+        }
+
+        Err(results
+            .into_iter()
+            .find_map(|(_, result)| result.err())
+            .unwrap_or(DhtError::Timeout))
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.inner.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+fn values(responses: &[(NodeInfo, Response)]) -> Vec<SocketAddr> {
+    let mut seen = HashSet::new();
+    responses
+        .iter()
+        .flat_map(|(_, response)| response.values.iter().copied())
+        .filter(|peer| seen.insert(*peer))
+        .collect()
+}
+
+impl Inner {
+    fn run(&self) {
+        let mut buffer = [0; 65536];
+
+        while !self.stopped.load(Ordering::Relaxed) {
+            // Timeouts only wake us up to check for shutdown, and errors
+            // caused by a single datagram do not affect the next ones
+            let Ok((length, from)) = self.socket.recv_from(&mut buffer) else {
+                continue;
+            };
+
+            match Message::decode(&buffer[..length]) {
+                Ok(Message {
+                    transaction,
+                    body: Body::Query(query),
+                }) => {
+                    let body = self.handle_query(from, query);
+                    self.send(from, &Message { transaction, body });
+                }
+                Ok(Message {
+                    transaction,
+                    body: Body::Response(response),
+                }) => self.complete(from, &transaction, Ok(response)),
+                Ok(Message {
+                    transaction,
+                    body: Body::Error(error),
+                }) => self.complete(from, &transaction, Err(DhtError::Remote(error))),
+                Err(invalid) => {
+                    if let (Some(transaction), true) = (invalid.transaction, invalid.query) {
+                        let body = Body::Error(invalid.error);
+                        self.send(from, &Message { transaction, body });
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&self, address: SocketAddr, message: &Message) {
+        // Lost datagrams are handled by timeouts
+        let _ = self.socket.send_to(&message.encode(), address);
+    }
+
+    fn handle_query(&self, from: SocketAddr, query: Query) -> Body {
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        table.insert(
+            NodeInfo {
+                id: query.id(),
+                address: from,
+            },
+            now,
+        );
+
+        let mut response = Response::new(self.id);
+        match query {
+            Query::Ping { .. } => {}
+            Query::FindNode { target, .. } => response.nodes = table.closest(&target, K),
+            Query::GetPeers { info_hash, .. } => {
+                response.nodes = table.closest(&info_hash, K);
+                response.token = Some(self.tokens.lock().unwrap().generate(from.ip(), now));
+
+                if let Some(swarm) = self.peers.lock().unwrap().get(&info_hash) {
+                    response.values = swarm
+                        .iter()
+                        .filter(|(_, announced)| now.duration_since(**announced) < PEER_TIMEOUT)
+                        .map(|(peer, _)| *peer)
+                        .collect();
+                    response.values.shuffle(&mut rand::thread_rng());
+                    response.values.truncate(MAX_VALUES);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                ..
+            } => {
+                if !self.tokens.lock().unwrap().validate(from.ip(), &token, now) {
+                    return Body::Error(KrpcError::new(ERROR_PROTOCOL, "bad token"));
+                }
+
+                let port = if implied_port { from.port() } else { port };
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .entry(info_hash)
+                    .or_default()
+                    .insert(SocketAddr::new(from.ip(), port), now);
+            }
+            Query::Get { target, seq, .. } => {
+                response.nodes = table.closest(&target, K);
+                response.token = Some(self.tokens.lock().unwrap().generate(from.ip(), now));
+
+                match self.items.lock().unwrap().get(&target) {
+                    Some((Item::Immutable(value), _)) => response.value = Some(value.clone()),
+                    // The requester has this version already
+                    Some((Item::Mutable(item), _)) if seq.is_some_and(|seq| seq >= item.seq) => {
+                        response.seq = Some(item.seq)
+                    }
+                    Some((Item::Mutable(item), _)) => {
+                        response.value = Some(item.value.clone());
+                        response.key = Some(item.key);
+                        response.signature = Some(item.signature);
+                        response.seq = Some(item.seq);
+                    }
+                    None => {}
+                }
+            }
+            Query::Put {
+                token, item, cas, ..
+            } => {
+                if !self.tokens.lock().unwrap().validate(from.ip(), &token, now) {
+                    return Body::Error(KrpcError::new(ERROR_PROTOCOL, "bad token"));
+                }
+
+                let mut items = self.items.lock().unwrap();
+                let target = item.target();
+                let stored = items.get(&target).map(|(stored, _)| stored);
+
+                if let Err(error) = item.validate().and_then(|_| item.replaces(stored, cas)) {
+                    return Body::Error(error);
+                }
+
+                items.insert(target, (item, now));
+            }
+        }
+
+        Body::Response(response)
+    }
+
+    /// Hands a reply to the query waiting for it. Replies from another
+    /// address than the query went to are ignored.
+    fn complete(&self, from: SocketAddr, transaction: &[u8], result: Result<Response, DhtError>) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending
+            .get(transaction)
+            .is_none_or(|pending| pending.address != from)
+        {
+            return;
+        }
+        let pending = pending.remove(transaction).unwrap();
+
+        if let Ok(response) = &result {
+            self.table.lock().unwrap().insert(
+                NodeInfo {
+                    id: response.id,
+                    address: from,
+                },
+                Instant::now(),
+            );
+        }
+
+        let _ = pending.reply.send((from, result));
+    }
+
+    /// Sends all queries at once, and waits for their replies until the
+    /// query timeout. Unanswered queries count as failures of their node.
+    fn query_all(&self, queries: Vec<(SocketAddr, Query)>) -> Vec<Reply> {
+        let (sender, receiver) = mpsc::channel();
+        let mut results = Vec::new();
+        let mut waiting = Vec::new();
+
+        for (address, query) in queries {
+            let transaction = self
+                .next_transaction
+                .fetch_add(1, Ordering::Relaxed)
+                .to_be_bytes()
+                .to_vec();
+            self.pending.lock().unwrap().insert(
+                transaction.clone(),
+                Pending {
+                    address,
+                    reply: sender.clone(),
+                },
+            );
+
+            let message = Message {
+                transaction: transaction.clone(),
+                body: Body::Query(query),
+            };
+            match self.socket.send_to(&message.encode(), address) {
+                Ok(_) => waiting.push((transaction, address)),
+                Err(error) => {
+                    self.pending.lock().unwrap().remove(&transaction);
+                    results.push((address, Err(error.into())));
+                }
+            }
+        }
+
+        let deadline = Instant::now() + self.options.query_timeout;
+        for _ in 0..waiting.len() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(left) {
+                Ok(reply) => results.push(reply),
+                Err(_) => break,
+            }
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        for (transaction, address) in waiting {
+            if pending.remove(&transaction).is_some() {
+                self.table.lock().unwrap().failed(&address);
+                results.push((address, Err(DhtError::Timeout)));
+            }
+        }
+        // Replies that arrived after the deadline but before the cleanup
+        results.extend(receiver.try_iter());
+
+        results
+    }
+
+    /// Iterative Kademlia lookup: keeps querying the closest nodes heard of
+    /// until the `K` closest ones all answered or failed. Returns the nodes
+    /// that answered with their responses, closest first.
+    fn iterate(&self, target: NodeId, query: impl Fn() -> Query) -> Vec<(NodeInfo, Response)> {
+        let v6 = self
+            .socket
+            .local_addr()
+            .is_ok_and(|address| address.is_ipv6());
+        let mut candidates = self.table.lock().unwrap().closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut responded: Vec<(NodeInfo, Response)> = Vec::new();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            candidates.sort_by_key(|node| node.id.distance(&target));
+
+            let next: Vec<(SocketAddr, Query)> = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.address))
+                .take(ALPHA)
+                .map(|node| (node.address, query()))
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+            queried.extend(next.iter().map(|(address, _)| *address));
+
+            for (address, result) in self.query_all(next) {
+                let Ok(response) = result else {
+                    candidates.retain(|node| node.address != address);
+                    continue;
+                };
+
+                // Nodes are known by the id they answer with
+                for node in candidates.iter_mut().filter(|node| node.address == address) {
+                    node.id = response.id;
+                }
+
+                for node in &response.nodes {
+                    if node.id != self.id
+                        && node.address.is_ipv6() == v6
+                        && !candidates.iter().any(|known| known.address == node.address)
+                    {
+                        candidates.push(*node);
+                    }
+                }
+
+                responded.push((
+                    NodeInfo {
+                        id: response.id,
+                        address,
+                    },
+                    response,
+                ));
+            }
+        }
+
+        responded.sort_by_key(|(node, _)| node.id.distance(&target));
+        responded.truncate(K);
+        responded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> DhtOptions {
+        DhtOptions {
+            query_timeout: Duration::from_millis(500),
+        }
+    }
+
+    fn start() -> Dht {
+        Dht::bind("127.0.0.1:0", options()).unwrap()
+    }
+
+    #[test]
+    fn test_tokens() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let token = tokens.generate(ip, now);
+        assert!(tokens.validate(ip, &token, now));
+        assert!(!tokens.validate("10.0.0.2".parse().unwrap(), &token, now));
+
+        // Still valid after one rotation, but not after two
+        assert!(tokens.validate(ip, &token, now + TOKEN_ROTATION));
+        assert!(!tokens.validate(ip, &token, now + TOKEN_ROTATION * 2));
+    }
+
+    #[test]
+    fn test_ping() {
+        let a = start();
+        let b = start();
+
+        assert_eq!(a.ping(b.local_addr().unwrap()).unwrap(), b.id());
+        assert_eq!(a.routing_table().nodes()[0].id, b.id());
+        assert_eq!(b.routing_table().nodes()[0].id, a.id());
+
+        // Nobody listens on the port of a dropped node
+        let address = b.local_addr().unwrap();
+        drop(b);
+        thread::sleep(POLL_INTERVAL * 2);
+        assert!(matches!(a.ping(address), Err(DhtError::Timeout)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let node = start();
+        let address = node.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut buffer = [0; 1500];
+        let mut exchange = |request: &[u8]| {
+            socket.send_to(request, address).unwrap();
+            let (length, _) = socket.recv_from(&mut buffer).unwrap();
+            Message::decode(&buffer[..length]).unwrap()
+        };
+
+        let reply = exchange(b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:xy1:y1:qe");
+        assert_eq!(reply.transaction, b"xy");
+        assert!(matches!(
+            reply.body,
+            Body::Error(KrpcError { code: 204, .. })
+        ));
+
+        let other = start();
+        let result = other.query(
+            address,
+            Query::AnnouncePeer {
+                id: other.id(),
+                info_hash: NodeId([1; 20]),
+                port: 6881,
+                implied_port: false,
+                token: b"forged".to_vec(),
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(DhtError::Remote(KrpcError { code: 203, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_network() {
+        let nodes: Vec<Dht> = (0..12).map(|_| start()).collect();
+        let bootstrap = nodes[0].local_addr().unwrap();
+
+        for node in &nodes[1..] {
+            assert!(node.bootstrap(&[bootstrap]) > 0);
+        }
+
+        // Every node is found by a lookup from the last one to join
+        let target = nodes[4].id();
+        let found = nodes[11].lookup(target);
+        assert_eq!(found[0].id, target);
+
+        let info_hash = NodeId::random();
+        assert!(nodes[3].announce(info_hash, Some(6881)).is_empty());
+        assert!(nodes[5]
+            .announce(info_hash, None)
+            .contains(&"127.0.0.1:6881".parse().unwrap()));
+
+        let peers = nodes[8].get_peers(info_hash);
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains(&nodes[5].local_addr().unwrap()));
+
+        // A node restarted from its saved table joins again without bootstrap
+        let path = std::env::temp_dir().join(format!("dht-{}.dat", nodes[7].id()));
+        nodes[7].save(&path).unwrap();
+        let table = RoutingTable::load(&path, Instant::now()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let restarted =
+            Dht::new(UdpSocket::bind("127.0.0.1:0").unwrap(), table, options()).unwrap();
+        assert_eq!(restarted.id(), nodes[7].id());
+        assert_eq!(restarted.get_peers(info_hash).len(), 2);
+    }
+
+    #[test]
+    fn test_items() {
+        let nodes: Vec<Dht> = (0..10).map(|_| start()).collect();
+        let bootstrap = nodes[0].local_addr().unwrap();
+        for node in &nodes[1..] {
+            node.bootstrap(&[bootstrap]);
+        }
+
+        let value = Bencode::String(b"latest release".to_vec());
+        let (target, stored) = nodes[2].put_immutable(value.clone()).unwrap();
+        assert!(stored > 0);
+        assert_eq!(nodes[7].get_immutable(target), Some(value));
+        assert_eq!(nodes[7].get_immutable(NodeId::random()), None);
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let public = key.verifying_key().to_bytes();
+        let feed = |seq: i64, value: &str| {
+            MutableItem::sign(&key, b"feed", seq, Bencode::String(value.into()))
+        };
+
+        assert!(nodes[3].put_mutable(feed(1, "first"), None).unwrap() > 0);
+        assert_eq!(
+            nodes[8].get_mutable(&public, b"feed"),
+            Some(feed(1, "first"))
+        );
+        assert_eq!(nodes[8].get_mutable(&public, b"other"), None);
+
+        assert!(nodes[4].put_mutable(feed(2, "second"), Some(1)).unwrap() > 0);
+        assert!(matches!(
+            nodes[4].put_mutable(feed(3, "third"), Some(1)),
+            Err(DhtError::Remote(KrpcError { code: 301, .. }))
+        ));
+        assert!(matches!(
+            nodes[5].put_mutable(feed(1, "stale"), None),
+            Err(DhtError::Remote(KrpcError { code: 302, .. }))
+        ));
+        assert_eq!(
+            nodes[9].get_mutable(&public, b"feed"),
+            Some(feed(2, "second"))
+        );
+
+        let mut forged = feed(4, "forged");
+        forged.value = Bencode::String(b"tampered".to_vec());
+        assert!(matches!(
+            nodes[5].put_mutable(forged, None),
+            Err(DhtError::InvalidItem(KrpcError { code: 206, .. }))
+        ));
+    }
+}
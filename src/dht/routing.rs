@@ -0,0 +1,372 @@
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use indexmap::IndexMap;
+
+use crate::bencode_decoder::Bencode;
+
+use super::{decode_compact_nodes, encode_compact_nodes, NodeId, NodeInfo};
+
+/// Number of nodes per bucket.
+pub const K: usize = 8;
+/// Nodes that failed to answer this many queries in a row are bad, and
+/// replaced by the next node that fits their bucket.
+pub const MAX_FAILURES: u32 = 3;
+/// Buckets without changes for this long should be refreshed.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+#[derive(Clone)]
+struct Bucket {
+    entries: Vec<Entry>,
+    last_changed: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Bucket {
+            entries: Vec::new(),
+            last_changed: now,
+        }
+    }
+}
+
+/// Kademlia routing table. Bucket `i` holds the nodes sharing exactly `i`
+/// leading bits with our id, except for the last bucket which also holds
+/// every closer node. Only that last bucket is split when it overflows.
+#[derive(Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId, now: Instant) -> Self {
+        RoutingTable {
+            id,
+            buckets: vec![Bucket::new(now)],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter().map(|entry| entry.node))
+            .collect()
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        (self.id.shared_prefix(id) as usize).min(self.buckets.len() - 1)
+    }
+
+    /// Adds a node that was just heard from, or refreshes it. Returns whether
+    /// the node is in the table afterwards.
+    pub fn insert(&mut self, node: NodeInfo, now: Instant) -> bool {
+        if node.id == self.id {
+            return false;
+        }
+
+        let index = self.bucket_index(&node.id);
+        let last = index == self.buckets.len() - 1;
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket
+            .entries
+            .iter_mut()
+            .find(|entry| entry.node.id == node.id)
+        {
+            // Another address claiming a known id is not trusted
+            if entry.node.address != node.address {
+                return false;
+            }
+
+            entry.last_seen = now;
+            entry.failures = 0;
+            bucket.last_changed = now;
+            return true;
+        }
+
+        let entry = Entry {
+            node,
+            last_seen: now,
+            failures: 0,
+        };
+
+        if bucket.entries.len() < K {
+            bucket.entries.push(entry);
+            bucket.last_changed = now;
+            return true;
+        }
+
+        if let Some(bad) = bucket.entries.iter_mut().find(|entry| entry.is_bad()) {
+            *bad = entry;
+            bucket.last_changed = now;
+            return true;
+        }
+
+        if last && self.buckets.len() < 160 {
+            self.split(now);
+            return self.insert(node, now);
+        }
+
+        false
+    }
+
+    fn split(&mut self, now: Instant) {
+        let index = self.buckets.len() - 1;
+        let (stay, moved) = std::mem::take(&mut self.buckets[index].entries)
+            .into_iter()
+            .partition(|entry| self.id.shared_prefix(&entry.node.id) as usize == index);
+
+        self.buckets[index].entries = stay;
+        self.buckets.push(Bucket {
+            entries: moved,
+            last_changed: now,
+        });
+    }
+
+    /// Records a query the node at `address` did not answer.
+    pub fn failed(&mut self, address: &std::net::SocketAddr) {
+        for bucket in &mut self.buckets {
+            for entry in &mut bucket.entries {
+                if entry.node.address == *address {
+                    entry.failures += 1;
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        let index = self.bucket_index(id);
+        self.buckets[index]
+            .entries
+            .retain(|entry| entry.node.id != *id);
+    }
+
+    /// The `count` nodes closest to `target`, closest first, leaving out bad
+    /// nodes.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect();
+
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes not heard from within the refresh interval, to be pinged.
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .filter(|entry| now.duration_since(entry.last_seen) >= REFRESH_INTERVAL)
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    /// A random target inside every bucket that did not change within the
+    /// refresh interval, to look up.
+    pub fn refresh_targets(&self, now: Instant) -> Vec<NodeId> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| now.duration_since(bucket.last_changed) >= REFRESH_INTERVAL)
+            .map(|(index, _)| self.id.random_at_distance(index as u32))
+            .collect()
+    }
+
+    pub fn to_bencode(&self) -> Bencode {
+        let nodes = self.nodes();
+
+        let mut dict = IndexMap::new();
+        dict.insert(b"id".to_vec(), Bencode::String(self.id.0.to_vec()));
+        dict.insert(
+            b"nodes".to_vec(),
+            Bencode::String(encode_compact_nodes(&nodes, false)),
+        );
+        dict.insert(
+            b"nodes6".to_vec(),
+            Bencode::String(encode_compact_nodes(&nodes, true)),
+        );
+
+        Bencode::Dictionary(dict)
+    }
+
+    /// Rebuilds a saved table. The nodes count as just seen, and get dropped
+    /// by the usual failure counting if they are gone.
+    pub fn from_bencode(value: &Bencode, now: Instant) -> Option<Self> {
+        let Bencode::Dictionary(dict) = value else {
+            return None;
+        };
+
+        let bytes = |key: &[u8]| match dict.get(key) {
+            Some(Bencode::String(value)) => Some(value.as_slice()),
+            _ => None,
+        };
+
+        let mut table = RoutingTable::new(NodeId(bytes(b"id")?.try_into().ok()?), now);
+        for (key, v6) in [(b"nodes".as_slice(), false), (b"nodes6", true)] {
+            for node in decode_compact_nodes(bytes(key).unwrap_or_default(), v6)? {
+                table.insert(node, now);
+            }
+        }
+
+        Some(table)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_bencode().encode_value())
+    }
+
+    pub fn load(path: &Path, now: Instant) -> io::Result<Self> {
+        let content = std::fs::read(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid routing table");
+
+        let (value, _) = Bencode::try_decode_value(content).map_err(|_| invalid())?;
+        RoutingTable::from_bencode(&value, now).ok_or_else(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn node(id: NodeId, port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    #[test]
+    fn test_buckets_split() {
+        let now = Instant::now();
+        let own = NodeId::random();
+        let mut table = RoutingTable::new(own, now);
+
+        // Far nodes only fill the first bucket
+        for port in 0..20 {
+            table.insert(node(own.random_at_distance(0), port), now);
+        }
+        assert_eq!(table.len(), K);
+
+        // Closer nodes split the bucket holding our id
+        for bits in 1..40 {
+            assert!(table.insert(node(own.random_at_distance(bits), bits as u16), now));
+        }
+        assert_eq!(table.len(), K + 39);
+        assert!(!table.insert(node(own.random_at_distance(0), 100), now));
+
+        let target = own.random_at_distance(30);
+        let closest = table.closest(&target, K);
+        assert_eq!(closest.len(), K);
+        assert!(closest
+            .windows(2)
+            .all(|pair| pair[0].id.distance(&target) <= pair[1].id.distance(&target)));
+        assert!(closest[0].id.shared_prefix(&target) > 30);
+    }
+
+    #[test]
+    fn test_bad_nodes_replaced() {
+        let now = Instant::now();
+        let own = NodeId::random();
+        let mut table = RoutingTable::new(own, now);
+
+        // Fill the bucket of far nodes, after splitting off our own
+        table.insert(node(own.random_at_distance(5), 999), now);
+        let far: Vec<NodeInfo> = (0..K as u16)
+            .map(|port| node(own.random_at_distance(0), port))
+            .collect();
+        for node in &far {
+            assert!(table.insert(*node, now));
+        }
+
+        let newcomer = node(own.random_at_distance(0), 500);
+        assert!(!table.insert(newcomer, now));
+
+        for _ in 0..MAX_FAILURES {
+            table.failed(&far[3].address);
+        }
+        assert!(!table.closest(&far[3].id, K + 1).contains(&far[3]));
+        assert!(table.insert(newcomer, now));
+        assert!(!table.nodes().contains(&far[3]));
+
+        // Known ids do not move to another address
+        assert!(!table.insert(node(far[0].id, 501), now));
+    }
+
+    #[test]
+    fn test_refresh() {
+        let now = Instant::now();
+        let own = NodeId::random();
+        let mut table = RoutingTable::new(own, now);
+        table.insert(node(own.random_at_distance(0), 1), now);
+
+        assert!(table.refresh_targets(now).is_empty());
+        let later = now + REFRESH_INTERVAL;
+        let targets = table.refresh_targets(later);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(table.questionable(later).len(), 1);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let now = Instant::now();
+        let own = NodeId::random();
+        let mut table = RoutingTable::new(own, now);
+        table.insert(node(own.random_at_distance(1), 1), now);
+        table.insert(
+            NodeInfo {
+                id: own.random_at_distance(2),
+                address: "[::1]:2".parse().unwrap(),
+            },
+            now,
+        );
+
+        let path = std::env::temp_dir().join(format!("dht-{own}.dat"));
+        table.save(&path).unwrap();
+        let loaded = RoutingTable::load(&path, now).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.id(), own);
+        let mut nodes = loaded.nodes();
+        nodes.sort_by_key(|node| node.id);
+        let mut expected = table.nodes();
+        expected.sort_by_key(|node| node.id);
+        assert_eq!(nodes, expected);
+    }
+}
@@ -0,0 +1,418 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::bencode_decoder::{Bencode, DecodeError, MAX_DEPTH};
+
+const PREVIEW_LEN: usize = 32;
+
+#[derive(PartialEq, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Value {
+    /// Strings are shown as text when they are valid UTF-8, otherwise as a
+    /// hex preview of their first bytes.
+    String {
+        length: usize,
+        text: Option<String>,
+        hex: Option<String>,
+    },
+    Integer {
+        value: i64,
+    },
+    List {
+        items: usize,
+    },
+    Dictionary {
+        keys: usize,
+    },
+    /// The input could not be decoded from this offset on.
+    Error {
+        message: String,
+    },
+}
+
+#[derive(PartialEq, Debug, Serialize)]
+pub struct Node {
+    pub offset: usize,
+    pub length: usize,
+    pub key: Option<String>,
+    pub value: Value,
+    pub children: Vec<Node>,
+    /// Set when the children were left out because of the depth limit.
+    pub truncated: bool,
+}
+
+impl Node {
+    fn is_error(&self) -> bool {
+        matches!(self.value, Value::Error { .. })
+    }
+
+    /// Whether decoding stopped at an error inside this node, which then
+    /// runs to the end of the input.
+    pub fn is_failed(&self) -> bool {
+        self.is_error() || self.children.last().is_some_and(Node::is_failed)
+    }
+}
+
+#[derive(PartialEq, Debug, Serialize)]
+pub struct Dump {
+    pub nodes: Vec<Node>,
+    pub trailing: usize,
+}
+
+/// Builds a tree of every value in a bencoded buffer with its byte offset and
+/// encoded length. Children deeper than `max_depth` are not expanded.
+pub fn dump(content: &[u8], max_depth: Option<usize>) -> Dump {
+    let mut nodes = Vec::new();
+    let mut offset = 0;
+
+    // Resume files and tracker captures may hold several values back to back
+    while offset < content.len() && is_value_start(content[offset]) {
+        let node = walk(content, offset, 0, None, max_depth);
+        offset += node.length;
+        let failed = node.is_failed();
+        nodes.push(node);
+
+        if failed {
+            break;
+        }
+    }
+
+    Dump {
+        nodes,
+        trailing: content.len() - offset,
+    }
+}
+
+fn is_value_start(byte: u8) -> bool {
+    matches!(byte, b'0'..=b'9' | b'i' | b'l' | b'd')
+}
+
+/// A node for undecodable input, covering the rest of the buffer.
+fn error(content: &[u8], offset: usize, key: Option<String>, error: DecodeError) -> Node {
+    Node {
+        offset,
+        length: content.len().saturating_sub(offset),
+        key,
+        value: Value::Error {
+            message: error.to_string(),
+        },
+        children: Vec::new(),
+        truncated: false,
+    }
+}
+
+fn display_bytes(bytes: &[u8]) -> (Option<String>, Option<String>) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(|c| c.is_control()) => (Some(text.to_string()), None),
+        _ => (
+            None,
+            Some(hex::encode(&bytes[..bytes.len().min(PREVIEW_LEN)])),
+        ),
+    }
+}
+
+fn walk(
+    content: &[u8],
+    offset: usize,
+    depth: usize,
+    key: Option<String>,
+    max_depth: Option<usize>,
+) -> Node {
+    let expand = max_depth.is_none_or(|max_depth| depth < max_depth);
+
+    let Some(&byte) = content.get(offset) else {
+        return error(content, offset, key, DecodeError::UnexpectedEnd);
+    };
+
+    match byte {
+        b'l' | b'd' if expand && depth == MAX_DEPTH => {
+            error(content, offset, key, DecodeError::TooDeep)
+        }
+        b'l' if expand => {
+            let mut children = Vec::new();
+            let mut position = offset + 1;
+
+            loop {
+                let child = match content.get(position) {
+                    Some(b'e') => {
+                        position += 1;
+                        break;
+                    }
+                    Some(_) => walk(content, position, depth + 1, None, max_depth),
+                    None => error(content, position, None, DecodeError::UnexpectedEnd),
+                };
+
+                position += child.length;
+                let failed = child.is_failed();
+                children.push(child);
+
+                if failed {
+                    break;
+                }
+            }
+
+            Node {
+                offset,
+                length: position - offset,
+                key,
+                value: Value::List {
+                    items: children.iter().filter(|child| !child.is_error()).count(),
+                },
+                children,
+                truncated: false,
+            }
+        }
+        b'd' if expand => {
+            let mut children = Vec::new();
+            let mut position = offset + 1;
+
+            loop {
+                let decoded_key = match content.get(position) {
+                    Some(b'e') => {
+                        position += 1;
+                        break;
+                    }
+                    Some(_) => Bencode::try_decode_slice(&content[position..]),
+                    None => Err(DecodeError::UnexpectedEnd),
+                };
+
+                let child = match decoded_key {
+                    Ok((Bencode::String(child_key), key_length)) => {
+                        let (text, hex) = display_bytes(&child_key);
+                        let child_key = text.unwrap_or_else(|| format!("0x{}", hex.unwrap()));
+
+                        position += key_length;
+                        walk(content, position, depth + 1, Some(child_key), max_depth)
+                    }
+                    Ok(_) => error(content, position, None, DecodeError::NonStringKey),
+                    Err(decode_error) => error(content, position, None, decode_error),
+                };
+
+                position += child.length;
+                let failed = child.is_failed();
+                children.push(child);
+
+                if failed {
+                    break;
+                }
+            }
+
+            Node {
+                offset,
+                length: position - offset,
+                key,
+                value: Value::Dictionary {
+                    keys: children.iter().filter(|child| !child.is_error()).count(),
+                },
+                children,
+                truncated: false,
+            }
+        }
+        _ => {
+            let (decoded, length) = match Bencode::try_decode_slice(&content[offset..]) {
+                Ok(decoded) => decoded,
+                Err(decode_error) => return error(content, offset, key, decode_error),
+            };
+
+            let (value, truncated) = match decoded {
+                Bencode::String(bytes) => {
+                    let (text, hex) = display_bytes(&bytes);
+                    (
+                        Value::String {
+                            length: bytes.len(),
+                            text,
+                            hex,
+                        },
+                        false,
+                    )
+                }
+                Bencode::Integer(value) => (Value::Integer { value }, false),
+                Bencode::List(list) => (Value::List { items: list.len() }, !list.is_empty()),
+                Bencode::Dictionary(dict) => {
+                    (Value::Dictionary { keys: dict.len() }, !dict.is_empty())
+                }
+            };
+
+            Node {
+                offset,
+                length,
+                key,
+                value,
+                children: Vec::new(),
+                truncated,
+            }
+        }
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn fmt_helper(
+            node: &Node,
+            indent: usize,
+            f: &mut std::fmt::Formatter<'_>,
+        ) -> std::fmt::Result {
+            write!(f, "{:>8} {}", node.offset, "  ".repeat(indent))?;
+
+            if let Some(key) = &node.key {
+                write!(f, "{key:?}: ")?;
+            }
+
+            match &node.value {
+                Value::String {
+                    length,
+                    text: Some(text),
+                    ..
+                } => {
+                    let preview: String = text.chars().take(PREVIEW_LEN * 2).collect();
+                    let ellipsis = if preview.len() < text.len() {
+                        "..."
+                    } else {
+                        ""
+                    };
+                    write!(f, "{preview:?}{ellipsis} ({length} bytes)")?
+                }
+                Value::String {
+                    length,
+                    hex: Some(hex),
+                    ..
+                } => {
+                    let ellipsis = if *length > PREVIEW_LEN { "..." } else { "" };
+                    write!(f, "<{length} bytes> {hex}{ellipsis}")?
+                }
+                Value::String { length, .. } => write!(f, "<{length} bytes>")?,
+                Value::Integer { value } => write!(f, "{value}")?,
+                Value::List { items } => write!(f, "list ({items} items, {} bytes)", node.length)?,
+                Value::Dictionary { keys } => {
+                    write!(f, "dict ({keys} keys, {} bytes)", node.length)?
+                }
+                Value::Error { message } => write!(f, "error: {message}")?,
+            }
+
+            if node.truncated {
+                write!(f, " ...")?;
+            }
+
+            writeln!(f)?;
+
+            for child in &node.children {
+                fmt_helper(child, indent + 1, f)?;
+            }
+
+            Ok(())
+        }
+
+        fmt_helper(self, 0, f)
+    }
+}
+
+impl Display for Dump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for node in &self.nodes {
+            write!(f, "{node}")?;
+        }
+
+        if self.trailing > 0 {
+            writeln!(f, "{} bytes of trailing data", self.trailing)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_offsets() {
+        let dump = dump(b"d3:fooli1ei22ee3:hex2:\xff\x00e", None);
+        let root = &dump.nodes[0];
+
+        assert_eq!(root.length, 25);
+        assert_eq!(root.value, Value::Dictionary { keys: 2 });
+
+        let list = &root.children[0];
+        assert_eq!(list.key, Some("foo".to_string()));
+        assert_eq!(list.offset, 6);
+        assert_eq!(list.length, 9);
+        assert_eq!(list.children[1].offset, 10);
+        assert_eq!(list.children[1].value, Value::Integer { value: 22 });
+
+        let binary = &root.children[1];
+        assert_eq!(binary.offset, 20);
+        assert_eq!(
+            binary.value,
+            Value::String {
+                length: 2,
+                text: None,
+                hex: Some("ff00".to_string())
+            }
+        );
+        assert_eq!(dump.trailing, 0);
+    }
+
+    #[test]
+    fn test_dump_depth_limit() {
+        let dump = dump(b"d1:ad1:bi1eee", Some(1));
+        let inner = &dump.nodes[0].children[0];
+
+        assert!(inner.truncated);
+        assert!(inner.children.is_empty());
+        assert_eq!(inner.length, 8);
+    }
+
+    #[test]
+    fn test_dump_text() {
+        let dump = dump(b"l4:spami-3eexyz", None);
+
+        assert_eq!(
+            dump.to_string(),
+            "       0 list (2 items, 12 bytes)\n       1   \"spam\" (4 bytes)\n       7   -3\n3 bytes of trailing data\n"
+        );
+    }
+
+    #[test]
+    fn test_dump_malformed() {
+        let truncated = dump(b"d3:fooli1ei2", None);
+        let list = &truncated.nodes[0].children[0];
+        assert!(truncated.nodes[0].is_failed());
+        assert_eq!(truncated.nodes[0].length, 12);
+        assert_eq!(list.children[0].value, Value::Integer { value: 1 });
+        assert_eq!(list.children[1].offset, 10);
+        assert_eq!(
+            list.children[1].value,
+            Value::Error {
+                message: "unexpected end of input".to_string()
+            }
+        );
+        assert_eq!(truncated.trailing, 0);
+
+        let bad_key = dump(b"d1:ai1ei2ei3ee", Some(1));
+        assert_eq!(
+            bad_key.to_string(),
+            "       0 dict (1 keys, 14 bytes)\n       4   \"a\": 1\n       7   error: dictionary key is not a string\n"
+        );
+
+        // Values left unexpanded are decoded whole, and fail the same way
+        let unexpanded = dump(b"l4:spaml1:", Some(1));
+        assert_eq!(unexpanded.nodes[0].children[1].offset, 7);
+        assert!(unexpanded.nodes[0].is_failed());
+
+        let deep = dump(&vec![b'l'; MAX_DEPTH + 10], None);
+        assert!(deep.nodes[0].is_failed());
+    }
+
+    #[test]
+    fn test_dump_torrent() {
+        let content = std::fs::read("test_folder.torrent").unwrap();
+        let dump = dump(&content, Some(2));
+        let info = &dump.nodes[0].children[1];
+
+        assert_eq!(info.key, Some("info".to_string()));
+        assert_eq!(info.children[0].key, Some("file tree".to_string()));
+        assert!(info.children[0].truncated);
+        assert_eq!(info.offset + info.length + 1, content.len());
+    }
+}
@@ -0,0 +1,291 @@
+use crate::torrent::Torrent;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Edit {
+    /// Adds a tracker to the given tier, or to a new tier at the end when no
+    /// tier is given or it does not exist yet.
+    AddTracker {
+        url: String,
+        tier: Option<usize>,
+    },
+    RemoveTracker(String),
+    RemoveTier(usize),
+    SetComment(Option<String>),
+    SetCreatedBy(Option<String>),
+    AddWebSeed(String),
+    RemoveWebSeed(String),
+    SetPrivate(bool),
+    SetSource(Option<String>),
+}
+
+#[derive(PartialEq, Debug)]
+pub struct EditSummary {
+    pub old_infohash: String,
+    pub new_infohash: String,
+    /// SHA-1 infohashes, for hybrid torrents.
+    pub old_infohash_v1: Option<String>,
+    pub new_infohash_v1: Option<String>,
+}
+
+impl EditSummary {
+    pub fn infohash_changed(&self) -> bool {
+        self.old_infohash != self.new_infohash
+    }
+
+    pub fn infohash_v1_changed(&self) -> bool {
+        self.old_infohash_v1 != self.new_infohash_v1
+    }
+}
+
+impl Torrent {
+    pub fn apply_edits(&mut self, edits: &[Edit]) -> EditSummary {
+        let old_infohash = self.info.get_infohash();
+        let old_infohash_v1 = self.info.get_infohash_v1();
+
+        for edit in edits {
+            self.apply_edit(edit);
+        }
+
+        EditSummary {
+            old_infohash,
+            new_infohash: self.info.get_infohash(),
+            old_infohash_v1,
+            new_infohash_v1: self.info.get_infohash_v1(),
+        }
+    }
+
+    pub fn apply_edit(&mut self, edit: &Edit) {
+        match edit {
+            Edit::AddTracker { url, tier } => {
+                if self.announce_list.is_empty() && !self.announce.is_empty() {
+                    self.announce_list.push(vec![self.announce.clone()]);
+                }
+
+                match tier.and_then(|tier| self.announce_list.get_mut(tier)) {
+                    Some(tier) => {
+                        if !tier.contains(url) {
+                            tier.push(url.clone())
+                        }
+                    }
+                    None => self.announce_list.push(vec![url.clone()]),
+                }
+
+                if self.announce.is_empty() {
+                    self.announce = url.clone();
+                }
+            }
+            Edit::RemoveTracker(url) => {
+                self.announce_list
+                    .iter_mut()
+                    .for_each(|tier| tier.retain(|tracker| tracker != url));
+                self.announce_list.retain(|tier| !tier.is_empty());
+
+                if &self.announce == url {
+                    self.reset_announce();
+                }
+            }
+            Edit::RemoveTier(tier) => {
+                if *tier < self.announce_list.len() {
+                    self.announce_list.remove(*tier);
+                    self.reset_announce();
+                }
+            }
+            Edit::SetComment(comment) => self.comment = comment.clone(),
+            Edit::SetCreatedBy(created_by) => self.created_by = created_by.clone(),
+            Edit::AddWebSeed(url) => {
+                if !self.url_list.contains(url) {
+                    self.url_list.push(url.clone())
+                }
+            }
+            Edit::RemoveWebSeed(url) => self.url_list.retain(|seed| seed != url),
+            Edit::SetPrivate(private) => {
+                self.info.private = if *private { Some(true) } else { None }
+            }
+            Edit::SetSource(source) => self.info.source = source.clone(),
+        }
+    }
+
+    /// Points `announce` at the first remaining tracker, for clients that do
+    /// not support `announce-list`.
+    fn reset_announce(&mut self) {
+        self.announce = self
+            .announce_list
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use super::*;
+    use crate::bencode_decoder::Bencode;
+
+    fn test_torrent() -> Torrent {
+        let mut file = fs::File::open("test_folder.torrent").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+
+        let (metainfo, _) = Bencode::decode_value(content);
+        Torrent::parse(&metainfo)
+    }
+
+    #[test]
+    fn test_add_and_remove_trackers() {
+        let mut torrent = test_torrent();
+
+        torrent.apply_edits(&[
+            Edit::AddTracker {
+                url: "udp://backup.example.com:6969".to_string(),
+                tier: Some(0),
+            },
+            Edit::AddTracker {
+                url: "http://other.example.com/announce".to_string(),
+                tier: None,
+            },
+        ]);
+
+        assert_eq!(
+            torrent.trackers(),
+            vec![
+                vec![
+                    "http://example.com/announce".to_string(),
+                    "udp://backup.example.com:6969".to_string()
+                ],
+                vec!["http://other.example.com/announce".to_string()]
+            ]
+        );
+
+        torrent.apply_edits(&[
+            Edit::RemoveTracker("http://example.com/announce".to_string()),
+            Edit::RemoveTier(1),
+        ]);
+
+        assert_eq!(
+            torrent.trackers(),
+            vec![vec!["udp://backup.example.com:6969".to_string()]]
+        );
+        assert_eq!(torrent.announce, "udp://backup.example.com:6969");
+    }
+
+    #[test]
+    fn test_metadata_edits_keep_infohash() {
+        let mut torrent = test_torrent();
+
+        let summary = torrent.apply_edits(&[
+            Edit::SetComment(Some("release".to_string())),
+            Edit::SetCreatedBy(Some("bittorent".to_string())),
+            Edit::AddWebSeed("https://mirror.example.com/".to_string()),
+        ]);
+
+        assert!(!summary.infohash_changed());
+        assert_eq!(torrent.comment, Some("release".to_string()));
+        assert_eq!(torrent.url_list, vec!["https://mirror.example.com/"]);
+    }
+
+    #[test]
+    fn test_private_and_source_change_infohash() {
+        let mut torrent = test_torrent();
+
+        let summary = torrent.apply_edits(&[
+            Edit::SetPrivate(true),
+            Edit::SetSource(Some("internal".to_string())),
+        ]);
+
+        assert!(summary.infohash_changed());
+        assert_eq!(
+            summary.old_infohash,
+            "22fd2f407dd4187ca9b77b7937587f53346f0aebe326a2a8ac583e3b8cfc8bdd"
+        );
+
+        let summary = torrent.apply_edits(&[Edit::SetPrivate(false), Edit::SetSource(None)]);
+
+        assert_eq!(
+            summary.new_infohash,
+            "22fd2f407dd4187ca9b77b7937587f53346f0aebe326a2a8ac583e3b8cfc8bdd"
+        );
+    }
+
+    #[test]
+    fn test_edit_round_trip_preserves_keys() {
+        let mut torrent = test_torrent();
+        torrent
+            .extra
+            .insert(b"creation date".to_vec(), Bencode::Integer(1700000000));
+        torrent
+            .info
+            .extra
+            .insert(b"x-custom".to_vec(), Bencode::String(b"kept".to_vec()));
+        torrent.apply_edit(&Edit::SetComment(Some("hello".to_string())));
+
+        let encoded = torrent.to_bencode().encode_value();
+        let (metainfo, _) = Bencode::decode_value(encoded);
+        let reparsed = Torrent::parse(&metainfo);
+
+        assert_eq!(
+            reparsed.extra.get(&b"creation date".to_vec()),
+            Some(&Bencode::Integer(1700000000))
+        );
+        assert_eq!(
+            reparsed.info.extra.get(&b"x-custom".to_vec()),
+            Some(&Bencode::String(b"kept".to_vec()))
+        );
+        assert_eq!(reparsed.comment, Some("hello".to_string()));
+        assert_eq!(reparsed.info.get_infohash(), torrent.info.get_infohash());
+    }
+
+    #[test]
+    fn test_edit_keeps_bytes_as_read() {
+        let info = b"d9:file treed1:ad0:d6:lengthi1e4:attr1:x11:pieces root32:0123456789abcdef0123456789abcdefeee4:name2:\xff\xfe12:piece lengthi16384e7:privatei2ee";
+        let content = [
+            &b"d7:comment2:\xfe\xff4:info"[..],
+            info,
+            b"8:url-list19:http://example.com/e",
+        ]
+        .concat();
+
+        let (metainfo, _) = Bencode::decode_value(content.clone());
+        let mut torrent = Torrent::parse(&metainfo);
+        assert_eq!(torrent.to_bencode().encode_value(), content);
+
+        let summary = torrent.apply_edits(&[Edit::SetCreatedBy(Some("bittorent".to_string()))]);
+        assert!(!summary.infohash_changed());
+        assert_eq!(summary.old_infohash, sha256::digest(&info[..]));
+        assert_eq!(
+            torrent.to_bencode().encode_value(),
+            [
+                &b"d7:comment2:\xfe\xff10:created by9:bittorent4:info"[..],
+                info,
+                b"8:url-list19:http://example.com/e",
+            ]
+            .concat()
+        );
+
+        let summary = torrent.apply_edits(&[Edit::SetPrivate(false)]);
+        assert!(summary.infohash_changed());
+    }
+
+    #[test]
+    fn test_hybrid_edits_report_v1_infohash() {
+        let content = fs::read("test_hybrid.torrent").unwrap();
+        let (metainfo, _) = Bencode::decode_value(content);
+        let mut torrent = Torrent::parse(&metainfo);
+
+        let summary = torrent.apply_edits(&[Edit::SetComment(Some("release".to_string()))]);
+        assert!(!summary.infohash_v1_changed());
+        assert_eq!(
+            summary.old_infohash_v1.as_deref(),
+            Some("fced2fcca0d0c4de886697379a4feb306bd352b1")
+        );
+
+        let summary = torrent.apply_edits(&[Edit::SetSource(Some("internal".to_string()))]);
+        assert!(summary.infohash_v1_changed());
+        assert_eq!(summary.new_infohash_v1, torrent.info.get_infohash_v1());
+
+        let summary = test_torrent().apply_edits(&[Edit::SetPrivate(true)]);
+        assert_eq!(summary.new_infohash_v1, None);
+    }
+}
@@ -0,0 +1,401 @@
+use crate::torrent::Info;
+
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct FileEntry {
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Offset of the file in the v1 view, where files are concatenated. In
+    /// hybrid torrents, BEP 47 pad files align every file after the first to
+    /// a piece boundary.
+    pub offset: u64,
+    /// Index of the first piece of the file in the v2 view, where every file
+    /// starts on a piece boundary.
+    pub first_piece: u32,
+    pub piece_count: u32,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Block {
+    pub offset: u32,
+    pub length: u32,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct FileSpan {
+    pub file_index: usize,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct PieceLocation {
+    pub piece: u32,
+    pub offset: u32,
+}
+
+pub struct Geometry {
+    piece_length: u64,
+    total_length: u64,
+    files: Vec<FileEntry>,
+}
+
+impl Geometry {
+    /// Panics when the piece length is zero, which parsed torrents never
+    /// have.
+    pub fn new(info: &Info) -> Self {
+        let piece_length = info.piece_length as u64;
+        if piece_length == 0 {
+            panic!("Piece length must be greater than zero");
+        }
+        // Only hybrid torrents have a v1 view, and BEP 52 requires them to pad
+        let padded = info.extra.contains_key(&b"pieces".to_vec());
+
+        let mut tree_files = Vec::new();
+        info.file_tree.get_files_with_paths(&[], &mut tree_files);
+
+        let mut files = Vec::new();
+        let mut offset = 0u64;
+        let mut first_piece = 0;
+
+        for (index, (path, file)) in tree_files.into_iter().enumerate() {
+            if padded && index > 0 {
+                offset = offset.next_multiple_of(piece_length);
+            }
+            let length = file.length;
+            let piece_count = length.div_ceil(piece_length) as u32;
+
+            files.push(FileEntry {
+                path,
+                length,
+                offset,
+                first_piece,
+                piece_count,
+            });
+
+            offset += length;
+            first_piece += piece_count;
+        }
+
+        Geometry {
+            piece_length,
+            total_length: offset,
+            files,
+        }
+    }
+
+    pub fn piece_length(&self) -> u32 {
+        self.piece_length as u32
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn file_piece_count(&self, file_index: usize) -> Option<u32> {
+        self.files.get(file_index).map(|file| file.piece_count)
+    }
+
+    pub fn file_piece_size(&self, file_index: usize, piece: u32) -> Option<u32> {
+        let file = self.files.get(file_index)?;
+        if piece >= file.piece_count {
+            return None;
+        }
+
+        let start = piece as u64 * self.piece_length;
+        Some((file.length - start).min(self.piece_length) as u32)
+    }
+
+    pub fn file_last_piece_size(&self, file_index: usize) -> Option<u32> {
+        let file = self.files.get(file_index)?;
+        file.piece_count
+            .checked_sub(1)
+            .and_then(|last| self.file_piece_size(file_index, last))
+    }
+
+    /// Piece of the file (v2, counted from the start of the file) holding the
+    /// byte at `offset`.
+    pub fn file_piece_at(&self, file_index: usize, offset: u64) -> Option<PieceLocation> {
+        let file = self.files.get(file_index)?;
+        if offset >= file.length {
+            return None;
+        }
+
+        Some(PieceLocation {
+            piece: (offset / self.piece_length) as u32,
+            offset: (offset % self.piece_length) as u32,
+        })
+    }
+
+    /// Maps a v2 piece index, where files are piece-aligned, back to the file
+    /// it belongs to and the piece index within that file.
+    pub fn v2_piece_file(&self, piece: u32) -> Option<(usize, u32)> {
+        self.files.iter().enumerate().find_map(|(i, file)| {
+            (piece >= file.first_piece && piece < file.first_piece + file.piece_count)
+                .then(|| (i, piece - file.first_piece))
+        })
+    }
+
+    pub fn v2_piece_count(&self) -> u32 {
+        self.files.iter().map(|file| file.piece_count).sum()
+    }
+
+    pub fn v1_piece_count(&self) -> u32 {
+        self.total_length.div_ceil(self.piece_length) as u32
+    }
+
+    pub fn v1_piece_size(&self, piece: u32) -> Option<u32> {
+        if piece >= self.v1_piece_count() {
+            return None;
+        }
+
+        let start = piece as u64 * self.piece_length;
+        Some((self.total_length - start).min(self.piece_length) as u32)
+    }
+
+    /// Files and byte ranges covered by a v1 piece, in order. A piece may span
+    /// several files; empty files and padding are never part of a span.
+    pub fn v1_piece_spans(&self, piece: u32) -> Option<Vec<FileSpan>> {
+        let size = self.v1_piece_size(piece)? as u64;
+        let start = piece as u64 * self.piece_length;
+        let end = start + size;
+
+        let spans = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.offset < end && file.offset + file.length > start)
+            .map(|(file_index, file)| {
+                let span_start = start.max(file.offset);
+                let span_end = end.min(file.offset + file.length);
+
+                FileSpan {
+                    file_index,
+                    offset: span_start - file.offset,
+                    length: span_end - span_start,
+                }
+            })
+            .collect();
+
+        Some(spans)
+    }
+
+    /// v1 piece holding the byte at `offset` of the given file.
+    pub fn v1_piece_at(&self, file_index: usize, offset: u64) -> Option<PieceLocation> {
+        let file = self.files.get(file_index)?;
+        if offset >= file.length {
+            return None;
+        }
+
+        let absolute = file.offset + offset;
+
+        Some(PieceLocation {
+            piece: (absolute / self.piece_length) as u32,
+            offset: (absolute % self.piece_length) as u32,
+        })
+    }
+
+    /// Splits a piece of `piece_size` bytes into 16 KiB blocks, the last one
+    /// possibly shorter.
+    pub fn blocks(piece_size: u32) -> Vec<Block> {
+        (0..piece_size.div_ceil(BLOCK_SIZE))
+            .map(|i| {
+                let offset = i * BLOCK_SIZE;
+                Block {
+                    offset,
+                    length: (piece_size - offset).min(BLOCK_SIZE),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use super::*;
+    use crate::{bencode_decoder::Bencode, torrent::Torrent};
+
+    fn test_geometry() -> Geometry {
+        geometry_of("test_folder.torrent")
+    }
+
+    fn geometry_of(path: &str) -> Geometry {
+        let mut file = fs::File::open(path).unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+
+        let (metainfo, _) = Bencode::decode_value(content);
+        let torrent = Torrent::parse(&metainfo);
+
+        Geometry::new(&torrent.info)
+    }
+
+    #[test]
+    fn test_file_entries() {
+        let geometry = test_geometry();
+        let files = geometry.files();
+
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].path, vec!["README".to_string()]);
+        assert_eq!(
+            files[1].path,
+            vec![
+                "images".to_string(),
+                "LOC_Main_Reading_Room_Highsmith.jpg".to_string()
+            ]
+        );
+        assert_eq!(files[1].offset, 20);
+        assert_eq!(files[2].offset, 17614547);
+        assert_eq!(geometry.total_length(), 19296724);
+    }
+
+    #[test]
+    fn test_file_piece_counts() {
+        let geometry = test_geometry();
+
+        assert_eq!(geometry.file_piece_count(0), Some(1));
+        assert_eq!(geometry.file_piece_count(1), Some(269));
+        assert_eq!(geometry.file_piece_count(2), Some(26));
+        assert_eq!(geometry.file_piece_count(3), None);
+        assert_eq!(geometry.v2_piece_count(), 296);
+    }
+
+    #[test]
+    fn test_file_last_piece_size() {
+        let geometry = test_geometry();
+
+        assert_eq!(geometry.file_last_piece_size(0), Some(20));
+        assert_eq!(geometry.file_last_piece_size(1), Some(50879));
+        assert_eq!(geometry.file_last_piece_size(2), Some(43777));
+        assert_eq!(geometry.file_piece_size(1, 0), Some(65536));
+        assert_eq!(geometry.file_piece_size(1, 269), None);
+    }
+
+    #[test]
+    fn test_file_piece_at() {
+        let geometry = test_geometry();
+
+        assert_eq!(
+            geometry.file_piece_at(1, 65536 * 3 + 5),
+            Some(PieceLocation {
+                piece: 3,
+                offset: 5
+            })
+        );
+        assert_eq!(geometry.file_piece_at(0, 20), None);
+        assert_eq!(geometry.v2_piece_file(0), Some((0, 0)));
+        assert_eq!(geometry.v2_piece_file(1), Some((1, 0)));
+        assert_eq!(geometry.v2_piece_file(270), Some((2, 0)));
+        assert_eq!(geometry.v2_piece_file(296), None);
+    }
+
+    #[test]
+    fn test_v1_piece_spans() {
+        let geometry = test_geometry();
+
+        assert_eq!(geometry.v1_piece_count(), 295);
+        assert_eq!(
+            geometry.v1_piece_spans(0),
+            Some(vec![
+                FileSpan {
+                    file_index: 0,
+                    offset: 0,
+                    length: 20
+                },
+                FileSpan {
+                    file_index: 1,
+                    offset: 0,
+                    length: 65516
+                }
+            ])
+        );
+        assert_eq!(
+            geometry.v1_piece_spans(294),
+            Some(vec![FileSpan {
+                file_index: 2,
+                offset: 1682177 - 29140,
+                length: 29140
+            }])
+        );
+        assert_eq!(geometry.v1_piece_size(294), Some(29140));
+        assert_eq!(geometry.v1_piece_spans(295), None);
+    }
+
+    #[test]
+    fn test_hybrid_padding() {
+        let geometry = geometry_of("test_hybrid.torrent");
+        let offsets: Vec<u64> = geometry.files().iter().map(|file| file.offset).collect();
+
+        // a.bin of 40000 bytes and c.txt of 5000 bytes are padded to the
+        // 32 KiB pieces, sub/b.bin is last
+        assert_eq!(offsets, vec![0, 65536, 98304]);
+        assert_eq!(geometry.total_length(), 168304);
+        assert_eq!(geometry.v1_piece_count(), 6);
+        assert_eq!(geometry.v1_piece_size(5), Some(4464));
+        assert_eq!(
+            geometry.v1_piece_spans(1),
+            Some(vec![FileSpan {
+                file_index: 0,
+                offset: 32768,
+                length: 7232
+            }])
+        );
+        assert_eq!(
+            geometry.v1_piece_at(2, 0),
+            Some(PieceLocation {
+                piece: 3,
+                offset: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_v1_piece_at() {
+        let geometry = test_geometry();
+
+        assert_eq!(
+            geometry.v1_piece_at(1, 65516),
+            Some(PieceLocation {
+                piece: 1,
+                offset: 0
+            })
+        );
+        assert_eq!(
+            geometry.v1_piece_at(0, 19),
+            Some(PieceLocation {
+                piece: 0,
+                offset: 19
+            })
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert_eq!(
+            Geometry::blocks(40000),
+            vec![
+                Block {
+                    offset: 0,
+                    length: 16384
+                },
+                Block {
+                    offset: 16384,
+                    length: 16384
+                },
+                Block {
+                    offset: 32768,
+                    length: 7232
+                }
+            ]
+        );
+        assert_eq!(Geometry::blocks(65536).len(), 4);
+        assert!(Geometry::blocks(0).is_empty());
+    }
+}
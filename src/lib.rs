@@ -0,0 +1,16 @@
+pub mod bencode_decoder;
+pub mod create;
+pub mod dht;
+pub mod dump;
+pub mod edit;
+pub mod geometry;
+pub mod lsd;
+pub mod merkle;
+pub mod peer;
+pub mod peers;
+pub mod picker;
+pub mod storage;
+pub mod torrent;
+pub mod tracker;
+pub mod utp;
+pub mod validate;
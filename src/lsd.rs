@@ -0,0 +1,492 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{mpsc::Sender, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+/// Each torrent is announced at most once per interval.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Announces of many torrents are split so no datagram exceeds this.
+pub const MAX_MESSAGE_SIZE: usize = 1400;
+
+const REQUEST_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum LsdError {
+    InvalidMessage(String),
+}
+
+impl Display for LsdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LsdError::InvalidMessage(reason) => write!(f, "invalid BT-SEARCH message: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for LsdError {}
+
+/// A `BT-SEARCH` message announcing that the sender is a peer of every
+/// torrent in `info_hashes`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Search {
+    /// Multicast group and port the message is sent to.
+    pub host: String,
+    /// Port the sender accepts peer connections on.
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets senders recognize, and ignore, their own messages.
+    pub cookie: Option<String>,
+}
+
+impl Search {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = format!(
+            "{REQUEST_LINE}\r\nHost: {}\r\nPort: {}\r\n",
+            self.host, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+
+        message.into_bytes()
+    }
+
+    /// Decodes a message. Header names are case insensitive, and info hashes
+    /// that are not 40 hex digits are skipped.
+    pub fn decode(bytes: &[u8]) -> Result<Self, LsdError> {
+        let invalid = |reason: &str| LsdError::InvalidMessage(reason.to_string());
+
+        let message = std::str::from_utf8(bytes).map_err(|_| invalid("not UTF-8"))?;
+        let mut lines = message.split("\r\n");
+
+        if lines.next() != Some(REQUEST_LINE) {
+            return Err(invalid("not a BT-SEARCH request"));
+        }
+
+        let mut search = Search {
+            host: String::new(),
+            port: 0,
+            info_hashes: Vec::new(),
+            cookie: None,
+        };
+        let mut port = None;
+
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(invalid("malformed header"));
+            };
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "host" => search.host = value.to_string(),
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    if let Some(info_hash) = hex::decode(value)
+                        .ok()
+                        .and_then(|info_hash| info_hash.try_into().ok())
+                    {
+                        search.info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => search.cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        search.port = port.ok_or_else(|| invalid("missing or invalid port"))?;
+        if search.info_hashes.is_empty() {
+            return Err(invalid("no info hash"));
+        }
+
+        Ok(search)
+    }
+}
+
+/// Messages announcing `info_hashes`, as many per message as fit in
+/// `MAX_MESSAGE_SIZE`.
+pub fn searches(
+    host: &str,
+    port: u16,
+    info_hashes: &[[u8; 20]],
+    cookie: Option<&str>,
+) -> Vec<Search> {
+    let mut searches: Vec<Search> = Vec::new();
+
+    for info_hash in info_hashes {
+        if let Some(search) = searches.last_mut() {
+            search.info_hashes.push(*info_hash);
+            if search.encode().len() <= MAX_MESSAGE_SIZE {
+                continue;
+            }
+            search.info_hashes.pop();
+        }
+
+        searches.push(Search {
+            host: host.to_string(),
+            port,
+            info_hashes: vec![*info_hash],
+            cookie: cookie.map(str::to_string),
+        });
+    }
+
+    searches
+}
+
+/// A peer announced on the local network.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct LsdPeer {
+    pub info_hash: [u8; 20],
+    pub address: SocketAddr,
+}
+
+pub struct LsdOptions {
+    /// Multicast port of both groups.
+    pub port: u16,
+    /// Interface to join the IPv4 group on, or `None` to not use IPv4.
+    pub interface_v4: Option<Ipv4Addr>,
+    /// Index of the interface to join the IPv6 group on, 0 for the default
+    /// one, or `None` to not use IPv6.
+    pub interface_v6: Option<u32>,
+}
+
+impl Default for LsdOptions {
+    fn default() -> Self {
+        LsdOptions {
+            port: LSD_PORT,
+            interface_v4: Some(Ipv4Addr::UNSPECIFIED),
+            interface_v6: Some(0),
+        }
+    }
+}
+
+fn bind_v4(port: u16, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Every client on the host listens on the same port
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    socket.join_multicast_v4(&LSD_GROUP_V4, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(1)?;
+    Ok(socket.into())
+}
+
+fn bind_v6(port: u16, interface: u32) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.join_multicast_v6(&LSD_GROUP_V6, interface)?;
+    socket.set_multicast_if_v6(interface)?;
+    socket.set_multicast_loop_v6(true)?;
+    Ok(socket.into())
+}
+
+/// Local Service Discovery (BEP 14) over the IPv4 and IPv6 multicast groups.
+pub struct Lsd {
+    /// Sockets joined to each group, with the group address.
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    listen_port: u16,
+    cookie: String,
+    announced: Mutex<HashMap<[u8; 20], Instant>>,
+}
+
+impl Lsd {
+    /// Joins the groups enabled in `options`, to announce peers listening on
+    /// `listen_port`. Fails only if no group could be joined.
+    pub fn bind(listen_port: u16, options: LsdOptions) -> io::Result<Self> {
+        let mut sockets = Vec::new();
+        let mut error = None;
+
+        let v4 = options.interface_v4.map(|interface| {
+            (
+                bind_v4(options.port, interface),
+                SocketAddr::from((LSD_GROUP_V4, options.port)),
+            )
+        });
+        let v6 = options.interface_v6.map(|interface| {
+            (
+                bind_v6(options.port, interface),
+                SocketAddr::from((LSD_GROUP_V6, options.port)),
+            )
+        });
+
+        for (socket, group) in v4.into_iter().chain(v6) {
+            match socket {
+                Ok(socket) => sockets.push((socket, group)),
+                Err(bind_error) => error = Some(bind_error),
+            }
+        }
+
+        if sockets.is_empty() {
+            return Err(error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no multicast group enabled")
+            }));
+        }
+
+        let mut rng = rand::thread_rng();
+        let cookie = (0..8)
+            .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+            .collect();
+
+        Ok(Lsd {
+            sockets,
+            listen_port,
+            cookie,
+            announced: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Announces the torrents among `info_hashes` that were not announced
+    /// within the announce interval, on every group. Returns how many were.
+    /// A group may fail on its own, commonly IPv6 without a route, so a
+    /// torrent counts as announced once any group got it, and an error is
+    /// only returned when every send failed.
+    pub fn announce(&self, info_hashes: &[[u8; 20]], now: Instant) -> io::Result<usize> {
+        let due: Vec<[u8; 20]> = {
+            let announced = self.announced.lock().unwrap();
+            info_hashes
+                .iter()
+                .filter(|info_hash| {
+                    announced
+                        .get(*info_hash)
+                        .is_none_or(|last| now.duration_since(*last) >= ANNOUNCE_INTERVAL)
+                })
+                .copied()
+                .collect()
+        };
+
+        let mut sent = HashSet::new();
+        let mut error = None;
+
+        for (socket, group) in &self.sockets {
+            for search in searches(
+                &group.to_string(),
+                self.listen_port,
+                &due,
+                Some(&self.cookie),
+            ) {
+                match socket.send_to(&search.encode(), group) {
+                    Ok(_) => sent.extend(search.info_hashes),
+                    Err(send_error) => error = Some(send_error),
+                }
+            }
+        }
+
+        if let Some(error) = error.filter(|_| sent.is_empty()) {
+            return Err(error);
+        }
+
+        let mut announced = self.announced.lock().unwrap();
+        for info_hash in &sent {
+            announced.insert(*info_hash, now);
+        }
+
+        Ok(sent.len())
+    }
+
+    /// Listens on every group in background threads, sending the peers
+    /// other clients announce to `peers`. Our own announces are ignored. A
+    /// thread stops at the first message after `peers` was disconnected, or
+    /// when its socket fails.
+    pub fn listen(&self, peers: Sender<LsdPeer>) -> io::Result<()> {
+        for (socket, _) in &self.sockets {
+            let socket = socket.try_clone()?;
+            let peers = peers.clone();
+            let cookie = self.cookie.clone();
+
+            thread::spawn(move || {
+                let mut buffer = [0; MAX_MESSAGE_SIZE * 2];
+
+                loop {
+                    let (length, from) = match socket.recv_from(&mut buffer) {
+                        Ok(received) => received,
+                        // Refused and reset report an earlier send, and are
+                        // cleared once read
+                        Err(error)
+                            if matches!(
+                                error.kind(),
+                                io::ErrorKind::WouldBlock
+                                    | io::ErrorKind::Interrupted
+                                    | io::ErrorKind::ConnectionRefused
+                                    | io::ErrorKind::ConnectionReset
+                            ) =>
+                        {
+                            continue
+                        }
+                        // Retrying a broken socket would spin
+                        Err(_) => return,
+                    };
+                    let Ok(search) = Search::decode(&buffer[..length]) else {
+                        continue;
+                    };
+                    if search.cookie.as_ref() == Some(&cookie) {
+                        continue;
+                    }
+
+                    for info_hash in search.info_hashes {
+                        let address = SocketAddr::new(from.ip(), search.port);
+                        if peers.send(LsdPeer { info_hash, address }).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_search_round_trip() {
+        let search = Search {
+            host: "[ff15::efc0:988f]:6771".to_string(),
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("abc".to_string()),
+        };
+        let encoded = search.encode();
+
+        assert!(encoded.starts_with(
+            b"BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\nPort: 6881\r\nInfohash: abababab"
+        ));
+        assert!(encoded.ends_with(b"cookie: abc\r\n\r\n\r\n"));
+        assert_eq!(Search::decode(&encoded).unwrap(), search);
+    }
+
+    #[test]
+    fn test_decode_lenient() {
+        let search = Search::decode(
+            b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nPORT:  51413\r\ninfohash: ABABABABABABABABABABABABABABABABABABABAB\r\nInfohash: nothex\r\nX-Other: 1\r\n\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(search.port, 51413);
+        assert_eq!(search.info_hashes, vec![[0xab; 20]]);
+        assert_eq!(search.cookie, None);
+
+        assert!(Search::decode(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        assert!(Search::decode(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+        assert!(Search::decode(
+            b"BT-SEARCH * HTTP/1.1\r\nPort: 99999\r\nInfohash: abababababababababababababababababababab\r\n\r\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_searches_split() {
+        let info_hashes: Vec<[u8; 20]> = (0..60).map(|index| [index; 20]).collect();
+        let searches = searches("239.192.152.143:6771", 6881, &info_hashes, Some("cookie"));
+
+        assert!(searches.len() > 1);
+        assert!(searches
+            .iter()
+            .all(|search| search.encode().len() <= MAX_MESSAGE_SIZE));
+        assert_eq!(
+            searches
+                .iter()
+                .flat_map(|search| search.info_hashes.clone())
+                .collect::<Vec<_>>(),
+            info_hashes
+        );
+    }
+
+    #[test]
+    fn test_announce_failing_group() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let unreachable: SocketAddr = "[::1]:6771".parse().unwrap();
+        let lsd = |groups: Vec<SocketAddr>| Lsd {
+            sockets: groups
+                .into_iter()
+                .map(|group| (UdpSocket::bind("127.0.0.1:0").unwrap(), group))
+                .collect(),
+            listen_port: 6881,
+            cookie: "cookie".to_string(),
+            announced: Mutex::new(HashMap::new()),
+        };
+        let now = Instant::now();
+
+        // An IPv4 socket cannot reach the IPv6 group, but the other group
+        // still gets the announce
+        let partial = lsd(vec![unreachable, receiver.local_addr().unwrap()]);
+        assert_eq!(partial.announce(&[[1; 20]], now).unwrap(), 1);
+        assert_eq!(partial.announce(&[[1; 20]], now).unwrap(), 0);
+
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let (length, _) = receiver.recv_from(&mut buffer).unwrap();
+        assert_eq!(
+            Search::decode(&buffer[..length]).unwrap().info_hashes,
+            vec![[1; 20]]
+        );
+
+        // Torrents are announced again on the next call when every group
+        // failed
+        let failing = lsd(vec![unreachable]);
+        assert!(failing.announce(&[[1; 20]], now).is_err());
+        assert!(failing.announce(&[[1; 20]], now).is_err());
+    }
+
+    #[test]
+    fn test_loopback_multicast() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let options = || LsdOptions {
+            port,
+            interface_v4: Some(Ipv4Addr::LOCALHOST),
+            interface_v6: None,
+        };
+
+        let seeder = Lsd::bind(6881, options()).unwrap();
+        let leecher = Lsd::bind(6882, options()).unwrap();
+
+        let (own, own_peers) = mpsc::channel();
+        seeder.listen(own).unwrap();
+        let (found, found_peers) = mpsc::channel();
+        leecher.listen(found).unwrap();
+
+        let now = Instant::now();
+        assert_eq!(seeder.announce(&[[1; 20], [2; 20]], now).unwrap(), 2);
+        // Announced torrents wait for the interval
+        assert_eq!(seeder.announce(&[[1; 20], [3; 20]], now).unwrap(), 1);
+
+        let address: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let mut peers: Vec<LsdPeer> = (0..3)
+            .map(|_| found_peers.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        peers.sort_by_key(|peer| peer.info_hash);
+        assert_eq!(
+            peers,
+            [[1; 20], [2; 20], [3; 20]].map(|info_hash| LsdPeer { info_hash, address })
+        );
+
+        leecher.announce(&[[1; 20]], now).unwrap();
+        let peer = own_peers.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(peer.address.port(), 6882);
+        // The seeder's own announces never showed up
+        assert!(own_peers.try_recv().is_err());
+    }
+}
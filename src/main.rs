@@ -1,38 +1,521 @@
-mod bencode_decoder;
-mod torrent;
+use std::{
+    fmt::Display,
+    fs,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    path::PathBuf,
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use core::panic;
-use std::{fs, io::Read, path::PathBuf};
-
-use bencode_decoder::Bencode;
-use clap::Parser;
-use torrent::Torrent;
+use bittorent::{
+    bencode_decoder::Bencode,
+    create::{self, CreateOptions},
+    dump,
+    edit::Edit,
+    torrent::Torrent,
+    tracker::{
+        self,
+        server::{self, Server, ServerOptions},
+    },
+    validate,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
 
 #[derive(Parser)]
 struct Cli {
-    action: String,
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the name, infohashes, files and trackers of a torrent
+    Info { path: PathBuf },
+    /// Report every problem found in a torrent
+    Validate { path: PathBuf },
+    /// Check that a torrent has the expected infohash
+    Verify {
+        path: PathBuf,
+        /// Expected v2 infohash, hex encoded
+        #[arg(long)]
+        infohash: String,
+    },
+    /// Create a v2 torrent from a file or directory
+    Create(CreateArgs),
+    /// Write a copy of a torrent with modified metadata
+    Edit(EditArgs),
+    /// Print the structure of any bencoded file with byte offsets
+    Dump {
+        path: PathBuf,
+        /// Do not expand lists and dictionaries deeper than this
+        #[arg(long)]
+        max_depth: Option<usize>,
+    },
+    /// Ask every tracker of a torrent for its swarm statistics
+    Scrape {
+        path: PathBuf,
+        /// Seconds to wait for each UDP tracker response, without retrying
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Run a tracker keeping its swarms in memory
+    Tracker(TrackerArgs),
+}
+
+#[derive(Args)]
+struct CreateArgs {
+    path: PathBuf,
+    /// File the torrent is written to
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Tracker URL, each one in its own tier
+    #[arg(short, long)]
+    announce: Vec<String>,
+    /// Piece length in bytes, a power of two of at least 16 KiB
+    #[arg(long)]
+    piece_length: Option<u32>,
+    #[arg(long)]
+    comment: Option<String>,
+    #[arg(long)]
+    created_by: Option<String>,
+    #[arg(long)]
+    web_seed: Vec<String>,
+    #[arg(long)]
+    private: bool,
+    #[arg(long)]
+    source: Option<String>,
+}
+
+#[derive(Args)]
+struct EditArgs {
     path: PathBuf,
+    /// File the edited torrent is written to
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Tracker to add, in a new tier unless --tier is given
+    #[arg(long)]
+    add_tracker: Vec<String>,
+    /// Tier index new trackers are added to
+    #[arg(long)]
+    tier: Option<usize>,
+    #[arg(long)]
+    remove_tracker: Vec<String>,
+    #[arg(long)]
+    remove_tier: Vec<usize>,
+    #[arg(long, conflicts_with = "clear_comment")]
+    comment: Option<String>,
+    #[arg(long)]
+    clear_comment: bool,
+    #[arg(long, conflicts_with = "clear_created_by")]
+    created_by: Option<String>,
+    #[arg(long)]
+    clear_created_by: bool,
+    #[arg(long)]
+    add_web_seed: Vec<String>,
+    #[arg(long)]
+    remove_web_seed: Vec<String>,
+    #[arg(long)]
+    private: Option<bool>,
+    #[arg(long, conflicts_with = "clear_source")]
+    source: Option<String>,
+    #[arg(long)]
+    clear_source: bool,
+}
+
+#[derive(Args)]
+struct TrackerArgs {
+    /// Address the HTTP tracker listens on
+    #[arg(long, required_unless_present = "udp")]
+    http: Option<SocketAddr>,
+    /// Address the UDP tracker listens on
+    #[arg(long)]
+    udp: Option<SocketAddr>,
+    /// Only track the torrents in these files
+    #[arg(long)]
+    allow: Vec<PathBuf>,
+    /// Seconds clients wait between announces
+    #[arg(long, default_value_t = 1800)]
+    interval: u64,
+}
+
+impl EditArgs {
+    fn edits(&self) -> Vec<Edit> {
+        let mut edits = Vec::new();
+
+        edits.extend(self.add_tracker.iter().map(|url| Edit::AddTracker {
+            url: url.clone(),
+            tier: self.tier,
+        }));
+        edits.extend(self.remove_tracker.iter().cloned().map(Edit::RemoveTracker));
+
+        // Remove from the last tier so earlier indices stay valid
+        let mut remove_tier = self.remove_tier.clone();
+        remove_tier.sort_unstable_by(|a, b| b.cmp(a));
+        edits.extend(remove_tier.into_iter().map(Edit::RemoveTier));
+
+        if self.comment.is_some() || self.clear_comment {
+            edits.push(Edit::SetComment(self.comment.clone()));
+        }
+        if self.created_by.is_some() || self.clear_created_by {
+            edits.push(Edit::SetCreatedBy(self.created_by.clone()));
+        }
+
+        edits.extend(self.add_web_seed.iter().cloned().map(Edit::AddWebSeed));
+        edits.extend(
+            self.remove_web_seed
+                .iter()
+                .cloned()
+                .map(Edit::RemoveWebSeed),
+        );
+
+        if let Some(private) = self.private {
+            edits.push(Edit::SetPrivate(private));
+        }
+        if self.source.is_some() || self.clear_source {
+            edits.push(Edit::SetSource(self.source.clone()));
+        }
+
+        edits
+    }
+}
+
+#[derive(Serialize)]
+struct InfoOutput {
+    name: String,
+    infohash_v2: String,
+    infohash_v1: Option<String>,
+    piece_length: u32,
+    files: Vec<FileOutput>,
+    trackers: Vec<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct FileOutput {
+    path: String,
+    length: u64,
+    pieces_root: String,
+}
+
+#[derive(Serialize)]
+struct ValidateOutput {
+    valid: bool,
+    problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct InfohashOutput {
+    infohash: String,
+    previous_infohash: Option<String>,
+    infohash_v1: Option<String>,
+    previous_infohash_v1: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VerifyOutput {
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct ScrapeOutput {
+    tracker: String,
+    error: Option<String>,
+    torrents: Vec<ScrapeTorrentOutput>,
+}
+
+#[derive(Serialize)]
+struct ScrapeTorrentOutput {
+    info_hash: String,
+    complete: u32,
+    downloaded: u32,
+    incomplete: u32,
+}
+
+/// Reports an error on stderr and exits, for input the command cannot use.
+fn fail(message: impl Display) -> ! {
+    eprintln!("Error: {message}");
+    process::exit(1);
+}
+
+fn read_file(path: &PathBuf) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|error| fail(format!("could not read file: {error}")))
+}
+
+fn read_torrent(path: &PathBuf) -> Torrent {
+    let (metainfo, _) = Bencode::try_decode_value(read_file(path))
+        .unwrap_or_else(|error| fail(format!("could not decode torrent: {error}")));
+
+    Torrent::try_parse(&metainfo)
+        .unwrap_or_else(|error| fail(format!("could not parse torrent: {error}")))
+}
+
+fn write_file(path: &PathBuf, content: Vec<u8>) {
+    fs::write(path, content).unwrap_or_else(|error| fail(format!("could not write file: {error}")))
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
 }
 
 fn main() {
     let args = Cli::parse();
 
-    println!("action: {:?}, path: {:?}", args.action, args.path);
+    match args.command {
+        Command::Info { path } => {
+            let torrent = read_torrent(&path);
 
-    let mut file = fs::File::open(args.path).expect("could not read file");
-    let mut content = Vec::new();
-    file.read_to_end(&mut content).unwrap();
+            if args.format == Format::Json {
+                let mut files = Vec::new();
+                torrent.info.file_tree.get_files_with_paths(&[], &mut files);
 
-    let (metainfo, _) = Bencode::decode_value(content);
-    let torrent = Torrent::parse(&metainfo);
+                print_json(&InfoOutput {
+                    name: torrent.info.name.clone(),
+                    infohash_v2: torrent.info.get_infohash(),
+                    infohash_v1: torrent.info.get_infohash_v1(),
+                    piece_length: torrent.info.piece_length,
+                    files: files
+                        .into_iter()
+                        .map(|(path, file)| FileOutput {
+                            path: path.join("/"),
+                            length: file.length,
+                            pieces_root: hex::encode(file.pieces_root),
+                        })
+                        .collect(),
+                    trackers: torrent.trackers(),
+                });
+                return;
+            }
 
-    match args.action.as_str() {
-        "info" => {
+            println!("Name: {}", torrent.info.name);
             println!("Tracker URL: {}", torrent.announce);
+            for (i, tier) in torrent.trackers().iter().enumerate() {
+                println!("Tier {i}: {}", tier.join(", "));
+            }
             println!("Files: \n{}", torrent.info.file_tree);
             println!("Info Hash: {}", torrent.info.get_infohash());
+            if let Some(infohash_v1) = torrent.info.get_infohash_v1() {
+                println!("Info Hash v1: {infohash_v1}");
+            }
             println!("Piece Length: {}", torrent.info.piece_length);
         }
-        _ => panic!("invalid argument"),
+        Command::Validate { path } => {
+            let content = read_file(&path);
+            let report = validate::validate_file(content);
+
+            if args.format == Format::Json {
+                print_json(&ValidateOutput {
+                    valid: report.is_valid(),
+                    problems: report.problems.iter().map(|p| p.to_string()).collect(),
+                });
+            } else {
+                print!("{report}");
+            }
+
+            if !report.is_valid() {
+                process::exit(1);
+            }
+        }
+        Command::Verify { path, infohash } => {
+            let torrent = read_torrent(&path);
+            let valid = torrent.verify_infohash(infohash.to_lowercase());
+
+            if args.format == Format::Json {
+                print_json(&VerifyOutput { valid });
+            } else if valid {
+                println!("Infohash matches");
+            } else {
+                println!("Infohash does not match");
+            }
+
+            if !valid {
+                process::exit(1);
+            }
+        }
+        Command::Create(create_args) => {
+            let options = CreateOptions {
+                piece_length: create_args.piece_length,
+                trackers: create_args
+                    .announce
+                    .iter()
+                    .map(|url| vec![url.clone()])
+                    .collect(),
+                comment: create_args.comment,
+                created_by: create_args.created_by,
+                web_seeds: create_args.web_seed,
+                private: create_args.private,
+                source: create_args.source,
+            };
+
+            let torrent = create::create(&create_args.path, &options)
+                .unwrap_or_else(|error| fail(format!("could not create torrent: {error}")));
+            write_file(&create_args.output, torrent.to_bencode().encode_value());
+
+            let infohash = torrent.info.get_infohash();
+            let infohash_v1 = torrent.info.get_infohash_v1();
+
+            if args.format == Format::Json {
+                print_json(&InfohashOutput {
+                    infohash,
+                    previous_infohash: None,
+                    infohash_v1,
+                    previous_infohash_v1: None,
+                });
+            } else {
+                println!("Info Hash: {infohash}");
+                if let Some(infohash_v1) = infohash_v1 {
+                    println!("Info Hash v1: {infohash_v1}");
+                }
+            }
+        }
+        Command::Edit(edit_args) => {
+            let mut torrent = read_torrent(&edit_args.path);
+
+            let summary = torrent.apply_edits(&edit_args.edits());
+            write_file(&edit_args.output, torrent.to_bencode().encode_value());
+
+            if args.format == Format::Json {
+                print_json(&InfohashOutput {
+                    previous_infohash: summary
+                        .infohash_changed()
+                        .then(|| summary.old_infohash.clone()),
+                    previous_infohash_v1: summary
+                        .infohash_v1_changed()
+                        .then(|| summary.old_infohash_v1.clone())
+                        .flatten(),
+                    infohash: summary.new_infohash,
+                    infohash_v1: summary.new_infohash_v1,
+                });
+                return;
+            }
+
+            if summary.infohash_changed() {
+                println!(
+                    "Info Hash changed: {} -> {}",
+                    summary.old_infohash, summary.new_infohash
+                );
+            } else {
+                println!("Info Hash: {}", summary.new_infohash);
+            }
+            match (&summary.old_infohash_v1, &summary.new_infohash_v1) {
+                (Some(old), Some(new)) if old != new => {
+                    println!("Info Hash v1 changed: {old} -> {new}")
+                }
+                (_, Some(new)) => println!("Info Hash v1: {new}"),
+                _ => {}
+            }
+        }
+        Command::Dump { path, max_depth } => {
+            let content = read_file(&path);
+            let dump = dump::dump(&content, max_depth);
+
+            if args.format == Format::Json {
+                print_json(&dump);
+            } else {
+                print!("{dump}");
+            }
+
+            // The dump shows what was read before the error
+            if let Err(error) = Bencode::try_decode_slice(&content) {
+                fail(format!("could not decode: {error}"));
+            }
+        }
+        Command::Scrape { path, timeout } => {
+            let torrent = read_torrent(&path);
+            let info_hashes = tracker::info_hashes(&torrent.info);
+            let timeout = Duration::from_secs(timeout);
+
+            let outputs: Vec<ScrapeOutput> = torrent
+                .trackers()
+                .into_iter()
+                .flatten()
+                .map(|url| match tracker::scrape(&url, &info_hashes, timeout) {
+                    Ok(stats) => ScrapeOutput {
+                        tracker: url,
+                        error: None,
+                        torrents: info_hashes
+                            .iter()
+                            .zip(stats)
+                            .map(|(info_hash, stats)| ScrapeTorrentOutput {
+                                info_hash: hex::encode(info_hash),
+                                complete: stats.complete,
+                                downloaded: stats.downloaded,
+                                incomplete: stats.incomplete,
+                            })
+                            .collect(),
+                    },
+                    Err(error) => ScrapeOutput {
+                        tracker: url,
+                        error: Some(error.to_string()),
+                        torrents: Vec::new(),
+                    },
+                })
+                .collect();
+
+            if args.format == Format::Json {
+                print_json(&outputs);
+                return;
+            }
+
+            for output in outputs {
+                println!("{}", output.tracker);
+                if let Some(error) = output.error {
+                    println!("  Error: {error}");
+                }
+                for torrent in output.torrents {
+                    println!(
+                        "  {}: {} seeders, {} leechers, {} downloads",
+                        torrent.info_hash, torrent.complete, torrent.incomplete, torrent.downloaded
+                    );
+                }
+            }
+        }
+        Command::Tracker(tracker_args) => {
+            let interval = Duration::from_secs(tracker_args.interval);
+            let mut server = Server::new(ServerOptions {
+                interval,
+                peer_timeout: interval * 2,
+            });
+
+            for path in &tracker_args.allow {
+                server
+                    .allow_file(path)
+                    .unwrap_or_else(|error| fail(format!("could not read torrent: {error}")));
+            }
+
+            let server = Arc::new(server);
+
+            if let Some(address) = tracker_args.http {
+                let listener = TcpListener::bind(address)
+                    .unwrap_or_else(|error| fail(format!("could not bind HTTP tracker: {error}")));
+                let server = server.clone();
+                thread::spawn(move || server::http::serve(listener, server));
+                println!("HTTP tracker listening on http://{address}/announce");
+            }
+
+            if let Some(address) = tracker_args.udp {
+                let socket = UdpSocket::bind(address)
+                    .unwrap_or_else(|error| fail(format!("could not bind UDP tracker: {error}")));
+                let server = server.clone();
+                thread::spawn(move || server::udp::serve(socket, server));
+                println!("UDP tracker listening on udp://{address}/announce");
+            }
+
+            loop {
+                thread::sleep(interval);
+                server.expire(Instant::now());
+            }
+        }
     }
 }
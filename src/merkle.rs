@@ -0,0 +1,152 @@
+use sha2::{Digest, Sha256};
+
+use crate::geometry::BLOCK_SIZE;
+
+pub const HASH_SIZE: usize = 32;
+
+pub type Hash = [u8; HASH_SIZE];
+
+pub fn hash_block(block: &[u8]) -> Hash {
+    Sha256::digest(block).into()
+}
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a subtree of the given height whose leaves are all zero, used to
+/// pad layers that do not fill a complete tree.
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold([0; HASH_SIZE], |hash, _| hash_pair(&hash, &hash))
+}
+
+/// Merkle root of `hashes` padded with `pad` up to `width` nodes. `width`
+/// must be a power of two no smaller than the number of hashes.
+pub fn root(hashes: &[Hash], width: usize, pad: Hash) -> Hash {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(1), pad);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+
+    layer[0]
+}
+
+/// Hashes of every 16 KiB block of `data`, the leaves of a BEP 52 file tree.
+pub fn leaf_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE as usize).map(hash_block).collect()
+}
+
+/// Piece layer of a file given its leaf hashes: one hash per piece, the last
+/// piece padded with zero leaves.
+pub fn piece_layer(leaves: &[Hash], piece_length: u32) -> Vec<Hash> {
+    let blocks_per_piece = (piece_length / BLOCK_SIZE) as usize;
+
+    leaves
+        .chunks(blocks_per_piece)
+        .map(|piece| root(piece, blocks_per_piece, [0; HASH_SIZE]))
+        .collect()
+}
+
+/// Pieces root of a file given its leaf hashes. Files no larger than a piece
+/// are padded to the next power of two blocks, larger files to a whole
+/// number of pieces.
+pub fn pieces_root(leaves: &[Hash], piece_length: u32) -> Hash {
+    let blocks_per_piece = (piece_length / BLOCK_SIZE) as usize;
+
+    if leaves.len() <= blocks_per_piece {
+        return root(leaves, leaves.len().next_power_of_two(), [0; HASH_SIZE]);
+    }
+
+    let layer = piece_layer(leaves, piece_length);
+    root(
+        &layer,
+        layer.len().next_power_of_two(),
+        pad_hash(blocks_per_piece.trailing_zeros()),
+    )
+}
+
+/// Number of layers above the leaves in the tree of a file of `length`
+/// bytes, with the padding described in [`pieces_root`].
+pub fn tree_height(length: u64, piece_length: u32) -> u32 {
+    let blocks = length.div_ceil(BLOCK_SIZE as u64).max(1);
+    let blocks_per_piece = (piece_length / BLOCK_SIZE) as u64;
+
+    if blocks <= blocks_per_piece {
+        return blocks.next_power_of_two().trailing_zeros();
+    }
+
+    let pieces = blocks.div_ceil(blocks_per_piece);
+    pieces.next_power_of_two().trailing_zeros() + blocks_per_piece.trailing_zeros()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_block_root() {
+        let leaves = leaf_hashes(b"hello");
+
+        assert_eq!(
+            hex::encode(pieces_root(&leaves, 65536)),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
+    #[test]
+    fn test_pad_hash() {
+        assert_eq!(pad_hash(0), [0; HASH_SIZE]);
+        assert_eq!(
+            pad_hash(2),
+            hash_pair(
+                &hash_pair(&[0; HASH_SIZE], &[0; HASH_SIZE]),
+                &hash_pair(&[0; HASH_SIZE], &[0; HASH_SIZE])
+            )
+        );
+    }
+
+    #[test]
+    fn test_small_file_root() {
+        let data = vec![7; BLOCK_SIZE as usize * 3];
+        let leaves = leaf_hashes(&data);
+
+        let expected = hash_pair(
+            &hash_pair(&leaves[0], &leaves[1]),
+            &hash_pair(&leaves[2], &[0; HASH_SIZE]),
+        );
+
+        assert_eq!(pieces_root(&leaves, 65536), expected);
+    }
+
+    #[test]
+    fn test_tree_height() {
+        let block = BLOCK_SIZE as u64;
+
+        assert_eq!(tree_height(5, 65536), 0);
+        assert_eq!(tree_height(3 * block, 65536), 2);
+        assert_eq!(tree_height(5 * block, 2 * BLOCK_SIZE), 3);
+        assert_eq!(tree_height(9 * block, 4 * BLOCK_SIZE), 4);
+    }
+
+    #[test]
+    fn test_multi_piece_root() {
+        let data = vec![1; BLOCK_SIZE as usize * 5];
+        let leaves = leaf_hashes(&data);
+        let layer = piece_layer(&leaves, 2 * BLOCK_SIZE);
+
+        assert_eq!(layer.len(), 3);
+        assert_eq!(layer[2], hash_pair(&leaves[4], &[0; HASH_SIZE]));
+        assert_eq!(
+            pieces_root(&leaves, 2 * BLOCK_SIZE),
+            root(&leaves, 8, [0; HASH_SIZE])
+        );
+    }
+}
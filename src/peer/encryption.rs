@@ -0,0 +1,594 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+};
+
+use num_bigint::BigUint;
+use rand::Rng;
+
+use super::handshake::PROTOCOL;
+
+/// Prime of the Diffie-Hellman key exchange, 768 bits.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+/// Size of public keys and of the shared secret.
+pub const KEY_SIZE: usize = 96;
+/// Longest padding either side may send.
+pub const MAX_PAD: usize = 512;
+/// Verification constant, sent encrypted to let the remote synchronize.
+const VC: [u8; 8] = [0; 8];
+/// Keystream bytes discarded before RC4 is used.
+const RC4_DISCARD: usize = 1024;
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether connections use Message Stream Encryption.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum EncryptionPolicy {
+    /// Only plaintext handshakes.
+    Disabled,
+    /// Outgoing connections are encrypted, and incoming ones may be either.
+    #[default]
+    Enabled,
+    /// Only encrypted connections, with RC4 for the whole stream.
+    Forced,
+}
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    Io(io::Error),
+    /// A plaintext handshake arrived while encryption is forced.
+    PlaintextRefused,
+    /// An encrypted handshake arrived while encryption is disabled.
+    EncryptionRefused,
+    /// The expected hash or verification constant was not found after the
+    /// padding.
+    SynchronizationFailed,
+    InvalidPadding(usize),
+    /// The remote asked for a torrent we do not have.
+    UnknownInfoHash,
+    /// No method of `crypto_provide`, or the `crypto_select`, is allowed.
+    NoCommonMethod(u32),
+}
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionError::Io(error) => write!(f, "I/O error: {error}"),
+            EncryptionError::PlaintextRefused => write!(f, "plaintext connections are refused"),
+            EncryptionError::EncryptionRefused => write!(f, "encrypted connections are refused"),
+            EncryptionError::SynchronizationFailed => {
+                write!(f, "could not synchronize on the encrypted stream")
+            }
+            EncryptionError::InvalidPadding(length) => {
+                write!(f, "padding of {length} bytes is too long")
+            }
+            EncryptionError::UnknownInfoHash => write!(f, "unknown info hash"),
+            EncryptionError::NoCommonMethod(methods) => {
+                write!(f, "no allowed encryption method in {methods:#x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+impl From<io::Error> for EncryptionError {
+    fn from(error: io::Error) -> Self {
+        EncryptionError::Io(error)
+    }
+}
+
+/// The RC4 stream cipher.
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (index, byte) in state.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts `data` in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = sha1_smol::Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.digest().bytes()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut result = a;
+    for (byte, other) in result.iter_mut().zip(b) {
+        *byte ^= other;
+    }
+    result
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).unwrap()
+}
+
+fn to_key_bytes(number: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = number.to_bytes_be();
+    let mut key = [0; KEY_SIZE];
+    key[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+/// One side of the Diffie-Hellman key exchange.
+pub struct KeyPair {
+    private: BigUint,
+    pub public: [u8; KEY_SIZE],
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime());
+
+        KeyPair {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    pub fn shared_secret(&self, remote: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+        let secret = BigUint::from_bytes_be(remote).modpow(&self.private, &prime());
+        to_key_bytes(&secret)
+    }
+}
+
+/// The ciphers of one side: the first encrypts what it sends, the second
+/// decrypts what it receives.
+fn ciphers(secret: &[u8; KEY_SIZE], info_hash: &[u8; 20], initiator: bool) -> (Rc4, Rc4) {
+    let key_a = hash(&[b"keyA", secret, info_hash]);
+    let key_b = hash(&[b"keyB", secret, info_hash]);
+    let (send, receive) = if initiator {
+        (key_a, key_b)
+    } else {
+        (key_b, key_a)
+    };
+
+    let mut send = Rc4::new(&send);
+    let mut receive = Rc4::new(&receive);
+    send.apply(&mut [0; RC4_DISCARD]);
+    receive.apply(&mut [0; RC4_DISCARD]);
+
+    (send, receive)
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let length = rng.gen_range(0..=MAX_PAD);
+    (0..length).map(|_| rng.gen()).collect()
+}
+
+/// Reads until `pattern`, which follows at most `MAX_PAD` bytes of padding.
+fn synchronize(stream: &mut impl Read, pattern: &[u8]) -> Result<(), EncryptionError> {
+    let mut window = Vec::with_capacity(MAX_PAD + pattern.len());
+    while !window.ends_with(pattern) {
+        if window.len() == MAX_PAD + pattern.len() {
+            return Err(EncryptionError::SynchronizationFailed);
+        }
+
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        window.push(byte[0]);
+    }
+
+    Ok(())
+}
+
+/// Reads and decrypts `length` bytes.
+fn read_decrypted(
+    stream: &mut impl Read,
+    cipher: &mut Rc4,
+    length: usize,
+) -> Result<Vec<u8>, EncryptionError> {
+    let mut bytes = vec![0; length];
+    stream.read_exact(&mut bytes)?;
+    cipher.apply(&mut bytes);
+    Ok(bytes)
+}
+
+/// Reads a padding length and skips that much padding.
+fn skip_padding(stream: &mut impl Read, cipher: &mut Rc4) -> Result<(), EncryptionError> {
+    let length = read_decrypted(stream, cipher, 2)?;
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+    if length > MAX_PAD {
+        return Err(EncryptionError::InvalidPadding(length));
+    }
+
+    read_decrypted(stream, cipher, length)?;
+    Ok(())
+}
+
+/// A peer connection after the encryption handshake, which may have
+/// settled on plaintext. The peer wire protocol, starting with the
+/// BitTorrent handshake, runs on top of it.
+pub struct EncryptedStream<S> {
+    stream: S,
+    /// Ciphers for sending and receiving, unless plaintext was selected.
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Bytes received during the handshake, read before the stream.
+    pending: Vec<u8>,
+}
+
+impl<S: Read + Write> EncryptedStream<S> {
+    pub fn plaintext(stream: S) -> Self {
+        EncryptedStream {
+            stream,
+            ciphers: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: Read> Read for EncryptedStream<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let length = buffer.len().min(self.pending.len());
+            buffer[..length].copy_from_slice(&self.pending[..length]);
+            self.pending.drain(..length);
+            return Ok(length);
+        }
+
+        let length = self.stream.read(buffer)?;
+        if let Some((_, receive)) = &mut self.ciphers {
+            receive.apply(&mut buffer[..length]);
+        }
+
+        Ok(length)
+    }
+}
+
+impl<S: Write> Write for EncryptedStream<S> {
+    /// Writes all of `data`, as the keystream already moved past it.
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match &mut self.ciphers {
+            Some((send, _)) => {
+                let mut encrypted = data.to_vec();
+                send.apply(&mut encrypted);
+                self.stream.write_all(&encrypted)?;
+            }
+            None => self.stream.write_all(data)?,
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Opens the encryption handshake for the torrent of `info_hash`, as the
+/// side that opened the connection.
+pub fn initiate<S: Read + Write>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<EncryptedStream<S>, EncryptionError> {
+    if policy == EncryptionPolicy::Disabled {
+        return Ok(EncryptedStream::plaintext(stream));
+    }
+
+    let keys = KeyPair::generate();
+    let mut message = keys.public.to_vec();
+    message.extend(random_pad());
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    let mut remote = [0; KEY_SIZE];
+    stream.read_exact(&mut remote)?;
+    let secret = keys.shared_secret(&remote);
+    let (mut send, mut receive) = ciphers(&secret, info_hash, true);
+
+    let provide = match policy {
+        EncryptionPolicy::Forced => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+
+    // The info hash is only sent obfuscated; no padding nor initial payload
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret])));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend(provide.to_be_bytes());
+    encrypted.extend(0u16.to_be_bytes());
+    encrypted.extend(0u16.to_be_bytes());
+    send.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    let mut vc = VC;
+    receive.apply(&mut vc);
+    synchronize(&mut stream, &vc)?;
+
+    let select = read_decrypted(&mut stream, &mut receive, 4)?;
+    let select = u32::from_be_bytes(select.try_into().unwrap());
+    skip_padding(&mut stream, &mut receive)?;
+
+    let ciphers = match select {
+        CRYPTO_RC4 => Some((send, receive)),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Forced => None,
+        _ => return Err(EncryptionError::NoCommonMethod(select)),
+    };
+
+    Ok(EncryptedStream {
+        stream,
+        ciphers,
+        pending: Vec::new(),
+    })
+}
+
+/// Answers the handshake of an incoming connection, encrypted or not. For
+/// encrypted ones, also returns which of `info_hashes` the remote asked for.
+pub fn accept<S: Read + Write>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(EncryptedStream<S>, Option<[u8; 20]>), EncryptionError> {
+    let mut remote = [0; KEY_SIZE];
+    stream.read_exact(&mut remote[..PROTOCOL.len()])?;
+
+    if remote[..PROTOCOL.len()] == PROTOCOL[..] {
+        if policy == EncryptionPolicy::Forced {
+            return Err(EncryptionError::PlaintextRefused);
+        }
+
+        let mut plaintext = EncryptedStream::plaintext(stream);
+        plaintext.pending = PROTOCOL.to_vec();
+        return Ok((plaintext, None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(EncryptionError::EncryptionRefused);
+    }
+
+    stream.read_exact(&mut remote[PROTOCOL.len()..])?;
+    let keys = KeyPair::generate();
+    let mut message = keys.public.to_vec();
+    message.extend(random_pad());
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    let secret = keys.shared_secret(&remote);
+    synchronize(&mut stream, &hash(&[b"req1", &secret]))?;
+
+    let mut obfuscated = [0; 20];
+    stream.read_exact(&mut obfuscated)?;
+    let mask = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| xor(hash(&[b"req2", *info_hash]), mask) == obfuscated)
+        .ok_or(EncryptionError::UnknownInfoHash)?;
+    let (mut send, mut receive) = ciphers(&secret, &info_hash, false);
+
+    let fields = read_decrypted(&mut stream, &mut receive, VC.len() + 4)?;
+    if fields[..VC.len()] != VC {
+        return Err(EncryptionError::SynchronizationFailed);
+    }
+    let provide = u32::from_be_bytes(fields[VC.len()..].try_into().unwrap());
+    skip_padding(&mut stream, &mut receive)?;
+
+    // The initial payload is encrypted whatever gets selected
+    let length = read_decrypted(&mut stream, &mut receive, 2)?;
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+    let pending = read_decrypted(&mut stream, &mut receive, length)?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(EncryptionError::NoCommonMethod(provide));
+    };
+
+    let mut message = VC.to_vec();
+    message.extend(select.to_be_bytes());
+    message.extend(0u16.to_be_bytes());
+    send.apply(&mut message);
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    let stream = EncryptedStream {
+        stream,
+        ciphers: (select == CRYPTO_RC4).then_some((send, receive)),
+        pending,
+    };
+
+    Ok((stream, Some(info_hash)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::peer::{
+        handshake::{self, Handshake, InfoHashes, Reserved, Version},
+        message::{Codec, Message},
+    };
+
+    const INFO_HASH: [u8; 20] = [5; 20];
+
+    /// Records the bytes written to a stream.
+    struct Recorder {
+        stream: TcpStream,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Recorder {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.stream.read(buffer)
+        }
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let written = self.stream.write(data)?;
+            self.written.lock().unwrap().extend(&data[..written]);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    fn pair() -> (Recorder, TcpStream, Arc<Mutex<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let written = Arc::new(Mutex::new(Vec::new()));
+
+        let recorder = Recorder {
+            stream: client,
+            written: written.clone(),
+        };
+        (recorder, server, written)
+    }
+
+    #[test]
+    fn test_rc4() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+
+        let mut data = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut data);
+        assert_eq!(hex::encode(data), "45a01f645fc35b383552544b9bf5");
+    }
+
+    #[test]
+    fn test_key_exchange() {
+        let a = KeyPair::generate();
+        let b = KeyPair::generate();
+        assert_ne!(a.public, b.public);
+        assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+    }
+
+    #[test]
+    fn test_encrypted_handshake() {
+        let (client, server, written) = pair();
+        let hashes = InfoHashes {
+            v1: Some(INFO_HASH),
+            v2: None,
+        };
+
+        let handle = thread::spawn(move || {
+            let (mut stream, info_hash) =
+                accept(server, &[[1; 20], INFO_HASH], EncryptionPolicy::Forced).unwrap();
+            assert_eq!(info_hash, Some(INFO_HASH));
+            assert!(stream.is_encrypted());
+
+            handshake::accept(&mut stream, |_| Some(hashes), Reserved::default(), [2; 20]).unwrap();
+            let message = Codec::default().read_from(&mut stream).unwrap();
+            Codec::default().write_to(&mut stream, &message).unwrap();
+        });
+
+        let mut stream = initiate(client, &INFO_HASH, EncryptionPolicy::Enabled).unwrap();
+        assert!(stream.is_encrypted());
+        let ours = Handshake::new(Reserved::default(), INFO_HASH, [1; 20]);
+        let (theirs, version) = handshake::initiate(&mut stream, &hashes, &ours).unwrap();
+        assert_eq!((theirs.peer_id, version), ([2; 20], Version::V1));
+
+        let message = Message::Have(7);
+        Codec::default().write_to(&mut stream, &message).unwrap();
+        assert_eq!(Codec::default().read_from(&mut stream).unwrap(), message);
+        handle.join().unwrap();
+
+        // Nothing on the wire gives the protocol or the info hash away
+        let written = written.lock().unwrap();
+        assert!(!written.windows(19).any(|window| window == &PROTOCOL[1..]));
+        assert!(!written.windows(20).any(|window| window == INFO_HASH));
+    }
+
+    #[test]
+    fn test_plaintext_fallback() {
+        let (client, server, _) = pair();
+        let handle = thread::spawn(move || accept(server, &[INFO_HASH], EncryptionPolicy::Enabled));
+
+        let mut stream = initiate(client, &INFO_HASH, EncryptionPolicy::Disabled).unwrap();
+        assert!(!stream.is_encrypted());
+        Handshake::new(Reserved::default(), INFO_HASH, [1; 20])
+            .write_to(&mut stream)
+            .unwrap();
+
+        let (mut accepted, info_hash) = handle.join().unwrap().unwrap();
+        assert_eq!(info_hash, None);
+        let handshake = Handshake::read_from(&mut accepted).unwrap();
+        assert_eq!(handshake.info_hash, INFO_HASH);
+    }
+
+    #[test]
+    fn test_policies() {
+        let (client, server, _) = pair();
+        let handle = thread::spawn(move || accept(server, &[INFO_HASH], EncryptionPolicy::Forced));
+        let mut stream = initiate(client, &INFO_HASH, EncryptionPolicy::Disabled).unwrap();
+        Handshake::new(Reserved::default(), INFO_HASH, [1; 20])
+            .write_to(&mut stream)
+            .unwrap();
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(EncryptionError::PlaintextRefused)
+        ));
+
+        let (client, server, _) = pair();
+        let handle =
+            thread::spawn(move || accept(server, &[INFO_HASH], EncryptionPolicy::Disabled));
+        assert!(initiate(client, &INFO_HASH, EncryptionPolicy::Enabled).is_err());
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(EncryptionError::EncryptionRefused)
+        ));
+
+        let (client, server, _) = pair();
+        let handle = thread::spawn(move || accept(server, &[[1; 20]], EncryptionPolicy::Enabled));
+        assert!(initiate(client, &INFO_HASH, EncryptionPolicy::Enabled).is_err());
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(EncryptionError::UnknownInfoHash)
+        ));
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use indexmap::IndexMap;
+
+use crate::bencode_decoder::{Bencode, DecodeError};
+
+use super::message::Message;
+
+/// Extended message id of the extended handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ExtensionError {
+    Decode(DecodeError),
+    InvalidHandshake(String),
+    /// A message arrived for an id we never assigned.
+    UnknownId(u8),
+    /// An extension refused a message.
+    InvalidMessage {
+        extension: String,
+        reason: String,
+    },
+}
+
+impl Display for ExtensionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtensionError::Decode(error) => write!(f, "invalid bencode: {error}"),
+            ExtensionError::InvalidHandshake(reason) => {
+                write!(f, "invalid extended handshake: {reason}")
+            }
+            ExtensionError::UnknownId(id) => write!(f, "unknown extended message id {id}"),
+            ExtensionError::InvalidMessage { extension, reason } => {
+                write!(f, "invalid {extension} message: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtensionError {}
+
+impl From<DecodeError> for ExtensionError {
+    fn from(error: DecodeError) -> Self {
+        ExtensionError::Decode(error)
+    }
+}
+
+/// The dictionary sent as extended message 0.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message id the sender wants to receive
+    /// them with. Id 0 disables an extension.
+    pub extensions: IndexMap<String, u8>,
+    /// Client name and version.
+    pub version: Option<String>,
+    /// Listening port.
+    pub port: Option<u16>,
+    /// Address the sender sees the receiver connecting from.
+    pub your_ip: Option<IpAddr>,
+    /// Number of outstanding requests the sender accepts.
+    pub reqq: Option<u32>,
+    /// Size of the info dictionary (BEP 9).
+    pub metadata_size: Option<u32>,
+    /// Keys that are not interpreted, kept for extensions that need them.
+    pub extra: IndexMap<Vec<u8>, Bencode>,
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = self.extra.clone();
+
+        let m = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Bencode::Integer((*id).into())))
+            .collect();
+        dict.insert(b"m".to_vec(), Bencode::Dictionary(m));
+
+        if let Some(metadata_size) = self.metadata_size {
+            dict.insert(
+                b"metadata_size".to_vec(),
+                Bencode::Integer(metadata_size.into()),
+            );
+        }
+        if let Some(port) = self.port {
+            dict.insert(b"p".to_vec(), Bencode::Integer(port.into()));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), Bencode::Integer(reqq.into()));
+        }
+        if let Some(version) = &self.version {
+            dict.insert(b"v".to_vec(), Bencode::String(version.as_bytes().to_vec()));
+        }
+        if let Some(your_ip) = self.your_ip {
+            let ip = match your_ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            dict.insert(b"yourip".to_vec(), Bencode::String(ip));
+        }
+
+        dict.sort_keys();
+        for (_, value) in dict.iter_mut() {
+            if let Bencode::Dictionary(m) = value {
+                m.sort_keys();
+            }
+        }
+
+        Bencode::Dictionary(dict).encode_value()
+    }
+
+    /// Decodes a handshake, ignoring fields of the wrong type as BEP 10 asks
+    /// for unknown or malformed entries.
+    pub fn decode(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let (value, _) = Bencode::try_decode_value(payload.to_vec())?;
+
+        let Bencode::Dictionary(mut dict) = value else {
+            return Err(ExtensionError::InvalidHandshake(
+                "handshake is not a dictionary".to_string(),
+            ));
+        };
+
+        let integer =
+            |dict: &mut IndexMap<Vec<u8>, Bencode>, key: &[u8]| match dict.shift_remove(key) {
+                Some(Bencode::Integer(value)) => Some(value),
+                _ => None,
+            };
+
+        let extensions = match dict.shift_remove(b"m".as_slice()) {
+            Some(Bencode::Dictionary(m)) => m
+                .into_iter()
+                .filter_map(|(name, id)| match id {
+                    Bencode::Integer(id) => {
+                        Some((String::from_utf8(name).ok()?, u8::try_from(id).ok()?))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => IndexMap::new(),
+        };
+
+        let version = match dict.shift_remove(b"v".as_slice()) {
+            Some(Bencode::String(version)) => Some(String::from_utf8_lossy(&version).to_string()),
+            _ => None,
+        };
+
+        let your_ip = match dict.shift_remove(b"yourip".as_slice()) {
+            Some(Bencode::String(ip)) => match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(ip.as_slice()).unwrap(),
+                ))),
+                16 => Some(IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(ip.as_slice()).unwrap(),
+                ))),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        Ok(ExtendedHandshake {
+            extensions,
+            version,
+            port: integer(&mut dict, b"p").and_then(|port| u16::try_from(port).ok()),
+            your_ip,
+            reqq: integer(&mut dict, b"reqq").and_then(|reqq| u32::try_from(reqq).ok()),
+            metadata_size: integer(&mut dict, b"metadata_size")
+                .and_then(|size| u32::try_from(size).ok()),
+            extra: dict,
+        })
+    }
+}
+
+impl ExtendedHandshake {
+    /// Applies a later handshake from the same peer. As BEP 10 asks, only
+    /// the fields it carries change: ids in `m` are updated, id 0 removes an
+    /// extension, and everything left out keeps its earlier value.
+    pub fn update(&mut self, later: ExtendedHandshake) {
+        for (name, id) in later.extensions {
+            if id == 0 {
+                self.extensions.shift_remove(&name);
+            } else {
+                self.extensions.insert(name, id);
+            }
+        }
+
+        self.version = later.version.or(self.version.take());
+        self.port = later.port.or(self.port);
+        self.your_ip = later.your_ip.or(self.your_ip);
+        self.reqq = later.reqq.or(self.reqq);
+        self.metadata_size = later.metadata_size.or(self.metadata_size);
+        self.extra.extend(later.extra);
+    }
+}
+
+/// An extension built on the extension protocol, such as `ut_metadata` or
+/// `ut_pex`.
+pub trait Extension {
+    /// Name the extension is advertised under in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Adds the extension's own keys to our handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called with the remote handshake, returning the payloads to send once
+    /// it arrived. `enabled` tells whether the remote supports this
+    /// extension.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake, _enabled: bool) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    /// Handles a message for this extension, returning the payloads to send
+    /// back.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError>;
+}
+
+/// The extensions of one connection. Local message ids are assigned in
+/// registration order, starting at 1.
+#[derive(Default)]
+pub struct Extensions {
+    extensions: Vec<Box<dyn Extension + Send>>,
+    version: Option<String>,
+    remote: Option<ExtendedHandshake>,
+}
+
+impl Extensions {
+    pub fn new(version: Option<String>) -> Self {
+        Extensions {
+            extensions: Vec::new(),
+            version,
+            remote: None,
+        }
+    }
+
+    /// Registers an extension and returns the id it receives messages with.
+    pub fn register(&mut self, extension: Box<dyn Extension + Send>) -> u8 {
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .position(|extension| extension.name() == name)
+            .map(|index| index as u8 + 1)
+    }
+
+    /// Id the remote wants to receive `name` messages with, once its
+    /// handshake arrived and if it enabled the extension.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote
+            .as_ref()?
+            .extensions
+            .get(name)
+            .copied()
+            .filter(|id| *id != 0)
+    }
+
+    pub fn remote_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// Our extended handshake, with `your_ip` set to the remote's address.
+    pub fn handshake(&self, your_ip: Option<IpAddr>) -> Message {
+        let mut handshake = ExtendedHandshake {
+            extensions: self
+                .extensions
+                .iter()
+                .enumerate()
+                .map(|(index, extension)| (extension.name().to_string(), index as u8 + 1))
+                .collect(),
+            version: self.version.clone(),
+            your_ip,
+            ..Default::default()
+        };
+
+        for extension in &self.extensions {
+            extension.extend_handshake(&mut handshake);
+        }
+
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: handshake.encode(),
+        }
+    }
+
+    /// Wraps a payload of the named extension with the remote's id for it.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        Some(Message::Extended {
+            id: self.remote_id(name)?,
+            payload,
+        })
+    }
+
+    /// Dispatches an extended message, returning the messages to send back.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>, ExtensionError> {
+        if id == HANDSHAKE_ID {
+            // Later handshakes update the earlier one
+            let later = ExtendedHandshake::decode(payload)?;
+            let mut handshake = self.remote.take().unwrap_or_default();
+            handshake.update(later);
+
+            let mut replies = Vec::new();
+            for extension in &mut self.extensions {
+                let remote_id = handshake
+                    .extensions
+                    .get(extension.name())
+                    .copied()
+                    .filter(|id| *id != 0);
+
+                replies.extend(
+                    extension
+                        .on_handshake(&handshake, remote_id.is_some())
+                        .into_iter()
+                        .filter_map(|payload| {
+                            Some(Message::Extended {
+                                id: remote_id?,
+                                payload,
+                            })
+                        }),
+                );
+            }
+
+            self.remote = Some(handshake);
+            return Ok(replies);
+        }
+
+        let extension = self
+            .extensions
+            .get_mut(id as usize - 1)
+            .ok_or(ExtensionError::UnknownId(id))?;
+        let name = extension.name();
+        let replies = extension.on_message(payload)?;
+
+        Ok(replies
+            .into_iter()
+            .filter_map(|payload| self.message(name, payload))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echoes every message back and records the remote handshake.
+    #[derive(Default)]
+    struct Echo {
+        enabled: bool,
+    }
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+            handshake.metadata_size = Some(1234);
+        }
+
+        fn on_handshake(&mut self, _handshake: &ExtendedHandshake, enabled: bool) -> Vec<Vec<u8>> {
+            self.enabled = enabled;
+            vec![b"hello".to_vec()]
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+            if payload.is_empty() {
+                return Err(ExtensionError::InvalidMessage {
+                    extension: "echo".to_string(),
+                    reason: "empty".to_string(),
+                });
+            }
+
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    struct Silent;
+
+    impl Extension for Silent {
+        fn name(&self) -> &'static str {
+            "silent"
+        }
+
+        fn on_message(&mut self, _payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let handshake = ExtendedHandshake {
+            extensions: IndexMap::from([("ut_metadata".to_string(), 3), ("ut_pex".to_string(), 1)]),
+            version: Some("bittorent 0.1.0".to_string()),
+            port: Some(6881),
+            your_ip: Some("2001:db8::1".parse().unwrap()),
+            reqq: Some(250),
+            metadata_size: Some(31235),
+            extra: IndexMap::from([(b"complete_ago".to_vec(), Bencode::Integer(1))]),
+        };
+
+        let encoded = handshake.encode();
+        assert!(encoded.starts_with(b"d12:complete_agoi1e1:md11:ut_metadatai3e6:ut_pexi1ee"));
+        assert_eq!(ExtendedHandshake::decode(&encoded).unwrap(), handshake);
+    }
+
+    #[test]
+    fn test_decode_lenient() {
+        let handshake =
+            ExtendedHandshake::decode(b"d1:md1:ai1e1:bi300ee1:pi70000e6:yourip3:abce").unwrap();
+
+        assert_eq!(handshake.extensions, IndexMap::from([("a".to_string(), 1)]));
+        assert_eq!(handshake.port, None);
+        assert_eq!(handshake.your_ip, None);
+
+        assert!(matches!(
+            ExtendedHandshake::decode(b"le"),
+            Err(ExtensionError::InvalidHandshake(_))
+        ));
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut extensions = Extensions::new(Some("test".to_string()));
+        assert_eq!(extensions.register(Box::new(Silent)), 1);
+        assert_eq!(extensions.register(Box::new(Echo::default())), 2);
+        assert_eq!(extensions.local_id("echo"), Some(2));
+
+        let Message::Extended { id: 0, payload } = extensions.handshake(None) else {
+            panic!("handshake should be extended message 0");
+        };
+        let ours = ExtendedHandshake::decode(&payload).unwrap();
+        assert_eq!(ours.extensions["echo"], 2);
+        assert_eq!(ours.metadata_size, Some(1234));
+
+        // Nothing is sent before the remote assigned its ids
+        assert_eq!(extensions.handle(2, b"hi").unwrap(), vec![]);
+
+        assert_eq!(
+            extensions
+                .handle(0, b"d1:md4:echoi7e6:silenti0eee")
+                .unwrap(),
+            vec![Message::Extended {
+                id: 7,
+                payload: b"hello".to_vec()
+            }]
+        );
+        assert_eq!(extensions.remote_id("echo"), Some(7));
+        assert_eq!(extensions.remote_id("silent"), None);
+
+        assert_eq!(
+            extensions.handle(2, b"hi").unwrap(),
+            vec![Message::Extended {
+                id: 7,
+                payload: b"hi".to_vec()
+            }]
+        );
+        assert!(matches!(
+            extensions.handle(2, b""),
+            Err(ExtensionError::InvalidMessage { .. })
+        ));
+        assert_eq!(extensions.handle(3, b""), Err(ExtensionError::UnknownId(3)));
+    }
+
+    #[test]
+    fn test_later_handshake() {
+        let mut extensions = Extensions::new(None);
+        extensions.register(Box::new(Silent));
+        extensions.register(Box::new(Echo::default()));
+
+        extensions
+            .handle(
+                0,
+                b"d1:md4:echoi7e6:silenti3ee1:v4:peer13:metadata_sizei99ee",
+            )
+            .unwrap();
+        extensions
+            .handle(0, b"d1:md6:silenti0ee1:pi6881ee")
+            .unwrap();
+
+        assert_eq!(extensions.remote_id("echo"), Some(7));
+        assert_eq!(extensions.remote_id("silent"), None);
+
+        let remote = extensions.remote_handshake().unwrap();
+        assert_eq!(remote.version, Some("peer".to_string()));
+        assert_eq!(remote.metadata_size, Some(99));
+        assert_eq!(remote.port, Some(6881));
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+};
+
+use crate::{torrent::Info, tracker::truncate_infohash};
+
+/// Length-prefixed protocol string every handshake starts with.
+pub const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+pub const HANDSHAKE_SIZE: usize = 68;
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    InvalidProtocol,
+    /// The info hash of the remote handshake is not one of the torrent's.
+    UnknownInfoHash([u8; 20]),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::Io(error) => write!(f, "I/O error: {error}"),
+            HandshakeError::InvalidProtocol => write!(f, "not a BitTorrent handshake"),
+            HandshakeError::UnknownInfoHash(info_hash) => {
+                write!(f, "unknown info hash {}", hex::encode(info_hash))
+            }
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    fn from(error: io::Error) -> Self {
+        HandshakeError::Io(error)
+    }
+}
+
+/// The 8 reserved bytes announcing protocol extensions.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    /// Extension protocol (BEP 10).
+    const EXTENSION: (usize, u8) = (5, 0x10);
+    /// Upgrade of hybrid torrents to v2 (BEP 52).
+    const V2: (usize, u8) = (7, 0x10);
+    /// DHT port message (BEP 5).
+    const DHT: (usize, u8) = (7, 0x01);
+
+    fn get(&self, (byte, mask): (usize, u8)) -> bool {
+        self.0[byte] & mask != 0
+    }
+
+    fn set(&mut self, (byte, mask): (usize, u8), enabled: bool) {
+        if enabled {
+            self.0[byte] |= mask;
+        } else {
+            self.0[byte] &= !mask;
+        }
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.get(Self::EXTENSION)
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.get(Self::V2)
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.get(Self::DHT)
+    }
+
+    pub fn set_extensions(&mut self, enabled: bool) {
+        self.set(Self::EXTENSION, enabled);
+    }
+
+    pub fn set_v2(&mut self, enabled: bool) {
+        self.set(Self::V2, enabled);
+    }
+
+    pub fn set_dht(&mut self, enabled: bool) {
+        self.set(Self::DHT, enabled);
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Handshake {
+    pub reserved: Reserved,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(reserved: Reserved, info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HANDSHAKE_SIZE] {
+        let mut bytes = [0; HANDSHAKE_SIZE];
+        bytes[..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved.0);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; HANDSHAKE_SIZE]) -> Result<Self, HandshakeError> {
+        if &bytes[..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+
+        Ok(Handshake {
+            reserved: Reserved(bytes[20..28].try_into().unwrap()),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..].try_into().unwrap(),
+        })
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.encode())?;
+        writer.flush()
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, HandshakeError> {
+        let mut bytes = [0; HANDSHAKE_SIZE];
+        reader.read_exact(&mut bytes)?;
+
+        Self::decode(&bytes)
+    }
+}
+
+/// Protocol version a connection ends up speaking.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// Info hashes a torrent can be reached under in handshakes.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct InfoHashes {
+    pub v1: Option<[u8; 20]>,
+    pub v2: Option<[u8; 32]>,
+}
+
+impl InfoHashes {
+    pub fn from_info(info: &Info) -> Self {
+        InfoHashes {
+            v1: info.get_infohash_v1_bytes(),
+            v2: Some(info.get_infohash_bytes()),
+        }
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.v1.is_some() && self.v2.is_some()
+    }
+
+    /// Info hash sent in handshakes for `version`. The v2 hash is truncated
+    /// to fit the 20 byte field.
+    pub fn handshake_hash(&self, version: Version) -> Option<[u8; 20]> {
+        match version {
+            Version::V1 => self.v1,
+            Version::V2 => self.v2.as_ref().map(truncate_infohash),
+        }
+    }
+
+    /// Version of the swarm a handshake info hash belongs to.
+    pub fn version_of(&self, info_hash: &[u8; 20]) -> Option<Version> {
+        if self.handshake_hash(Version::V2).as_ref() == Some(info_hash) {
+            Some(Version::V2)
+        } else if self.v1.as_ref() == Some(info_hash) {
+            Some(Version::V1)
+        } else {
+            None
+        }
+    }
+
+    /// Reserved bits to send for this torrent, with the upgrade bit set for
+    /// hybrid torrents.
+    pub fn reserved(&self, mut reserved: Reserved) -> Reserved {
+        reserved.set_v2(self.is_hybrid());
+        reserved
+    }
+
+    /// Picks the version of a connection once both handshakes were exchanged.
+    /// Connections made with the v1 info hash of a hybrid torrent switch to v2
+    /// when both sides set the upgrade bit.
+    pub fn negotiate(
+        &self,
+        ours: &Handshake,
+        theirs: &Handshake,
+    ) -> Result<Version, HandshakeError> {
+        match self.version_of(&theirs.info_hash) {
+            Some(Version::V1)
+                if self.is_hybrid()
+                    && ours.reserved.supports_v2()
+                    && theirs.reserved.supports_v2() =>
+            {
+                Ok(Version::V2)
+            }
+            Some(version) => Ok(version),
+            None => Err(HandshakeError::UnknownInfoHash(theirs.info_hash)),
+        }
+    }
+}
+
+/// Sends our handshake first, as the side that opened the connection does,
+/// then reads and checks the remote one.
+pub fn initiate(
+    stream: &mut (impl Read + Write),
+    hashes: &InfoHashes,
+    ours: &Handshake,
+) -> Result<(Handshake, Version), HandshakeError> {
+    ours.write_to(stream)?;
+    let theirs = Handshake::read_from(stream)?;
+
+    // The remote must answer for the swarm we asked for
+    if theirs.info_hash != ours.info_hash {
+        return Err(HandshakeError::UnknownInfoHash(theirs.info_hash));
+    }
+
+    let version = hashes.negotiate(ours, &theirs)?;
+
+    Ok((theirs, version))
+}
+
+/// Reads the remote handshake of an incoming connection, finds its torrent
+/// with `lookup` and answers with the same info hash.
+pub fn accept(
+    stream: &mut (impl Read + Write),
+    lookup: impl Fn(&[u8; 20]) -> Option<InfoHashes>,
+    reserved: Reserved,
+    peer_id: [u8; 20],
+) -> Result<(Handshake, InfoHashes, Version), HandshakeError> {
+    let theirs = Handshake::read_from(stream)?;
+
+    let hashes =
+        lookup(&theirs.info_hash).ok_or(HandshakeError::UnknownInfoHash(theirs.info_hash))?;
+
+    let ours = Handshake::new(hashes.reserved(reserved), theirs.info_hash, peer_id);
+    ours.write_to(stream)?;
+
+    let version = hashes.negotiate(&ours, &theirs)?;
+
+    Ok((theirs, hashes, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+
+    const HYBRID: InfoHashes = InfoHashes {
+        v1: Some([1; 20]),
+        v2: Some([2; 32]),
+    };
+
+    #[test]
+    fn test_encode_decode() {
+        let mut reserved = Reserved::default();
+        reserved.set_extensions(true);
+        reserved.set_dht(true);
+        let handshake = Handshake::new(reserved, [3; 20], *b"-BT0100-abcdefghijkl");
+        let bytes = handshake.encode();
+
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&bytes[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!(Handshake::decode(&bytes).unwrap(), handshake);
+
+        let mut bytes = bytes;
+        bytes[0] = 18;
+        assert!(matches!(
+            Handshake::decode(&bytes),
+            Err(HandshakeError::InvalidProtocol)
+        ));
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        let mut reserved = Reserved::default();
+        reserved.set_v2(true);
+
+        assert_eq!(reserved.0, [0, 0, 0, 0, 0, 0, 0, 0x10]);
+        assert!(reserved.supports_v2());
+        assert!(!reserved.supports_dht());
+        assert!(!reserved.supports_extensions());
+
+        reserved.set_v2(false);
+        assert_eq!(reserved, Reserved::default());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let upgrade = HYBRID.reserved(Reserved::default());
+        let truncated = [2; 20];
+
+        let ours = Handshake::new(upgrade, [1; 20], [0; 20]);
+        let theirs = Handshake::new(upgrade, [1; 20], [9; 20]);
+        assert_eq!(HYBRID.negotiate(&ours, &theirs).unwrap(), Version::V2);
+
+        let theirs = Handshake::new(Reserved::default(), [1; 20], [9; 20]);
+        assert_eq!(HYBRID.negotiate(&ours, &theirs).unwrap(), Version::V1);
+
+        let theirs = Handshake::new(Reserved::default(), truncated, [9; 20]);
+        assert_eq!(HYBRID.negotiate(&ours, &theirs).unwrap(), Version::V2);
+
+        let v1_only = InfoHashes {
+            v1: Some([1; 20]),
+            v2: None,
+        };
+        let theirs = Handshake::new(upgrade, [1; 20], [9; 20]);
+        assert_eq!(v1_only.negotiate(&ours, &theirs).unwrap(), Version::V1);
+
+        let theirs = Handshake::new(upgrade, [7; 20], [9; 20]);
+        assert!(matches!(
+            HYBRID.negotiate(&ours, &theirs),
+            Err(HandshakeError::UnknownInfoHash(_))
+        ));
+    }
+
+    #[test]
+    fn test_info_hashes_from_info() {
+        let content = std::fs::read("test_folder.torrent").unwrap();
+        let (metainfo, _) = crate::bencode_decoder::Bencode::decode_value(content);
+        let torrent = crate::torrent::Torrent::parse(&metainfo);
+        let hashes = InfoHashes::from_info(&torrent.info);
+
+        assert!(!hashes.is_hybrid());
+        assert_eq!(
+            hex::encode(hashes.handshake_hash(Version::V2).unwrap()),
+            "22fd2f407dd4187ca9b77b7937587f53346f0aeb"
+        );
+        assert_eq!(hashes.handshake_hash(Version::V1), None);
+    }
+
+    #[test]
+    fn test_loopback_upgrade() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            accept(
+                &mut stream,
+                |info_hash| HYBRID.version_of(info_hash).map(|_| HYBRID),
+                Reserved::default(),
+                [8; 20],
+            )
+            .unwrap()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let ours = Handshake::new(HYBRID.reserved(Reserved::default()), [1; 20], [9; 20]);
+        let (theirs, version) = initiate(&mut stream, &HYBRID, &ours).unwrap();
+
+        assert_eq!(theirs.peer_id, [8; 20]);
+        assert!(theirs.reserved.supports_v2());
+        assert_eq!(version, Version::V2);
+
+        let (remote, _, remote_version) = server.join().unwrap();
+        assert_eq!(remote, ours);
+        assert_eq!(remote_version, Version::V2);
+    }
+
+    #[test]
+    fn test_loopback_unknown_torrent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            accept(&mut stream, |_| None, Reserved::default(), [8; 20]).map(|_| ())
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let ours = Handshake::new(Reserved::default(), [5; 20], [9; 20]);
+
+        // The connection is dropped without an answer
+        assert!(initiate(&mut stream, &HYBRID, &ours).is_err());
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(HandshakeError::UnknownInfoHash(info_hash)) if info_hash == [5; 20]
+        ));
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use crate::{
+    geometry::BLOCK_SIZE,
+    merkle::{self, Hash, HASH_SIZE},
+    torrent::Torrent,
+};
+
+use super::message::{HashRequest, Message};
+
+/// Largest number of base layer hashes asked for in one request.
+pub const MAX_HASHES_PER_REQUEST: u32 = 512;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum HashesError {
+    /// No file of the torrent has this pieces root.
+    UnknownRoot(Hash),
+    /// The request does not describe a valid range of the tree.
+    InvalidRequest,
+    /// The response was not asked for.
+    Unexpected,
+    WrongCount {
+        expected: usize,
+        found: usize,
+    },
+    /// The proof stops before reaching the root, so the hashes cannot be
+    /// checked.
+    IncompleteProof,
+    RootMismatch,
+}
+
+impl Display for HashesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashesError::UnknownRoot(root) => {
+                write!(f, "unknown pieces root {}", hex::encode(root))
+            }
+            HashesError::InvalidRequest => write!(f, "invalid hash request"),
+            HashesError::Unexpected => write!(f, "hashes were not requested"),
+            HashesError::WrongCount { expected, found } => {
+                write!(f, "expected {expected} hashes, got {found}")
+            }
+            HashesError::IncompleteProof => write!(f, "proof does not reach the pieces root"),
+            HashesError::RootMismatch => write!(f, "hashes do not match the pieces root"),
+        }
+    }
+}
+
+impl std::error::Error for HashesError {}
+
+/// Height of the piece layer above the 16 KiB blocks.
+pub fn piece_height(piece_length: u32) -> u32 {
+    (piece_length / BLOCK_SIZE).trailing_zeros()
+}
+
+/// Checks that a request fits in a tree of `tree_height` layers, and returns
+/// the layer of the subtree root covering the requested hashes along with
+/// the number of uncle hashes that go with them.
+///
+/// As in other implementations, `proof_layers` counts from the base layer,
+/// so the layers spanned by the requested hashes need no uncles.
+fn proof_shape(request: &HashRequest, tree_height: u32) -> Result<(u32, u32), HashesError> {
+    let length = request.length;
+
+    if !length.is_power_of_two()
+        || length > MAX_HASHES_PER_REQUEST
+        || !request.index.is_multiple_of(length)
+    {
+        return Err(HashesError::InvalidRequest);
+    }
+
+    let top = request.base_layer + length.trailing_zeros();
+    if top > tree_height {
+        return Err(HashesError::InvalidRequest);
+    }
+
+    // The layer is padded up to a power of two nodes
+    let width = 1u64 << (tree_height - request.base_layer);
+    if request.index as u64 + length as u64 > width {
+        return Err(HashesError::InvalidRequest);
+    }
+
+    let uncles = request
+        .proof_layers
+        .saturating_sub(length.trailing_zeros())
+        .min(tree_height - top);
+
+    Ok((top, uncles))
+}
+
+/// Checks the hashes of a response against the pieces root of the file,
+/// returning the base layer hashes without the proof.
+pub fn verify_hashes(
+    request: &HashRequest,
+    tree_height: u32,
+    hashes: &[Hash],
+) -> Result<Vec<Hash>, HashesError> {
+    let (top, uncles) = proof_shape(request, tree_height)?;
+    let length = request.length as usize;
+
+    if hashes.len() != length + uncles as usize {
+        return Err(HashesError::WrongCount {
+            expected: length + uncles as usize,
+            found: hashes.len(),
+        });
+    }
+
+    if top + uncles < tree_height {
+        return Err(HashesError::IncompleteProof);
+    }
+
+    let (base, proof) = hashes.split_at(length);
+    let mut node = request.index / request.length;
+    let mut hash = merkle::root(base, length, [0; HASH_SIZE]);
+
+    for uncle in proof {
+        hash = if node.is_multiple_of(2) {
+            merkle::hash_pair(&hash, uncle)
+        } else {
+            merkle::hash_pair(uncle, &hash)
+        };
+        node /= 2;
+    }
+
+    if hash != request.pieces_root {
+        return Err(HashesError::RootMismatch);
+    }
+
+    Ok(base.to_vec())
+}
+
+fn split_hashes(bytes: &[u8]) -> Vec<Hash> {
+    bytes
+        .chunks_exact(HASH_SIZE)
+        .map(|hash| hash.try_into().unwrap())
+        .collect()
+}
+
+/// Every layer of a file tree from the piece layer up to the root, each
+/// padded to a power of two nodes.
+fn tree_layers(piece_layer: &[Hash], piece_height: u32, tree_height: u32) -> Vec<Vec<Hash>> {
+    let mut layer = piece_layer.to_vec();
+    layer.resize(
+        1 << (tree_height - piece_height),
+        merkle::pad_hash(piece_height),
+    );
+
+    let mut layers = vec![layer];
+    while layers.last().unwrap().len() > 1 {
+        let next = layers
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| merkle::hash_pair(&pair[0], &pair[1]))
+            .collect();
+        layers.push(next);
+    }
+
+    layers
+}
+
+/// Answers a hash request from the piece layers of `torrent`. Only layers at
+/// or above the piece layer can be served since block hashes are not kept.
+pub fn serve(torrent: &Torrent, request: &HashRequest) -> Message {
+    hashes_for(torrent, request)
+        .map(|hashes| Message::Hashes {
+            request: *request,
+            hashes,
+        })
+        .unwrap_or(Message::HashReject(*request))
+}
+
+fn hashes_for(torrent: &Torrent, request: &HashRequest) -> Option<Vec<Hash>> {
+    let piece_layer = torrent.piece_layers.get(request.pieces_root.as_slice())?;
+
+    let mut files = Vec::new();
+    torrent.info.file_tree.get_files(&mut files);
+    let file = files
+        .iter()
+        .find(|file| file.pieces_root == request.pieces_root)?;
+
+    let piece_height = piece_height(torrent.info.piece_length);
+    let tree_height = merkle::tree_height(file.length, torrent.info.piece_length);
+
+    let (top, uncles) = proof_shape(request, tree_height).ok()?;
+    if request.base_layer < piece_height {
+        return None;
+    }
+
+    let layers = tree_layers(&split_hashes(piece_layer), piece_height, tree_height);
+
+    let start = request.index as usize;
+    let mut hashes = layers[(request.base_layer - piece_height) as usize]
+        [start..start + request.length as usize]
+        .to_vec();
+
+    let mut node = (request.index / request.length) as usize;
+    for layer in &layers[(top - piece_height) as usize..][..uncles as usize] {
+        hashes.push(layer[node ^ 1]);
+        node /= 2;
+    }
+
+    Some(hashes)
+}
+
+struct PendingLayer {
+    piece_count: u32,
+    tree_height: u32,
+    hashes: Vec<Hash>,
+    /// Start index of the requests not answered yet.
+    missing: HashSet<u32>,
+    outstanding: HashSet<u32>,
+}
+
+/// Fetches the piece layers missing from a torrent started from a magnet
+/// link, one request of at most [`MAX_HASHES_PER_REQUEST`] hashes at a time.
+pub struct PieceLayerFetcher {
+    piece_height: u32,
+    pending: HashMap<Hash, PendingLayer>,
+}
+
+impl PieceLayerFetcher {
+    pub fn new(torrent: &Torrent) -> Self {
+        let piece_length = torrent.info.piece_length;
+
+        let mut files = Vec::new();
+        torrent.info.file_tree.get_files(&mut files);
+
+        // Files of a single piece have their pieces root as only piece hash
+        let pending = files
+            .into_iter()
+            .filter(|file| file.length > piece_length as u64)
+            .filter(|file| !torrent.piece_layers.contains_key(&file.pieces_root))
+            .filter_map(|file| {
+                let pieces_root: Hash = file.pieces_root.as_slice().try_into().ok()?;
+                let piece_count = file.length.div_ceil(piece_length as u64) as u32;
+
+                Some((
+                    pieces_root,
+                    PendingLayer {
+                        piece_count,
+                        tree_height: merkle::tree_height(file.length, piece_length),
+                        hashes: vec![[0; HASH_SIZE]; piece_count as usize],
+                        missing: (0..piece_count)
+                            .step_by(MAX_HASHES_PER_REQUEST as usize)
+                            .collect(),
+                        outstanding: HashSet::new(),
+                    },
+                ))
+            })
+            .collect();
+
+        PieceLayerFetcher {
+            piece_height: piece_height(piece_length),
+            pending,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Next request to send, with enough proof layers to reach the root.
+    pub fn next_request(&mut self) -> Option<HashRequest> {
+        let piece_height = self.piece_height;
+
+        self.pending.iter_mut().find_map(|(pieces_root, layer)| {
+            let index = *layer
+                .missing
+                .iter()
+                .filter(|index| !layer.outstanding.contains(index))
+                .min()?;
+            layer.outstanding.insert(index);
+
+            let length = (layer.piece_count - index)
+                .min(MAX_HASHES_PER_REQUEST)
+                .next_power_of_two();
+
+            Some(HashRequest {
+                pieces_root: *pieces_root,
+                base_layer: piece_height,
+                index,
+                length,
+                proof_layers: layer.tree_height - piece_height,
+            })
+        })
+    }
+
+    /// Makes a rejected or timed out request available again.
+    pub fn on_reject(&mut self, request: &HashRequest) {
+        if let Some(layer) = self.pending.get_mut(&request.pieces_root) {
+            layer.outstanding.remove(&request.index);
+        }
+    }
+
+    /// Checks a response and stores its hashes, moving the layer into
+    /// `torrent.piece_layers` once every part of it arrived.
+    pub fn on_hashes(
+        &mut self,
+        torrent: &mut Torrent,
+        request: &HashRequest,
+        hashes: &[Hash],
+    ) -> Result<(), HashesError> {
+        let layer = self
+            .pending
+            .get_mut(&request.pieces_root)
+            .ok_or(HashesError::UnknownRoot(request.pieces_root))?;
+
+        if request.base_layer != self.piece_height || !layer.missing.contains(&request.index) {
+            return Err(HashesError::Unexpected);
+        }
+
+        let verified = match verify_hashes(request, layer.tree_height, hashes) {
+            Ok(verified) => verified,
+            Err(error) => {
+                layer.outstanding.remove(&request.index);
+                return Err(error);
+            }
+        };
+
+        // Hashes past the last piece are padding
+        let start = request.index as usize;
+        let end = (start + verified.len()).min(layer.piece_count as usize);
+        layer.hashes[start..end].copy_from_slice(&verified[..end - start]);
+        layer.missing.remove(&request.index);
+        layer.outstanding.remove(&request.index);
+
+        if layer.missing.is_empty() {
+            let layer = self.pending.remove(&request.pieces_root).unwrap();
+            torrent
+                .piece_layers
+                .insert(request.pieces_root.to_vec(), layer.hashes.concat());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::create::{create, CreateOptions};
+
+    fn piece_tree(pieces: usize) -> (Vec<Hash>, Hash) {
+        let leaves: Vec<Hash> = (0..pieces * 2)
+            .map(|block| merkle::hash_block(&[block as u8]))
+            .collect();
+        let root = merkle::pieces_root(&leaves, 2 * BLOCK_SIZE);
+
+        (merkle::piece_layer(&leaves, 2 * BLOCK_SIZE), root)
+    }
+
+    #[test]
+    fn test_verify_hashes() {
+        let (layer, root) = piece_tree(5);
+        // 5 pieces of 2 blocks: 8 padded pieces above 1 layer of blocks
+        let layers = tree_layers(&layer, 1, 4);
+
+        let request = HashRequest {
+            pieces_root: root,
+            base_layer: 1,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        let mut hashes = vec![layers[0][4], layers[0][5], layers[1][3], layers[2][0]];
+
+        assert_eq!(
+            verify_hashes(&request, 4, &hashes),
+            Ok(vec![layers[0][4], layers[0][5]])
+        );
+
+        hashes.swap(2, 3);
+        assert_eq!(
+            verify_hashes(&request, 4, &hashes),
+            Err(HashesError::RootMismatch)
+        );
+
+        assert_eq!(
+            verify_hashes(&request, 4, &hashes[..3]),
+            Err(HashesError::WrongCount {
+                expected: 4,
+                found: 3
+            })
+        );
+
+        let short_proof = HashRequest {
+            proof_layers: 2,
+            ..request
+        };
+        assert_eq!(
+            verify_hashes(&short_proof, 4, &hashes[..3]),
+            Err(HashesError::IncompleteProof)
+        );
+
+        for invalid in [
+            HashRequest {
+                length: 3,
+                ..request
+            },
+            HashRequest {
+                index: 3,
+                ..request
+            },
+            HashRequest {
+                index: 8,
+                ..request
+            },
+            HashRequest {
+                base_layer: 4,
+                ..request
+            },
+        ] {
+            assert_eq!(
+                verify_hashes(&invalid, 4, &hashes),
+                Err(HashesError::InvalidRequest)
+            );
+        }
+    }
+
+    #[test]
+    fn test_fetch_piece_layers() {
+        let dir = env::temp_dir().join(format!("bittorent-hashes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..BLOCK_SIZE as usize * 11)
+            .map(|i| (i / 7) as u8)
+            .collect();
+        fs::write(dir.join("data.bin"), data).unwrap();
+
+        let options = CreateOptions {
+            piece_length: Some(2 * BLOCK_SIZE),
+            ..Default::default()
+        };
+        let seeder = create(&dir.join("data.bin"), &options).unwrap();
+        let mut leecher = create(&dir.join("data.bin"), &options).unwrap();
+        leecher.piece_layers.clear();
+        fs::remove_dir_all(dir).unwrap();
+
+        let mut fetcher = PieceLayerFetcher::new(&leecher);
+        let request = fetcher.next_request().unwrap();
+        assert_eq!((request.index, request.length), (0, 8));
+        assert_eq!(fetcher.next_request(), None);
+
+        let Message::Hashes { hashes, .. } = serve(&seeder, &request) else {
+            panic!("seeder should answer the request");
+        };
+        assert_eq!(hashes.len(), 8);
+
+        // A tampered response is refused and the request can be sent again
+        let mut tampered = hashes.clone();
+        tampered[0][0] ^= 1;
+        assert_eq!(
+            fetcher.on_hashes(&mut leecher, &request, &tampered),
+            Err(HashesError::RootMismatch)
+        );
+        assert_eq!(fetcher.next_request(), Some(request));
+
+        fetcher.on_hashes(&mut leecher, &request, &hashes).unwrap();
+        assert!(fetcher.is_complete());
+        assert_eq!(leecher.piece_layers, seeder.piece_layers);
+        assert!(leecher.validate().is_valid());
+
+        // Block hashes are not kept, and the leecher had nothing to serve
+        let below = HashRequest {
+            base_layer: 0,
+            ..request
+        };
+        assert_eq!(serve(&seeder, &below), Message::HashReject(below));
+    }
+
+    #[test]
+    fn test_serve_with_proofs() {
+        let dir = env::temp_dir().join(format!("bittorent-serve-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.bin"), vec![5; BLOCK_SIZE as usize * 9]).unwrap();
+
+        let seeder = create(
+            &dir.join("data.bin"),
+            &CreateOptions {
+                piece_length: Some(BLOCK_SIZE),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        let mut files = Vec::new();
+        seeder.info.file_tree.get_files(&mut files);
+        let request = HashRequest {
+            pieces_root: files[0].pieces_root.as_slice().try_into().unwrap(),
+            base_layer: 1,
+            index: 2,
+            length: 2,
+            proof_layers: 4,
+        };
+
+        let Message::Hashes { hashes, .. } = serve(&seeder, &request) else {
+            panic!("seeder should answer the request");
+        };
+        assert_eq!(hashes.len(), 2 + 2);
+        assert!(verify_hashes(&request, 4, &hashes).is_ok());
+    }
+}
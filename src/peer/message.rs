@@ -0,0 +1,512 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+};
+
+use crate::merkle::{Hash, HASH_SIZE};
+
+/// Large enough for a 16 KiB block, the bitfield of a few million pieces and
+/// a full layer of hashes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+const ID_CHOKE: u8 = 0;
+const ID_UNCHOKE: u8 = 1;
+const ID_INTERESTED: u8 = 2;
+const ID_NOT_INTERESTED: u8 = 3;
+const ID_HAVE: u8 = 4;
+const ID_BITFIELD: u8 = 5;
+const ID_REQUEST: u8 = 6;
+const ID_PIECE: u8 = 7;
+const ID_CANCEL: u8 = 8;
+const ID_PORT: u8 = 9;
+const ID_EXTENDED: u8 = 20;
+const ID_HASH_REQUEST: u8 = 21;
+const ID_HASHES: u8 = 22;
+const ID_HASH_REJECT: u8 = 23;
+
+/// Size of the fields shared by the hash request, hashes and hash reject
+/// messages.
+const HASH_REQUEST_SIZE: usize = HASH_SIZE + 16;
+
+#[derive(Debug)]
+pub enum MessageError {
+    Io(io::Error),
+    TooLarge {
+        length: usize,
+        max: usize,
+    },
+    UnknownId(u8),
+    /// The payload does not have the size the message id requires.
+    InvalidLength {
+        id: u8,
+        length: usize,
+    },
+}
+
+impl Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::Io(error) => write!(f, "I/O error: {error}"),
+            MessageError::TooLarge { length, max } => {
+                write!(f, "message of {length} bytes exceeds the limit of {max}")
+            }
+            MessageError::UnknownId(id) => write!(f, "unknown message id {id}"),
+            MessageError::InvalidLength { id, length } => {
+                write!(f, "invalid payload length {length} for message id {id}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<io::Error> for MessageError {
+    fn from(error: io::Error) -> Self {
+        MessageError::Io(error)
+    }
+}
+
+/// A block of a piece, as asked for by request and cancel messages.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// Hashes of a layer of the merkle tree of a file (BEP 52). `base_layer` is
+/// the height of the requested layer above the 16 KiB blocks, and
+/// `proof_layers` asks for the uncle hashes needed to verify them against
+/// the pieces root.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct HashRequest {
+    pub pieces_root: Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(BlockRequest),
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel(BlockRequest),
+    /// DHT port of the peer (BEP 5).
+    Port(u16),
+    HashRequest(HashRequest),
+    /// The requested hashes followed by the proof hashes.
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<Hash>,
+    },
+    HashReject(HashRequest),
+    /// Extension protocol message (BEP 10). Id 0 is the extended handshake,
+    /// other ids are assigned in it.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl BlockRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.begin.to_be_bytes());
+        out.extend_from_slice(&self.length.to_be_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        BlockRequest {
+            index: read_u32(payload, 0),
+            begin: read_u32(payload, 4),
+            length: read_u32(payload, 8),
+        }
+    }
+}
+
+impl HashRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.pieces_root);
+        out.extend_from_slice(&self.base_layer.to_be_bytes());
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.length.to_be_bytes());
+        out.extend_from_slice(&self.proof_layers.to_be_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        HashRequest {
+            pieces_root: payload[..HASH_SIZE].try_into().unwrap(),
+            base_layer: read_u32(payload, HASH_SIZE),
+            index: read_u32(payload, HASH_SIZE + 4),
+            length: read_u32(payload, HASH_SIZE + 8),
+            proof_layers: read_u32(payload, HASH_SIZE + 12),
+        }
+    }
+}
+
+impl Message {
+    /// Encodes the message with its 4 byte length prefix.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0; 4];
+
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke => out.push(ID_CHOKE),
+            Message::Unchoke => out.push(ID_UNCHOKE),
+            Message::Interested => out.push(ID_INTERESTED),
+            Message::NotInterested => out.push(ID_NOT_INTERESTED),
+            Message::Have(index) => {
+                out.push(ID_HAVE);
+                out.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::Bitfield(bitfield) => {
+                out.push(ID_BITFIELD);
+                out.extend_from_slice(bitfield);
+            }
+            Message::Request(request) => {
+                out.push(ID_REQUEST);
+                request.encode(&mut out);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                out.push(ID_PIECE);
+                out.extend_from_slice(&index.to_be_bytes());
+                out.extend_from_slice(&begin.to_be_bytes());
+                out.extend_from_slice(block);
+            }
+            Message::Cancel(request) => {
+                out.push(ID_CANCEL);
+                request.encode(&mut out);
+            }
+            Message::Port(port) => {
+                out.push(ID_PORT);
+                out.extend_from_slice(&port.to_be_bytes());
+            }
+            Message::HashRequest(request) => {
+                out.push(ID_HASH_REQUEST);
+                request.encode(&mut out);
+            }
+            Message::Hashes { request, hashes } => {
+                out.push(ID_HASHES);
+                request.encode(&mut out);
+                hashes.iter().for_each(|hash| out.extend_from_slice(hash));
+            }
+            Message::HashReject(request) => {
+                out.push(ID_HASH_REJECT);
+                request.encode(&mut out);
+            }
+            Message::Extended { id, payload } => {
+                out.push(ID_EXTENDED);
+                out.push(*id);
+                out.extend_from_slice(payload);
+            }
+        }
+
+        let length = (out.len() - 4) as u32;
+        out[..4].copy_from_slice(&length.to_be_bytes());
+
+        out
+    }
+
+    /// Decodes the id and payload of a message, without its length prefix.
+    pub fn decode_body(body: &[u8]) -> Result<Self, MessageError> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+
+        let expect = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(MessageError::InvalidLength {
+                    id,
+                    length: payload.len(),
+                })
+            }
+        };
+
+        let message = match id {
+            ID_CHOKE => expect(0).map(|_| Message::Choke)?,
+            ID_UNCHOKE => expect(0).map(|_| Message::Unchoke)?,
+            ID_INTERESTED => expect(0).map(|_| Message::Interested)?,
+            ID_NOT_INTERESTED => expect(0).map(|_| Message::NotInterested)?,
+            ID_HAVE => expect(4).map(|_| Message::Have(read_u32(payload, 0)))?,
+            ID_BITFIELD => Message::Bitfield(payload.to_vec()),
+            ID_REQUEST => expect(12).map(|_| Message::Request(BlockRequest::decode(payload)))?,
+            ID_PIECE => {
+                if payload.len() < 8 {
+                    return Err(MessageError::InvalidLength {
+                        id,
+                        length: payload.len(),
+                    });
+                }
+
+                Message::Piece {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            ID_CANCEL => expect(12).map(|_| Message::Cancel(BlockRequest::decode(payload)))?,
+            ID_PORT => {
+                expect(2).map(|_| Message::Port(u16::from_be_bytes([payload[0], payload[1]])))?
+            }
+            ID_EXTENDED => {
+                let Some((&id, payload)) = payload.split_first() else {
+                    return Err(MessageError::InvalidLength { id, length: 0 });
+                };
+
+                Message::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                }
+            }
+            ID_HASH_REQUEST => expect(HASH_REQUEST_SIZE)
+                .map(|_| Message::HashRequest(HashRequest::decode(payload)))?,
+            ID_HASHES => {
+                if payload.len() < HASH_REQUEST_SIZE
+                    || !(payload.len() - HASH_REQUEST_SIZE).is_multiple_of(HASH_SIZE)
+                {
+                    return Err(MessageError::InvalidLength {
+                        id,
+                        length: payload.len(),
+                    });
+                }
+
+                Message::Hashes {
+                    request: HashRequest::decode(payload),
+                    hashes: payload[HASH_REQUEST_SIZE..]
+                        .chunks(HASH_SIZE)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                }
+            }
+            ID_HASH_REJECT => expect(HASH_REQUEST_SIZE)
+                .map(|_| Message::HashReject(HashRequest::decode(payload)))?,
+            _ => return Err(MessageError::UnknownId(id)),
+        };
+
+        Ok(message)
+    }
+}
+
+/// Splits a byte stream into messages, refusing any larger than `max_size`.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub max_size: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec {
+            max_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl Codec {
+    pub fn new(max_size: usize) -> Self {
+        Codec { max_size }
+    }
+
+    fn check_length(&self, prefix: [u8; 4]) -> Result<usize, MessageError> {
+        let length = u32::from_be_bytes(prefix) as usize;
+
+        if length > self.max_size {
+            return Err(MessageError::TooLarge {
+                length,
+                max: self.max_size,
+            });
+        }
+
+        Ok(length)
+    }
+
+    /// Decodes the first message of `buffer`, returning it with the number of
+    /// bytes it took, or `None` when the buffer does not hold a whole message
+    /// yet.
+    pub fn decode(&self, buffer: &[u8]) -> Result<Option<(Message, usize)>, MessageError> {
+        if buffer.len() < 4 {
+            return Ok(None);
+        }
+
+        let length = self.check_length(buffer[..4].try_into().unwrap())?;
+
+        let Some(body) = buffer.get(4..4 + length) else {
+            return Ok(None);
+        };
+
+        Ok(Some((Message::decode_body(body)?, 4 + length)))
+    }
+
+    pub fn read_from(&self, reader: &mut impl Read) -> Result<Message, MessageError> {
+        let mut prefix = [0; 4];
+        reader.read_exact(&mut prefix)?;
+
+        let mut body = vec![0; self.check_length(prefix)?];
+        reader.read_exact(&mut body)?;
+
+        Message::decode_body(&body)
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write, message: &Message) -> io::Result<()> {
+        writer.write_all(&message.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_request() -> HashRequest {
+        HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 0,
+            index: 8,
+            length: 4,
+            proof_layers: 2,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let request = BlockRequest {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        };
+        let messages = vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(42),
+            Message::Bitfield(vec![0b1010_0000, 0]),
+            Message::Request(request),
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![9; 100],
+            },
+            Message::Cancel(request),
+            Message::Port(6881),
+            Message::HashRequest(hash_request()),
+            Message::Hashes {
+                request: hash_request(),
+                hashes: vec![[1; 32], [2; 32], [3; 32]],
+            },
+            Message::HashReject(hash_request()),
+            Message::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+        ];
+
+        let stream: Vec<u8> = messages.iter().flat_map(Message::encode).collect();
+        let codec = Codec::default();
+
+        let mut offset = 0;
+        for message in &messages {
+            let (decoded, used) = codec.decode(&stream[offset..]).unwrap().unwrap();
+            assert_eq!(&decoded, message);
+            offset += used;
+        }
+        assert_eq!(offset, stream.len());
+
+        let mut reader = stream.as_slice();
+        for message in &messages {
+            assert_eq!(&codec.read_from(&mut reader).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(Message::KeepAlive.encode(), vec![0, 0, 0, 0]);
+        assert_eq!(Message::Have(258).encode(), vec![0, 0, 0, 5, 4, 0, 0, 1, 2]);
+        assert_eq!(
+            Message::Port(6881).encode(),
+            vec![0, 0, 0, 3, 9, 0x1a, 0xe1]
+        );
+        assert_eq!(
+            Message::HashRequest(hash_request()).encode().len(),
+            4 + 1 + 48
+        );
+    }
+
+    #[test]
+    fn test_partial_buffer() {
+        let encoded = Message::Have(1).encode();
+        let codec = Codec::default();
+
+        for end in 0..encoded.len() {
+            assert!(codec.decode(&encoded[..end]).unwrap().is_none());
+        }
+        assert!(codec.decode(&encoded).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_errors() {
+        let codec = Codec::new(16);
+
+        assert!(matches!(
+            codec.decode(&[0, 0, 0, 17]),
+            Err(MessageError::TooLarge {
+                length: 17,
+                max: 16
+            })
+        ));
+        assert!(matches!(
+            codec.decode(&[0, 0, 0, 1, 99]),
+            Err(MessageError::UnknownId(99))
+        ));
+        assert!(matches!(
+            codec.decode(&[0, 0, 0, 2, ID_HAVE, 0]),
+            Err(MessageError::InvalidLength { id: 4, length: 1 })
+        ));
+        assert!(matches!(
+            codec.decode(&[0, 0, 0, 1, ID_EXTENDED]),
+            Err(MessageError::InvalidLength { id: 20, length: 0 })
+        ));
+        assert!(matches!(
+            codec.decode(&[0, 0, 0, 2, ID_CHOKE, 0]),
+            Err(MessageError::InvalidLength { id: 0, length: 1 })
+        ));
+
+        let mut hashes = Message::Hashes {
+            request: hash_request(),
+            hashes: vec![[1; 32]],
+        }
+        .encode();
+        hashes.pop();
+        assert!(matches!(
+            Message::decode_body(&hashes[4..]),
+            Err(MessageError::InvalidLength { id: 22, length: 79 })
+        ));
+
+        let mut reader: &[u8] = &[0, 0, 0, 5, 4];
+        assert!(matches!(
+            Codec::default().read_from(&mut reader),
+            Err(MessageError::Io(_))
+        ));
+    }
+}
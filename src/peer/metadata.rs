@@ -0,0 +1,451 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use indexmap::IndexMap;
+use sha2::Digest;
+
+use crate::{
+    bencode_decoder::Bencode,
+    torrent::{Info, Torrent},
+};
+
+use super::{
+    extension::{ExtendedHandshake, Extension, ExtensionError},
+    handshake::InfoHashes,
+};
+
+pub const EXTENSION_NAME: &str = "ut_metadata";
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Info dictionaries announced as larger than this are not fetched.
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: u32,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+fn invalid(reason: &str) -> ExtensionError {
+    ExtensionError::InvalidMessage {
+        extension: EXTENSION_NAME.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+impl MetadataMessage {
+    /// Encodes the message dictionary, followed by the piece for data
+    /// messages.
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (MSG_REQUEST, piece),
+            MetadataMessage::Data { piece, .. } => (MSG_DATA, piece),
+            MetadataMessage::Reject { piece } => (MSG_REJECT, piece),
+        };
+
+        let mut dict = IndexMap::new();
+        dict.insert(b"msg_type".to_vec(), Bencode::Integer(msg_type));
+        dict.insert(b"piece".to_vec(), Bencode::Integer((*piece).into()));
+
+        if let MetadataMessage::Data { total_size, .. } = self {
+            dict.insert(
+                b"total_size".to_vec(),
+                Bencode::Integer((*total_size).into()),
+            );
+        }
+
+        let mut encoded = Bencode::Dictionary(dict).encode_value();
+        if let MetadataMessage::Data { data, .. } = self {
+            encoded.extend_from_slice(data);
+        }
+
+        encoded
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let (value, rest) = Bencode::try_decode_value(payload.to_vec())?;
+
+        let Bencode::Dictionary(dict) = value else {
+            return Err(invalid("message is not a dictionary"));
+        };
+
+        let integer = |key: &[u8]| match dict.get(key) {
+            Some(Bencode::Integer(value)) => u32::try_from(*value).ok(),
+            _ => None,
+        };
+
+        let piece = integer(b"piece").ok_or_else(|| invalid("missing piece"))?;
+
+        match dict.get(b"msg_type".as_slice()) {
+            Some(Bencode::Integer(MSG_REQUEST)) => Ok(MetadataMessage::Request { piece }),
+            Some(Bencode::Integer(MSG_DATA)) => Ok(MetadataMessage::Data {
+                piece,
+                total_size: integer(b"total_size").ok_or_else(|| invalid("missing total_size"))?,
+                data: rest,
+            }),
+            Some(Bencode::Integer(MSG_REJECT)) => Ok(MetadataMessage::Reject { piece }),
+            _ => Err(invalid("unknown msg_type")),
+        }
+    }
+}
+
+/// Checks an info dictionary against whichever info hashes are known.
+pub fn verify_metadata(metadata: &[u8], hashes: &InfoHashes) -> bool {
+    let v1_matches = hashes
+        .v1
+        .is_some_and(|v1| sha1_smol::Sha1::from(metadata).digest().bytes() == v1);
+    let v2_matches = hashes
+        .v2
+        .is_some_and(|v2| <[u8; 32]>::from(sha2::Sha256::digest(metadata)) == v2);
+
+    v1_matches || v2_matches
+}
+
+struct Download {
+    total_size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+    requested: HashSet<u32>,
+}
+
+/// The info dictionary of a torrent, shared by the `ut_metadata` extensions
+/// of all its connections. It is either known from the start, or assembled
+/// from the pieces peers send.
+pub struct MetadataStore {
+    hashes: InfoHashes,
+    metadata: Mutex<Option<Arc<Vec<u8>>>>,
+    download: Mutex<Option<Download>>,
+}
+
+impl MetadataStore {
+    /// Store of a torrent started from a magnet link.
+    pub fn new(hashes: InfoHashes) -> Self {
+        MetadataStore {
+            hashes,
+            metadata: Mutex::new(None),
+            download: Mutex::new(None),
+        }
+    }
+
+    /// Store of a torrent whose info dictionary we have and can serve.
+    pub fn from_info(info: &Info) -> Self {
+        let store = MetadataStore::new(InfoHashes::from_info(info));
+        *store.metadata.lock().unwrap() = Some(Arc::new(info.to_bencode().encode_value()));
+        store
+    }
+
+    pub fn metadata(&self) -> Option<Arc<Vec<u8>>> {
+        self.metadata.lock().unwrap().clone()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.metadata.lock().unwrap().is_some()
+    }
+
+    /// Builds the torrent once the info dictionary was fetched and verified.
+    pub fn torrent(&self) -> Option<Torrent> {
+        let metadata = self.metadata()?;
+        let (info, _) = Bencode::try_decode_value(metadata.to_vec()).ok()?;
+
+        Info::try_parse(&info).ok().map(Torrent::from_info)
+    }
+
+    /// Pieces to request from a peer announcing `total_size`. Pieces already
+    /// asked from another peer are only requested again once every piece
+    /// was asked for.
+    fn requests(&self, total_size: usize) -> Vec<u32> {
+        if self.is_complete() || total_size == 0 || total_size > MAX_METADATA_SIZE {
+            return Vec::new();
+        }
+
+        let mut download = self.download.lock().unwrap();
+        let download = match &mut *download {
+            Some(download) if download.total_size == total_size => download,
+            download => download.insert(Download {
+                total_size,
+                pieces: vec![None; total_size.div_ceil(METADATA_PIECE_SIZE)],
+                requested: HashSet::new(),
+            }),
+        };
+
+        let missing: Vec<u32> = (0..download.pieces.len() as u32)
+            .filter(|piece| download.pieces[*piece as usize].is_none())
+            .collect();
+        let unrequested: Vec<u32> = missing
+            .iter()
+            .copied()
+            .filter(|piece| !download.requested.contains(piece))
+            .collect();
+
+        let requests = if unrequested.is_empty() {
+            missing
+        } else {
+            unrequested
+        };
+        download.requested.extend(&requests);
+
+        requests
+    }
+
+    /// Stores a received piece. Once every piece arrived the dictionary is
+    /// verified, and thrown away if it does not match the info hash.
+    fn add_piece(
+        &self,
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    ) -> Result<(), ExtensionError> {
+        let mut download = self.download.lock().unwrap();
+        let Some(current) = download.as_mut() else {
+            return Ok(());
+        };
+
+        let piece = piece as usize;
+        if total_size != current.total_size || piece >= current.pieces.len() {
+            return Err(invalid("piece does not match the metadata size"));
+        }
+
+        let expected = if piece + 1 == current.pieces.len() {
+            total_size - piece * METADATA_PIECE_SIZE
+        } else {
+            METADATA_PIECE_SIZE
+        };
+        if data.len() != expected {
+            return Err(invalid("piece has the wrong length"));
+        }
+
+        current.pieces[piece] = Some(data);
+
+        if current.pieces.iter().any(Option::is_none) {
+            return Ok(());
+        }
+
+        let metadata: Vec<u8> = current
+            .pieces
+            .iter_mut()
+            .flat_map(|piece| piece.take().unwrap())
+            .collect();
+        *download = None;
+
+        if !verify_metadata(&metadata, &self.hashes) {
+            return Err(invalid("metadata does not match the info hash"));
+        }
+
+        *self.metadata.lock().unwrap() = Some(Arc::new(metadata));
+
+        Ok(())
+    }
+}
+
+/// The `ut_metadata` extension of one connection.
+pub struct UtMetadata {
+    store: Arc<MetadataStore>,
+}
+
+impl UtMetadata {
+    pub fn new(store: Arc<MetadataStore>) -> Self {
+        UtMetadata { store }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = self.store.metadata().map(|metadata| metadata.len() as u32);
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake, enabled: bool) -> Vec<Vec<u8>> {
+        let Some(total_size) = handshake.metadata_size.filter(|_| enabled) else {
+            return Vec::new();
+        };
+
+        self.store
+            .requests(total_size as usize)
+            .into_iter()
+            .map(|piece| MetadataMessage::Request { piece }.encode())
+            .collect()
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        match MetadataMessage::decode(payload)? {
+            MetadataMessage::Request { piece } => {
+                let start = piece as usize * METADATA_PIECE_SIZE;
+
+                let reply = match self.store.metadata() {
+                    Some(metadata) if start < metadata.len() => MetadataMessage::Data {
+                        piece,
+                        total_size: metadata.len() as u32,
+                        data: metadata[start..(start + METADATA_PIECE_SIZE).min(metadata.len())]
+                            .to_vec(),
+                    },
+                    _ => MetadataMessage::Reject { piece },
+                };
+
+                Ok(vec![reply.encode()])
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                self.store.add_piece(piece, total_size as usize, data)?;
+                Ok(Vec::new())
+            }
+            MetadataMessage::Reject { .. } => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::peer::{
+        extension::Extensions,
+        handshake::{self, Handshake, Reserved, Version},
+        message::{Codec, Message},
+    };
+
+    fn test_info() -> Info {
+        let content = std::fs::read("test_folder.torrent").unwrap();
+        let (metainfo, _) = Bencode::decode_value(content);
+        let mut info = Torrent::parse(&metainfo).info;
+
+        // Make the dictionary span several metadata pieces
+        info.extra
+            .insert(b"x-padding".to_vec(), Bencode::String(vec![b'x'; 40_000]));
+        info
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        for message in [
+            MetadataMessage::Request { piece: 2 },
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 3,
+                data: b"abc".to_vec(),
+            },
+            MetadataMessage::Reject { piece: 1 },
+        ] {
+            assert_eq!(MetadataMessage::decode(&message.encode()).unwrap(), message);
+        }
+
+        assert_eq!(
+            MetadataMessage::Request { piece: 1 }.encode(),
+            b"d8:msg_typei0e5:piecei1ee"
+        );
+        assert!(MetadataMessage::decode(b"d8:msg_typei5e5:piecei1ee").is_err());
+    }
+
+    #[test]
+    fn test_corrupt_metadata() {
+        let info = test_info();
+        let seeder = MetadataStore::from_info(&info);
+        let leecher = MetadataStore::new(InfoHashes::from_info(&info));
+        let metadata = seeder.metadata().unwrap();
+
+        let requests = leecher.requests(metadata.len());
+        assert_eq!(requests, vec![0, 1, 2]);
+        // Everything is requested again once no piece is left to ask for
+        assert_eq!(leecher.requests(metadata.len()), vec![0, 1, 2]);
+
+        for piece in requests {
+            let start = piece as usize * METADATA_PIECE_SIZE;
+            let mut data =
+                metadata[start..(start + METADATA_PIECE_SIZE).min(metadata.len())].to_vec();
+            data[0] ^= 1;
+
+            let result = leecher.add_piece(piece, metadata.len(), data);
+            assert_eq!(result.is_err(), piece == 2);
+        }
+
+        assert!(!leecher.is_complete());
+        assert!(leecher.add_piece(0, metadata.len(), vec![0; 10]).is_ok());
+    }
+
+    /// Runs one side of a connection: handshakes, then answers extended
+    /// messages until `done` holds.
+    fn run_peer(
+        mut stream: TcpStream,
+        store: Arc<MetadataStore>,
+        initiator: bool,
+        done: impl Fn(&MetadataStore) -> bool,
+    ) {
+        let hashes = InfoHashes::from_info(&test_info());
+        let mut reserved = Reserved::default();
+        reserved.set_extensions(true);
+
+        if initiator {
+            let info_hash = hashes.handshake_hash(Version::V2).unwrap();
+            let ours = Handshake::new(reserved, info_hash, [1; 20]);
+            handshake::initiate(&mut stream, &hashes, &ours).unwrap();
+        } else {
+            handshake::accept(&mut stream, |_| Some(hashes), reserved, [2; 20]).unwrap();
+        }
+
+        let mut extensions = Extensions::new(None);
+        extensions.register(Box::new(UtMetadata::new(store.clone())));
+
+        let codec = Codec::default();
+        codec
+            .write_to(&mut stream, &extensions.handshake(None))
+            .unwrap();
+
+        while !done(&store) {
+            let Ok(Message::Extended { id, payload }) = codec.read_from(&mut stream) else {
+                return;
+            };
+
+            for reply in extensions.handle(id, &payload).unwrap() {
+                codec.write_to(&mut stream, &reply).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_fetch_over_loopback() {
+        let info = test_info();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let seeder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let store = Arc::new(MetadataStore::from_info(&test_info()));
+            run_peer(stream, store, false, |_| false);
+        });
+
+        let store = Arc::new(MetadataStore::new(InfoHashes::from_info(&info)));
+        let stream = TcpStream::connect(address).unwrap();
+        run_peer(stream, store.clone(), true, MetadataStore::is_complete);
+
+        let torrent = store.torrent().unwrap();
+        assert_eq!(torrent.info.get_infohash(), info.get_infohash());
+        assert_eq!(torrent.info.name, info.name);
+        assert!(torrent.trackers().iter().flatten().all(String::is_empty));
+
+        // Closing the connection ends the seeder's loop
+        drop(store);
+        seeder.join().unwrap();
+    }
+}
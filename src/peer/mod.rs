@@ -0,0 +1,8 @@
+pub mod encryption;
+pub mod extension;
+pub mod handshake;
+pub mod hashes;
+pub mod message;
+pub mod metadata;
+pub mod pex;
+pub mod stream;
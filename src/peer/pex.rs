@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use indexmap::IndexMap;
+
+use crate::{
+    bencode_decoder::Bencode,
+    peers::{decode_compact_v4, decode_compact_v6, encode_compact_v4, encode_compact_v6},
+    torrent::Info,
+};
+
+use super::extension::{Extension, ExtensionError, Extensions};
+
+pub const EXTENSION_NAME: &str = "ut_pex";
+/// Peers may drop connections sending PEX messages more often than this.
+pub const MIN_PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers added or dropped in one message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Flags of an added peer, one byte per peer in `added.f`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Hash)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    const ENCRYPTION: u8 = 0x01;
+    const SEED: u8 = 0x02;
+    const UTP: u8 = 0x04;
+    const HOLEPUNCH: u8 = 0x08;
+    /// The peer accepted an outgoing connection, so it is reachable.
+    const REACHABLE: u8 = 0x10;
+
+    fn get(&self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+
+    fn set(&mut self, flag: u8, enabled: bool) {
+        if enabled {
+            self.0 |= flag;
+        } else {
+            self.0 &= !flag;
+        }
+    }
+
+    pub fn prefers_encryption(&self) -> bool {
+        self.get(Self::ENCRYPTION)
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.get(Self::SEED)
+    }
+
+    pub fn supports_utp(&self) -> bool {
+        self.get(Self::UTP)
+    }
+
+    pub fn supports_holepunch(&self) -> bool {
+        self.get(Self::HOLEPUNCH)
+    }
+
+    pub fn is_reachable(&self) -> bool {
+        self.get(Self::REACHABLE)
+    }
+
+    pub fn set_encryption(&mut self, enabled: bool) {
+        self.set(Self::ENCRYPTION, enabled);
+    }
+
+    pub fn set_seed(&mut self, enabled: bool) {
+        self.set(Self::SEED, enabled);
+    }
+
+    pub fn set_utp(&mut self, enabled: bool) {
+        self.set(Self::UTP, enabled);
+    }
+
+    pub fn set_holepunch(&mut self, enabled: bool) {
+        self.set(Self::HOLEPUNCH, enabled);
+    }
+
+    pub fn set_reachable(&mut self, enabled: bool) {
+        self.set(Self::REACHABLE, enabled);
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub struct PexPeer {
+    pub address: SocketAddr,
+    pub flags: PexFlags,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+fn invalid(reason: String) -> ExtensionError {
+    ExtensionError::InvalidMessage {
+        extension: EXTENSION_NAME.to_string(),
+        reason,
+    }
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = IndexMap::new();
+
+        for v6 in [false, true] {
+            let (added, flags): (Vec<SocketAddr>, Vec<u8>) = self
+                .added
+                .iter()
+                .filter(|peer| peer.address.is_ipv6() == v6)
+                .map(|peer| (peer.address, peer.flags.0))
+                .unzip();
+            let suffix = if v6 { "6" } else { "" };
+            let encode = if v6 {
+                encode_compact_v6
+            } else {
+                encode_compact_v4
+            };
+
+            dict.insert(
+                format!("added{suffix}").into_bytes(),
+                Bencode::String(encode(&added)),
+            );
+            dict.insert(
+                format!("added{suffix}.f").into_bytes(),
+                Bencode::String(flags),
+            );
+            dict.insert(
+                format!("dropped{suffix}").into_bytes(),
+                Bencode::String(encode(&self.dropped)),
+            );
+        }
+
+        dict.sort_keys();
+        Bencode::Dictionary(dict).encode_value()
+    }
+
+    /// Decodes a message. Missing lists are empty, and peers without an
+    /// entry in the flags string get no flags.
+    pub fn decode(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let (value, _) = Bencode::try_decode_value(payload.to_vec())?;
+
+        let Bencode::Dictionary(dict) = value else {
+            return Err(invalid("message is not a dictionary".to_string()));
+        };
+
+        let string = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Bencode::String(value)) => value.as_slice(),
+            _ => &[],
+        };
+
+        let mut message = PexMessage::default();
+        for (suffix, decode) in [
+            ("", decode_compact_v4 as fn(&[u8]) -> _),
+            ("6", decode_compact_v6),
+        ] {
+            let added = decode(string(&format!("added{suffix}")))
+                .map_err(|error| invalid(error.to_string()))?;
+            let flags = string(&format!("added{suffix}.f"));
+
+            message.added.extend(
+                added
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, address)| PexPeer {
+                        address,
+                        flags: PexFlags(flags.get(index).copied().unwrap_or_default()),
+                    }),
+            );
+            message.dropped.extend(
+                decode(string(&format!("dropped{suffix}")))
+                    .map_err(|error| invalid(error.to_string()))?,
+            );
+        }
+
+        Ok(message)
+    }
+}
+
+/// PEX is not used for private torrents (BEP 27).
+pub fn is_allowed(info: &Info) -> bool {
+    info.private != Some(true)
+}
+
+/// Registers `ut_pex` on a connection of `info`'s torrent, unless the torrent
+/// is private. Peers the remote adds are sent to `discovered`, and the
+/// returned sender produces the messages to send it.
+pub fn register(
+    extensions: &mut Extensions,
+    info: &Info,
+    discovered: Sender<PexPeer>,
+) -> Option<PexSender> {
+    if !is_allowed(info) {
+        return None;
+    }
+
+    extensions.register(Box::new(UtPex::new(discovered)));
+    Some(PexSender::default())
+}
+
+/// The receiving side of `ut_pex` on one connection.
+pub struct UtPex {
+    discovered: Sender<PexPeer>,
+}
+
+impl UtPex {
+    pub fn new(discovered: Sender<PexPeer>) -> Self {
+        UtPex { discovered }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        for peer in PexMessage::decode(payload)?.added {
+            // The swarm is gone if nobody listens anymore
+            let _ = self.discovered.send(peer);
+        }
+
+        Ok(Vec::new())
+    }
+}
+
+/// The sending side of `ut_pex` on one connection. It remembers which peers
+/// the remote was told about, so each message only carries the changes.
+#[derive(Default)]
+pub struct PexSender {
+    sent: HashMap<SocketAddr, PexFlags>,
+    last_sent: Option<Instant>,
+}
+
+impl PexSender {
+    /// Compares the connected peers with the ones sent before. Returns
+    /// `None` when nothing changed or the last message is too recent.
+    pub fn update(&mut self, now: Instant, connected: &[PexPeer]) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.duration_since(last_sent) < MIN_PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let current: HashMap<SocketAddr, PexFlags> = connected
+            .iter()
+            .map(|peer| (peer.address, peer.flags))
+            .collect();
+
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .keys()
+            .filter(|address| !current.contains_key(address))
+            .copied()
+            .take(MAX_PEX_PEERS)
+            .collect();
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|peer| self.sent.get(&peer.address) != Some(&peer.flags))
+            .copied()
+            .take(MAX_PEX_PEERS)
+            .collect();
+
+        for address in &dropped {
+            self.sent.remove(address);
+        }
+        for peer in &added {
+            self.sent.insert(peer.address, peer.flags);
+        }
+
+        let message = PexMessage { added, dropped };
+        if message.is_empty() {
+            return None;
+        }
+
+        self.last_sent = Some(now);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{bencode_decoder::Bencode, peer::message::Message, torrent::Torrent};
+
+    fn peer(address: &str, flags: u8) -> PexPeer {
+        PexPeer {
+            address: address.parse().unwrap(),
+            flags: PexFlags(flags),
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = PexMessage {
+            added: vec![peer("10.0.0.1:6881", 0x12), peer("[::1]:51413", 0x04)],
+            dropped: vec!["10.0.0.2:80".parse().unwrap()],
+        };
+        let encoded = message.encode();
+
+        assert!(encoded.starts_with(b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x12"));
+        assert_eq!(PexMessage::decode(&encoded).unwrap(), message);
+
+        let decoded = PexMessage::decode(
+            b"d5:added12:\x7f\x00\x00\x01\x00\x50\x7f\x00\x00\x02\x00\x517:added.f1:\x03e",
+        )
+        .unwrap();
+        assert_eq!(
+            decoded.added,
+            vec![peer("127.0.0.1:80", 0x03), peer("127.0.0.2:81", 0)]
+        );
+        assert!(decoded.added[0].flags.prefers_encryption());
+        assert!(decoded.added[0].flags.is_seed());
+        assert!(!decoded.added[0].flags.supports_utp());
+
+        assert!(PexMessage::decode(b"d5:added5:12345e").is_err());
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut flags = PexFlags::default();
+        flags.set_utp(true);
+        flags.set_holepunch(true);
+        flags.set_reachable(true);
+        assert_eq!(flags, PexFlags(0x1c));
+
+        flags.set_holepunch(false);
+        assert!(flags.supports_utp() && flags.is_reachable());
+        assert!(!flags.supports_holepunch() && !flags.is_seed());
+    }
+
+    #[test]
+    fn test_sender_rate_limit() {
+        let mut sender = PexSender::default();
+        let start = Instant::now();
+        let a = peer("10.0.0.1:1", 0);
+        let b = peer("10.0.0.2:2", 0);
+
+        let message = sender.update(start, &[a, b]).unwrap();
+        assert_eq!(message.added, vec![a, b]);
+
+        // Changes wait for the interval to pass
+        assert_eq!(sender.update(start + Duration::from_secs(10), &[a]), None);
+
+        let seeding = peer("10.0.0.1:1", 0x02);
+        let message = sender.update(start + MIN_PEX_INTERVAL, &[seeding]).unwrap();
+        assert_eq!(message.added, vec![seeding]);
+        assert_eq!(message.dropped, vec![b.address]);
+
+        // Nothing changed, so nothing is sent
+        assert_eq!(
+            sender.update(start + MIN_PEX_INTERVAL * 3, &[seeding]),
+            None
+        );
+
+        let many: Vec<PexPeer> = (0..120)
+            .map(|port| peer(&format!("10.1.0.1:{port}"), 0))
+            .collect();
+        let mut later = start + MIN_PEX_INTERVAL * 4;
+        let mut total = 0;
+        while let Some(message) = sender.update(later, &many) {
+            assert!(message.added.len() <= MAX_PEX_PEERS);
+            total += message.added.len();
+            later += MIN_PEX_INTERVAL;
+        }
+        assert_eq!(total, 120);
+    }
+
+    #[test]
+    fn test_register() {
+        let content = std::fs::read("test_folder.torrent").unwrap();
+        let (metainfo, _) = Bencode::decode_value(content);
+        let mut info = Torrent::parse(&metainfo).info;
+        let (discovered, peers) = mpsc::channel();
+
+        let mut extensions = Extensions::new(None);
+        assert!(register(&mut extensions, &info, discovered.clone()).is_some());
+
+        let message = PexMessage {
+            added: vec![peer("10.0.0.1:6881", 0x02)],
+            dropped: Vec::new(),
+        };
+        let id = extensions.local_id(EXTENSION_NAME).unwrap();
+        assert!(extensions.handle(id, &message.encode()).unwrap().is_empty());
+        assert_eq!(peers.try_recv().unwrap(), message.added[0]);
+
+        info.private = Some(true);
+        let mut extensions = Extensions::new(None);
+        assert!(register(&mut extensions, &info, discovered).is_none());
+        let Message::Extended { payload, .. } = extensions.handshake(None) else {
+            unreachable!();
+        };
+        assert_eq!(payload, b"d1:mdee");
+    }
+}
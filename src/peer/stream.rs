@@ -0,0 +1,59 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+use crate::utp::UtpStream;
+
+/// A connection to a peer over either transport, so the wire protocol runs
+/// the same on both.
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PeerStream::Tcp(stream) => stream.peer_addr(),
+            PeerStream::Utp(stream) => Ok(stream.peer_addr()),
+        }
+    }
+}
+
+impl From<TcpStream> for PeerStream {
+    fn from(stream: TcpStream) -> Self {
+        PeerStream::Tcp(stream)
+    }
+}
+
+impl From<UtpStream> for PeerStream {
+    fn from(stream: UtpStream) -> Self {
+        PeerStream::Utp(stream)
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Tcp(stream) => stream.read(buffer),
+            PeerStream::Utp(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Tcp(stream) => stream.write(data),
+            PeerStream::Utp(stream) => stream.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PeerStream::Tcp(stream) => stream.flush(),
+            PeerStream::Utp(stream) => stream.flush(),
+        }
+    }
+}
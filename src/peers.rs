@@ -0,0 +1,234 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use indexmap::IndexMap;
+
+use crate::bencode_decoder::Bencode;
+
+/// Size of a compact IPv4 peer: 4 address bytes and a big-endian port.
+pub const COMPACT_V4_SIZE: usize = 6;
+/// Size of a compact IPv6 peer (BEP 7): 16 address bytes and a port.
+pub const COMPACT_V6_SIZE: usize = 18;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum PeerListError {
+    InvalidLength { length: usize, entry_size: usize },
+    InvalidPeer(String),
+}
+
+impl Display for PeerListError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerListError::InvalidLength { length, entry_size } => write!(
+                f,
+                "compact peer list of {length} bytes is not a multiple of {entry_size}"
+            ),
+            PeerListError::InvalidPeer(reason) => write!(f, "invalid peer: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for PeerListError {}
+
+/// Decodes a single compact peer of either 6 or 18 bytes.
+pub fn decode_compact_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    match bytes.len() {
+        COMPACT_V4_SIZE => {
+            let ip: [u8; 4] = bytes[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+        }
+        COMPACT_V6_SIZE => {
+            let ip: [u8; 16] = bytes[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([bytes[16], bytes[17]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        }
+        _ => None,
+    }
+}
+
+pub fn encode_compact_peer(peer: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match peer.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&peer.port().to_be_bytes());
+    bytes
+}
+
+fn decode_compact(bytes: &[u8], entry_size: usize) -> Result<Vec<SocketAddr>, PeerListError> {
+    if !bytes.len().is_multiple_of(entry_size) {
+        return Err(PeerListError::InvalidLength {
+            length: bytes.len(),
+            entry_size,
+        });
+    }
+
+    Ok(bytes
+        .chunks(entry_size)
+        .map(|peer| decode_compact_peer(peer).unwrap())
+        .collect())
+}
+
+/// Decodes a `peers` string of 6-byte IPv4 peers (BEP 23).
+pub fn decode_compact_v4(bytes: &[u8]) -> Result<Vec<SocketAddr>, PeerListError> {
+    decode_compact(bytes, COMPACT_V4_SIZE)
+}
+
+/// Decodes a `peers6` string of 18-byte IPv6 peers (BEP 7).
+pub fn decode_compact_v6(bytes: &[u8]) -> Result<Vec<SocketAddr>, PeerListError> {
+    decode_compact(bytes, COMPACT_V6_SIZE)
+}
+
+/// Encodes the IPv4 peers of `peers`, leaving out IPv6 ones.
+pub fn encode_compact_v4(peers: &[SocketAddr]) -> Vec<u8> {
+    peers
+        .iter()
+        .filter(|peer| peer.is_ipv4())
+        .flat_map(encode_compact_peer)
+        .collect()
+}
+
+/// Encodes the IPv6 peers of `peers`, leaving out IPv4 ones.
+pub fn encode_compact_v6(peers: &[SocketAddr]) -> Vec<u8> {
+    peers
+        .iter()
+        .filter(|peer| peer.is_ipv6())
+        .flat_map(encode_compact_peer)
+        .collect()
+}
+
+/// Decodes the original BEP 3 list of `ip`/`port`/`peer id` dictionaries.
+/// Entries that are not a valid address, such as hostnames, are skipped.
+pub fn decode_peer_dicts(peers: &[Bencode]) -> Vec<SocketAddr> {
+    peers
+        .iter()
+        .filter_map(|peer| {
+            let Bencode::Dictionary(peer) = peer else {
+                return None;
+            };
+
+            let ip = match peer.get(b"ip".as_slice()) {
+                Some(Bencode::String(ip)) => std::str::from_utf8(ip)
+                    .ok()
+                    .and_then(|ip| ip.parse::<IpAddr>().ok()),
+                _ => None,
+            }?;
+
+            let port = match peer.get(b"port".as_slice()) {
+                Some(Bencode::Integer(port)) => u16::try_from(*port).ok(),
+                _ => None,
+            }?;
+
+            Some(SocketAddr::new(ip, port))
+        })
+        .collect()
+}
+
+pub fn encode_peer_dicts(peers: &[SocketAddr]) -> Bencode {
+    Bencode::List(
+        peers
+            .iter()
+            .map(|peer| {
+                let mut dict = IndexMap::new();
+                dict.insert(
+                    b"ip".to_vec(),
+                    Bencode::String(peer.ip().to_string().into_bytes()),
+                );
+                dict.insert(b"port".to_vec(), Bencode::Integer(peer.port().into()));
+                Bencode::Dictionary(dict)
+            })
+            .collect(),
+    )
+}
+
+/// Decodes a `peers` value in either of its forms: a compact IPv4 string or a
+/// list of dictionaries.
+pub fn decode_peers(value: &Bencode) -> Result<Vec<SocketAddr>, PeerListError> {
+    match value {
+        Bencode::String(compact) => decode_compact_v4(compact),
+        Bencode::List(peers) => Ok(decode_peer_dicts(peers)),
+        _ => Err(PeerListError::InvalidPeer(
+            "peers is neither a string nor a list".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_v4_round_trip() {
+        let peers: Vec<SocketAddr> = vec![
+            "127.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+        ];
+        let encoded = encode_compact_v4(&peers);
+
+        assert_eq!(encoded, b"\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50");
+        assert_eq!(decode_compact_v4(&encoded), Ok(peers));
+    }
+
+    #[test]
+    fn test_compact_v6_round_trip() {
+        let peers: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:51413".parse().unwrap(),
+            "127.0.0.1:1".parse().unwrap(),
+        ];
+        let encoded = encode_compact_v6(&peers);
+
+        assert_eq!(encoded.len(), COMPACT_V6_SIZE);
+        assert_eq!(decode_compact_v6(&encoded), Ok(vec![peers[0]]));
+    }
+
+    #[test]
+    fn test_malformed_lengths() {
+        assert_eq!(
+            decode_compact_v4(&[0; 7]),
+            Err(PeerListError::InvalidLength {
+                length: 7,
+                entry_size: 6
+            })
+        );
+        assert_eq!(
+            decode_compact_v6(&[0; 12]),
+            Err(PeerListError::InvalidLength {
+                length: 12,
+                entry_size: 18
+            })
+        );
+        assert_eq!(decode_compact_peer(&[0; 5]), None);
+    }
+
+    #[test]
+    fn test_peer_dicts_round_trip() {
+        let peers: Vec<SocketAddr> = vec![
+            "192.168.1.5:6881".parse().unwrap(),
+            "[::1]:6882".parse().unwrap(),
+        ];
+        let encoded = encode_peer_dicts(&peers);
+
+        assert_eq!(
+            encoded.clone().encode_value(),
+            b"ld2:ip11:192.168.1.54:porti6881eed2:ip3:::14:porti6882eee".to_vec()
+        );
+        assert_eq!(decode_peers(&encoded), Ok(peers));
+    }
+
+    #[test]
+    fn test_invalid_peer_dicts() {
+        let (value, _) = Bencode::decode_value(
+            b"ld2:ip6:bad.ip4:porti1eei1ed2:ip9:127.0.0.14:porti70000eed2:ip8:10.0.0.14:porti80eee"
+                .to_vec(),
+        );
+        assert_eq!(
+            decode_peers(&value),
+            Ok(vec!["10.0.0.1:80".parse().unwrap()])
+        );
+
+        assert!(decode_peers(&Bencode::Integer(1)).is_err());
+    }
+}
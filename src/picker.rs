@@ -0,0 +1,578 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    ops::Range,
+};
+
+use rand::seq::SliceRandom;
+
+use crate::{
+    geometry::{Geometry, BLOCK_SIZE},
+    peer::{handshake::Version, message::BlockRequest},
+};
+
+/// How much a file is wanted. Pieces get the highest priority of the files
+/// they hold.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Default)]
+pub enum Priority {
+    /// Not downloaded at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+enum BlockState {
+    Missing,
+    /// Requested from these peers, more than one in end-game.
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+/// What receiving a block changed.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Received {
+    /// Peers the block was also requested from, to be sent a cancel.
+    pub cancels: Vec<SocketAddr>,
+    /// Every block of the piece arrived, so it can be verified.
+    pub piece_complete: bool,
+}
+
+/// Decides which blocks to request from which peer: pieces are picked
+/// rarest first, or in order in sequential mode, within file priorities.
+/// Started pieces are finished first, and once every missing block was
+/// requested, end-game mode requests them again from other peers.
+pub struct PiecePicker {
+    piece_sizes: Vec<u32>,
+    /// Files each piece holds.
+    piece_files: Vec<Range<usize>>,
+    file_paths: Vec<Vec<String>>,
+    file_priorities: Vec<Priority>,
+    have: Vec<bool>,
+    /// Number of connected peers having each piece.
+    availability: Vec<u32>,
+    peers: HashMap<SocketAddr, Vec<bool>>,
+    /// Blocks of the pieces being downloaded.
+    partial: BTreeMap<u32, Vec<BlockState>>,
+    sequential: bool,
+}
+
+fn bit(bitfield: &[u8], index: usize) -> bool {
+    bitfield
+        .get(index / 8)
+        .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+impl PiecePicker {
+    pub fn new(geometry: &Geometry, version: Version) -> Self {
+        let files = geometry.files();
+
+        let (piece_sizes, piece_files): (Vec<u32>, Vec<Range<usize>>) = match version {
+            Version::V1 => (0..geometry.v1_piece_count())
+                .map(|piece| {
+                    let spans = geometry.v1_piece_spans(piece).unwrap();
+                    let first = spans.first().unwrap().file_index;
+                    let last = spans.last().unwrap().file_index;
+                    (geometry.v1_piece_size(piece).unwrap(), first..last + 1)
+                })
+                .unzip(),
+            Version::V2 => (0..geometry.v2_piece_count())
+                .map(|piece| {
+                    let (file_index, file_piece) = geometry.v2_piece_file(piece).unwrap();
+                    (
+                        geometry.file_piece_size(file_index, file_piece).unwrap(),
+                        file_index..file_index + 1,
+                    )
+                })
+                .unzip(),
+        };
+        let piece_count = piece_sizes.len();
+
+        PiecePicker {
+            piece_sizes,
+            piece_files,
+            file_paths: files.iter().map(|file| file.path.clone()).collect(),
+            file_priorities: vec![Priority::Normal; files.len()],
+            have: vec![false; piece_count],
+            availability: vec![0; piece_count],
+            peers: HashMap::new(),
+            partial: BTreeMap::new(),
+            sequential: false,
+        }
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.piece_sizes.len() as u32
+    }
+
+    pub fn has_piece(&self, piece: u32) -> bool {
+        self.have.get(piece as usize).copied().unwrap_or(false)
+    }
+
+    pub fn availability(&self, piece: u32) -> u32 {
+        self.availability.get(piece as usize).copied().unwrap_or(0)
+    }
+
+    /// Our bitfield, to send to peers.
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0; self.have.len().div_ceil(8)];
+        for (piece, _) in self.have.iter().enumerate().filter(|(_, have)| **have) {
+            bitfield[piece / 8] |= 0x80 >> (piece % 8);
+        }
+        bitfield
+    }
+
+    /// Requests pieces in order, for streaming, instead of rarest first.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Sets the priority of a file, by its index in the file tree.
+    pub fn set_file_priority(&mut self, file_index: usize, priority: Priority) {
+        if let Some(file_priority) = self.file_priorities.get_mut(file_index) {
+            *file_priority = priority;
+        }
+    }
+
+    /// Sets the priority of the file at `path` in the file tree, returning
+    /// whether there is one.
+    pub fn set_path_priority(&mut self, path: &[String], priority: Priority) -> bool {
+        match self.file_paths.iter().position(|file| file == path) {
+            Some(file_index) => {
+                self.set_file_priority(file_index, priority);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn piece_priority(&self, piece: u32) -> Priority {
+        self.piece_files
+            .get(piece as usize)
+            .and_then(|files| self.file_priorities[files.clone()].iter().copied().max())
+            .unwrap_or(Priority::Skip)
+    }
+
+    /// Whether every piece that is not skipped was downloaded.
+    pub fn is_complete(&self) -> bool {
+        (0..self.piece_count())
+            .all(|piece| self.has_piece(piece) || self.piece_priority(piece) == Priority::Skip)
+    }
+
+    pub fn on_bitfield(&mut self, peer: SocketAddr, bitfield: &[u8]) {
+        self.remove_peer(peer);
+
+        let pieces: Vec<bool> = (0..self.have.len())
+            .map(|piece| bit(bitfield, piece))
+            .collect();
+        for (availability, _) in self
+            .availability
+            .iter_mut()
+            .zip(&pieces)
+            .filter(|(_, has)| **has)
+        {
+            *availability += 1;
+        }
+        self.peers.insert(peer, pieces);
+    }
+
+    pub fn on_have(&mut self, peer: SocketAddr, piece: u32) {
+        let piece = piece as usize;
+        if piece >= self.have.len() {
+            return;
+        }
+
+        let pieces = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| vec![false; self.have.len()]);
+        if !pieces[piece] {
+            pieces[piece] = true;
+            self.availability[piece] += 1;
+        }
+    }
+
+    /// Forgets a disconnected peer: its pieces and its pending requests.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        if let Some(pieces) = self.peers.remove(&peer) {
+            for (availability, _) in self
+                .availability
+                .iter_mut()
+                .zip(&pieces)
+                .filter(|(_, has)| **has)
+            {
+                *availability -= 1;
+            }
+        }
+
+        self.abort_requests(peer);
+    }
+
+    /// Makes the blocks requested from `peer` available to others again, as
+    /// when it chokes us or rejects the requests.
+    pub fn abort_requests(&mut self, peer: SocketAddr) {
+        for blocks in self.partial.values_mut() {
+            for block in blocks.iter_mut() {
+                if let BlockState::Requested(peers) = block {
+                    peers.retain(|requester| *requester != peer);
+                    if peers.is_empty() {
+                        *block = BlockState::Missing;
+                    }
+                }
+            }
+        }
+    }
+
+    fn peer_has(&self, peer: &SocketAddr, piece: u32) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|pieces| pieces[piece as usize])
+    }
+
+    fn is_wanted(&self, piece: u32) -> bool {
+        !self.has_piece(piece) && self.piece_priority(piece) != Priority::Skip
+    }
+
+    fn request(&self, piece: u32, block: usize) -> BlockRequest {
+        let begin = block as u32 * BLOCK_SIZE;
+        BlockRequest {
+            index: piece,
+            begin,
+            length: (self.piece_sizes[piece as usize] - begin).min(BLOCK_SIZE),
+        }
+    }
+
+    /// Pieces the peer could give us, best first.
+    fn candidates(&self, peer: &SocketAddr) -> Vec<u32> {
+        let mut pieces: Vec<u32> = (0..self.piece_count())
+            .filter(|piece| self.is_wanted(*piece) && self.peer_has(peer, *piece))
+            .collect();
+
+        if self.sequential {
+            pieces.sort_by_key(|piece| std::cmp::Reverse(self.piece_priority(*piece)));
+        } else {
+            // Pieces equally rare come in random order
+            pieces.shuffle(&mut rand::thread_rng());
+            pieces.sort_by_key(|piece| {
+                (
+                    std::cmp::Reverse(self.piece_priority(*piece)),
+                    self.availability[*piece as usize],
+                )
+            });
+        }
+
+        // Started pieces are finished first
+        pieces.sort_by_key(|piece| !self.partial.contains_key(piece));
+        pieces
+    }
+
+    /// Picks up to `count` blocks to request from `peer`.
+    pub fn pick(&mut self, peer: SocketAddr, count: usize) -> Vec<BlockRequest> {
+        let candidates = self.candidates(&peer);
+        let mut requests = Vec::new();
+
+        for &piece in &candidates {
+            if requests.len() == count {
+                break;
+            }
+
+            let blocks = self.piece_sizes[piece as usize].div_ceil(BLOCK_SIZE) as usize;
+            let states = self
+                .partial
+                .entry(piece)
+                .or_insert_with(|| vec![BlockState::Missing; blocks]);
+
+            for (block, state) in states.iter_mut().enumerate() {
+                if requests.len() == count {
+                    break;
+                }
+                if *state == BlockState::Missing {
+                    *state = BlockState::Requested(vec![peer]);
+                    requests.push((piece, block));
+                }
+            }
+        }
+
+        if requests.len() < count && self.in_end_game() {
+            for &piece in &candidates {
+                for (block, state) in self.partial.get_mut(&piece).unwrap().iter_mut().enumerate() {
+                    if requests.len() == count {
+                        break;
+                    }
+                    if let BlockState::Requested(peers) = state {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                            requests.push((piece, block));
+                        }
+                    }
+                }
+            }
+        }
+
+        requests
+            .into_iter()
+            .map(|(piece, block)| self.request(piece, block))
+            .collect()
+    }
+
+    /// Whether every block still missing was requested: the last blocks
+    /// are then requested from several peers, to not wait on a slow one.
+    pub fn in_end_game(&self) -> bool {
+        (0..self.piece_count())
+            .filter(|piece| self.is_wanted(*piece))
+            .all(|piece| {
+                self.partial
+                    .get(&piece)
+                    .is_some_and(|blocks| !blocks.contains(&BlockState::Missing))
+            })
+    }
+
+    pub fn on_block(&mut self, peer: SocketAddr, request: &BlockRequest) -> Received {
+        let block = (request.begin / BLOCK_SIZE) as usize;
+        let Some(state) = self
+            .partial
+            .get_mut(&request.index)
+            .and_then(|blocks| blocks.get_mut(block))
+        else {
+            return Received::default();
+        };
+
+        let cancels = match std::mem::replace(state, BlockState::Received) {
+            BlockState::Requested(peers) => peers
+                .into_iter()
+                .filter(|requester| *requester != peer)
+                .collect(),
+            BlockState::Missing => Vec::new(),
+            // A duplicate, as when a cancel came too late in end game: the
+            // piece was already reported complete
+            BlockState::Received => return Received::default(),
+        };
+
+        let piece_complete = self.partial[&request.index]
+            .iter()
+            .all(|block| *block == BlockState::Received);
+
+        Received {
+            cancels,
+            piece_complete,
+        }
+    }
+
+    /// Records the verdict on a complete piece. A failed piece has its bad
+    /// blocks downloaded again, or all of them when they are not known.
+    pub fn on_piece_verified(&mut self, piece: u32, passed: bool, bad_blocks: Option<&[u32]>) {
+        if piece >= self.piece_count() {
+            return;
+        }
+
+        if passed {
+            self.have[piece as usize] = true;
+            self.partial.remove(&piece);
+            return;
+        }
+
+        match (self.partial.get_mut(&piece), bad_blocks) {
+            (Some(blocks), Some(bad_blocks)) => {
+                for block in bad_blocks {
+                    if let Some(state) = blocks.get_mut(*block as usize) {
+                        *state = BlockState::Missing;
+                    }
+                }
+            }
+            _ => {
+                self.partial.remove(&piece);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::torrent::{FileTree, Info};
+
+    const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+
+    /// Files of two and a half and of two pieces.
+    fn geometry() -> Geometry {
+        Geometry::new(&Info::with_files(
+            "folder",
+            PIECE_LENGTH,
+            vec![
+                FileTree::file("a", PIECE_LENGTH as u64 * 5 / 2),
+                FileTree::file("b", PIECE_LENGTH as u64 * 2),
+            ],
+        ))
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn pieces(requests: &[BlockRequest]) -> Vec<u32> {
+        let mut pieces: Vec<u32> = requests.iter().map(|request| request.index).collect();
+        pieces.dedup();
+        pieces
+    }
+
+    #[test]
+    fn test_layouts() {
+        let v2 = PiecePicker::new(&geometry(), Version::V2);
+        assert_eq!(v2.piece_count(), 5);
+        assert_eq!(
+            v2.piece_sizes,
+            [
+                PIECE_LENGTH,
+                PIECE_LENGTH,
+                BLOCK_SIZE,
+                PIECE_LENGTH,
+                PIECE_LENGTH
+            ]
+        );
+
+        let mut v1 = PiecePicker::new(&geometry(), Version::V1);
+        assert_eq!(v1.piece_count(), 5);
+        assert_eq!(v1.piece_files[2], 0..2);
+
+        // A piece across a skipped and a wanted file is still wanted
+        v1.set_file_priority(0, Priority::Skip);
+        assert_eq!(v1.piece_priority(1), Priority::Skip);
+        assert_eq!(v1.piece_priority(2), Priority::Normal);
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut picker = PiecePicker::new(&geometry(), Version::V2);
+        picker.on_bitfield(peer(1), &[0b1111_1000]);
+        picker.on_bitfield(peer(2), &[0b1101_1000]);
+        picker.on_bitfield(peer(3), &[0b0101_0000]);
+        assert_eq!(
+            (0..5)
+                .map(|piece| picker.availability(piece))
+                .collect::<Vec<_>>(),
+            [2, 3, 1, 3, 2]
+        );
+
+        assert_eq!(pieces(&picker.pick(peer(1), 1)), [2]);
+
+        // Pieces 0 and 4 are equally rare, and picked in random order
+        let mut first = HashSet::new();
+        for _ in 0..50 {
+            let mut picker = PiecePicker::new(&geometry(), Version::V2);
+            picker.on_bitfield(peer(1), &[0b1000_1000]);
+            first.insert(picker.pick(peer(1), 1)[0].index);
+        }
+        assert_eq!(first, HashSet::from([0, 4]));
+
+        picker.remove_peer(peer(2));
+        assert_eq!(picker.availability(1), 2);
+    }
+
+    #[test]
+    fn test_sequential_and_priorities() {
+        let mut picker = PiecePicker::new(&geometry(), Version::V2);
+        picker.on_bitfield(peer(1), &[0b1111_1000]);
+        picker.on_have(peer(2), 4);
+        picker.set_sequential(true);
+        assert_eq!(pieces(&picker.pick(peer(1), 4)), [0, 1]);
+
+        let mut picker = PiecePicker::new(&geometry(), Version::V2);
+        picker.on_bitfield(peer(1), &[0b1111_1000]);
+        picker.set_sequential(true);
+        assert!(picker.set_path_priority(&["b".to_string()], Priority::High));
+        assert!(!picker.set_path_priority(&["c".to_string()], Priority::High));
+        picker.set_file_priority(0, Priority::Skip);
+        assert_eq!(pieces(&picker.pick(peer(1), 10)), [3, 4]);
+        assert!(picker.pick(peer(1), 10).is_empty());
+    }
+
+    #[test]
+    fn test_partial_pieces() {
+        let mut picker = PiecePicker::new(&geometry(), Version::V2);
+        picker.on_bitfield(peer(1), &[0b1111_1000]);
+        picker.on_bitfield(peer(2), &[0b1111_1000]);
+        picker.set_sequential(true);
+
+        // The second peer continues the piece the first one started
+        let first = picker.pick(peer(1), 1);
+        assert_eq!(
+            first,
+            [BlockRequest {
+                index: 0,
+                begin: 0,
+                length: BLOCK_SIZE
+            }]
+        );
+        let second = picker.pick(peer(2), 1);
+        assert_eq!((second[0].index, second[0].begin), (0, BLOCK_SIZE));
+
+        assert!(!picker.on_block(peer(1), &first[0]).piece_complete);
+        assert!(picker.on_block(peer(2), &second[0]).piece_complete);
+
+        // Only the bad block of a failed piece is requested again
+        picker.on_piece_verified(0, false, Some(&[1]));
+        assert_eq!(picker.pick(peer(1), 1), second);
+        picker.on_block(peer(1), &second[0]);
+        picker.on_piece_verified(0, true, None);
+        assert!(picker.has_piece(0));
+        assert_eq!(picker.bitfield(), [0b1000_0000]);
+
+        // Choked requests go to the next peer
+        let requests = picker.pick(peer(1), 2);
+        picker.abort_requests(peer(1));
+        assert_eq!(picker.pick(peer(2), 2), requests);
+    }
+
+    #[test]
+    fn test_end_game() {
+        let mut picker = PiecePicker::new(&geometry(), Version::V2);
+        picker.on_bitfield(peer(1), &[0b1111_1000]);
+        picker.on_bitfield(peer(2), &[0b1111_1000]);
+
+        let requests = picker.pick(peer(1), 100);
+        assert_eq!(requests.len(), 9);
+        assert!(picker.in_end_game());
+
+        // The other peer is asked for the same blocks, and cancelled once
+        // they arrive
+        let duplicates = picker.pick(peer(2), 100);
+        assert_eq!(duplicates.len(), 9);
+        assert!(picker.pick(peer(2), 100).is_empty());
+
+        let received = picker.on_block(peer(2), &duplicates[0]);
+        assert_eq!(received.cancels, [peer(1)]);
+        assert!(picker.on_block(peer(1), &duplicates[0]).cancels.is_empty());
+
+        for request in &requests {
+            picker.on_block(peer(1), request);
+        }
+        for piece in 0..5 {
+            picker.on_piece_verified(piece, true, None);
+        }
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn test_end_game_duplicate() {
+        let mut picker = PiecePicker::new(&geometry(), Version::V2);
+        picker.on_bitfield(peer(1), &[0b1111_1000]);
+        picker.on_bitfield(peer(2), &[0b1111_1000]);
+        let requests = picker.pick(peer(1), 100);
+        picker.pick(peer(2), 100);
+
+        // Both blocks of the first piece arrive, then the copy whose cancel
+        // lost the race
+        assert!(!picker.on_block(peer(1), &requests[0]).piece_complete);
+        let received = picker.on_block(peer(1), &requests[1]);
+        assert!(received.piece_complete);
+        assert_eq!(received.cancels, [peer(2)]);
+
+        let duplicate = picker.on_block(peer(2), &requests[1]);
+        assert!(!duplicate.piece_complete);
+        assert!(duplicate.cancels.is_empty());
+    }
+}
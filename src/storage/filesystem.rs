@@ -0,0 +1,159 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{Allocation, Backend};
+
+/// Zeros written at once when preallocating.
+const ZERO_CHUNK: usize = 1 << 20;
+
+/// Keeps the files in a download directory.
+pub struct FilesystemBackend {
+    root: PathBuf,
+    /// Files opened so far, kept open for the next blocks.
+    open: Mutex<HashMap<PathBuf, fs::File>>,
+}
+
+impl FilesystemBackend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FilesystemBackend {
+            root: root.into(),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Runs `f` on the open file at `path`, opening it first if needed.
+    fn with_file<T>(
+        &self,
+        path: &Path,
+        f: impl FnOnce(&mut fs::File) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut open = self.open.lock().unwrap();
+        let file = match open.entry(path.to_path_buf()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(self.root.join(path))?,
+            ),
+        };
+
+        f(file)
+    }
+}
+
+impl Backend for FilesystemBackend {
+    fn create(&self, path: &Path, length: u64, allocation: Allocation) -> io::Result<()> {
+        let full_path = self.root.join(path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&full_path)?;
+
+        // Data already there, from an earlier session, is kept
+        let existing = file.metadata()?.len();
+        if allocation == Allocation::Preallocate && existing < length {
+            file.seek(SeekFrom::Start(existing))?;
+            let zeros = vec![0; ZERO_CHUNK];
+            let mut remaining = length - existing;
+            while remaining > 0 {
+                let chunk = remaining.min(ZERO_CHUNK as u64) as usize;
+                file.write_all(&zeros[..chunk])?;
+                remaining -= chunk as u64;
+            }
+        }
+        file.set_len(length)?;
+
+        self.open.lock().unwrap().insert(path.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.with_file(path, |file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(data)
+        })
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.with_file(path, |file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(buffer)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bittorent-storage-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_create_and_access() {
+        let dir = test_dir("access");
+        let backend = FilesystemBackend::new(&dir);
+        let path = Path::new("torrent/sub/file");
+
+        backend.create(path, 100, Allocation::Sparse).unwrap();
+        assert_eq!(fs::metadata(dir.join(path)).unwrap().len(), 100);
+
+        backend.write_at(path, 90, b"0123456789").unwrap();
+        let mut buffer = [0; 12];
+        backend.read_at(path, 88, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"\x00\x000123456789");
+        assert!(backend.read_at(path, 95, &mut buffer).is_err());
+
+        // Files written earlier are kept when created again
+        let backend = FilesystemBackend::new(&dir);
+        backend.create(path, 100, Allocation::Preallocate).unwrap();
+        let mut buffer = [0; 10];
+        backend.read_at(path, 90, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"0123456789");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let dir = test_dir("preallocate");
+        let backend = FilesystemBackend::new(&dir);
+        let path = Path::new("big");
+
+        backend
+            .create(path, ZERO_CHUNK as u64 * 2 + 5, Allocation::Preallocate)
+            .unwrap();
+        assert_eq!(
+            fs::read(dir.join(path)).unwrap(),
+            vec![0; ZERO_CHUNK * 2 + 5]
+        );
+
+        backend
+            .create(Path::new("empty"), 0, Allocation::Preallocate)
+            .unwrap();
+        assert_eq!(fs::metadata(dir.join("empty")).unwrap().len(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{Allocation, Backend};
+
+/// Keeps the files in memory, for tests and for torrents that are not
+/// meant to be saved.
+#[derive(Default)]
+pub struct MemoryBackend {
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+}
+
+fn range(file: &[u8], offset: u64, length: usize) -> io::Result<std::ops::Range<usize>> {
+    let start = offset as usize;
+    if start + length > file.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "access past the end of the file",
+        ));
+    }
+
+    Ok(start..start + length)
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "file was not created")
+}
+
+impl Backend for MemoryBackend {
+    fn create(&self, path: &Path, length: u64, _: Allocation) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .resize(length as usize, 0);
+        Ok(())
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.get_mut(path).ok_or_else(not_found)?;
+        let range = range(file, offset, data.len())?;
+        file[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let files = self.files.lock().unwrap();
+        let file = files.get(path).ok_or_else(not_found)?;
+        buffer.copy_from_slice(&file[range(file, offset, buffer.len())?]);
+        Ok(())
+    }
+}
@@ -0,0 +1,481 @@
+pub mod filesystem;
+pub mod memory;
+pub mod verify;
+
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    geometry::Geometry,
+    peer::handshake::Version,
+    torrent::{FileTree, Info},
+};
+
+/// Longest file name most filesystems accept, in bytes.
+const MAX_COMPONENT_LENGTH: usize = 255;
+/// Names Windows reserves for devices, with any extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    InvalidPiece(u32),
+    /// The block does not fit in its piece.
+    OutOfBounds {
+        piece: u32,
+        offset: u32,
+        length: usize,
+    },
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(error) => write!(f, "I/O error: {error}"),
+            StorageError::InvalidPiece(piece) => write!(f, "invalid piece {piece}"),
+            StorageError::OutOfBounds {
+                piece,
+                offset,
+                length,
+            } => write!(
+                f,
+                "block of {length} bytes at offset {offset} is outside of piece {piece}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(error: io::Error) -> Self {
+        StorageError::Io(error)
+    }
+}
+
+/// How files are created before any data is written.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Allocation {
+    /// Files get their length without taking the space, where the
+    /// filesystem supports it.
+    #[default]
+    Sparse,
+    /// Files are filled with zeros, so the space is taken upfront.
+    Preallocate,
+}
+
+/// Where the contents of the files are kept. Paths are relative, and
+/// already sanitized.
+pub trait Backend: Send + Sync {
+    /// Creates the file, and its missing directories, with `length` bytes.
+    fn create(&self, path: &Path, length: u64, allocation: Allocation) -> io::Result<()>;
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Fills `buffer` with the bytes at `offset`.
+    fn read_at(&self, path: &Path, offset: u64, buffer: &mut [u8]) -> io::Result<()>;
+}
+
+impl<B: Backend + ?Sized> Backend for Arc<B> {
+    fn create(&self, path: &Path, length: u64, allocation: Allocation) -> io::Result<()> {
+        (**self).create(path, length, allocation)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        (**self).write_at(path, offset, data)
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        (**self).read_at(path, offset, buffer)
+    }
+}
+
+/// Makes a name from the torrent safe to use as a file name: separators,
+/// reserved characters and device names cannot escape the download
+/// directory or fail to be created.
+pub fn sanitize_component(component: &str) -> String {
+    let mut sanitized: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows drops trailing dots and spaces
+    sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+    if sanitized.is_empty() {
+        return "_".to_string();
+    }
+
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        sanitized.insert(0, '_');
+    }
+
+    if sanitized.len() > MAX_COMPONENT_LENGTH {
+        let mut end = MAX_COMPONENT_LENGTH;
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+    }
+
+    sanitized
+}
+
+/// Relative paths of the files, in the order of the geometry. Files of a
+/// multi-file torrent are put in a directory named after it.
+pub fn file_paths(info: &Info) -> Vec<PathBuf> {
+    let single_file = matches!(
+        &info.file_tree,
+        FileTree::Directory(_, contents)
+            if matches!(contents.as_slice(), [FileTree::File(name, _)] if *name == info.name)
+    );
+
+    let mut files = Vec::new();
+    info.file_tree.get_files_with_paths(&[], &mut files);
+
+    files
+        .into_iter()
+        .map(|(components, _)| {
+            let mut path = PathBuf::new();
+            if !single_file {
+                path.push(sanitize_component(&info.name));
+            }
+            for component in &components {
+                path.push(sanitize_component(component));
+            }
+            path
+        })
+        .collect()
+}
+
+/// A run of a block that lies in a single file.
+struct Extent {
+    file_index: usize,
+    /// Offset in the file.
+    offset: u64,
+    /// Range of the block.
+    start: usize,
+    end: usize,
+}
+
+/// Reads and writes blocks of pieces in the files of a torrent. With the v1
+/// layout pieces run across file boundaries, with the v2 layout every file
+/// starts on a piece.
+pub struct Storage {
+    backend: Box<dyn Backend>,
+    geometry: Geometry,
+    version: Version,
+    paths: Vec<PathBuf>,
+}
+
+impl Storage {
+    pub fn new(info: &Info, backend: Box<dyn Backend>, version: Version) -> Self {
+        Storage {
+            backend,
+            geometry: Geometry::new(info),
+            version,
+            paths: file_paths(info),
+        }
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Creates every file, empty ones included.
+    pub fn create(&self, allocation: Allocation) -> Result<(), StorageError> {
+        for (path, file) in self.paths.iter().zip(self.geometry.files()) {
+            self.backend.create(path, file.length, allocation)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        match self.version {
+            Version::V1 => self.geometry.v1_piece_count(),
+            Version::V2 => self.geometry.v2_piece_count(),
+        }
+    }
+
+    pub fn piece_size(&self, piece: u32) -> Option<u32> {
+        match self.version {
+            Version::V1 => self.geometry.v1_piece_size(piece),
+            Version::V2 => {
+                let (file_index, file_piece) = self.geometry.v2_piece_file(piece)?;
+                self.geometry.file_piece_size(file_index, file_piece)
+            }
+        }
+    }
+
+    /// Splits the block at `offset` of `piece` by file.
+    fn extents(&self, piece: u32, offset: u32, length: usize) -> Result<Vec<Extent>, StorageError> {
+        let size = self
+            .piece_size(piece)
+            .ok_or(StorageError::InvalidPiece(piece))?;
+        if offset as u64 + length as u64 > size as u64 {
+            return Err(StorageError::OutOfBounds {
+                piece,
+                offset,
+                length,
+            });
+        }
+
+        let (block_start, block_end) = (offset as u64, offset as u64 + length as u64);
+
+        let extents = match self.version {
+            // Padding between files belongs to no file: it is not written,
+            // and reads back as zeros
+            Version::V1 => {
+                let piece_start = piece as u64 * self.geometry.piece_length() as u64;
+                let mut extents = Vec::new();
+                for span in self.geometry.v1_piece_spans(piece).unwrap() {
+                    let file = &self.geometry.files()[span.file_index];
+                    let span_start = file.offset + span.offset - piece_start;
+                    let span_end = span_start + span.length;
+                    let (start, end) = (span_start.max(block_start), span_end.min(block_end));
+                    if start < end {
+                        extents.push(Extent {
+                            file_index: span.file_index,
+                            offset: span.offset + start - span_start,
+                            start: (start - block_start) as usize,
+                            end: (end - block_start) as usize,
+                        });
+                    }
+                }
+                extents
+            }
+            Version::V2 => {
+                let (file_index, file_piece) = self.geometry.v2_piece_file(piece).unwrap();
+                let piece_length = self.geometry.piece_length() as u64;
+                vec![Extent {
+                    file_index,
+                    offset: file_piece as u64 * piece_length + block_start,
+                    start: 0,
+                    end: length,
+                }]
+            }
+        };
+
+        Ok(extents)
+    }
+
+    pub fn write_block(&self, piece: u32, offset: u32, data: &[u8]) -> Result<(), StorageError> {
+        for extent in self.extents(piece, offset, data.len())? {
+            self.backend.write_at(
+                &self.paths[extent.file_index],
+                extent.offset,
+                &data[extent.start..extent.end],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_block(
+        &self,
+        piece: u32,
+        offset: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut block = vec![0; length as usize];
+        for extent in self.extents(piece, offset, block.len())? {
+            self.backend.read_at(
+                &self.paths[extent.file_index],
+                extent.offset,
+                &mut block[extent.start..extent.end],
+            )?;
+        }
+
+        Ok(block)
+    }
+
+    pub fn read_piece(&self, piece: u32) -> Result<Vec<u8>, StorageError> {
+        let size = self
+            .piece_size(piece)
+            .ok_or(StorageError::InvalidPiece(piece))?;
+        self.read_block(piece, 0, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{memory::MemoryBackend, *};
+    use crate::bencode_decoder::Bencode;
+
+    /// Three files of 10, 30 and 25 bytes, with pieces of 16 bytes.
+    fn info() -> Info {
+        Info::with_files(
+            "folder",
+            16,
+            vec![
+                FileTree::file("a", 10),
+                FileTree::Directory("sub".to_string(), vec![FileTree::file("b", 30)]),
+                FileTree::file("empty", 0),
+                FileTree::file("c", 25),
+            ],
+        )
+    }
+
+    fn storage(version: Version) -> (Storage, Arc<MemoryBackend>) {
+        let backend = Arc::new(MemoryBackend::new());
+        let storage = Storage::new(&info(), Box::new(backend.clone()), version);
+        storage.create(Allocation::Sparse).unwrap();
+        (storage, backend)
+    }
+
+    #[test]
+    fn test_sanitize_component() {
+        assert_eq!(sanitize_component("normal.txt"), "normal.txt");
+        assert_eq!(sanitize_component(".."), "_");
+        assert_eq!(sanitize_component(""), "_");
+        assert_eq!(sanitize_component("a/../b"), "a_.._b");
+        assert_eq!(sanitize_component("C:\\x"), "C__x");
+        assert_eq!(sanitize_component("what?\0"), "what__");
+        assert_eq!(sanitize_component("trailing. "), "trailing");
+        assert_eq!(sanitize_component("con.txt"), "_con.txt");
+        assert_eq!(sanitize_component("console"), "console");
+        assert_eq!(sanitize_component(&"é".repeat(200)).len(), 254);
+    }
+
+    #[test]
+    fn test_file_paths() {
+        assert_eq!(
+            file_paths(&info()),
+            vec![
+                PathBuf::from("folder/a"),
+                PathBuf::from("folder/sub/b"),
+                PathBuf::from("folder/empty"),
+                PathBuf::from("folder/c"),
+            ]
+        );
+
+        let single = Info {
+            name: "single.bin".to_string(),
+            file_tree: FileTree::Directory(String::new(), vec![FileTree::file("single.bin", 5)]),
+            ..info()
+        };
+        assert_eq!(file_paths(&single), vec![PathBuf::from("single.bin")]);
+
+        let unsafe_name = Info {
+            name: "..".to_string(),
+            ..info()
+        };
+        assert_eq!(file_paths(&unsafe_name)[0], PathBuf::from("_/a"));
+    }
+
+    #[test]
+    fn test_v1_spanning_pieces() {
+        let (storage, backend) = storage(Version::V1);
+        assert_eq!(storage.piece_count(), 5);
+        assert_eq!(storage.piece_size(4), Some(1));
+
+        let data: Vec<u8> = (0..65).collect();
+        for piece in 0..5 {
+            let start = piece as usize * 16;
+            let end = (start + 16).min(data.len());
+            storage.write_block(piece, 0, &data[start..end]).unwrap();
+        }
+
+        assert_eq!(backend.contents(Path::new("folder/a")).unwrap(), data[..10]);
+        assert_eq!(
+            backend.contents(Path::new("folder/sub/b")).unwrap(),
+            data[10..40]
+        );
+        assert_eq!(backend.contents(Path::new("folder/empty")).unwrap(), b"");
+        assert_eq!(backend.contents(Path::new("folder/c")).unwrap(), data[40..]);
+
+        // A block across the first and second files
+        assert_eq!(storage.read_block(0, 8, 4).unwrap(), data[8..12]);
+        assert_eq!(storage.read_piece(2).unwrap(), data[32..48]);
+    }
+
+    #[test]
+    fn test_v1_padded_pieces() {
+        let mut info = info();
+        info.extra
+            .insert(b"pieces".to_vec(), Bencode::String(vec![0; 5 * 20]));
+        let backend = Arc::new(MemoryBackend::new());
+        let storage = Storage::new(&info, Box::new(backend.clone()), Version::V1);
+        storage.create(Allocation::Sparse).unwrap();
+        // a and b are padded to 16 and 32 bytes, c starts at 48
+        assert_eq!(storage.piece_count(), 5);
+        assert_eq!(storage.piece_size(4), Some(9));
+
+        let a: Vec<u8> = (1..=10).collect();
+        let b: Vec<u8> = (11..=40).collect();
+        let c: Vec<u8> = (41..=65).collect();
+        let padded = [&a[..], &[0xee; 6], &b, &[0xee; 2], &c].concat();
+        for piece in 0..5 {
+            let start = piece as usize * 16;
+            let end = (start + 16).min(padded.len());
+            storage.write_block(piece, 0, &padded[start..end]).unwrap();
+        }
+
+        assert_eq!(backend.contents(Path::new("folder/a")).unwrap(), a);
+        assert_eq!(backend.contents(Path::new("folder/sub/b")).unwrap(), b);
+        assert_eq!(backend.contents(Path::new("folder/c")).unwrap(), c);
+
+        // Padding reads back as zeros
+        assert_eq!(storage.read_piece(0).unwrap(), [&a[..], &[0; 6]].concat());
+        assert_eq!(storage.read_block(2, 12, 4).unwrap(), [39, 40, 0, 0]);
+    }
+
+    #[test]
+    fn test_v2_aligned_pieces() {
+        let (storage, backend) = storage(Version::V2);
+        // One piece for a, two for b and c each
+        assert_eq!(storage.piece_count(), 5);
+        assert_eq!(storage.piece_size(0), Some(10));
+        assert_eq!(storage.piece_size(2), Some(14));
+
+        storage.write_block(2, 4, &[7; 10]).unwrap();
+        storage.write_block(3, 0, &[9; 16]).unwrap();
+
+        let b = backend.contents(Path::new("folder/sub/b")).unwrap();
+        assert_eq!(b[20..], [7; 10]);
+        let c = backend.contents(Path::new("folder/c")).unwrap();
+        assert_eq!(c[..16], [9; 16]);
+        assert_eq!(storage.read_block(2, 4, 10).unwrap(), [7; 10]);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let (storage, _) = storage(Version::V2);
+
+        assert!(matches!(
+            storage.write_block(0, 8, &[0; 4]),
+            Err(StorageError::OutOfBounds {
+                piece: 0,
+                offset: 8,
+                length: 4
+            })
+        ));
+        assert!(matches!(
+            storage.read_block(5, 0, 1),
+            Err(StorageError::InvalidPiece(5))
+        ));
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    bencode_decoder::Bencode,
+    geometry::{Geometry, BLOCK_SIZE},
+    merkle::{self, Hash, HASH_SIZE},
+    peer::{handshake::Version, message::HashRequest},
+    torrent::Torrent,
+};
+
+use super::{Storage, StorageError};
+
+/// Expected hash of a piece.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum PieceHash {
+    /// SHA-1 of the piece.
+    V1([u8; 20]),
+    /// Merkle root of the blocks of the piece, padded with zero leaves to
+    /// `width` blocks, and where the piece lies in the tree of its file.
+    V2 {
+        root: Hash,
+        width: usize,
+        pieces_root: Hash,
+        first_block: u32,
+        tree_height: u32,
+    },
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Verdict {
+    Passed,
+    /// The blocks that did not match, when the block hashes were known;
+    /// otherwise the whole piece must be downloaded again.
+    Failed {
+        bad_blocks: Option<Vec<u32>>,
+    },
+}
+
+/// Outcome of checking a piece in the pipeline.
+#[derive(Debug)]
+pub struct Verification {
+    pub piece: u32,
+    pub verdict: Result<Verdict, StorageError>,
+}
+
+/// Checks pieces against the hashes of the torrent: SHA-1 from `pieces` for
+/// v1, and SHA-256 merkle roots from the piece layers for v2.
+pub struct Verifier {
+    hashes: Vec<PieceHash>,
+}
+
+impl Verifier {
+    /// Returns `None` when the torrent lacks the hashes of `version`, such
+    /// as piece layers not fetched yet.
+    pub fn new(torrent: &Torrent, version: Version) -> Option<Self> {
+        let hashes = match version {
+            Version::V1 => v1_hashes(torrent)?,
+            Version::V2 => v2_hashes(torrent)?,
+        };
+
+        Some(Verifier { hashes })
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.hashes.len() as u32
+    }
+
+    /// Checks leaf hashes received for a v2 piece, by rebuilding its root.
+    pub fn check_block_hashes(&self, piece: u32, block_hashes: &[Hash]) -> bool {
+        match self.hashes.get(piece as usize) {
+            Some(PieceHash::V2 { root, width, .. }) => {
+                block_hashes.len() <= *width
+                    && merkle::root(block_hashes, *width, [0; HASH_SIZE]) == *root
+            }
+            _ => false,
+        }
+    }
+
+    /// Request for the leaf hashes of a v2 piece, with the proof up to the
+    /// pieces root of its file.
+    pub fn block_hashes_request(&self, piece: u32) -> Option<HashRequest> {
+        match self.hashes.get(piece as usize)? {
+            PieceHash::V1(_) => None,
+            PieceHash::V2 {
+                width,
+                pieces_root,
+                first_block,
+                tree_height,
+                ..
+            } => Some(HashRequest {
+                pieces_root: *pieces_root,
+                base_layer: 0,
+                index: *first_block,
+                length: *width as u32,
+                proof_layers: *tree_height,
+            }),
+        }
+    }
+
+    /// Checks the data of a piece. For a failed v2 piece, leaf hashes that
+    /// pass [`Verifier::check_block_hashes`] tell which blocks were bad.
+    pub fn verify(&self, piece: u32, data: &[u8], block_hashes: Option<&[Hash]>) -> Verdict {
+        let failed = Verdict::Failed { bad_blocks: None };
+
+        match self.hashes.get(piece as usize) {
+            None => failed,
+            Some(PieceHash::V1(expected)) => {
+                if sha1_smol::Sha1::from(data).digest().bytes() == *expected {
+                    Verdict::Passed
+                } else {
+                    failed
+                }
+            }
+            Some(PieceHash::V2 { root, width, .. }) => {
+                let leaves = merkle::leaf_hashes(data);
+                if leaves.len() <= *width && merkle::root(&leaves, *width, [0; HASH_SIZE]) == *root
+                {
+                    return Verdict::Passed;
+                }
+
+                let Some(block_hashes) = block_hashes
+                    .filter(|hashes| hashes.len() == leaves.len())
+                    .filter(|hashes| self.check_block_hashes(piece, hashes))
+                else {
+                    return failed;
+                };
+
+                let bad_blocks = leaves
+                    .iter()
+                    .zip(block_hashes)
+                    .enumerate()
+                    .filter(|(_, (leaf, expected))| leaf != expected)
+                    .map(|(block, _)| block as u32)
+                    .collect();
+
+                Verdict::Failed {
+                    bad_blocks: Some(bad_blocks),
+                }
+            }
+        }
+    }
+}
+
+fn v1_hashes(torrent: &Torrent) -> Option<Vec<PieceHash>> {
+    let Some(Bencode::String(pieces)) = torrent.info.extra.get(&b"pieces".to_vec()) else {
+        return None;
+    };
+
+    let geometry = Geometry::new(&torrent.info);
+    if pieces.len() != geometry.v1_piece_count() as usize * 20 {
+        return None;
+    }
+
+    Some(
+        pieces
+            .chunks_exact(20)
+            .map(|hash| PieceHash::V1(hash.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+fn v2_hashes(torrent: &Torrent) -> Option<Vec<PieceHash>> {
+    let geometry = Geometry::new(&torrent.info);
+    let blocks_per_piece = (geometry.piece_length() / BLOCK_SIZE) as usize;
+
+    let mut files = Vec::new();
+    torrent.info.file_tree.get_files(&mut files);
+
+    let mut hashes = Vec::new();
+    for (file, entry) in files.iter().zip(geometry.files()) {
+        if entry.piece_count == 0 {
+            continue;
+        }
+        let pieces_root: Hash = file.pieces_root.as_slice().try_into().ok()?;
+        let tree_height = merkle::tree_height(entry.length, geometry.piece_length());
+
+        // The pieces root of a file of one piece covers only its blocks
+        if entry.piece_count == 1 {
+            hashes.push(PieceHash::V2 {
+                root: pieces_root,
+                width: 1 << tree_height,
+                pieces_root,
+                first_block: 0,
+                tree_height,
+            });
+            continue;
+        }
+
+        let layer = torrent.piece_layers.get(&file.pieces_root)?;
+        if layer.len() != entry.piece_count as usize * HASH_SIZE {
+            return None;
+        }
+
+        hashes.extend(
+            layer
+                .chunks_exact(HASH_SIZE)
+                .enumerate()
+                .map(|(index, root)| PieceHash::V2 {
+                    root: root.try_into().unwrap(),
+                    width: blocks_per_piece,
+                    pieces_root,
+                    first_block: (index * blocks_per_piece) as u32,
+                    tree_height,
+                }),
+        );
+    }
+
+    Some(hashes)
+}
+
+/// A piece waiting to be checked, with the leaf hashes known for it.
+struct Job {
+    piece: u32,
+    block_hashes: Option<Vec<Hash>>,
+}
+
+/// Checks completed pieces on worker threads, so hashing does not hold up
+/// the network. Pieces are read back from storage, and the verdicts sent
+/// to `results`. The workers stop once the pipeline is dropped.
+pub struct Pipeline {
+    jobs: Sender<Job>,
+}
+
+impl Pipeline {
+    pub fn new(
+        storage: Arc<Storage>,
+        verifier: Arc<Verifier>,
+        workers: usize,
+        results: Sender<Verification>,
+    ) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let queue: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(queue));
+
+        for _ in 0..workers.max(1) {
+            let (storage, verifier, queue, results) = (
+                storage.clone(),
+                verifier.clone(),
+                queue.clone(),
+                results.clone(),
+            );
+
+            thread::spawn(move || loop {
+                let Ok(job) = queue.lock().unwrap().recv() else {
+                    return;
+                };
+
+                let verdict = storage
+                    .read_piece(job.piece)
+                    .map(|data| verifier.verify(job.piece, &data, job.block_hashes.as_deref()));
+                let verification = Verification {
+                    piece: job.piece,
+                    verdict,
+                };
+                if results.send(verification).is_err() {
+                    return;
+                }
+            });
+        }
+
+        Pipeline { jobs }
+    }
+
+    /// Queues a piece whose blocks were all written.
+    pub fn submit(&self, piece: u32, block_hashes: Option<Vec<Hash>>) {
+        // Workers only stop when the results are not wanted anymore
+        let _ = self.jobs.send(Job {
+            piece,
+            block_hashes,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::{
+        create::{create, CreateOptions},
+        peer::hashes::verify_hashes,
+        storage::{memory::MemoryBackend, Allocation},
+    };
+
+    const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+
+    /// A torrent of a 100 000 byte file of four pieces, and a 5 byte file.
+    fn test_torrent(name: &str) -> (Torrent, Vec<u8>) {
+        let dir = env::temp_dir().join(format!("bittorent-verify-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("data")).unwrap();
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(dir.join("data/a.bin"), &data).unwrap();
+        fs::write(dir.join("data/b.txt"), b"hello").unwrap();
+
+        let options = CreateOptions {
+            piece_length: Some(PIECE_LENGTH),
+            ..Default::default()
+        };
+        let torrent = create(&dir.join("data"), &options).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        (torrent, data)
+    }
+
+    fn storage(torrent: &Torrent, version: Version) -> Arc<Storage> {
+        let storage = Storage::new(&torrent.info, Box::new(MemoryBackend::new()), version);
+        storage.create(Allocation::Sparse).unwrap();
+        Arc::new(storage)
+    }
+
+    /// Writes the files through the v2 layout, where every piece lies in a
+    /// single file.
+    fn write_files(storage: &Storage, files: &[&[u8]]) {
+        for piece in 0..storage.piece_count() {
+            let size = storage.piece_size(piece).unwrap() as usize;
+            let (file, file_piece) = storage.geometry().v2_piece_file(piece).unwrap();
+            let start = file_piece as usize * storage.geometry().piece_length() as usize;
+            storage
+                .write_block(piece, 0, &files[file][start..start + size])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_v2_pinpoints_bad_blocks() {
+        let (torrent, data) = test_torrent("v2");
+        let verifier = Verifier::new(&torrent, Version::V2).unwrap();
+        assert_eq!(verifier.piece_count(), 5);
+
+        let storage = storage(&torrent, Version::V2);
+        write_files(&storage, &[&data, b"hello"]);
+        for piece in 0..5 {
+            let piece_data = storage.read_piece(piece).unwrap();
+            assert_eq!(verifier.verify(piece, &piece_data, None), Verdict::Passed);
+        }
+
+        // The second block of the second piece is corrupted
+        let good = storage.read_piece(1).unwrap();
+        let leaves = merkle::leaf_hashes(&good);
+        assert!(verifier.check_block_hashes(1, &leaves));
+        storage
+            .write_block(1, BLOCK_SIZE, &[0; BLOCK_SIZE as usize])
+            .unwrap();
+        let bad = storage.read_piece(1).unwrap();
+
+        assert_eq!(
+            verifier.verify(1, &bad, None),
+            Verdict::Failed { bad_blocks: None }
+        );
+        assert_eq!(
+            verifier.verify(1, &bad, Some(&leaves)),
+            Verdict::Failed {
+                bad_blocks: Some(vec![1])
+            }
+        );
+
+        // Peers having the file can send the leaf hashes with their proof
+        let request = verifier.block_hashes_request(1).unwrap();
+        assert_eq!((request.index, request.length), (2, 2));
+        let tree_height = merkle::tree_height(data.len() as u64, PIECE_LENGTH);
+        let mut response = leaves.clone();
+        let layer = merkle::piece_layer(&merkle::leaf_hashes(&data), PIECE_LENGTH);
+        response.push(layer[0]);
+        response.push(merkle::hash_pair(&layer[2], &layer[3]));
+        assert_eq!(
+            verify_hashes(&request, tree_height, &response).unwrap(),
+            leaves
+        );
+
+        // Leaf hashes that do not match the piece are not trusted
+        let forged = merkle::leaf_hashes(&bad);
+        assert!(!verifier.check_block_hashes(1, &forged));
+        assert_eq!(
+            verifier.verify(1, &bad, Some(&forged)),
+            Verdict::Failed { bad_blocks: None }
+        );
+    }
+
+    /// Contents of the files of test_hybrid.torrent, in the order of its
+    /// file tree: a.bin, c.txt and sub/b.bin.
+    fn hybrid_files() -> Vec<Vec<u8>> {
+        [40_000u32, 5_000, 70_000]
+            .into_iter()
+            .enumerate()
+            .map(|(k, length)| {
+                (0..length)
+                    .map(|j| ((j * 7 + k as u32 * 13) % 251) as u8)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_v1_pieces() {
+        let (torrent, _) = test_torrent("v1");
+        assert!(Verifier::new(&torrent, Version::V1).is_none());
+
+        // The v1 pieces of a hybrid torrent hash its files with pad files
+        let content = fs::read("test_hybrid.torrent").unwrap();
+        let (metainfo, _) = Bencode::decode_value(content);
+        let torrent = Torrent::parse(&metainfo);
+        let verifier = Verifier::new(&torrent, Version::V1).unwrap();
+        assert_eq!(verifier.piece_count(), 6);
+
+        let backend = Arc::new(MemoryBackend::new());
+        let v2 = Storage::new(&torrent.info, Box::new(backend.clone()), Version::V2);
+        v2.create(Allocation::Sparse).unwrap();
+        let files = hybrid_files();
+        let files: Vec<&[u8]> = files.iter().map(Vec::as_slice).collect();
+        write_files(&v2, &files);
+
+        let v2_verifier = Verifier::new(&torrent, Version::V2).unwrap();
+        for piece in 0..v2.piece_count() {
+            let piece_data = v2.read_piece(piece).unwrap();
+            assert_eq!(
+                v2_verifier.verify(piece, &piece_data, None),
+                Verdict::Passed
+            );
+        }
+
+        let v1 = Storage::new(&torrent.info, Box::new(backend), Version::V1);
+        for piece in 0..6 {
+            let piece_data = v1.read_piece(piece).unwrap();
+            assert_eq!(verifier.verify(piece, &piece_data, None), Verdict::Passed);
+        }
+
+        // The second piece ends a.bin and is padded
+        let piece = v1.read_piece(1).unwrap();
+        assert_eq!(piece[7232..], [0; 32768 - 7232]);
+        assert_eq!(
+            verifier.verify(2, &piece, None),
+            Verdict::Failed { bad_blocks: None }
+        );
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let (torrent, data) = test_torrent("pipeline");
+        let verifier = Arc::new(Verifier::new(&torrent, Version::V2).unwrap());
+        let storage = storage(&torrent, Version::V2);
+        write_files(&storage, &[&data, b"hello"]);
+        storage.write_block(4, 0, b"jello").unwrap();
+
+        let (results, verifications) = mpsc::channel();
+        let pipeline = Pipeline::new(storage.clone(), verifier, 2, results);
+        for piece in 0..5 {
+            pipeline.submit(piece, None);
+        }
+        pipeline.submit(9, None);
+        drop(pipeline);
+
+        let mut verifications: Vec<_> = verifications.iter().collect();
+        verifications.sort_by_key(|verification| verification.piece);
+        assert_eq!(verifications.len(), 6);
+        for verification in &verifications[..4] {
+            assert_eq!(verification.verdict.as_ref().unwrap(), &Verdict::Passed);
+        }
+        assert_eq!(
+            verifications[4].verdict.as_ref().unwrap(),
+            &Verdict::Failed { bad_blocks: None }
+        );
+        assert!(matches!(
+            verifications[5].verdict,
+            Err(StorageError::InvalidPiece(9))
+        ));
+    }
+}
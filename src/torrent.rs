@@ -1,30 +1,78 @@
-use core::panic;
 use indexmap::IndexMap;
+use sha2::Digest;
 use std::collections::HashMap;
 
-use crate::bencode_decoder::Bencode;
+use crate::{bencode_decoder::Bencode, validate::Problem};
 
 pub struct Torrent {
     pub announce: String,
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub url_list: Vec<String>,
     pub info: Info,
     pub piece_layers: HashMap<Vec<u8>, Vec<u8>>,
+    /// Keys of the metainfo dictionary that are not interpreted, kept so the
+    /// torrent can be written back unchanged.
+    pub extra: IndexMap<Vec<u8>, Bencode>,
+    pub original: Option<Original>,
 }
 
+#[derive(Default)]
 pub struct Info {
     pub name: String,
     pub piece_length: u32,
     pub meta_version: u8,
     pub file_tree: FileTree,
+    pub private: Option<bool>,
+    pub source: Option<String>,
+    /// Keys of the info dictionary that are not interpreted. They are part of
+    /// the infohash, so they must be encoded back as they were.
+    pub extra: IndexMap<Vec<u8>, Bencode>,
+    pub original: Option<Original>,
 }
 
-impl Default for Info {
-    fn default() -> Self {
-        Info {
-            name: String::new(),
-            piece_length: 0,
-            meta_version: 0,
-            file_tree: FileTree::default(),
+/// A dictionary as it was read, next to the encoding of its parsed fields at
+/// that time. Parsing loses details, such as invalid UTF-8 or the form of
+/// `url-list`, so values that were not changed since are written back as
+/// read, keeping the infohash.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Original {
+    read: IndexMap<Vec<u8>, Bencode>,
+    parsed: IndexMap<Vec<u8>, Bencode>,
+}
+
+impl Original {
+    fn restore(&self, mut dict: IndexMap<Vec<u8>, Bencode>) -> Bencode {
+        if dict == self.parsed {
+            return Bencode::Dictionary(self.read.clone());
+        }
+
+        // Unchanged values are restored, or left out when parsing made them up,
+        // such as a default meta version
+        dict.retain(|key, value| {
+            if self.parsed.get(key) != Some(value) {
+                return true;
+            }
+
+            match self.read.get(key) {
+                Some(read) => {
+                    *value = read.clone();
+                    true
+                }
+                None => false,
+            }
+        });
+
+        // Keys that parsing dropped, such as a comment that is not a string
+        for (key, value) in &self.read {
+            if !self.parsed.contains_key(key) && !dict.contains_key(key) {
+                dict.insert(key.clone(), value.clone());
+            }
         }
+        dict.sort_keys();
+
+        Bencode::Dictionary(dict)
     }
 }
 
@@ -42,131 +90,348 @@ impl Default for FileTree {
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct File {
-    pub length: u32,
+    pub length: u64,
     pub pieces_root: Vec<u8>,
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum ParseError {
+    MissingInfo,
+    /// A field is missing or has the wrong type, named by its key.
+    InvalidField(String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::MissingInfo => f.write_str("missing info dictionary"),
+            ParseError::InvalidField(field) => write!(f, "invalid {field} field"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn invalid(field: &str) -> ParseError {
+    ParseError::InvalidField(field.to_string())
+}
+
 impl Torrent {
     pub fn parse(metainfo: &Bencode) -> Self {
-        if let Bencode::Dictionary(metainfo_dict) = metainfo {
-            let mut announce = String::new();
-            let mut info = Info::default();
-            let mut piece_layers = HashMap::new();
-
-            for (k, mut v) in metainfo_dict.iter() {
-                let key = String::from_utf8(k.clone()).unwrap();
-
-                match key.as_str() {
-                    "announce" => {
-                        announce = match v {
-                            Bencode::String(announce) => {
-                                String::from_utf8_lossy(announce).to_string()
-                            }
-                            _ => panic!("Invalid or missing announce field"),
-                        }
+        Self::try_parse(metainfo)
+            .unwrap_or_else(|error| panic!("Error parsing torrent file: {error}"))
+    }
+
+    /// Same as `parse`, but returns an error instead of panicking, for files
+    /// that may be malformed.
+    pub fn try_parse(metainfo: &Bencode) -> Result<Self, ParseError> {
+        let Bencode::Dictionary(metainfo_dict) = metainfo else {
+            return Err(invalid("metainfo"));
+        };
+
+        let mut announce = String::new();
+        let mut announce_list = Vec::new();
+        let mut comment = None;
+        let mut created_by = None;
+        let mut url_list = Vec::new();
+        let mut info = None;
+        let mut piece_layers = HashMap::new();
+        let mut extra = IndexMap::new();
+
+        for (k, v) in metainfo_dict.iter() {
+            let key = String::from_utf8_lossy(k);
+
+            match key.as_ref() {
+                "announce" => {
+                    announce = match v {
+                        Bencode::String(announce) => String::from_utf8_lossy(announce).to_string(),
+                        _ => return Err(invalid("announce")),
                     }
-                    "info" => info = Info::parse(&mut v),
-                    "piece layers" => {
-                        piece_layers = match v {
-                            Bencode::Dictionary(piece_layers_dict) => piece_layers_dict
-                                .iter()
-                                .map(|(k, v)| {
-                                    (
-                                        k.clone(),
-                                        match v {
-                                            Bencode::String(v) => v.clone(),
-                                            _ => panic!("Invalid piece layer value"),
-                                        },
-                                    )
-                                })
-                                .collect(),
-                            _ => HashMap::new(),
-                        }
+                }
+                "announce-list" => {
+                    announce_list = match v {
+                        Bencode::List(tiers) => tiers
+                            .iter()
+                            .map(|tier| match tier {
+                                Bencode::List(urls) => urls
+                                    .iter()
+                                    .map(|url| match url {
+                                        Bencode::String(url) => {
+                                            Ok(String::from_utf8_lossy(url).to_string())
+                                        }
+                                        _ => Err(invalid("announce-list")),
+                                    })
+                                    .collect(),
+                                _ => Err(invalid("announce-list")),
+                            })
+                            .collect::<Result<_, _>>()?,
+                        _ => return Err(invalid("announce-list")),
                     }
-                    _ => println!("Invalid key in metainfo: {:?}", key),
+                }
+                "comment" => {
+                    if let Bencode::String(comment_bytes) = v {
+                        comment = Some(String::from_utf8_lossy(comment_bytes).to_string());
+                    }
+                }
+                "created by" => {
+                    if let Bencode::String(created_by_bytes) = v {
+                        created_by = Some(String::from_utf8_lossy(created_by_bytes).to_string());
+                    }
+                }
+                "url-list" => {
+                    url_list = match v {
+                        Bencode::String(url) if url.is_empty() => Vec::new(),
+                        Bencode::String(url) => vec![String::from_utf8_lossy(url).to_string()],
+                        Bencode::List(urls) => urls
+                            .iter()
+                            .filter_map(|url| match url {
+                                Bencode::String(url) => {
+                                    Some(String::from_utf8_lossy(url).to_string())
+                                }
+                                _ => None,
+                            })
+                            .collect(),
+                        _ => return Err(invalid("url-list")),
+                    }
+                }
+                "info" => info = Some(Info::try_parse(v)?),
+                "piece layers" => {
+                    piece_layers = match v {
+                        Bencode::Dictionary(piece_layers_dict) => piece_layers_dict
+                            .iter()
+                            .map(|(k, v)| match v {
+                                Bencode::String(v) => Ok((k.clone(), v.clone())),
+                                _ => Err(invalid("piece layers")),
+                            })
+                            .collect::<Result<_, _>>()?,
+                        _ => HashMap::new(),
+                    }
+                }
+                _ => {
+                    extra.insert(k.clone(), v.clone());
                 }
             }
+        }
 
-            return Torrent {
-                announce,
-                info,
-                piece_layers,
-            };
+        let mut torrent = Torrent {
+            announce,
+            announce_list,
+            comment,
+            created_by,
+            url_list,
+            info: info.ok_or(ParseError::MissingInfo)?,
+            piece_layers,
+            extra,
+            original: None,
+        };
+
+        if let Bencode::Dictionary(parsed) = torrent.to_bencode() {
+            torrent.original = Some(Original {
+                read: metainfo_dict.clone(),
+                parsed,
+            });
+        }
+
+        Ok(torrent)
+    }
+
+    /// Torrent without trackers or piece layers, as obtained from the info
+    /// dictionary alone.
+    pub fn from_info(info: Info) -> Self {
+        Torrent {
+            announce: String::new(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            url_list: Vec::new(),
+            info,
+            piece_layers: HashMap::new(),
+            extra: IndexMap::new(),
+            original: None,
+        }
+    }
+
+    /// Trackers grouped in tiers as described by BEP 12, falling back to the
+    /// single `announce` URL when there is no `announce-list`.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        if !self.announce_list.is_empty() {
+            self.announce_list.clone()
+        } else if !self.announce.is_empty() {
+            vec![vec![self.announce.clone()]]
         } else {
-            panic!("Error parsing torrent file");
+            Vec::new()
         }
     }
 
-    fn verify_infohash(&self, infohash: String) -> bool {
-        if infohash != self.info.get_infohash() {
-            println!("Infohash is invalid");
-            return false;
+    pub fn to_bencode(&self) -> Bencode {
+        let mut metainfo = self.extra.clone();
+
+        if !self.announce.is_empty() {
+            metainfo.insert(
+                b"announce".to_vec(),
+                Bencode::String(self.announce.as_bytes().to_vec()),
+            );
         }
 
-        let mut files = Vec::new();
-        self.info.file_tree.get_files(&mut files);
+        if !self.announce_list.is_empty() {
+            let tiers = self
+                .announce_list
+                .iter()
+                .map(|tier| {
+                    Bencode::List(
+                        tier.iter()
+                            .map(|url| Bencode::String(url.as_bytes().to_vec()))
+                            .collect(),
+                    )
+                })
+                .collect();
+            metainfo.insert(b"announce-list".to_vec(), Bencode::List(tiers));
+        }
+
+        if let Some(comment) = &self.comment {
+            metainfo.insert(
+                b"comment".to_vec(),
+                Bencode::String(comment.as_bytes().to_vec()),
+            );
+        }
 
-        let piece_roots: Vec<Vec<u8>> = files.iter().map(|file| file.pieces_root.clone()).collect();
+        if let Some(created_by) = &self.created_by {
+            metainfo.insert(
+                b"created by".to_vec(),
+                Bencode::String(created_by.as_bytes().to_vec()),
+            );
+        }
 
-        for key in self.piece_layers.keys() {
-            if !piece_roots.contains(key) {
-                println!("Piece root not found: {:?}", key);
-                return false;
-            }
+        if !self.url_list.is_empty() {
+            let urls = self
+                .url_list
+                .iter()
+                .map(|url| Bencode::String(url.as_bytes().to_vec()))
+                .collect();
+            metainfo.insert(b"url-list".to_vec(), Bencode::List(urls));
+        }
+
+        metainfo.insert(b"info".to_vec(), self.info.to_bencode());
+
+        if !self.piece_layers.is_empty() {
+            let mut piece_layers: IndexMap<Vec<u8>, Bencode> = self
+                .piece_layers
+                .iter()
+                .map(|(root, layer)| (root.clone(), Bencode::String(layer.clone())))
+                .collect();
+            piece_layers.sort_keys();
+            metainfo.insert(b"piece layers".to_vec(), Bencode::Dictionary(piece_layers));
+        }
+
+        metainfo.sort_keys();
+
+        match &self.original {
+            Some(original) => original.restore(metainfo),
+            None => Bencode::Dictionary(metainfo),
         }
+    }
 
-        return true;
+    pub fn verify_infohash(&self, infohash: String) -> bool {
+        infohash == self.info.get_infohash()
+            && !self
+                .validate()
+                .problems
+                .iter()
+                .any(|problem| matches!(problem, Problem::UnknownPieceLayer(_)))
     }
 }
 
 impl Info {
-    fn parse(info: &Bencode) -> Self {
-        if let Bencode::Dictionary(info_dict) = info {
-            let mut name = String::new();
-            let mut piece_length = 0;
-            let mut meta_version = 0;
-            let mut file_tree = FileTree::default();
-
-            for (key, value) in info_dict.iter() {
-                match String::from_utf8_lossy(&key).as_ref() {
-                    "name" => {
-                        if let Bencode::String(name_bytes) = value {
-                            name = String::from_utf8_lossy(&name_bytes).to_string();
+    pub fn parse(info: &Bencode) -> Self {
+        Self::try_parse(info).unwrap_or_else(|error| panic!("Error parsing torrent file: {error}"))
+    }
+
+    pub fn try_parse(info: &Bencode) -> Result<Self, ParseError> {
+        let Bencode::Dictionary(info_dict) = info else {
+            return Err(invalid("info"));
+        };
+
+        let mut name = String::new();
+        let mut piece_length = 0;
+        let mut meta_version = 0;
+        let mut file_tree = FileTree::default();
+        let mut private = None;
+        let mut source = None;
+        let mut extra = IndexMap::new();
+
+        for (key, value) in info_dict.iter() {
+            match String::from_utf8_lossy(key).as_ref() {
+                "name" => {
+                    name = match value {
+                        Bencode::String(name_bytes) => {
+                            String::from_utf8_lossy(name_bytes).to_string()
                         }
+                        _ => return Err(invalid("name")),
                     }
-                    "piece length" => {
-                        if let Bencode::Integer(piece_length_int) = value {
-                            piece_length = *piece_length_int as u32;
+                }
+                "piece length" => {
+                    piece_length = match value {
+                        Bencode::Integer(piece_length_int) => {
+                            u32::try_from(*piece_length_int).map_err(|_| invalid("piece length"))?
                         }
+                        _ => return Err(invalid("piece length")),
                     }
-                    "meta version" => {
-                        if let Bencode::Integer(meta_version_int) = value {
-                            meta_version = *meta_version_int as u8;
+                }
+                "meta version" => {
+                    meta_version = match value {
+                        Bencode::Integer(meta_version_int) => {
+                            u8::try_from(*meta_version_int).map_err(|_| invalid("meta version"))?
                         }
+                        _ => return Err(invalid("meta version")),
                     }
-                    "file tree" => {
-                        file_tree = FileTree::Directory("".to_string(), FileTree::parse(value));
+                }
+                "file tree" => {
+                    file_tree = FileTree::Directory("".to_string(), FileTree::try_parse(value)?);
+                }
+                "private" => {
+                    if let Bencode::Integer(private_int) = value {
+                        private = Some(*private_int == 1);
                     }
-                    _ => println!(
-                        "Invalid key in info dictionary: {:?}",
-                        String::from_utf8_lossy(&key)
-                    ),
+                }
+                "source" => {
+                    if let Bencode::String(source_bytes) = value {
+                        source = Some(String::from_utf8_lossy(source_bytes).to_string());
+                    }
+                }
+                _ => {
+                    extra.insert(key.clone(), value.clone());
                 }
             }
+        }
 
-            Info {
-                name,
-                piece_length,
-                meta_version,
-                file_tree,
-            }
-        } else {
-            panic!("Error parsing torrent file");
+        // Pieces of no length cannot be mapped to files
+        if piece_length == 0 {
+            return Err(invalid("piece length"));
+        }
+
+        let mut info = Info {
+            name,
+            piece_length,
+            meta_version,
+            file_tree,
+            private,
+            source,
+            extra,
+            original: None,
+        };
+
+        if let Bencode::Dictionary(parsed) = info.to_bencode() {
+            info.original = Some(Original {
+                read: info_dict.clone(),
+                parsed,
+            });
         }
+
+        Ok(info)
     }
 
-    fn to_bencode(&self) -> Bencode {
-        let mut info = IndexMap::new();
+    pub fn to_bencode(&self) -> Bencode {
+        let mut info = self.extra.clone();
 
         let file_tree = self.file_tree.to_bencode();
         info.insert(b"file tree".to_vec(), file_tree);
@@ -180,7 +445,23 @@ impl Info {
         let piece_length = Bencode::Integer(self.piece_length.into());
         info.insert(b"piece length".to_vec(), piece_length);
 
-        Bencode::Dictionary(info)
+        if let Some(private) = self.private {
+            info.insert(b"private".to_vec(), Bencode::Integer(private.into()));
+        }
+
+        if let Some(source) = &self.source {
+            info.insert(
+                b"source".to_vec(),
+                Bencode::String(source.as_bytes().to_vec()),
+            );
+        }
+
+        info.sort_keys();
+
+        match &self.original {
+            Some(original) => original.restore(info),
+            None => Bencode::Dictionary(info),
+        }
     }
 
     pub fn get_infohash(&self) -> String {
@@ -188,49 +469,106 @@ impl Info {
 
         sha256::digest(&info_bytes)
     }
+
+    pub fn get_infohash_bytes(&self) -> [u8; 32] {
+        let info_bytes = self.to_bencode().encode_value();
+
+        sha2::Sha256::digest(&info_bytes).into()
+    }
+
+    /// SHA-1 infohash as raw bytes, see `get_infohash_v1`.
+    pub fn get_infohash_v1_bytes(&self) -> Option<[u8; 20]> {
+        if !self.extra.contains_key(&b"pieces".to_vec()) {
+            return None;
+        }
+
+        let info_bytes = self.to_bencode().encode_value();
+
+        Some(sha1_smol::Sha1::from(&info_bytes).digest().bytes())
+    }
+
+    /// SHA-1 infohash, only defined for hybrid torrents that also carry the
+    /// v1 `pieces` field.
+    pub fn get_infohash_v1(&self) -> Option<String> {
+        if !self.extra.contains_key(&b"pieces".to_vec()) {
+            return None;
+        }
+
+        let info_bytes = self.to_bencode().encode_value();
+
+        Some(sha1_smol::Sha1::from(&info_bytes).digest().to_string())
+    }
+}
+
+#[cfg(test)]
+impl Info {
+    /// Version 2 info of a folder holding `files`, for tests of the modules
+    /// built on it.
+    pub(crate) fn with_files(name: &str, piece_length: u32, files: Vec<FileTree>) -> Self {
+        Info {
+            name: name.to_string(),
+            piece_length,
+            meta_version: 2,
+            file_tree: FileTree::Directory(String::new(), files),
+            ..Default::default()
+        }
+    }
 }
 
 impl FileTree {
-    fn parse(file_tree: &Bencode) -> Vec<FileTree> {
-        if let Bencode::Dictionary(file_tree_dict) = file_tree {
-            let mut content = Vec::new();
-
-            for (k, v) in file_tree_dict.iter() {
-                let name = String::from_utf8(k.clone()).unwrap();
-
-                match v {
-                    Bencode::Dictionary(child) => {
-                        if let Some(Bencode::Dictionary(file_dict)) = child.get(&b"".to_vec()) {
-                            let length = match file_dict.get(&b"length".to_vec()) {
-                                Some(Bencode::Integer(length_int)) => *length_int as u32,
-                                _ => panic!("Invalid or missing length field"),
-                            };
-
-                            let pieces_root = match file_dict.get(&b"pieces root".to_vec()) {
-                                Some(Bencode::String(pieces_root_bytes)) => {
-                                    pieces_root_bytes.clone()
-                                }
-                                _ => panic!("Invalid or missing pieces root field"),
-                            };
+    /// File without a pieces root, for tests.
+    #[cfg(test)]
+    pub(crate) fn file(name: &str, length: u64) -> Self {
+        FileTree::File(
+            name.to_string(),
+            File {
+                length,
+                pieces_root: Vec::new(),
+            },
+        )
+    }
 
-                            let file = File {
-                                length,
-                                pieces_root,
-                            };
+    fn try_parse(file_tree: &Bencode) -> Result<Vec<FileTree>, ParseError> {
+        let Bencode::Dictionary(file_tree_dict) = file_tree else {
+            return Err(invalid("file tree"));
+        };
 
-                            content.push(FileTree::File(name, file));
-                        } else {
-                            content.push(FileTree::Directory(name, FileTree::parse(&v)));
-                        }
+        let mut content = Vec::new();
+
+        for (k, v) in file_tree_dict.iter() {
+            let name = String::from_utf8(k.clone()).map_err(|_| invalid("file tree"))?;
+
+            let Bencode::Dictionary(child) = v else {
+                return Err(invalid("file tree"));
+            };
+
+            if let Some(Bencode::Dictionary(file_dict)) = child.get(&b"".to_vec()) {
+                let length = match file_dict.get(&b"length".to_vec()) {
+                    Some(Bencode::Integer(length_int)) => {
+                        u64::try_from(*length_int).map_err(|_| invalid("length"))?
                     }
-                    _ => panic!("Expected dictionary"),
-                }
+                    _ => return Err(invalid("length")),
+                };
+
+                let pieces_root = match file_dict.get(&b"pieces root".to_vec()) {
+                    Some(Bencode::String(pieces_root_bytes)) => pieces_root_bytes.clone(),
+                    // Empty files have no pieces root
+                    None if length == 0 => Vec::new(),
+                    _ => return Err(invalid("pieces root")),
+                };
+
+                let file = File {
+                    length,
+                    pieces_root,
+                };
+
+                content.push(FileTree::File(name, file));
+            } else {
+                content.push(FileTree::Directory(name, FileTree::try_parse(v)?));
             }
-
-            return content;
-        } else {
-            panic!("Expected dictionary when parsing file tree")
         }
+
+        Ok(content)
     }
 
     fn to_bencode(&self) -> Bencode {
@@ -242,12 +580,14 @@ impl FileTree {
                     FileTree::File(name, file) => {
                         let mut inner_description = IndexMap::new();
                         inner_description
-                            .insert(b"length".to_vec(), Bencode::Integer(file.length.into()));
+                            .insert(b"length".to_vec(), Bencode::Integer(file.length as i64));
 
-                        inner_description.insert(
-                            b"pieces root".to_vec(),
-                            Bencode::String(file.pieces_root.clone()),
-                        );
+                        if file.length > 0 {
+                            inner_description.insert(
+                                b"pieces root".to_vec(),
+                                Bencode::String(file.pieces_root.clone()),
+                            );
+                        }
 
                         let inner_description_bencode = Bencode::Dictionary(inner_description);
 
@@ -270,7 +610,7 @@ impl FileTree {
         }
     }
 
-    fn get_files(&self, files: &mut Vec<File>) {
+    pub fn get_files(&self, files: &mut Vec<File>) {
         match self {
             FileTree::Directory(_, contents) => {
                 contents.iter().for_each(|content| content.get_files(files))
@@ -278,6 +618,25 @@ impl FileTree {
             FileTree::File(_, file) => files.push(file.clone()),
         };
     }
+
+    pub fn get_files_with_paths(&self, path: &[String], files: &mut Vec<(Vec<String>, File)>) {
+        match self {
+            FileTree::Directory(name, contents) => {
+                let mut new_path = path.to_vec();
+                if !name.is_empty() {
+                    new_path.push(name.clone());
+                }
+                contents
+                    .iter()
+                    .for_each(|content| content.get_files_with_paths(&new_path, files))
+            }
+            FileTree::File(name, file) => {
+                let mut file_path = path.to_vec();
+                file_path.push(name.clone());
+                files.push((file_path, file.clone()))
+            }
+        };
+    }
 }
 
 impl std::fmt::Display for FileTree {
@@ -425,15 +784,11 @@ mod test {
             },
         );
 
-        let mut images_content = Vec::new();
-        images_content.push(loc_main);
-        images_content.push(melk_abbey_library);
+        let images_content = vec![loc_main, melk_abbey_library];
 
         let images = FileTree::Directory("images".to_string(), images_content);
 
-        let mut file_tree_content = Vec::new();
-        file_tree_content.push(readme);
-        file_tree_content.push(images);
+        let file_tree_content = vec![readme, images];
 
         let file_tree = FileTree::Directory("".to_string(), file_tree_content);
 
@@ -461,7 +816,42 @@ mod test {
         let (metainfo, _) = Bencode::decode_value(content);
         let torrent = Torrent::parse(&metainfo);
 
-        assert_eq!(String::from_utf8_lossy(&torrent.info.to_bencode().encode_value()).into_owned(), String::from_utf8_lossy(&hex::decode("64393a66696c65207472656564363a524541444d4564303a64363a6c656e6774686932306531313a70696563657320726f6f7433323ac87e2ca771bab6024c269b933389d2a92d4941c848c52f155b9b84e1f109fe356565363a696d616765736433353a4c4f435f4d61696e5f52656164696e675f526f6f6d5f48696768736d6974682e6a706764303a64363a6c656e6774686931373631343532376531313a70696563657320726f6f7433323a90a24c4b7a34568fc4a2a62a0079204e9766e19f9a0069546189f120017656f9656532323a6d656c6b2d61626265792d6c6962726172792e6a706764303a64363a6c656e67746869313638323137376531313a70696563657320726f6f7433323a9e2f0845f16dcb0844fa09370622fd211027c9300838b021502fd7a63a452ffe6565656531323a6d6574612076657273696f6e693265343a6e616d6531313a746573745f666f6c64657231323a7069656365206c656e6774686936353533366565").unwrap()).to_owned());
+        assert_eq!(String::from_utf8_lossy(&torrent.info.to_bencode().encode_value()).into_owned(), String::from_utf8_lossy(&hex::decode("64393a66696c65207472656564363a524541444d4564303a64363a6c656e6774686932306531313a70696563657320726f6f7433323ac87e2ca771bab6024c269b933389d2a92d4941c848c52f155b9b84e1f109fe356565363a696d616765736433353a4c4f435f4d61696e5f52656164696e675f526f6f6d5f48696768736d6974682e6a706764303a64363a6c656e6774686931373631343532376531313a70696563657320726f6f7433323a90a24c4b7a34568fc4a2a62a0079204e9766e19f9a0069546189f120017656f9656532323a6d656c6b2d61626265792d6c6962726172792e6a706764303a64363a6c656e67746869313638323137376531313a70696563657320726f6f7433323a9e2f0845f16dcb0844fa09370622fd211027c9300838b021502fd7a63a452ffe6565656531323a6d6574612076657273696f6e693265343a6e616d6531313a746573745f666f6c64657231323a7069656365206c656e6774686936353533366565").unwrap()).into_owned());
+    }
+
+    #[test]
+    fn test_torrent_to_bencode() {
+        let mut file = fs::File::open("test_folder.torrent").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+
+        let (metainfo, _) = Bencode::decode_value(content.clone());
+        let torrent = Torrent::parse(&metainfo);
+
+        assert_eq!(torrent.to_bencode().encode_value(), content);
+    }
+
+    #[test]
+    fn test_infohash_v1() {
+        let mut file = fs::File::open("test_folder.torrent").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+
+        let (metainfo, _) = Bencode::decode_value(content);
+        let mut torrent = Torrent::parse(&metainfo);
+
+        assert_eq!(torrent.info.get_infohash_v1(), None);
+
+        torrent
+            .info
+            .extra
+            .insert(b"pieces".to_vec(), Bencode::String(vec![0; 20]));
+        let info_bytes = torrent.info.to_bencode().encode_value();
+
+        assert_eq!(
+            torrent.info.get_infohash_v1(),
+            Some(sha1_smol::Sha1::from(&info_bytes).digest().to_string())
+        );
     }
 
     #[test]
@@ -518,4 +908,24 @@ mod test {
             "22fd2f407dd4187ca9b77b7937587f53346f0aebe326a2a8ac583e3b8cfc8bdd".to_string()
         ))
     }
+    #[test]
+    fn test_invalid_piece_length() {
+        for piece_length in [&b"i0e"[..], b"i-16384e", b"i4294967296e", b"1:a"] {
+            let info = [
+                &b"d9:file treede4:name1:a12:piece length"[..],
+                piece_length,
+                b"e",
+            ]
+            .concat();
+            let (info, _) = Bencode::decode_value(info);
+
+            assert_eq!(
+                Info::try_parse(&info).err(),
+                Some(ParseError::InvalidField("piece length".to_string()))
+            );
+        }
+
+        let (info, _) = Bencode::decode_value(b"d9:file treede4:name1:ae".to_vec());
+        assert!(Info::try_parse(&info).is_err());
+    }
 }
@@ -0,0 +1,461 @@
+use std::{io::Read, time::Duration};
+
+use indexmap::IndexMap;
+
+use crate::{bencode_decoder::Bencode, peers};
+
+use super::{AnnounceRequest, AnnounceResponse, ScrapeStats, TrackerError};
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RESPONSE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Percent-encodes every byte outside the RFC 3986 unreserved set, as needed
+/// for the binary `info_hash` and `peer_id` parameters.
+pub fn url_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Reverses [`url_encode`], or any other percent-encoding. Returns `None` for
+/// truncated or invalid escapes.
+pub fn url_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bytes = encoded.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Appends `params` to `url`, keeping any query the URL already has.
+pub(crate) fn with_query(url: &str, params: &[(&str, String)]) -> String {
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let separator = if url.contains('?') { '&' } else { '?' };
+
+    format!("{url}{separator}{query}")
+}
+
+pub fn announce_url(announce: &str, request: &AnnounceRequest) -> String {
+    let mut params = vec![
+        ("info_hash", url_encode(&request.info_hash)),
+        ("peer_id", url_encode(&request.peer_id)),
+        ("port", request.port.to_string()),
+        ("uploaded", request.uploaded.to_string()),
+        ("downloaded", request.downloaded.to_string()),
+        ("left", request.left.to_string()),
+        (
+            "compact",
+            if request.compact { "1" } else { "0" }.to_string(),
+        ),
+    ];
+
+    if let Some(event) = request.event.as_str() {
+        params.push(("event", event.to_string()));
+    }
+
+    if let Some(numwant) = request.numwant {
+        params.push(("numwant", numwant.to_string()));
+    }
+
+    if let Some(key) = request.key {
+        params.push(("key", format!("{key:08X}")));
+    }
+
+    if let Some(tracker_id) = &request.tracker_id {
+        params.push(("trackerid", url_encode(tracker_id.as_bytes())));
+    }
+
+    with_query(announce, &params)
+}
+
+pub fn announce(
+    announce: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    let body = get(&announce_url(announce, request))?;
+
+    parse_announce_response(body)
+}
+
+pub(crate) fn get(url: &str) -> Result<Vec<u8>, TrackerError> {
+    let response = ureq::get(url)
+        .timeout(TIMEOUT)
+        .call()
+        .map_err(|error| TrackerError::Http(error.to_string()))?;
+
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_RESPONSE_SIZE)
+        .read_to_end(&mut body)?;
+
+    Ok(body)
+}
+
+/// Decodes a tracker response dictionary, turning a `failure reason` into an
+/// error.
+pub(crate) fn decode_response(body: Vec<u8>) -> Result<IndexMap<Vec<u8>, Bencode>, TrackerError> {
+    let (response, _) = Bencode::try_decode_value(body)?;
+
+    let Bencode::Dictionary(response) = response else {
+        return Err(TrackerError::InvalidResponse(
+            "response is not a dictionary".to_string(),
+        ));
+    };
+
+    if let Some(Bencode::String(reason)) = response.get(&b"failure reason".to_vec()) {
+        return Err(TrackerError::Failure(
+            String::from_utf8_lossy(reason).to_string(),
+        ));
+    }
+
+    Ok(response)
+}
+
+pub(crate) fn get_integer(dict: &IndexMap<Vec<u8>, Bencode>, key: &[u8]) -> Option<u32> {
+    match dict.get(key) {
+        Some(Bencode::Integer(value)) => u32::try_from(*value).ok(),
+        _ => None,
+    }
+}
+
+fn get_string(dict: &IndexMap<Vec<u8>, Bencode>, key: &[u8]) -> Option<String> {
+    match dict.get(key) {
+        Some(Bencode::String(value)) => Some(String::from_utf8_lossy(value).to_string()),
+        _ => None,
+    }
+}
+
+pub fn parse_announce_response(body: Vec<u8>) -> Result<AnnounceResponse, TrackerError> {
+    let response = decode_response(body)?;
+
+    let interval = get_integer(&response, b"interval")
+        .ok_or_else(|| TrackerError::InvalidResponse("missing interval".to_string()))?;
+
+    let mut peers = match response.get(b"peers".as_slice()) {
+        Some(value) => peers::decode_peers(value)?,
+        None => Vec::new(),
+    };
+
+    if let Some(Bencode::String(compact)) = response.get(b"peers6".as_slice()) {
+        peers.extend(peers::decode_compact_v6(compact)?);
+    }
+
+    Ok(AnnounceResponse {
+        interval,
+        min_interval: get_integer(&response, b"min interval"),
+        tracker_id: get_string(&response, b"tracker id"),
+        warning: get_string(&response, b"warning message"),
+        complete: get_integer(&response, b"complete"),
+        incomplete: get_integer(&response, b"incomplete"),
+        peers,
+    })
+}
+
+/// Derives the scrape URL of a tracker by replacing `announce` at the start
+/// of the last path component with `scrape`. Trackers whose announce URL does
+/// not follow this convention do not support scraping.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+
+    let slash = path.rfind('/')?;
+    let rest = path[slash + 1..].strip_prefix("announce")?;
+
+    let mut url = format!("{}/scrape{rest}", &path[..slash]);
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+
+    Some(url)
+}
+
+/// Scrapes several torrents at once. Stats are returned in the order of
+/// `info_hashes`, with zeros for torrents the tracker does not know about.
+pub fn scrape(announce: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+    let url = scrape_url(announce).ok_or_else(|| {
+        TrackerError::InvalidResponse(format!("{announce} does not support scraping"))
+    })?;
+
+    let params: Vec<(&str, String)> = info_hashes
+        .iter()
+        .map(|info_hash| ("info_hash", url_encode(info_hash)))
+        .collect();
+
+    let body = get(&with_query(&url, &params))?;
+
+    parse_scrape_response(body, info_hashes)
+}
+
+pub fn parse_scrape_response(
+    body: Vec<u8>,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeStats>, TrackerError> {
+    let response = decode_response(body)?;
+
+    let Some(Bencode::Dictionary(files)) = response.get(b"files".as_slice()) else {
+        return Err(TrackerError::InvalidResponse(
+            "missing files dictionary".to_string(),
+        ));
+    };
+
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| match files.get(info_hash.as_slice()) {
+            Some(Bencode::Dictionary(stats)) => ScrapeStats {
+                complete: get_integer(stats, b"complete").unwrap_or_default(),
+                downloaded: get_integer(stats, b"downloaded").unwrap_or_default(),
+                incomplete: get_integer(stats, b"incomplete").unwrap_or_default(),
+            },
+            _ => ScrapeStats::default(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpListener, sync::mpsc, thread};
+
+    use super::*;
+    use crate::tracker::Event;
+
+    /// Serves a single HTTP request with `body`, sending the request line back
+    /// through the returned channel.
+    fn mock_tracker(body: Vec<u8>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            let request = String::from_utf8_lossy(&request).to_string();
+            sender
+                .send(request.lines().next().unwrap_or_default().to_string())
+                .unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        });
+
+        (format!("http://{address}/announce"), receiver)
+    }
+
+    #[test]
+    fn test_url_encode() {
+        assert_eq!(url_encode(b"ab-_.~"), "ab-_.~");
+        assert_eq!(url_encode(&[0x12, 0x34, b' ', 0xff]), "%124%20%FF");
+    }
+
+    #[test]
+    fn test_announce_url() {
+        let mut request = AnnounceRequest::new([0xab; 20], *b"-BT0100-abcdefghijkl", 6881);
+        request.left = 100;
+        request.event = Event::Started;
+        request.numwant = Some(50);
+        request.key = Some(0xdeadbeef);
+        request.tracker_id = Some("id 1".to_string());
+
+        assert_eq!(
+            announce_url("http://example.com/announce?passkey=x", &request),
+            format!(
+                "http://example.com/announce?passkey=x&info_hash={}&peer_id=-BT0100-abcdefghijkl&port=6881&uploaded=0&downloaded=0&left=100&compact=1&event=started&numwant=50&key=DEADBEEF&trackerid=id%201",
+                "%AB".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_compact_response() {
+        let response = parse_announce_response(
+            b"d8:completei5e10:incompletei2e8:intervali1800e12:min intervali60e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50e"
+                .to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.complete, Some(5));
+        assert_eq!(response.incomplete, Some(2));
+        assert_eq!(
+            response.peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dictionary_response() {
+        let response = parse_announce_response(
+            b"d8:intervali900e5:peersld2:ip3:::17:peer id20:-BT0100-abcdefghijkl4:porti51413eee15:warning message4:slowe"
+                .to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(response.peers, vec!["[::1]:51413".parse().unwrap()]);
+        assert_eq!(response.warning, Some("slow".to_string()));
+    }
+
+    #[test]
+    fn test_parse_ipv6_response() {
+        let response = parse_announce_response(
+            b"d8:intervali900e5:peers0:6:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\xc8\xd5e"
+                .to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(response.peers, vec!["[2001:db8::1]:51413".parse().unwrap()]);
+        assert!(matches!(
+            parse_announce_response(b"d8:intervali900e6:peers65:abcdee".to_vec()),
+            Err(TrackerError::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_failure_response() {
+        match parse_announce_response(b"d14:failure reason12:unregisterede".to_vec()) {
+            Err(TrackerError::Failure(reason)) => assert_eq!(reason, "unregistered"),
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        assert!(matches!(
+            parse_announce_response(b"d5:peers5:abcdee".to_vec()),
+            Err(TrackerError::InvalidResponse(_))
+        ));
+        assert!(matches!(
+            parse_announce_response(b"d8:interval".to_vec()),
+            Err(TrackerError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_url_decode() {
+        let bytes: Vec<u8> = (0..=255).collect();
+
+        assert_eq!(url_decode(&url_encode(&bytes)), Some(bytes));
+        assert_eq!(url_decode("a%2fb"), Some(b"a/b".to_vec()));
+        assert_eq!(url_decode("%4"), None);
+        assert_eq!(url_decode("%zz"), None);
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(
+            scrape_url("http://example.com/announce"),
+            Some("http://example.com/scrape".to_string())
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?passkey=abc"),
+            Some("http://example.com/x/scrape.php?passkey=abc".to_string())
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let response = parse_scrape_response(
+            format!(
+                "d5:filesd20:{}d8:completei5e10:downloadedi50e10:incompletei10eeee",
+                "a".repeat(20)
+            )
+            .into_bytes(),
+            &[[b'a'; 20], [b'b'; 20]],
+        )
+        .unwrap();
+
+        assert_eq!(
+            response,
+            vec![
+                ScrapeStats {
+                    complete: 5,
+                    downloaded: 50,
+                    incomplete: 10
+                },
+                ScrapeStats::default()
+            ]
+        );
+        assert!(matches!(
+            parse_scrape_response(b"de".to_vec(), &[[0; 20]]),
+            Err(TrackerError::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_scrape_mock_tracker() {
+        let (url, requests) = mock_tracker(
+            format!(
+                "d5:filesd20:{}d8:completei1e10:downloadedi2e10:incompletei3eeee",
+                "\x01".repeat(20)
+            )
+            .into_bytes(),
+        );
+
+        let stats = scrape(&url, &[[1; 20], [2; 20]]).unwrap();
+        let request_line = requests.recv().unwrap();
+
+        assert!(request_line.starts_with(&format!(
+            "GET /scrape?info_hash={}&info_hash={} ",
+            "%01".repeat(20),
+            "%02".repeat(20)
+        )));
+        assert_eq!(stats[0].downloaded, 2);
+        assert_eq!(stats[1], ScrapeStats::default());
+    }
+
+    #[test]
+    fn test_announce_mock_tracker() {
+        let (url, requests) =
+            mock_tracker(b"d8:intervali120e5:peers6:\x7f\x00\x00\x01\x1a\xe1e".to_vec());
+
+        let mut request = AnnounceRequest::new([1; 20], [2; 20], 6881);
+        request.event = Event::Completed;
+
+        let response = announce(&url, &request).unwrap();
+        let request_line = requests.recv().unwrap();
+
+        assert!(request_line.starts_with("GET /announce?info_hash=%01%01"));
+        assert!(request_line.contains("&event=completed"));
+        assert_eq!(response.interval, 120);
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+}
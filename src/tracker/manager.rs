@@ -0,0 +1,640 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::torrent::Torrent;
+
+use super::{udp::UdpTracker, AnnounceRequest, AnnounceResponse, Event, TrackerError};
+
+/// Used when a tracker does not send a `min interval`.
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// Used until a tracker answered with its own interval.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1800);
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1800);
+/// UDP retransmission schedule, kept short so a dead tracker gives way to the
+/// next one after 6 seconds instead of the hour-long BEP 15 schedule.
+const UDP_TIMEOUT: Duration = Duration::from_secs(2);
+const UDP_RETRIES: u32 = 1;
+
+/// Sends a single announce to a tracker. Lets the manager be driven without
+/// a network.
+pub trait Announcer {
+    fn announce(
+        &mut self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError>;
+}
+
+/// Announces over HTTP(S) or UDP, keeping one UDP client per tracker so
+/// connection ids are reused.
+#[derive(Default)]
+pub struct NetworkAnnouncer {
+    udp: HashMap<String, UdpTracker>,
+}
+
+impl Announcer for NetworkAnnouncer {
+    fn announce(
+        &mut self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        if !url.starts_with("udp://") {
+            return super::announce(url, request);
+        }
+
+        if !self.udp.contains_key(url) {
+            let tracker = UdpTracker::from_url(url)?.with_timeout(UDP_TIMEOUT, UDP_RETRIES);
+            self.udp.insert(url.to_string(), tracker);
+        }
+
+        self.udp.get_mut(url).unwrap().announce(request)
+    }
+}
+
+/// Transfer totals reported in announces.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Progress {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+/// Announces a torrent following BEP 12: trackers of a tier are tried in
+/// order and the first that answers is moved to the front of its tier. Every
+/// tier is announced to, and the peers they return are merged.
+pub struct Manager<A: Announcer = NetworkAnnouncer> {
+    tiers: Vec<Vec<String>>,
+    info_hashes: Vec<[u8; 20]>,
+    peer_id: [u8; 20],
+    port: u16,
+    key: u32,
+    announcer: A,
+    progress: Progress,
+    /// Event sent with the next announce to each tracker. Trackers that
+    /// never answered get the started event.
+    events: HashMap<String, Event>,
+    /// Trackers the download completed for before their started event went
+    /// out, which get the completed event right after it.
+    completed_pending: HashSet<String>,
+    /// Trackers of the last successful announces, which get the stopped
+    /// event.
+    current: Vec<String>,
+    tracker_ids: HashMap<String, String>,
+    interval: Duration,
+    min_interval: Duration,
+    last_announce: Option<Instant>,
+    next_announce: Option<Instant>,
+    failures: u32,
+    /// Peers returned by the last round. A peer is returned again once a
+    /// round left it out.
+    seen: HashSet<SocketAddr>,
+}
+
+impl Manager {
+    /// Creates a manager for the trackers of `torrent`, shuffling every tier
+    /// as BEP 12 asks.
+    pub fn from_torrent(torrent: &Torrent, peer_id: [u8; 20], port: u16) -> Self {
+        let mut tiers = torrent.trackers();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+
+        Manager::new(
+            tiers,
+            super::info_hashes(&torrent.info),
+            peer_id,
+            port,
+            NetworkAnnouncer::default(),
+        )
+    }
+}
+
+impl<A: Announcer> Manager<A> {
+    pub fn new(
+        tiers: Vec<Vec<String>>,
+        info_hashes: Vec<[u8; 20]>,
+        peer_id: [u8; 20],
+        port: u16,
+        announcer: A,
+    ) -> Self {
+        Manager {
+            tiers,
+            info_hashes,
+            peer_id,
+            port,
+            key: rand::random(),
+            announcer,
+            progress: Progress::default(),
+            events: HashMap::new(),
+            completed_pending: HashSet::new(),
+            current: Vec::new(),
+            tracker_ids: HashMap::new(),
+            interval: DEFAULT_INTERVAL,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            last_announce: None,
+            next_announce: None,
+            failures: 0,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = progress;
+    }
+
+    /// When the next announce is due, or `None` if it is due right away.
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.next_announce
+    }
+
+    /// Schedules a completed event as soon as the trackers allow it, after
+    /// the started event for trackers that did not get it yet. Torrents that
+    /// were complete from the start never send one.
+    pub fn completed(&mut self, now: Instant) {
+        for url in self.tiers.iter().flatten() {
+            if self.events.contains_key(url) {
+                self.events.insert(url.clone(), Event::Completed);
+            } else {
+                self.completed_pending.insert(url.clone());
+            }
+        }
+
+        if self.events.values().any(|event| *event == Event::Completed) {
+            self.schedule_completed(now);
+        }
+    }
+
+    fn schedule_completed(&mut self, now: Instant) {
+        self.next_announce = Some(match self.last_announce {
+            Some(last_announce) => (last_announce + self.min_interval).max(now),
+            None => now,
+        });
+    }
+
+    /// Announces if one is due at `now`, returning the peers that the
+    /// previous round did not return.
+    pub fn poll(&mut self, now: Instant) -> Vec<SocketAddr> {
+        if self.next_announce.is_some_and(|next| now < next) {
+            return Vec::new();
+        }
+
+        let Some(peers) = self.announce_round() else {
+            self.failures += 1;
+            let delay = BASE_RETRY_DELAY
+                .saturating_mul(2u32.saturating_pow(self.failures - 1))
+                .min(MAX_RETRY_DELAY);
+            self.next_announce = Some(now + delay);
+            return Vec::new();
+        };
+
+        self.failures = 0;
+        self.last_announce = Some(now);
+        self.next_announce = Some(now + self.interval);
+
+        if self.events.values().any(|event| *event == Event::Completed) {
+            self.schedule_completed(now);
+        }
+
+        let mut seen = HashSet::new();
+        let peers = peers
+            .into_iter()
+            .filter(|peer| seen.insert(*peer) && !self.seen.contains(peer))
+            .collect();
+        self.seen = seen;
+
+        peers
+    }
+
+    /// Sends the stopped event to the tracker last announced to. Errors are
+    /// ignored since the tracker will expire us anyway.
+    pub fn stop(&mut self) {
+        // Nothing to stop when no tracker has heard of us
+        if self.current.is_empty() {
+            return;
+        }
+
+        for url in std::mem::take(&mut self.current) {
+            for request in self.requests(&url, Event::Stopped) {
+                let _ = self.announcer.announce(&url, &request);
+            }
+        }
+    }
+
+    fn requests(&self, url: &str, event: Event) -> Vec<AnnounceRequest> {
+        self.info_hashes
+            .iter()
+            .map(|info_hash| {
+                let mut request = AnnounceRequest::new(*info_hash, self.peer_id, self.port);
+                request.uploaded = self.progress.uploaded;
+                request.downloaded = self.progress.downloaded;
+                request.left = self.progress.left;
+                request.event = event;
+                request.key = Some(self.key);
+                request.tracker_id = self.tracker_ids.get(url).cloned();
+                request
+            })
+            .collect()
+    }
+
+    /// Announces to the first tracker of every tier that answers for at
+    /// least one info hash, merging their peers. The intervals of the first
+    /// tier that answered are used.
+    fn announce_round(&mut self) -> Option<Vec<SocketAddr>> {
+        let mut answered = false;
+        let mut peers = Vec::new();
+        let mut current = Vec::new();
+
+        for tier in 0..self.tiers.len() {
+            for index in 0..self.tiers[tier].len() {
+                let url = self.tiers[tier][index].clone();
+                let event = self.events.get(&url).copied().unwrap_or(Event::Started);
+
+                let responses: Vec<AnnounceResponse> = self
+                    .requests(&url, event)
+                    .iter()
+                    .filter_map(|request| self.announcer.announce(&url, request).ok())
+                    .collect();
+
+                if responses.is_empty() {
+                    continue;
+                }
+
+                let tracker = self.tiers[tier].remove(index);
+                self.tiers[tier].insert(0, tracker);
+
+                if !answered {
+                    answered = true;
+
+                    // A zero interval would announce in a busy loop
+                    self.min_interval = responses[0]
+                        .min_interval
+                        .filter(|min_interval| *min_interval > 0)
+                        .map_or(DEFAULT_MIN_INTERVAL, |min_interval| {
+                            Duration::from_secs(min_interval.into())
+                        });
+                    self.interval =
+                        Duration::from_secs(responses[0].interval.into()).max(self.min_interval);
+                }
+
+                if let Some(tracker_id) = responses.iter().find_map(|r| r.tracker_id.clone()) {
+                    self.tracker_ids.insert(url.clone(), tracker_id);
+                }
+
+                let next_event = if self.completed_pending.remove(&url) {
+                    Event::Completed
+                } else {
+                    Event::None
+                };
+                self.events.insert(url.clone(), next_event);
+
+                current.push(url);
+                peers.extend(responses.into_iter().flat_map(|response| response.peers));
+                break;
+            }
+        }
+
+        if !answered {
+            return None;
+        }
+
+        self.current = current;
+        Some(peers)
+    }
+}
+
+enum Command {
+    Progress(Progress),
+    Completed,
+    Stop,
+}
+
+/// Controls a manager running on its own thread.
+pub struct ManagerHandle {
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ManagerHandle {
+    pub fn set_progress(&self, progress: Progress) {
+        let _ = self.commands.send(Command::Progress(progress));
+    }
+
+    pub fn completed(&self) {
+        let _ = self.commands.send(Command::Completed);
+    }
+
+    /// Sends the stopped event and waits for the manager thread to finish.
+    pub fn stop(mut self) {
+        let _ = self.commands.send(Command::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<A: Announcer + Send + 'static> Manager<A> {
+    /// Runs the manager on a thread, sending every new peer through the
+    /// returned receiver.
+    pub fn spawn(mut self) -> (ManagerHandle, Receiver<SocketAddr>) {
+        let (commands, command_receiver) = mpsc::channel();
+        let (peer_sender, peers) = mpsc::channel();
+
+        let thread = thread::spawn(move || loop {
+            for peer in self.poll(Instant::now()) {
+                let _ = peer_sender.send(peer);
+            }
+
+            let timeout = self.next_announce.map_or(Duration::ZERO, |next| {
+                next.saturating_duration_since(Instant::now())
+            });
+
+            match command_receiver.recv_timeout(timeout) {
+                Ok(Command::Progress(progress)) => self.set_progress(progress),
+                Ok(Command::Completed) => self.completed(Instant::now()),
+                Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                    self.stop();
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        });
+
+        (
+            ManagerHandle {
+                commands,
+                thread: Some(thread),
+            },
+            peers,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<(String, Event)>>>;
+
+    /// Answers for the trackers in `working` with their index as the peer
+    /// port, and records every announce.
+    struct FakeAnnouncer {
+        working: Vec<String>,
+        interval: u32,
+        log: Log,
+    }
+
+    impl Announcer for FakeAnnouncer {
+        fn announce(
+            &mut self,
+            url: &str,
+            request: &AnnounceRequest,
+        ) -> Result<AnnounceResponse, TrackerError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push((url.to_string(), request.event));
+
+            let index = self
+                .working
+                .iter()
+                .position(|working| working == url)
+                .ok_or_else(|| TrackerError::Http("unreachable".to_string()))?;
+
+            Ok(AnnounceResponse {
+                interval: self.interval,
+                min_interval: Some(30),
+                peers: vec![
+                    SocketAddr::from(([10, 0, 0, 1], 6881)),
+                    SocketAddr::from(([10, 0, 0, 2], index as u16 + 1)),
+                ],
+                ..Default::default()
+            })
+        }
+    }
+
+    fn manager(tiers: &[&[&str]], working: &[&str]) -> (Manager<FakeAnnouncer>, Log) {
+        let log = Log::default();
+        let announcer = FakeAnnouncer {
+            working: working.iter().map(|url| url.to_string()).collect(),
+            interval: 300,
+            log: log.clone(),
+        };
+        let tiers = tiers
+            .iter()
+            .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+            .collect();
+
+        (
+            Manager::new(tiers, vec![[1; 20]], [2; 20], 6881, announcer),
+            log,
+        )
+    }
+
+    fn urls(log: &Log) -> Vec<String> {
+        log.lock().unwrap().drain(..).map(|(url, _)| url).collect()
+    }
+
+    #[test]
+    fn test_tier_failover() {
+        let (mut manager, log) = manager(&[&["a", "b"], &["c"]], &["b", "c"]);
+        let now = Instant::now();
+
+        // Every tier is announced to, and their peers merged
+        let peers: HashSet<SocketAddr> = manager.poll(now).into_iter().collect();
+        assert_eq!(urls(&log), vec!["a", "b", "c"]);
+        assert_eq!(manager.tiers()[0], vec!["b", "a"]);
+        assert_eq!(
+            peers,
+            HashSet::from([
+                SocketAddr::from(([10, 0, 0, 1], 6881)),
+                SocketAddr::from(([10, 0, 0, 2], 1)),
+                SocketAddr::from(([10, 0, 0, 2], 2)),
+            ])
+        );
+        assert_eq!(
+            manager.next_announce(),
+            Some(now + Duration::from_secs(300))
+        );
+
+        // Nothing happens before the interval passed
+        manager.poll(now + Duration::from_secs(299));
+        assert!(urls(&log).is_empty());
+
+        manager.poll(now + Duration::from_secs(300));
+        assert_eq!(urls(&log), vec!["b", "c"]);
+
+        manager.stop();
+        assert_eq!(urls(&log), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_zero_interval() {
+        let (mut manager, _) = manager(&[&["a"]], &["a"]);
+        manager.announcer.interval = 0;
+        let now = Instant::now();
+
+        manager.poll(now);
+        assert_eq!(manager.next_announce(), Some(now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let (mut manager, log) = manager(&[&["a"], &["b"]], &[]);
+        let now = Instant::now();
+
+        manager.poll(now);
+        assert_eq!(urls(&log), vec!["a", "b"]);
+        assert_eq!(manager.next_announce(), Some(now + Duration::from_secs(15)));
+
+        manager.poll(now + Duration::from_secs(15));
+        assert_eq!(
+            manager.next_announce(),
+            Some(now + Duration::from_secs(15 + 30))
+        );
+
+        for _ in 0..10 {
+            manager.poll(manager.next_announce().unwrap());
+        }
+        let last = log.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(last, ("b".to_string(), Event::Started));
+
+        let before = manager.next_announce().unwrap();
+        manager.poll(before);
+        assert_eq!(
+            manager.next_announce(),
+            Some(before + Duration::from_secs(1800))
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let (mut manager, log) = manager(&[&["a"]], &["a"]);
+        let now = Instant::now();
+
+        // Completing before the first announce went out sends the event
+        // right after the started one
+        manager.completed(now);
+        manager.poll(now);
+        assert_eq!(manager.next_announce(), Some(now + Duration::from_secs(30)));
+
+        manager.poll(now + Duration::from_secs(30));
+        manager.poll(now + Duration::from_secs(330));
+        manager.stop();
+        // Stopping twice only notifies the tracker once
+        manager.stop();
+
+        let events: Vec<Event> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, event)| *event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Started,
+                Event::Completed,
+                Event::None,
+                Event::Stopped
+            ]
+        );
+    }
+
+    #[test]
+    fn test_completed_after_started() {
+        let (mut manager, _) = manager(&[&["a"]], &["a"]);
+        let now = Instant::now();
+
+        manager.poll(now);
+        manager.completed(now + Duration::from_secs(10));
+        assert_eq!(manager.next_announce(), Some(now + Duration::from_secs(30)));
+        manager.completed(now + Duration::from_secs(40));
+        assert_eq!(manager.next_announce(), Some(now + Duration::from_secs(40)));
+    }
+
+    #[test]
+    fn test_events_per_tracker() {
+        let (mut manager, log) = manager(&[&["a"], &["b"]], &["a"]);
+        let now = Instant::now();
+
+        manager.poll(now);
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![
+                ("a".to_string(), Event::Started),
+                ("b".to_string(), Event::Started)
+            ]
+        );
+
+        // The tracker that was down still gets the started event
+        manager.announcer.working.push("b".to_string());
+        manager.poll(now + Duration::from_secs(300));
+        manager.completed(now + Duration::from_secs(600));
+        manager.poll(now + Duration::from_secs(600));
+        manager.stop();
+
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![
+                ("a".to_string(), Event::None),
+                ("b".to_string(), Event::Started),
+                ("a".to_string(), Event::Completed),
+                ("b".to_string(), Event::Completed),
+                ("a".to_string(), Event::Stopped),
+                ("b".to_string(), Event::Stopped)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_returning_peers() {
+        let (mut manager, _) = manager(&[&["a"], &["b"]], &["a", "b"]);
+        let now = Instant::now();
+        let from_b = SocketAddr::from(([10, 0, 0, 2], 2));
+
+        assert_eq!(manager.poll(now).len(), 3);
+
+        // The peer of b is left out of a round, then returned again
+        manager.announcer.working.pop();
+        assert!(manager.poll(now + Duration::from_secs(300)).is_empty());
+        manager.announcer.working.push("b".to_string());
+        assert_eq!(manager.poll(now + Duration::from_secs(600)), vec![from_b]);
+        assert!(manager.poll(now + Duration::from_secs(900)).is_empty());
+    }
+
+    #[test]
+    fn test_deduplicated_peers() {
+        let (manager, log) = manager(&[&["a", "b"]], &["a", "b"]);
+        let (handle, peers) = manager.spawn();
+
+        let first: HashSet<SocketAddr> = [peers.recv().unwrap(), peers.recv().unwrap()].into();
+        assert_eq!(
+            first,
+            HashSet::from([
+                SocketAddr::from(([10, 0, 0, 1], 6881)),
+                SocketAddr::from(([10, 0, 0, 2], 1)),
+            ])
+        );
+
+        handle.stop();
+        assert!(peers.recv().is_err());
+        assert_eq!(
+            log.lock().unwrap().last().cloned(),
+            Some(("a".to_string(), Event::Stopped))
+        );
+    }
+}
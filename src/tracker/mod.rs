@@ -0,0 +1,224 @@
+use std::{fmt::Display, io, net::SocketAddr, time::Duration};
+
+use rand::Rng;
+
+use crate::{bencode_decoder::DecodeError, peers::PeerListError, torrent::Info};
+
+pub mod http;
+pub mod manager;
+pub mod server;
+pub mod udp;
+
+/// Azureus-style client prefix of our peer ids.
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-BT0100-";
+
+#[derive(Debug)]
+pub enum TrackerError {
+    Io(io::Error),
+    Http(String),
+    Decode(DecodeError),
+    InvalidResponse(String),
+    /// The tracker answered with a `failure reason`.
+    Failure(String),
+}
+
+impl Display for TrackerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackerError::Io(error) => write!(f, "I/O error: {error}"),
+            TrackerError::Http(error) => write!(f, "HTTP error: {error}"),
+            TrackerError::Decode(error) => write!(f, "invalid bencode in response: {error}"),
+            TrackerError::InvalidResponse(reason) => write!(f, "invalid response: {reason}"),
+            TrackerError::Failure(reason) => write!(f, "tracker failure: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+impl From<io::Error> for TrackerError {
+    fn from(error: io::Error) -> Self {
+        TrackerError::Io(error)
+    }
+}
+
+impl From<PeerListError> for TrackerError {
+    fn from(error: PeerListError) -> Self {
+        TrackerError::InvalidResponse(error.to_string())
+    }
+}
+
+impl From<DecodeError> for TrackerError {
+    fn from(error: DecodeError) -> Self {
+        TrackerError::Decode(error)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Event {
+    #[default]
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Started => Some("started"),
+            Event::Completed => Some("completed"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+    pub compact: bool,
+    pub numwant: Option<u32>,
+    pub key: Option<u32>,
+    pub tracker_id: Option<String>,
+}
+
+impl AnnounceRequest {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], port: u16) -> Self {
+        AnnounceRequest {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: Event::None,
+            compact: true,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct AnnounceResponse {
+    pub interval: u32,
+    pub min_interval: Option<u32>,
+    pub tracker_id: Option<String>,
+    pub warning: Option<String>,
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    pub peers: Vec<SocketAddr>,
+}
+
+/// Swarm statistics for one torrent returned by a scrape.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct ScrapeStats {
+    /// Number of seeders.
+    pub complete: u32,
+    /// Number of times the torrent was completely downloaded.
+    pub downloaded: u32,
+    /// Number of leechers.
+    pub incomplete: u32,
+}
+
+/// Announces to an HTTP(S) or UDP tracker depending on the scheme of its URL.
+pub fn announce(url: &str, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+    if url.starts_with("udp://") {
+        udp::UdpTracker::from_url(url)?.announce(request)
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        http::announce(url, request)
+    } else {
+        Err(TrackerError::InvalidResponse(format!(
+            "unsupported tracker URL {url}"
+        )))
+    }
+}
+
+/// Scrapes an HTTP(S) or UDP tracker depending on the scheme of its URL. UDP
+/// packets are sent once and waited for `udp_timeout`, as a one-off scrape
+/// should not sit through the full BEP 15 retransmission schedule.
+pub fn scrape(
+    url: &str,
+    info_hashes: &[[u8; 20]],
+    udp_timeout: Duration,
+) -> Result<Vec<ScrapeStats>, TrackerError> {
+    if url.starts_with("udp://") {
+        udp::UdpTracker::from_url(url)?
+            .with_timeout(udp_timeout, 0)
+            .scrape(info_hashes)
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        http::scrape(url, info_hashes)
+    } else {
+        Err(TrackerError::InvalidResponse(format!(
+            "unsupported tracker URL {url}"
+        )))
+    }
+}
+
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+
+    let mut rng = rand::thread_rng();
+    for byte in &mut peer_id[8..] {
+        *byte = rng.sample(rand::distributions::Alphanumeric);
+    }
+
+    peer_id
+}
+
+/// Hashes a torrent is announced under: the SHA-1 infohash for hybrid
+/// torrents, and the SHA-256 infohash truncated to 20 bytes as described in
+/// BEP 52.
+pub fn info_hashes(info: &Info) -> Vec<[u8; 20]> {
+    let mut hashes = Vec::new();
+
+    if let Some(infohash_v1) = info.get_infohash_v1_bytes() {
+        hashes.push(infohash_v1);
+    }
+
+    hashes.push(truncate_infohash(&info.get_infohash_bytes()));
+
+    hashes
+}
+
+pub fn truncate_infohash(infohash: &[u8; 32]) -> [u8; 20] {
+    infohash[..20].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bencode_decoder::Bencode, torrent::Torrent};
+
+    #[test]
+    fn test_generate_peer_id() {
+        let peer_id = generate_peer_id();
+
+        assert!(peer_id.starts_with(PEER_ID_PREFIX));
+        assert!(peer_id[8..].iter().all(|byte| byte.is_ascii_alphanumeric()));
+        assert_ne!(peer_id, generate_peer_id());
+    }
+
+    #[test]
+    fn test_info_hashes() {
+        let content = std::fs::read("test_folder.torrent").unwrap();
+        let (metainfo, _) = Bencode::decode_value(content);
+        let torrent = Torrent::parse(&metainfo);
+
+        let truncated: [u8; 20] = hex::decode("22fd2f407dd4187ca9b77b7937587f53346f0aeb")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        assert_eq!(info_hashes(&torrent.info), vec![truncated]);
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use indexmap::IndexMap;
+
+use crate::{
+    bencode_decoder::Bencode,
+    peers,
+    tracker::{http::url_decode, AnnounceRequest, AnnounceResponse, Event, ScrapeStats},
+};
+
+use super::Server;
+
+/// Time a client has to send its whole request, so slow clients cannot hold
+/// a connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Connections handled at once. Further ones are closed right away, so idle
+/// clients cannot exhaust the threads of the tracker.
+const MAX_CONNECTIONS: usize = 256;
+
+/// Answers announce and scrape requests on `listener`, one thread per
+/// connection. Only returns when accepting fails.
+pub fn serve(listener: TcpListener, server: Arc<Server>) -> io::Result<()> {
+    serve_connections(listener, server, MAX_CONNECTIONS)
+}
+
+fn serve_connections(
+    listener: TcpListener,
+    server: Arc<Server>,
+    max_connections: usize,
+) -> io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        let (stream, _) = listener.accept()?;
+        if connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+
+        let (server, connections) = (server.clone(), connections.clone());
+        thread::spawn(move || {
+            // The client hanging up early is not our problem
+            let _ = handle(stream, &server);
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn handle(mut stream: TcpStream, server: &Server) -> io::Result<()> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+
+        let read = stream.read(&mut buffer)?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let target = match request.split(' ').collect::<Vec<_>>()[..] {
+        ["GET", target, ..] => target,
+        _ => return respond(&mut stream, "405 Method Not Allowed", b""),
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let Some(params) = parse_query(query) else {
+        return respond(&mut stream, "400 Bad Request", b"");
+    };
+
+    let ip = stream.peer_addr()?.ip();
+
+    let body = if path.ends_with("/announce") {
+        announce(server, &params, ip)
+    } else if path.ends_with("/scrape") {
+        scrape(server, &params)
+    } else {
+        return respond(&mut stream, "404 Not Found", b"");
+    };
+
+    respond(&mut stream, "200 OK", &body)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+fn parse_query(query: &str) -> Option<Vec<(String, Vec<u8>)>> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            Some((key.to_string(), url_decode(value)?))
+        })
+        .collect()
+}
+
+fn get_param<'a>(params: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(param, _)| param == key)
+        .map(|(_, value)| value.as_slice())
+}
+
+fn get_number<T: std::str::FromStr>(params: &[(String, Vec<u8>)], key: &str) -> Option<T> {
+    std::str::from_utf8(get_param(params, key)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    let mut response = IndexMap::new();
+    response.insert(
+        b"failure reason".to_vec(),
+        Bencode::String(reason.as_bytes().to_vec()),
+    );
+
+    Bencode::Dictionary(response).encode_value()
+}
+
+fn parse_announce(params: &[(String, Vec<u8>)]) -> Result<AnnounceRequest, &'static str> {
+    let info_hash = get_param(params, "info_hash")
+        .and_then(|info_hash| info_hash.try_into().ok())
+        .ok_or("invalid info_hash")?;
+    let peer_id = get_param(params, "peer_id")
+        .and_then(|peer_id| peer_id.try_into().ok())
+        .ok_or("invalid peer_id")?;
+    let port = get_number(params, "port").ok_or("invalid port")?;
+
+    let mut request = AnnounceRequest::new(info_hash, peer_id, port);
+    request.uploaded = get_number(params, "uploaded").unwrap_or(0);
+    request.downloaded = get_number(params, "downloaded").unwrap_or(0);
+    request.left = get_number(params, "left").unwrap_or(0);
+    request.numwant = get_number(params, "numwant");
+    request.compact = get_param(params, "compact") != Some(b"0");
+    request.event = match get_param(params, "event") {
+        None | Some(b"") => Event::None,
+        Some(b"started") => Event::Started,
+        Some(b"completed") => Event::Completed,
+        Some(b"stopped") => Event::Stopped,
+        Some(_) => return Err("invalid event"),
+    };
+
+    Ok(request)
+}
+
+fn announce(server: &Server, params: &[(String, Vec<u8>)], ip: IpAddr) -> Vec<u8> {
+    let request = match parse_announce(params) {
+        Ok(request) => request,
+        Err(reason) => return failure(reason),
+    };
+
+    match server.announce(&request, ip) {
+        Ok(response) => encode_announce_response(&response, request.compact),
+        Err(reason) => failure(reason),
+    }
+}
+
+/// Encodes an announce response with compact `peers` and `peers6` strings
+/// (BEP 23 and BEP 7), or a list of peer dictionaries when the client asked
+/// for `compact=0`.
+pub fn encode_announce_response(response: &AnnounceResponse, compact: bool) -> Vec<u8> {
+    let mut dict = IndexMap::new();
+
+    // Keys are inserted in sorted order as bencode requires
+    if let Some(complete) = response.complete {
+        dict.insert(b"complete".to_vec(), Bencode::Integer(complete.into()));
+    }
+    if let Some(incomplete) = response.incomplete {
+        dict.insert(b"incomplete".to_vec(), Bencode::Integer(incomplete.into()));
+    }
+    dict.insert(
+        b"interval".to_vec(),
+        Bencode::Integer(response.interval.into()),
+    );
+    if let Some(min_interval) = response.min_interval {
+        dict.insert(
+            b"min interval".to_vec(),
+            Bencode::Integer(min_interval.into()),
+        );
+    }
+
+    if compact {
+        dict.insert(
+            b"peers".to_vec(),
+            Bencode::String(peers::encode_compact_v4(&response.peers)),
+        );
+        dict.insert(
+            b"peers6".to_vec(),
+            Bencode::String(peers::encode_compact_v6(&response.peers)),
+        );
+    } else {
+        dict.insert(b"peers".to_vec(), peers::encode_peer_dicts(&response.peers));
+    }
+
+    Bencode::Dictionary(dict).encode_value()
+}
+
+fn scrape(server: &Server, params: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let info_hashes: Vec<[u8; 20]> = params
+        .iter()
+        .filter(|(key, _)| key == "info_hash")
+        .filter_map(|(_, info_hash)| info_hash.as_slice().try_into().ok())
+        .collect();
+
+    let mut files: Vec<([u8; 20], ScrapeStats)> = if info_hashes.is_empty() {
+        server.scrape_all()
+    } else {
+        info_hashes
+            .iter()
+            .zip(server.scrape(&info_hashes))
+            .filter_map(|(info_hash, stats)| Some((*info_hash, stats?)))
+            .collect()
+    };
+    files.sort_by_key(|(info_hash, _)| *info_hash);
+
+    let files = files
+        .into_iter()
+        .map(|(info_hash, stats)| {
+            let mut dict = IndexMap::new();
+            dict.insert(
+                b"complete".to_vec(),
+                Bencode::Integer(stats.complete.into()),
+            );
+            dict.insert(
+                b"downloaded".to_vec(),
+                Bencode::Integer(stats.downloaded.into()),
+            );
+            dict.insert(
+                b"incomplete".to_vec(),
+                Bencode::Integer(stats.incomplete.into()),
+            );
+            (info_hash.to_vec(), Bencode::Dictionary(dict))
+        })
+        .collect();
+
+    let mut response = IndexMap::new();
+    response.insert(b"files".to_vec(), Bencode::Dictionary(files));
+
+    Bencode::Dictionary(response).encode_value()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::tracker::{http, server::ServerOptions, TrackerError};
+
+    fn start(server: Server) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || serve(listener, Arc::new(server)));
+
+        format!("http://{address}/announce")
+    }
+
+    #[test]
+    fn test_announce_and_scrape() {
+        let url = start(Server::new(ServerOptions::default()));
+
+        let mut seeder = AnnounceRequest::new([7; 20], [1; 20], 6881);
+        seeder.event = Event::Started;
+        let response = http::announce(&url, &seeder).unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.complete, Some(1));
+
+        let mut leecher = AnnounceRequest::new([7; 20], [2; 20], 6882);
+        leecher.left = 1000;
+        let response = http::announce(&url, &leecher).unwrap();
+        let expected: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        assert_eq!(response.peers, vec![expected]);
+        assert_eq!(response.interval, 1800);
+
+        leecher.compact = false;
+        let response = http::announce(&url, &leecher).unwrap();
+        assert_eq!(response.peers, vec![expected]);
+
+        let stats = http::scrape(&url, &[[7; 20], [8; 20]]).unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 1,
+                    downloaded: 0,
+                    incomplete: 1
+                },
+                ScrapeStats::default()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(ServerOptions::default()));
+        thread::spawn(move || serve_connections(listener, server, 1));
+        let url = format!("http://{address}/announce");
+
+        // An idle client takes the only connection, so the next one is closed
+        let idle = TcpStream::connect(address).unwrap();
+        let mut refused = TcpStream::connect(address).unwrap();
+        refused
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(refused.read(&mut [0; 1]).unwrap(), 0);
+
+        drop(idle);
+        let request = AnnounceRequest::new([7; 20], [1; 20], 6881);
+        let answered = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(20));
+            http::announce(&url, &request).is_ok()
+        });
+        assert!(answered);
+    }
+
+    #[test]
+    fn test_refused_announce() {
+        let mut server = Server::new(ServerOptions::default());
+        let content = std::fs::read("test_folder.torrent").unwrap();
+        let (metainfo, _) = Bencode::decode_value(content);
+        server.allow(&crate::torrent::Torrent::parse(&metainfo));
+
+        let url = start(server);
+
+        let error = http::announce(&url, &AnnounceRequest::new([7; 20], [1; 20], 6881));
+        assert!(matches!(
+            error,
+            Err(TrackerError::Failure(reason)) if reason == "torrent is not allowed on this tracker"
+        ));
+    }
+
+    #[test]
+    fn test_encode_announce_response() {
+        let response = AnnounceResponse {
+            interval: 60,
+            complete: Some(1),
+            incomplete: Some(0),
+            peers: vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:6881".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        let encoded = encode_announce_response(&response, true);
+        assert!(encoded.starts_with(b"d8:completei1e10:incompletei0e8:intervali60e5:peers6:"));
+        assert_eq!(http::parse_announce_response(encoded).unwrap(), response);
+    }
+}
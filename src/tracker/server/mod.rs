@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::{bencode_decoder::Bencode, torrent::Torrent};
+
+use super::{AnnounceRequest, AnnounceResponse, Event, ScrapeStats};
+
+pub mod http;
+pub mod udp;
+
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+
+pub struct ServerOptions {
+    /// Interval clients are asked to wait between announces.
+    pub interval: Duration,
+    /// Peers that did not announce for this long are dropped from swarms.
+    pub peer_timeout: Duration,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            interval: Duration::from_secs(1800),
+            peer_timeout: Duration::from_secs(3600),
+        }
+    }
+}
+
+struct Peer {
+    address: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], Peer>,
+    downloaded: u32,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u32;
+
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u32 - complete,
+        }
+    }
+}
+
+/// In-memory swarm state shared by the HTTP and UDP tracker front ends.
+pub struct Server {
+    options: ServerOptions,
+    /// Maps every allowed info hash to the key of its swarm, so that the v1
+    /// and v2 info hashes of a hybrid torrent share their peers. Every torrent
+    /// is accepted when there is no allow-list.
+    allowed: Option<HashMap<[u8; 20], [u8; 20]>>,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+impl Server {
+    pub fn new(options: ServerOptions) -> Self {
+        Server {
+            options,
+            allowed: None,
+            swarms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.options.interval
+    }
+
+    /// Adds a torrent to the allow-list under all of its info hashes. Once a
+    /// torrent is allowed, announces for any other torrent are refused.
+    pub fn allow(&mut self, torrent: &Torrent) {
+        let info_hashes = super::info_hashes(&torrent.info);
+        let allowed = self.allowed.get_or_insert_with(HashMap::new);
+
+        for info_hash in &info_hashes {
+            allowed.insert(*info_hash, info_hashes[0]);
+        }
+    }
+
+    pub fn allow_file(&mut self, path: &Path) -> io::Result<()> {
+        let content = std::fs::read(path)?;
+        let (metainfo, _) = Bencode::try_decode_value(content)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+        let torrent = Torrent::try_parse(&metainfo)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        self.allow(&torrent);
+
+        Ok(())
+    }
+
+    fn swarm_key(&self, info_hash: &[u8; 20]) -> Option<[u8; 20]> {
+        match &self.allowed {
+            Some(allowed) => allowed.get(info_hash).copied(),
+            None => Some(*info_hash),
+        }
+    }
+
+    /// Records an announce from `ip` and picks peers for it. Errors are the
+    /// failure reason sent back to the client.
+    pub fn announce(
+        &self,
+        request: &AnnounceRequest,
+        ip: IpAddr,
+    ) -> Result<AnnounceResponse, &'static str> {
+        let key = self
+            .swarm_key(&request.info_hash)
+            .ok_or("torrent is not allowed on this tracker")?;
+
+        if request.port == 0 {
+            return Err("invalid port");
+        }
+
+        let now = Instant::now();
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(key).or_default();
+
+        swarm
+            .peers
+            .retain(|_, peer| now.duration_since(peer.last_seen) < self.options.peer_timeout);
+
+        let mut peers = Vec::new();
+
+        if request.event == Event::Stopped {
+            swarm.peers.remove(&request.peer_id);
+        } else {
+            let previous = swarm.peers.insert(
+                request.peer_id,
+                Peer {
+                    address: SocketAddr::new(ip.to_canonical(), request.port),
+                    left: request.left,
+                    last_seen: now,
+                },
+            );
+
+            // Repeated completed events of a seeder are only counted once
+            let finished = previous.is_none_or(|previous| previous.left > 0);
+            if request.event == Event::Completed && finished {
+                swarm.downloaded += 1;
+            }
+
+            // Seeders have no use for other seeders
+            peers = swarm
+                .peers
+                .iter()
+                .filter(|(peer_id, peer)| {
+                    **peer_id != request.peer_id && (request.left > 0 || peer.left > 0)
+                })
+                .map(|(_, peer)| peer.address)
+                .collect();
+
+            peers.shuffle(&mut rand::thread_rng());
+            peers.truncate(
+                request
+                    .numwant
+                    .map_or(DEFAULT_NUMWANT, |numwant| numwant as usize)
+                    .min(MAX_NUMWANT),
+            );
+        }
+
+        let stats = swarm.stats();
+
+        Ok(AnnounceResponse {
+            interval: self.options.interval.as_secs() as u32,
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
+            complete: Some(stats.complete),
+            incomplete: Some(stats.incomplete),
+            peers,
+        })
+    }
+
+    /// Returns the stats of each torrent, or `None` for torrents that are not
+    /// allowed.
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<Option<ScrapeStats>> {
+        let swarms = self.swarms.lock().unwrap();
+
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                let key = self.swarm_key(info_hash)?;
+                Some(swarms.get(&key).map(Swarm::stats).unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// Returns the stats of every known swarm, for scrapes without info hash.
+    pub fn scrape_all(&self) -> Vec<([u8; 20], ScrapeStats)> {
+        let swarms = self.swarms.lock().unwrap();
+
+        swarms
+            .iter()
+            .map(|(info_hash, swarm)| (*info_hash, swarm.stats()))
+            .collect()
+    }
+
+    /// Drops peers that have not announced within the peer timeout at `now`,
+    /// and swarms left without peers.
+    pub fn expire(&self, now: Instant) {
+        let mut swarms = self.swarms.lock().unwrap();
+
+        for swarm in swarms.values_mut() {
+            swarm.peers.retain(|_, peer| {
+                now.saturating_duration_since(peer.last_seen) < self.options.peer_timeout
+            });
+        }
+
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer_id: u8, port: u16, left: u64, event: Event) -> AnnounceRequest {
+        let mut request = AnnounceRequest::new([1; 20], [peer_id; 20], port);
+        request.left = left;
+        request.event = event;
+        request
+    }
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[test]
+    fn test_announce() {
+        let server = Server::new(ServerOptions::default());
+
+        let response = server
+            .announce(&request(1, 6881, 0, Event::Started), localhost())
+            .unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.interval, 1800);
+
+        let response = server
+            .announce(&request(2, 6882, 100, Event::Started), localhost())
+            .unwrap();
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!((response.complete, response.incomplete), (Some(1), Some(1)));
+
+        // A seeder is only given leechers
+        let response = server
+            .announce(&request(3, 6883, 0, Event::Started), localhost())
+            .unwrap();
+        assert_eq!(response.peers, vec!["127.0.0.1:6882".parse().unwrap()]);
+
+        server
+            .announce(&request(2, 6882, 0, Event::Completed), localhost())
+            .unwrap();
+        server
+            .announce(&request(3, 6883, 0, Event::Stopped), localhost())
+            .unwrap();
+
+        assert_eq!(
+            server.scrape(&[[1; 20], [2; 20]]),
+            vec![
+                Some(ScrapeStats {
+                    complete: 2,
+                    downloaded: 1,
+                    incomplete: 0
+                }),
+                Some(ScrapeStats::default())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let content = std::fs::read("test_folder.torrent").unwrap();
+        let (metainfo, _) = Bencode::decode_value(content);
+        let mut torrent = Torrent::parse(&metainfo);
+        torrent
+            .info
+            .extra
+            .insert(b"pieces".to_vec(), Bencode::String(vec![0; 20]));
+
+        let mut server = Server::new(ServerOptions::default());
+        server.allow(&torrent);
+
+        let [v1, v2] = super::super::info_hashes(&torrent.info)[..] else {
+            panic!("hybrid torrent should have two info hashes");
+        };
+
+        let mut first = request(1, 6881, 10, Event::Started);
+        first.info_hash = v1;
+        let mut second = request(2, 6882, 10, Event::Started);
+        second.info_hash = v2;
+
+        server.announce(&first, localhost()).unwrap();
+        let response = server.announce(&second, localhost()).unwrap();
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        assert_eq!(
+            server.announce(&request(3, 6883, 0, Event::Started), localhost()),
+            Err("torrent is not allowed on this tracker")
+        );
+        assert_eq!(server.scrape(&[[1; 20]]), vec![None]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let server = Server::new(ServerOptions {
+            interval: Duration::from_secs(60),
+            peer_timeout: Duration::from_secs(120),
+        });
+
+        server
+            .announce(&request(1, 6881, 10, Event::Started), localhost())
+            .unwrap();
+
+        server.expire(Instant::now() + Duration::from_secs(60));
+        assert_eq!(server.scrape_all().len(), 1);
+
+        server.expire(Instant::now() + Duration::from_secs(121));
+        assert!(server.scrape_all().is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    peers,
+    tracker::{
+        udp::{
+            read_u32, ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE,
+            MAX_SCRAPE_HASHES, PROTOCOL_ID,
+        },
+        AnnounceRequest, Event,
+    },
+};
+
+use super::Server;
+
+const ANNOUNCE_SIZE: usize = 98;
+/// Connection ids are derived from a minute counter and accepted for the
+/// current and the previous minute.
+const CONNECTION_ID_WINDOW: u64 = 60;
+
+/// Answers UDP tracker requests (BEP 15) received on `socket`. Connection ids
+/// are keyed hashes of the client address, so no per-client state is kept.
+/// Only returns when the socket fails.
+pub fn serve(socket: UdpSocket, server: Arc<Server>) -> io::Result<()> {
+    let secret = RandomState::new();
+    let mut buffer = [0; 2048];
+
+    loop {
+        let (read, from) = socket.recv_from(&mut buffer)?;
+
+        if let Some(response) = handle(&buffer[..read], from, &server, &secret) {
+            // Sending can fail for unreachable clients, which must not stop
+            // the tracker
+            let _ = socket.send_to(&response, from);
+        }
+    }
+}
+
+fn connection_id(secret: &RandomState, from: SocketAddr, window: u64) -> u64 {
+    secret.hash_one((from, window))
+}
+
+fn current_window() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / CONNECTION_ID_WINDOW
+}
+
+fn error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut response = Vec::new();
+    response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+    response.extend_from_slice(&transaction_id.to_be_bytes());
+    response.extend_from_slice(message.as_bytes());
+    response
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn handle(
+    packet: &[u8],
+    from: SocketAddr,
+    server: &Server,
+    secret: &RandomState,
+) -> Option<Vec<u8>> {
+    if packet.len() < 16 {
+        return None;
+    }
+
+    let connection = read_u64(packet, 0);
+    let action = read_u32(packet, 8);
+    let transaction_id = read_u32(packet, 12);
+
+    let mut response = Vec::new();
+    response.extend_from_slice(&action.to_be_bytes());
+    response.extend_from_slice(&transaction_id.to_be_bytes());
+
+    let window = current_window();
+
+    if action == ACTION_CONNECT {
+        if connection != PROTOCOL_ID {
+            return None;
+        }
+
+        response.extend_from_slice(&connection_id(secret, from, window).to_be_bytes());
+        return Some(response);
+    }
+
+    let valid = [window, window.saturating_sub(1)]
+        .iter()
+        .any(|&window| connection_id(secret, from, window) == connection);
+    if !valid {
+        return Some(error(transaction_id, "invalid connection id"));
+    }
+
+    match action {
+        ACTION_ANNOUNCE => {
+            if packet.len() < ANNOUNCE_SIZE {
+                return Some(error(transaction_id, "announce request too short"));
+            }
+
+            let mut request = AnnounceRequest::new(
+                packet[16..36].try_into().unwrap(),
+                packet[36..56].try_into().unwrap(),
+                u16::from_be_bytes([packet[96], packet[97]]),
+            );
+            request.downloaded = read_u64(packet, 56);
+            request.left = read_u64(packet, 64);
+            request.uploaded = read_u64(packet, 72);
+            request.event = match Event::from_udp_code(read_u32(packet, 80)) {
+                Some(event) => event,
+                None => return Some(error(transaction_id, "invalid event")),
+            };
+            let numwant = read_u32(packet, 92) as i32;
+            request.numwant = (numwant >= 0).then_some(numwant as u32);
+
+            // The ip field is ignored, peers are always registered under the
+            // source address of the packet
+            let ip = from.ip().to_canonical();
+
+            let announce = match server.announce(&request, ip) {
+                Ok(announce) => announce,
+                Err(reason) => return Some(error(transaction_id, reason)),
+            };
+
+            response.extend_from_slice(&announce.interval.to_be_bytes());
+            response.extend_from_slice(&announce.incomplete.unwrap_or(0).to_be_bytes());
+            response.extend_from_slice(&announce.complete.unwrap_or(0).to_be_bytes());
+
+            // Clients decode peers in the address family they reached us with.
+            // IPv4 clients of a dual-stack socket show up as mapped addresses,
+            // but still expect IPv4 peers
+            if ip.is_ipv6() {
+                response.extend(peers::encode_compact_v6(&announce.peers));
+            } else {
+                response.extend(peers::encode_compact_v4(&announce.peers));
+            }
+
+            Some(response)
+        }
+        ACTION_SCRAPE => {
+            let info_hashes: Vec<[u8; 20]> = packet[16..]
+                .chunks_exact(20)
+                .take(MAX_SCRAPE_HASHES)
+                .map(|info_hash| info_hash.try_into().unwrap())
+                .collect();
+
+            for stats in server.scrape(&info_hashes) {
+                let stats = stats.unwrap_or_default();
+                response.extend_from_slice(&stats.complete.to_be_bytes());
+                response.extend_from_slice(&stats.downloaded.to_be_bytes());
+                response.extend_from_slice(&stats.incomplete.to_be_bytes());
+            }
+
+            Some(response)
+        }
+        _ => Some(error(transaction_id, "unknown action")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::tracker::{server::ServerOptions, udp::UdpTracker, ScrapeStats, TrackerError};
+
+    fn start(bind: &str) -> SocketAddr {
+        let socket = UdpSocket::bind(bind).unwrap();
+        let address = socket.local_addr().unwrap();
+        let server = Arc::new(Server::new(ServerOptions::default()));
+
+        thread::spawn(move || serve(socket, server));
+
+        address
+    }
+
+    fn client(address: SocketAddr) -> UdpTracker {
+        UdpTracker::new(address)
+            .unwrap()
+            .with_timeout(Duration::from_millis(200), 2)
+    }
+
+    #[test]
+    fn test_announce_and_scrape() {
+        let address = start("127.0.0.1:0");
+
+        let mut seeder = AnnounceRequest::new([7; 20], [1; 20], 6881);
+        seeder.event = Event::Started;
+        client(address).announce(&seeder).unwrap();
+
+        let mut leecher = AnnounceRequest::new([7; 20], [2; 20], 6882);
+        leecher.left = 10;
+        let response = client(address).announce(&leecher).unwrap();
+
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(response.interval, 1800);
+        assert_eq!((response.complete, response.incomplete), (Some(1), Some(1)));
+
+        let stats = client(address).scrape(&[[7; 20], [8; 20]]).unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 1,
+                    downloaded: 0,
+                    incomplete: 1
+                },
+                ScrapeStats::default()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ipv6_announce() {
+        let address = start("[::1]:0");
+
+        let mut first = AnnounceRequest::new([7; 20], [1; 20], 6881);
+        first.left = 10;
+        client(address).announce(&first).unwrap();
+
+        let mut second = AnnounceRequest::new([7; 20], [2; 20], 6882);
+        second.left = 10;
+        let response = client(address).announce(&second).unwrap();
+
+        assert_eq!(response.peers, vec!["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_mapped_ipv4_announce() {
+        let secret = RandomState::new();
+        let server = Server::new(ServerOptions::default());
+
+        let announce = |from: &str, peer_id: u8| {
+            let from: SocketAddr = from.parse().unwrap();
+
+            let mut connect = PROTOCOL_ID.to_be_bytes().to_vec();
+            connect.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            connect.extend_from_slice(&1u32.to_be_bytes());
+            let connected = handle(&connect, from, &server, &secret).unwrap();
+
+            let mut packet = connected[8..16].to_vec();
+            packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend_from_slice(&2u32.to_be_bytes());
+            packet.extend_from_slice(&[7; 20]);
+            packet.extend_from_slice(&[peer_id; 20]);
+            packet.extend_from_slice(&0u64.to_be_bytes());
+            packet.extend_from_slice(&10u64.to_be_bytes());
+            packet.extend_from_slice(&0u64.to_be_bytes());
+            // Event, ip and key
+            packet.extend_from_slice(&[0; 12]);
+            packet.extend_from_slice(&(-1i32).to_be_bytes());
+            packet.extend_from_slice(&from.port().to_be_bytes());
+
+            handle(&packet, from, &server, &secret).unwrap()
+        };
+
+        announce("[::ffff:10.0.0.1]:6881", 1);
+        let response = announce("[::ffff:10.0.0.2]:6882", 2);
+
+        assert_eq!(
+            peers::decode_compact_v4(&response[20..]),
+            Ok(vec!["10.0.0.1:6881".parse().unwrap()])
+        );
+    }
+
+    #[test]
+    fn test_invalid_connection_id() {
+        let secret = RandomState::new();
+        let server = Server::new(ServerOptions::default());
+        let from: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&1u64.to_be_bytes());
+        packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        packet.extend_from_slice(&5u32.to_be_bytes());
+        packet.extend_from_slice(&[0; 20]);
+
+        assert_eq!(
+            handle(&packet, from, &server, &secret),
+            Some(error(5, "invalid connection id"))
+        );
+        assert_eq!(handle(&packet[..10], from, &server, &secret), None);
+
+        let error =
+            client(start("127.0.0.1:0")).announce(&AnnounceRequest::new([7; 20], [1; 20], 0));
+        assert!(matches!(error, Err(TrackerError::Failure(reason)) if reason == "invalid port"));
+    }
+}
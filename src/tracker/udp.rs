@@ -0,0 +1,474 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::peers;
+
+use super::{AnnounceRequest, AnnounceResponse, Event, ScrapeStats, TrackerError};
+
+/// Magic constant sent as the connection id of connect requests.
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+/// Trackers accept a connection id for two minutes; clients should only reuse
+/// it for one.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRIES: u32 = 8;
+const MAX_PACKET_SIZE: usize = 65536;
+/// At most 74 info hashes fit in a scrape request.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+impl Event {
+    pub fn udp_code(&self) -> u32 {
+        match self {
+            Event::None => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+
+    pub fn from_udp_code(code: u32) -> Option<Event> {
+        match code {
+            0 => Some(Event::None),
+            1 => Some(Event::Completed),
+            2 => Some(Event::Started),
+            3 => Some(Event::Stopped),
+            _ => None,
+        }
+    }
+}
+
+/// Client for the UDP tracker protocol (BEP 15).
+pub struct UdpTracker {
+    socket: UdpSocket,
+    address: SocketAddr,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retries: u32,
+}
+
+/// Resolves the host and port of a `udp://host:port/path` URL.
+pub fn resolve(url: &str) -> Result<SocketAddr, TrackerError> {
+    let authority = url
+        .strip_prefix("udp://")
+        .and_then(|rest| rest.split(['/', '?']).next())
+        .filter(|authority| !authority.is_empty())
+        .ok_or_else(|| TrackerError::InvalidResponse(format!("invalid UDP tracker URL {url}")))?;
+
+    authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| TrackerError::InvalidResponse(format!("could not resolve {authority}")))
+}
+
+impl UdpTracker {
+    pub fn new(address: SocketAddr) -> Result<Self, TrackerError> {
+        let bind_address: SocketAddr = if address.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+
+        Ok(UdpTracker {
+            socket: UdpSocket::bind(bind_address)?,
+            address,
+            connection: None,
+            base_timeout: BASE_TIMEOUT,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    pub fn from_url(url: &str) -> Result<Self, TrackerError> {
+        Self::new(resolve(url)?)
+    }
+
+    /// Overrides the retransmission schedule of `base * 2^n` for attempt `n`.
+    pub fn with_timeout(mut self, base_timeout: Duration, max_retries: u32) -> Self {
+        self.base_timeout = base_timeout;
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let body = self.request(ACTION_ANNOUNCE, |packet| {
+            packet.extend_from_slice(&request.info_hash);
+            packet.extend_from_slice(&request.peer_id);
+            packet.extend_from_slice(&request.downloaded.to_be_bytes());
+            packet.extend_from_slice(&request.left.to_be_bytes());
+            packet.extend_from_slice(&request.uploaded.to_be_bytes());
+            packet.extend_from_slice(&request.event.udp_code().to_be_bytes());
+            // Let the tracker use the source address of the packet
+            packet.extend_from_slice(&0u32.to_be_bytes());
+            packet.extend_from_slice(&request.key.unwrap_or(0).to_be_bytes());
+            let numwant = request.numwant.map(|numwant| numwant as i32).unwrap_or(-1);
+            packet.extend_from_slice(&numwant.to_be_bytes());
+            packet.extend_from_slice(&request.port.to_be_bytes());
+        })?;
+
+        if body.len() < 12 {
+            return Err(TrackerError::InvalidResponse(
+                "announce response too short".to_string(),
+            ));
+        }
+
+        // Peers are 18 bytes long when talking to the tracker over IPv6
+        let peers = if self.address.is_ipv6() {
+            peers::decode_compact_v6(&body[12..])?
+        } else {
+            peers::decode_compact_v4(&body[12..])?
+        };
+
+        Ok(AnnounceResponse {
+            interval: read_u32(&body, 0),
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
+            incomplete: Some(read_u32(&body, 4)),
+            complete: Some(read_u32(&body, 8)),
+            peers,
+        })
+    }
+
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        if info_hashes.is_empty() || info_hashes.len() > MAX_SCRAPE_HASHES {
+            return Err(TrackerError::InvalidResponse(format!(
+                "a scrape must have between 1 and {MAX_SCRAPE_HASHES} info hashes"
+            )));
+        }
+
+        let body = self.request(ACTION_SCRAPE, |packet| {
+            info_hashes
+                .iter()
+                .for_each(|info_hash| packet.extend_from_slice(info_hash))
+        })?;
+
+        if body.len() != info_hashes.len() * 12 {
+            return Err(TrackerError::InvalidResponse(
+                "scrape response does not match the requested info hashes".to_string(),
+            ));
+        }
+
+        Ok(body
+            .chunks(12)
+            .map(|stats| ScrapeStats {
+                complete: read_u32(stats, 0),
+                downloaded: read_u32(stats, 4),
+                incomplete: read_u32(stats, 8),
+            })
+            .collect())
+    }
+
+    /// Returns the cached connection id, connecting again once it expired.
+    fn connection_id(&mut self) -> Result<u64, TrackerError> {
+        if let Some((connection_id, obtained)) = self.connection {
+            if obtained.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+        }
+
+        let body = self.transact(PROTOCOL_ID, ACTION_CONNECT, |_| {})?;
+        if body.len() < 8 {
+            return Err(TrackerError::InvalidResponse(
+                "connect response too short".to_string(),
+            ));
+        }
+
+        let connection_id = u64::from_be_bytes(body[..8].try_into().unwrap());
+        self.connection = Some((connection_id, Instant::now()));
+
+        Ok(connection_id)
+    }
+
+    fn request(
+        &mut self,
+        action: u32,
+        body: impl Fn(&mut Vec<u8>),
+    ) -> Result<Vec<u8>, TrackerError> {
+        let connection_id = self.connection_id()?;
+
+        self.transact(connection_id, action, body)
+    }
+
+    /// Sends a request until a response with the same transaction id arrives,
+    /// doubling the timeout after every attempt. Returns the response body
+    /// following the action and transaction id.
+    fn transact(
+        &mut self,
+        connection_id: u64,
+        action: u32,
+        body: impl Fn(&mut Vec<u8>),
+    ) -> Result<Vec<u8>, TrackerError> {
+        let transaction_id: u32 = rand::random();
+
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        body(&mut packet);
+
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+
+        for attempt in 0..=self.max_retries {
+            self.socket.send_to(&packet, self.address)?;
+
+            let deadline = Instant::now() + self.base_timeout * 2u32.pow(attempt);
+
+            while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                self.socket
+                    .set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+                let (read, from) = match self.socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(error)
+                        if matches!(
+                            error.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break
+                    }
+                    Err(error) => return Err(error.into()),
+                };
+
+                let response = &buffer[..read];
+                if from != self.address || read < 8 || read_u32(response, 4) != transaction_id {
+                    continue;
+                }
+
+                return match read_u32(response, 0) {
+                    ACTION_ERROR => Err(TrackerError::Failure(
+                        String::from_utf8_lossy(&response[8..]).to_string(),
+                    )),
+                    response_action if response_action == action => Ok(response[8..].to_vec()),
+                    _ => Err(TrackerError::InvalidResponse(
+                        "unexpected action in response".to_string(),
+                    )),
+                };
+            }
+
+            // The connection id may have expired while we were retrying
+            if action != ACTION_CONNECT {
+                if let Some((_, obtained)) = self.connection {
+                    if obtained.elapsed() >= CONNECTION_ID_LIFETIME {
+                        self.connection = None;
+                        let connection_id = self.connection_id()?;
+                        packet[..8].copy_from_slice(&connection_id.to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        Err(TrackerError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "UDP tracker did not respond",
+        )))
+    }
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+
+    /// Stand-in tracker answering `requests` packets. Packets whose index is
+    /// in `drop` are ignored to exercise retransmission. Every received
+    /// packet is sent back through the returned channel.
+    fn stand_in_tracker(
+        bind: &str,
+        requests: usize,
+        drop: Vec<usize>,
+    ) -> (SocketAddr, mpsc::Receiver<Vec<u8>>) {
+        let socket = UdpSocket::bind(bind).unwrap();
+        let address = socket.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut buffer = [0; 2048];
+
+            for index in 0..requests {
+                let (read, from) = socket.recv_from(&mut buffer).unwrap();
+                let packet = buffer[..read].to_vec();
+                sender.send(packet.clone()).unwrap();
+
+                if drop.contains(&index) {
+                    continue;
+                }
+
+                let action = read_u32(&packet, 8);
+                let mut response = Vec::new();
+                response.extend_from_slice(&packet[8..16]);
+
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(&packet[..8], &PROTOCOL_ID.to_be_bytes());
+                        response.extend_from_slice(&0x1122334455667788u64.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        if packet[16..36] == [0xee; 20] {
+                            response[..4].copy_from_slice(&ACTION_ERROR.to_be_bytes());
+                            response.extend_from_slice(b"unknown torrent");
+                        } else {
+                            response.extend_from_slice(&1800u32.to_be_bytes());
+                            response.extend_from_slice(&3u32.to_be_bytes());
+                            response.extend_from_slice(&7u32.to_be_bytes());
+                            let peer: SocketAddr = if from.is_ipv6() {
+                                "[2001:db8::1]:6881".parse().unwrap()
+                            } else {
+                                "10.1.2.3:6881".parse().unwrap()
+                            };
+                            response.extend_from_slice(&peers::encode_compact_peer(&peer));
+                        }
+                    }
+                    ACTION_SCRAPE => {
+                        for (i, _) in packet[16..].chunks(20).enumerate() {
+                            response.extend_from_slice(&(10 + i as u32).to_be_bytes());
+                            response.extend_from_slice(&20u32.to_be_bytes());
+                            response.extend_from_slice(&30u32.to_be_bytes());
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+
+        (address, receiver)
+    }
+
+    #[test]
+    fn test_announce() {
+        let (address, packets) = stand_in_tracker("127.0.0.1:0", 2, vec![]);
+        let mut tracker = UdpTracker::new(address).unwrap();
+
+        let mut request = AnnounceRequest::new([1; 20], [2; 20], 6881);
+        request.event = Event::Started;
+        request.left = 1000;
+
+        let response = tracker.announce(&request).unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(response.complete, Some(7));
+        assert_eq!(response.peers, vec!["10.1.2.3:6881".parse().unwrap()]);
+
+        let _connect = packets.recv().unwrap();
+        let announce = packets.recv().unwrap();
+        assert_eq!(announce.len(), 98);
+        assert_eq!(&announce[..8], &0x1122334455667788u64.to_be_bytes());
+        assert_eq!(read_u32(&announce, 80), Event::Started.udp_code());
+        assert_eq!(&announce[96..], &6881u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_connection_id_is_cached() {
+        let (address, packets) = stand_in_tracker("127.0.0.1:0", 3, vec![]);
+        let mut tracker = UdpTracker::new(address).unwrap();
+
+        tracker
+            .announce(&AnnounceRequest::new([1; 20], [2; 20], 6881))
+            .unwrap();
+        let stats = tracker.scrape(&[[1; 20], [3; 20]]).unwrap();
+
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 10,
+                    downloaded: 20,
+                    incomplete: 30
+                },
+                ScrapeStats {
+                    complete: 11,
+                    downloaded: 20,
+                    incomplete: 30
+                }
+            ]
+        );
+
+        let actions: Vec<u32> = packets.iter().take(3).map(|p| read_u32(&p, 8)).collect();
+        assert_eq!(
+            actions,
+            vec![ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_SCRAPE]
+        );
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let (address, packets) = stand_in_tracker("127.0.0.1:0", 4, vec![0, 2]);
+        let mut tracker = UdpTracker::new(address)
+            .unwrap()
+            .with_timeout(Duration::from_millis(50), 3);
+
+        let response = tracker
+            .announce(&AnnounceRequest::new([1; 20], [2; 20], 6881))
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+
+        let packets: Vec<Vec<u8>> = packets.iter().take(4).collect();
+        // Retransmissions reuse the same transaction id
+        assert_eq!(packets[0], packets[1]);
+        assert_eq!(packets[2], packets[3]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (address, _packets) = stand_in_tracker("127.0.0.1:0", 2, vec![0, 1]);
+        let mut tracker = UdpTracker::new(address)
+            .unwrap()
+            .with_timeout(Duration::from_millis(10), 1);
+
+        assert!(matches!(
+            tracker.announce(&AnnounceRequest::new([1; 20], [2; 20], 6881)),
+            Err(TrackerError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_error_action() {
+        let (address, _packets) = stand_in_tracker("127.0.0.1:0", 2, vec![]);
+        let mut tracker = UdpTracker::new(address).unwrap();
+
+        match tracker.announce(&AnnounceRequest::new([0xee; 20], [2; 20], 6881)) {
+            Err(TrackerError::Failure(message)) => assert_eq!(message, "unknown torrent"),
+            other => panic!("unexpected response {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_ipv6_announce() {
+        let (address, _packets) = stand_in_tracker("[::1]:0", 2, vec![]);
+        let mut tracker = UdpTracker::from_url(&format!("udp://{address}/announce")).unwrap();
+
+        let response = tracker
+            .announce(&AnnounceRequest::new([1; 20], [2; 20], 6881))
+            .unwrap();
+
+        assert_eq!(response.peers, vec!["[2001:db8::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("udp://127.0.0.1:6969/announce").unwrap(),
+            "127.0.0.1:6969".parse().unwrap()
+        );
+        assert!(resolve("http://127.0.0.1:6969/announce").is_err());
+    }
+}
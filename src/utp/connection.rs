@@ -0,0 +1,782 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::packet::{Packet, PacketType};
+
+/// Largest payload of a data packet, small enough to avoid fragmentation.
+pub const MAX_PAYLOAD: usize = 1200;
+/// Bytes buffered for reading before the advertised window closes.
+pub const RECEIVE_BUFFER: usize = 1 << 20;
+/// Bytes accepted from the writer before writes block.
+pub const SEND_BUFFER: usize = 1 << 20;
+
+/// Queuing delay LEDBAT aims for.
+const TARGET_DELAY_US: f64 = 100_000.0;
+/// Most the congestion window grows by in one round trip.
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = 4.0 * MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+/// Base delays are kept per minute, for this many minutes.
+const DELAY_HISTORY: usize = 2;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+/// Timeouts in a row after which the connection is given up.
+const MAX_TIMEOUTS: u32 = 7;
+/// Duplicate ACKs, or packets selectively acknowledged past an unacked
+/// one, after which it counts as lost.
+const LOSS_THRESHOLD: usize = 3;
+/// Packets further than this ahead of the last in-order one are dropped.
+const MAX_OUT_OF_ORDER: u16 = 1024;
+/// Bits in the selective ACK bitmask we send.
+const SELECTIVE_ACK_BITS: u16 = 32;
+
+/// Whether sequence number `a` comes before `b`, with wrapping.
+fn seq_before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+/// Whether delay `a` is below `b`. Delays mix the clocks of both sides, so
+/// they are only meaningful relative to each other, and may wrap.
+fn delay_below(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000_0000
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum State {
+    SynSent,
+    Connected,
+    /// Both sides sent a FIN that was acknowledged.
+    Closed,
+    Reset,
+    TimedOut,
+}
+
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Lost, and waiting for the window to be resent.
+    resend: bool,
+    /// Packets sent after this transmission that were selectively acked.
+    sacked_after: usize,
+}
+
+/// One uTP connection, without I/O: packets are fed in with `on_packet`,
+/// and the packets to send are taken out with `poll_transmit`.
+pub struct Connection {
+    state: State,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    /// Next sequence number to send.
+    seq_nr: u16,
+    /// Last sequence number received in order.
+    ack_nr: u16,
+    epoch: Instant,
+    finished_at: Option<Instant>,
+
+    send_buffer: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    cwnd: f64,
+    peer_window: u32,
+    /// Smoothed round trip time and its variation.
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    timeout_at: Option<Instant>,
+    timeouts: u32,
+    last_ack: u16,
+    duplicate_acks: usize,
+    /// Losses of packets sent before this one do not shrink the window
+    /// again.
+    recovery_until: Option<u16>,
+    /// Lowest delay measured in each of the last minutes.
+    base_delays: VecDeque<u32>,
+    base_delay_started: Instant,
+    closing: bool,
+    fin_seq: Option<u16>,
+    fin_acked: bool,
+
+    out_of_order: HashMap<u16, (PacketType, Vec<u8>)>,
+    read_buffer: VecDeque<u8>,
+    eof: bool,
+    /// Delay of the last packet received, echoed to the remote.
+    reply_micro: u32,
+    outgoing: Vec<Packet>,
+}
+
+impl Connection {
+    fn new(remote: SocketAddr, recv_id: u16, send_id: u16, seq_nr: u16, now: Instant) -> Self {
+        Connection {
+            state: State::SynSent,
+            remote,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            epoch: now,
+            finished_at: None,
+            send_buffer: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            cwnd: INITIAL_WINDOW,
+            peer_window: MAX_PAYLOAD as u32,
+            rtt: None,
+            rto: INITIAL_RTO,
+            timeout_at: None,
+            timeouts: 0,
+            last_ack: 0,
+            duplicate_acks: 0,
+            recovery_until: None,
+            base_delays: VecDeque::new(),
+            base_delay_started: now,
+            closing: false,
+            fin_seq: None,
+            fin_acked: false,
+            out_of_order: HashMap::new(),
+            read_buffer: VecDeque::new(),
+            eof: false,
+            reply_micro: 0,
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Opens a connection receiving on `recv_id`, sending the SYN.
+    pub fn connect(remote: SocketAddr, recv_id: u16, now: Instant) -> Self {
+        let mut connection = Connection::new(remote, recv_id, recv_id.wrapping_add(1), 1, now);
+
+        // The SYN is the only packet sent with the receive id
+        let syn = Packet::new(PacketType::Syn, recv_id, 1, 0);
+        connection.seq_nr = 2;
+        connection.queue(syn, now);
+
+        connection
+    }
+
+    /// Accepts a connection from its SYN, acknowledging it.
+    pub fn accept(syn: &Packet, remote: SocketAddr, now: Instant) -> Self {
+        let mut connection = Connection::new(
+            remote,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            rand::random(),
+            now,
+        );
+        connection.state = State::Connected;
+        connection.ack_nr = syn.seq_nr;
+        connection.peer_window = syn.window;
+        connection.reply_micro = connection.now_us(now).wrapping_sub(syn.timestamp);
+        connection.send_state(now);
+
+        connection
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    pub fn send_id(&self) -> u16 {
+        self.send_id
+    }
+
+    /// Whether the connection is over, for good or bad, and since when.
+    pub fn finished_at(&self) -> Option<Instant> {
+        self.finished_at
+    }
+
+    /// Whether a read would return data, end of stream, or an error.
+    pub fn is_readable(&self) -> bool {
+        !self.read_buffer.is_empty() || self.eof || self.is_broken()
+    }
+
+    /// Whether the connection failed, rather than closed.
+    pub fn is_broken(&self) -> bool {
+        matches!(self.state, State::Reset | State::TimedOut)
+    }
+
+    /// Whether everything written, and the FIN, was acknowledged.
+    pub fn is_flushed(&self) -> bool {
+        self.in_flight.is_empty() && self.send_buffer.is_empty()
+    }
+
+    /// Whether written data still waits to be sent.
+    pub fn has_unsent(&self) -> bool {
+        !self.send_buffer.is_empty()
+    }
+
+    pub fn congestion_window(&self) -> usize {
+        self.cwnd as usize
+    }
+
+    pub fn poll_transmit(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn now_us(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn receive_window(&self) -> u32 {
+        let buffered = self.read_buffer.len()
+            + self
+                .out_of_order
+                .values()
+                .map(|(_, payload)| payload.len())
+                .sum::<usize>();
+        RECEIVE_BUFFER.saturating_sub(buffered) as u32
+    }
+
+    fn in_flight_bytes(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|sent| !sent.resend)
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    fn finish(&mut self, state: State, now: Instant) {
+        self.state = state;
+        self.finished_at.get_or_insert(now);
+        self.timeout_at = None;
+    }
+
+    /// Fills in the header fields that change with every transmission.
+    fn stamp(&self, packet: &mut Packet, now: Instant) {
+        if packet.kind != PacketType::Syn {
+            packet.connection_id = self.send_id;
+        }
+        packet.timestamp = self.now_us(now);
+        packet.timestamp_diff = self.reply_micro;
+        packet.window = self.receive_window();
+        packet.ack_nr = self.ack_nr;
+    }
+
+    fn send_state(&mut self, now: Instant) {
+        let mut state = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        self.stamp(&mut state, now);
+
+        if !self.out_of_order.is_empty() {
+            let mut mask = vec![0; SELECTIVE_ACK_BITS as usize / 8];
+            for bit in 0..SELECTIVE_ACK_BITS {
+                if self
+                    .out_of_order
+                    .contains_key(&self.ack_nr.wrapping_add(2 + bit))
+                {
+                    mask[bit as usize / 8] |= 1 << (bit % 8);
+                }
+            }
+            state.selective_ack = Some(mask);
+        }
+
+        self.outgoing.push(state);
+    }
+
+    /// Adds a packet that takes a sequence number, and sends it.
+    fn queue(&mut self, packet: Packet, now: Instant) {
+        self.in_flight.push_back(Sent {
+            packet,
+            sent_at: now,
+            transmissions: 0,
+            resend: true,
+            sacked_after: 0,
+        });
+        self.transmit(self.in_flight.len() - 1, now);
+    }
+
+    fn transmit(&mut self, index: usize, now: Instant) {
+        let mut packet = self.in_flight[index].packet.clone();
+        self.stamp(&mut packet, now);
+
+        let sent = &mut self.in_flight[index];
+        sent.packet = packet.clone();
+        sent.sent_at = now;
+        sent.transmissions += 1;
+        sent.resend = false;
+        sent.sacked_after = 0;
+
+        self.outgoing.push(packet);
+        self.timeout_at.get_or_insert(now + self.rto);
+    }
+
+    /// Sends what the windows allow: lost packets first, then new data, then
+    /// the FIN once everything was sent.
+    pub fn flush(&mut self, now: Instant) {
+        if !matches!(self.state, State::SynSent | State::Connected) {
+            return;
+        }
+
+        let window = (self.cwnd as usize).min(self.peer_window as usize);
+        // One packet may always be in flight, probing a closed window
+        let fits = |connection: &Connection, length: usize| {
+            let in_flight = connection.in_flight_bytes();
+            in_flight == 0 || in_flight + length <= window
+        };
+
+        for index in 0..self.in_flight.len() {
+            if self.in_flight[index].resend {
+                if !fits(self, self.in_flight[index].packet.payload.len()) {
+                    return;
+                }
+                self.transmit(index, now);
+            }
+        }
+
+        if self.state != State::Connected {
+            return;
+        }
+
+        while !self.send_buffer.is_empty() {
+            let length = self.send_buffer.len().min(MAX_PAYLOAD);
+            if !fits(self, length) {
+                return;
+            }
+
+            let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, self.ack_nr);
+            packet.payload = self.send_buffer.drain(..length).collect();
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.queue(packet, now);
+        }
+
+        if self.closing && self.fin_seq.is_none() {
+            let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, self.ack_nr);
+            self.fin_seq = Some(self.seq_nr);
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.queue(fin, now);
+        }
+    }
+
+    /// Buffers data to send, returning how much fit in the send buffer.
+    pub fn write(&mut self, data: &[u8], now: Instant) -> usize {
+        if self.state != State::Connected || self.closing {
+            return 0;
+        }
+
+        let accepted = data.len().min(SEND_BUFFER - self.send_buffer.len());
+        self.send_buffer.extend(&data[..accepted]);
+        self.flush(now);
+
+        accepted
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8], now: Instant) -> usize {
+        let was_closing = self.receive_window() < (RECEIVE_BUFFER / 4) as u32;
+
+        let length = buffer.len().min(self.read_buffer.len());
+        for (byte, read) in buffer.iter_mut().zip(self.read_buffer.drain(..length)) {
+            *byte = read;
+        }
+
+        // Tell a sender stalled by our window that it opened again
+        if was_closing && self.receive_window() >= (RECEIVE_BUFFER / 4) as u32 {
+            self.send_state(now);
+        }
+
+        length
+    }
+
+    /// Sends a FIN once the buffered data was sent.
+    pub fn close(&mut self, now: Instant) {
+        self.closing = true;
+        self.flush(now);
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant) {
+        if packet.kind == PacketType::Reset {
+            if !matches!(self.state, State::Closed | State::TimedOut) {
+                self.finish(State::Reset, now);
+            }
+            return;
+        }
+
+        match self.state {
+            State::Reset | State::TimedOut => return,
+            // Our ACK of the remote FIN may have been lost
+            State::Closed => {
+                if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+                    self.send_state(now);
+                }
+                return;
+            }
+            State::SynSent => {
+                if packet.kind != PacketType::State {
+                    return;
+                }
+                // State packets carry the next sequence number of the sender
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                self.state = State::Connected;
+            }
+            State::Connected => {}
+        }
+
+        self.reply_micro = self.now_us(now).wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window;
+
+        match packet.kind {
+            // The remote did not get our reply to its SYN
+            PacketType::Syn => self.send_state(now),
+            PacketType::State => self.on_ack(&packet, now),
+            PacketType::Data | PacketType::Fin => {
+                self.on_ack(&packet, now);
+                self.receive(packet, now);
+            }
+            PacketType::Reset => unreachable!(),
+        }
+
+        if self.fin_acked && self.eof {
+            self.finish(State::Closed, now);
+        }
+
+        self.flush(now);
+    }
+
+    fn receive(&mut self, packet: Packet, now: Instant) {
+        let seq = packet.seq_nr;
+
+        if seq == self.ack_nr.wrapping_add(1) {
+            self.deliver(packet.kind, packet.payload);
+            self.ack_nr = seq;
+
+            while let Some((kind, payload)) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1))
+            {
+                self.deliver(kind, payload);
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+            }
+        } else if seq_before(self.ack_nr, seq) && seq.wrapping_sub(self.ack_nr) <= MAX_OUT_OF_ORDER
+        {
+            self.out_of_order
+                .entry(seq)
+                .or_insert((packet.kind, packet.payload));
+        }
+
+        self.send_state(now);
+    }
+
+    fn deliver(&mut self, kind: PacketType, payload: Vec<u8>) {
+        if self.eof {
+            return;
+        }
+
+        match kind {
+            PacketType::Fin => self.eof = true,
+            _ => self.read_buffer.extend(payload),
+        }
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let ack = packet.ack_nr;
+        let mut acked = Vec::new();
+
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|sent| !seq_before(ack, sent.packet.seq_nr))
+        {
+            acked.push(self.in_flight.pop_front().unwrap());
+        }
+
+        let mut lost = Vec::new();
+        if let Some(mask) = &packet.selective_ack {
+            let selected: Vec<u16> = (0..mask.len() * 8)
+                .filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+                .map(|bit| ack.wrapping_add(2 + bit as u16))
+                .collect();
+
+            let (sacked, remaining): (VecDeque<Sent>, _) = std::mem::take(&mut self.in_flight)
+                .into_iter()
+                .partition(|sent| selected.contains(&sent.packet.seq_nr));
+            self.in_flight = remaining;
+
+            // Packets with enough selectively acked ones sent after them are
+            // lost
+            for sent in &mut self.in_flight {
+                sent.sacked_after += sacked
+                    .iter()
+                    .filter(|sacked| {
+                        seq_before(sent.packet.seq_nr, sacked.packet.seq_nr)
+                            && sacked.sent_at >= sent.sent_at
+                    })
+                    .count();
+                if sent.sacked_after >= LOSS_THRESHOLD && !sent.resend {
+                    sent.resend = true;
+                    lost.push(sent.packet.seq_nr);
+                }
+            }
+            acked.extend(sacked);
+        }
+
+        if acked.is_empty() {
+            let duplicate = packet.kind == PacketType::State
+                && ack == self.last_ack
+                && !self.in_flight.is_empty();
+            if duplicate {
+                self.duplicate_acks += 1;
+                if self.duplicate_acks == LOSS_THRESHOLD {
+                    let front = self.in_flight.front_mut().unwrap();
+                    front.resend = true;
+                    lost.push(front.packet.seq_nr);
+                }
+            }
+        } else {
+            self.duplicate_acks = 0;
+            self.timeouts = 0;
+            self.timeout_at = (!self.in_flight.is_empty()).then(|| now + self.rto);
+
+            let mut bytes_acked = 0;
+            for sent in &acked {
+                bytes_acked += sent.packet.payload.len();
+                if Some(sent.packet.seq_nr) == self.fin_seq {
+                    self.fin_acked = true;
+                }
+            }
+
+            // Round trips of resent packets are ambiguous
+            if let Some(sent) = acked
+                .iter()
+                .filter(|sent| sent.transmissions == 1)
+                .max_by_key(|sent| sent.sent_at)
+            {
+                self.update_rtt(now.duration_since(sent.sent_at));
+            }
+
+            self.update_window(bytes_acked, packet.timestamp_diff, now);
+        }
+        self.last_ack = ack;
+
+        if let Some(seq) = lost.into_iter().min_by_key(|seq| seq.wrapping_sub(ack)) {
+            self.on_loss(seq);
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let (rtt, variation) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variation)) => {
+                let difference = rtt.abs_diff(sample);
+                ((rtt * 7 + sample) / 8, (variation * 3 + difference) / 4)
+            }
+        };
+
+        self.rtt = Some((rtt, variation));
+        self.rto = (rtt + variation * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// LEDBAT: grows the window while the queuing delay the remote measures
+    /// is below target, and shrinks it above.
+    fn update_window(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        // A delay of 0 means the remote did not measure one yet
+        if delay != 0 {
+            if self.base_delays.is_empty()
+                || now.duration_since(self.base_delay_started) >= Duration::from_secs(60)
+            {
+                self.base_delays.push_back(delay);
+                self.base_delay_started = now;
+                if self.base_delays.len() > DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+            let current = self.base_delays.back_mut().unwrap();
+            if delay_below(delay, *current) {
+                *current = delay;
+            }
+        }
+
+        let queuing = match self.base_delays.iter().copied().reduce(|base, delay| {
+            if delay_below(delay, base) {
+                delay
+            } else {
+                base
+            }
+        }) {
+            Some(base) if delay != 0 => delay.wrapping_sub(base) as f64,
+            _ => 0.0,
+        };
+        let off_target = ((TARGET_DELAY_US - queuing) / TARGET_DELAY_US).clamp(-1.0, 1.0);
+
+        let window_factor = bytes_acked as f64 / self.cwnd.max(bytes_acked as f64);
+        self.cwnd += MAX_CWND_INCREASE_PER_RTT * window_factor * off_target;
+        self.cwnd = self.cwnd.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn on_loss(&mut self, seq: u16) {
+        if self
+            .recovery_until
+            .is_some_and(|recovery_until| seq_before(seq, recovery_until))
+        {
+            return;
+        }
+
+        self.cwnd = (self.cwnd / 2.0).max(MIN_WINDOW);
+        self.recovery_until = Some(self.seq_nr);
+    }
+
+    /// Resends everything in flight once the oldest packet timed out, and
+    /// gives up after too many timeouts in a row.
+    pub fn on_tick(&mut self, now: Instant) {
+        if self.timeout_at.is_none_or(|timeout_at| now < timeout_at) {
+            return;
+        }
+        self.timeout_at = None;
+
+        if self.in_flight.is_empty() {
+            return;
+        }
+
+        self.timeouts += 1;
+        if self.timeouts > MAX_TIMEOUTS {
+            self.finish(State::TimedOut, now);
+            return;
+        }
+
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.cwnd = MIN_WINDOW;
+        self.recovery_until = Some(self.seq_nr);
+        for sent in &mut self.in_flight {
+            sent.resend = true;
+        }
+
+        self.flush(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses() -> (SocketAddr, SocketAddr) {
+        (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        )
+    }
+
+    /// Connects two connections, delivering every packet.
+    fn pair(now: Instant) -> (Connection, Connection) {
+        let (a, b) = addresses();
+        let mut client = Connection::connect(b, 100, now);
+        let syn = client.poll_transmit().pop().unwrap();
+        assert_eq!(syn.kind, PacketType::Syn);
+
+        let mut server = Connection::accept(&syn, a, now);
+        assert_eq!((server.recv_id(), server.send_id()), (101, 100));
+        for packet in server.poll_transmit() {
+            client.on_packet(packet, now);
+        }
+        assert_eq!(client.state(), State::Connected);
+
+        (client, server)
+    }
+
+    fn deliver(packets: Vec<Packet>, to: &mut Connection, now: Instant) {
+        for packet in packets {
+            to.on_packet(packet, now);
+        }
+    }
+
+    #[test]
+    fn test_selective_ack_recovery() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+
+        let data: Vec<u8> = (0..MAX_PAYLOAD * 4).map(|index| index as u8).collect();
+        assert_eq!(client.write(&data, now), data.len());
+        let mut packets = client.poll_transmit();
+        assert_eq!(packets.len(), 4);
+
+        // The first packet is lost, and the others are acknowledged
+        // selectively
+        let lost = packets.remove(0);
+        deliver(packets, &mut server, now);
+        let acks = server.poll_transmit();
+        assert_eq!(
+            acks.last().unwrap().selective_ack,
+            Some(vec![0b111, 0, 0, 0])
+        );
+        assert!(!server.is_readable());
+
+        deliver(acks, &mut client, now);
+        let resent = client.poll_transmit();
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq_nr, lost.seq_nr);
+        assert!(client.congestion_window() < INITIAL_WINDOW as usize);
+
+        deliver(resent, &mut server, now);
+        let mut received = vec![0; data.len() + 10];
+        assert_eq!(server.read(&mut received, now), data.len());
+        assert_eq!(received[..data.len()], data);
+    }
+
+    #[test]
+    fn test_close() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+
+        client.write(b"bye", now);
+        client.close(now);
+        let packets = client.poll_transmit();
+        assert_eq!(packets.last().unwrap().kind, PacketType::Fin);
+        deliver(packets, &mut server, now);
+
+        let mut buffer = [0; 8];
+        assert_eq!(server.read(&mut buffer, now), 3);
+        assert!(server.is_readable());
+        assert_eq!(server.read(&mut buffer, now), 0);
+
+        server.close(now);
+        deliver(server.poll_transmit(), &mut client, now);
+        deliver(client.poll_transmit(), &mut server, now);
+        assert_eq!(client.state(), State::Closed);
+        assert_eq!(server.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let now = Instant::now();
+        let (mut client, _) = pair(now);
+
+        client.write(&[0; MAX_PAYLOAD * 2], now);
+        assert_eq!(client.poll_transmit().len(), 2);
+
+        // Everything is resent, within the window that shrank to a packet
+        client.on_tick(now + INITIAL_RTO);
+        let resent = client.poll_transmit();
+        assert_eq!(resent.len(), 1);
+        assert_eq!(client.congestion_window(), MAX_PAYLOAD);
+
+        let mut later = now;
+        for _ in 0..MAX_TIMEOUTS {
+            later += MAX_RTO;
+            client.on_tick(later);
+        }
+        assert_eq!(client.state(), State::TimedOut);
+        assert!(client.is_broken());
+    }
+
+    #[test]
+    fn test_ledbat() {
+        let now = Instant::now();
+        let (mut client, _) = pair(now);
+        let window = client.congestion_window();
+
+        // Acks without queuing delay grow the window
+        client.update_window(MAX_PAYLOAD, 20_000, now);
+        client.update_window(MAX_PAYLOAD, 20_000, now);
+        assert!(client.congestion_window() > window);
+
+        // Delays far above target shrink it
+        let window = client.congestion_window();
+        client.update_window(MAX_PAYLOAD, 320_000, now);
+        assert!(client.congestion_window() < window);
+    }
+}
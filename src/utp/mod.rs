@@ -0,0 +1,466 @@
+pub mod connection;
+pub mod packet;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use connection::{Connection, State};
+use packet::{Packet, PacketType};
+
+/// How often timeouts are checked.
+const TICK: Duration = Duration::from_millis(10);
+/// Connections not yet accepted; further SYNs are ignored.
+const BACKLOG: usize = 64;
+/// Finished connections are kept this long to acknowledge retransmitted
+/// FINs.
+const LINGER: Duration = Duration::from_secs(10);
+const MAX_DATAGRAM: usize = 65536;
+
+struct Entry {
+    connection: Mutex<Connection>,
+    /// Notified whenever the connection may have become readable, writable
+    /// or finished.
+    changed: Condvar,
+    /// Whether the stream was dropped.
+    detached: AtomicBool,
+}
+
+impl Entry {
+    fn new(connection: Connection) -> Arc<Self> {
+        Arc::new(Entry {
+            connection: Mutex::new(connection),
+            changed: Condvar::new(),
+            detached: AtomicBool::new(false),
+        })
+    }
+}
+
+struct Shared {
+    socket: UdpSocket,
+    /// Connections by remote address and receive id.
+    connections: Mutex<HashMap<(SocketAddr, u16), Arc<Entry>>>,
+    incoming: Mutex<VecDeque<Arc<Entry>>>,
+    accepted: Condvar,
+}
+
+impl Shared {
+    /// Sends the packets the connection queued.
+    fn transmit(&self, connection: &mut Connection) {
+        for packet in connection.poll_transmit() {
+            // Lost datagrams are resent like any other loss
+            let _ = self.socket.send_to(&packet.encode(), connection.remote());
+        }
+    }
+
+    fn lookup(&self, key: (SocketAddr, u16)) -> Option<Arc<Entry>> {
+        self.connections.lock().unwrap().get(&key).cloned()
+    }
+
+    fn dispatch(&self, bytes: &[u8], from: SocketAddr, now: Instant) {
+        let Ok(packet) = Packet::decode(bytes) else {
+            return;
+        };
+
+        let entry = match packet.kind {
+            PacketType::Syn => {
+                let key = (from, packet.connection_id.wrapping_add(1));
+                match self.lookup(key) {
+                    Some(entry) => entry,
+                    None => return self.on_syn(&packet, from, key, now),
+                }
+            }
+            // A reset carries the id its sender sends with, one off ours
+            PacketType::Reset => {
+                let id = packet.connection_id;
+                let found = [id.wrapping_sub(1), id.wrapping_add(1)]
+                    .into_iter()
+                    .filter_map(|recv_id| self.lookup((from, recv_id)))
+                    .find(|entry| entry.connection.lock().unwrap().send_id() == id);
+                match found {
+                    Some(entry) => entry,
+                    None => return,
+                }
+            }
+            _ => match self.lookup((from, packet.connection_id)) {
+                Some(entry) => entry,
+                None => {
+                    let reset = Packet::new(
+                        PacketType::Reset,
+                        packet.connection_id,
+                        rand::random(),
+                        packet.seq_nr,
+                    );
+                    let _ = self.socket.send_to(&reset.encode(), from);
+                    return;
+                }
+            },
+        };
+
+        let mut connection = entry.connection.lock().unwrap();
+        connection.on_packet(packet, now);
+        self.transmit(&mut connection);
+        entry.changed.notify_all();
+    }
+
+    fn on_syn(&self, syn: &Packet, from: SocketAddr, key: (SocketAddr, u16), now: Instant) {
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.len() >= BACKLOG {
+            return;
+        }
+
+        let mut connection = Connection::accept(syn, from, now);
+        self.transmit(&mut connection);
+
+        let entry = Entry::new(connection);
+        self.connections.lock().unwrap().insert(key, entry.clone());
+        incoming.push_back(entry);
+        self.accepted.notify_one();
+    }
+
+    /// Checks timeouts, and forgets connections both sides are done with.
+    fn tick(&self, now: Instant) {
+        let entries: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, entry)| (*key, entry.clone()))
+            .collect();
+
+        for (key, entry) in entries {
+            let mut connection = entry.connection.lock().unwrap();
+            connection.on_tick(now);
+            self.transmit(&mut connection);
+            entry.changed.notify_all();
+
+            let expired = connection
+                .finished_at()
+                .is_some_and(|finished_at| now.duration_since(finished_at) >= LINGER);
+            if expired && entry.detached.load(Ordering::Relaxed) {
+                self.connections.lock().unwrap().remove(&key);
+            }
+        }
+    }
+
+    /// Whether no connection has anything left to deliver.
+    fn is_idle(&self) -> bool {
+        self.connections.lock().unwrap().values().all(|entry| {
+            let connection = entry.connection.lock().unwrap();
+            connection.finished_at().is_some() || connection.is_flushed()
+        })
+    }
+}
+
+/// Receives datagrams for every connection of the socket. Once the socket
+/// and its streams are all dropped, it keeps going until the data written
+/// to them was delivered.
+fn run(shared: Arc<Shared>) {
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    let mut last_tick = Instant::now();
+
+    while Arc::strong_count(&shared) > 1 || !shared.is_idle() {
+        if let Ok((length, from)) = shared.socket.recv_from(&mut buffer) {
+            shared.dispatch(&buffer[..length], from, Instant::now());
+        }
+
+        let now = Instant::now();
+        if now.duration_since(last_tick) >= TICK {
+            shared.tick(now);
+            last_tick = now;
+        }
+    }
+}
+
+/// A UDP socket carrying uTP connections (BEP 29), both outgoing and
+/// incoming.
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+}
+
+impl UtpSocket {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(TICK))?;
+
+        let shared = Arc::new(Shared {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            incoming: Mutex::new(VecDeque::new()),
+            accepted: Condvar::new(),
+        });
+
+        let background = shared.clone();
+        thread::spawn(move || run(background));
+
+        Ok(UtpSocket { shared })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    /// Connects to `remote`, blocking until it accepts or the connection
+    /// times out.
+    pub fn connect(&self, remote: SocketAddr) -> io::Result<UtpStream> {
+        let entry = {
+            let mut connections = self.shared.connections.lock().unwrap();
+            let recv_id = loop {
+                let recv_id: u16 = rand::random();
+                if !connections.contains_key(&(remote, recv_id)) {
+                    break recv_id;
+                }
+            };
+
+            let mut connection = Connection::connect(remote, recv_id, Instant::now());
+            self.shared.transmit(&mut connection);
+
+            let entry = Entry::new(connection);
+            connections.insert((remote, recv_id), entry.clone());
+            entry
+        };
+
+        let stream = UtpStream {
+            shared: self.shared.clone(),
+            entry,
+            read_timeout: None,
+        };
+
+        let mut connection = stream.lock();
+        while connection.state() == State::SynSent {
+            connection = stream.entry.changed.wait(connection).unwrap();
+        }
+        connection_error(&connection)?;
+        drop(connection);
+
+        Ok(stream)
+    }
+
+    /// Blocks until a remote connects.
+    pub fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        let mut incoming = self.shared.incoming.lock().unwrap();
+        let entry = loop {
+            match incoming.pop_front() {
+                Some(entry) => break entry,
+                None => incoming = self.shared.accepted.wait(incoming).unwrap(),
+            }
+        };
+
+        let remote = entry.connection.lock().unwrap().remote();
+        let stream = UtpStream {
+            shared: self.shared.clone(),
+            entry,
+            read_timeout: None,
+        };
+
+        Ok((stream, remote))
+    }
+}
+
+fn connection_error(connection: &Connection) -> io::Result<()> {
+    match connection.state() {
+        State::Reset => Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        State::TimedOut => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        _ => Ok(()),
+    }
+}
+
+/// A uTP connection, read and written like a `TcpStream`. Dropping it
+/// closes the connection once the written data was sent.
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    entry: Arc<Entry>,
+    read_timeout: Option<Duration>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.lock().remote()
+    }
+
+    /// Makes reads fail with `WouldBlock` after waiting this long.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.entry.connection.lock().unwrap()
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+        let mut connection = self.lock();
+        while !connection.is_readable() {
+            connection = match deadline {
+                None => self.entry.changed.wait(connection).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                    }
+                    self.entry
+                        .changed
+                        .wait_timeout(connection, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+
+        let read = connection.read(buffer, Instant::now());
+        self.shared.transmit(&mut connection);
+        if read == 0 && !buffer.is_empty() {
+            connection_error(&connection)?;
+        }
+
+        Ok(read)
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut connection = self.lock();
+        loop {
+            connection_error(&connection)?;
+            if connection.state() == State::Closed {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+
+            let written = connection.write(data, Instant::now());
+            self.shared.transmit(&mut connection);
+            if written > 0 || data.is_empty() {
+                return Ok(written);
+            }
+
+            connection = self.entry.changed.wait(connection).unwrap();
+        }
+    }
+
+    /// Waits until the written data was sent, though not acknowledged.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.lock();
+        while connection.has_unsent() {
+            connection_error(&connection)?;
+            connection = self.entry.changed.wait(connection).unwrap();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut connection = self.lock();
+        connection.close(Instant::now());
+        self.shared.transmit(&mut connection);
+        self.entry.detached.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::peer::{
+        message::{Codec, Message},
+        stream::PeerStream,
+    };
+
+    fn connected(server: &UtpSocket, address: SocketAddr) -> (UtpStream, UtpStream) {
+        let client = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let handle = thread::spawn(move || client.connect(address).unwrap());
+        let (accepted, _) = server.accept().unwrap();
+        (handle.join().unwrap(), accepted)
+    }
+
+    #[test]
+    fn test_peer_messages() {
+        let server = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let (client, accepted) = connected(&server, server.local_addr().unwrap());
+        let codec = Codec::default();
+
+        let mut client = PeerStream::from(client);
+        let mut accepted = PeerStream::from(accepted);
+        codec.write_to(&mut client, &Message::Interested).unwrap();
+        assert_eq!(codec.read_from(&mut accepted).unwrap(), Message::Interested);
+
+        let piece = Message::Piece {
+            index: 3,
+            begin: 0,
+            block: (0..16384).map(|index| index as u8).collect(),
+        };
+        codec.write_to(&mut accepted, &piece).unwrap();
+        assert_eq!(codec.read_from(&mut client).unwrap(), piece);
+
+        drop(client);
+        let mut rest = Vec::new();
+        accepted.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    /// Relays datagrams between a client and `server`, dropping some in
+    /// both directions.
+    fn lossy_proxy(server: SocketAddr, loss: f64) -> SocketAddr {
+        let proxy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = proxy.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut rng = StdRng::seed_from_u64(29);
+            let mut client = None;
+            let mut buffer = vec![0; MAX_DATAGRAM];
+
+            while let Ok((length, from)) = proxy.recv_from(&mut buffer) {
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+
+                if !rng.gen_bool(loss) {
+                    let _ = proxy.send_to(&buffer[..length], to);
+                }
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn test_transfer_with_loss() {
+        let server = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let proxy = lossy_proxy(server.local_addr().unwrap(), 0.1);
+        let (mut client, mut accepted) = connected(&server, proxy);
+
+        let mut rng = StdRng::seed_from_u64(46);
+        let data: Vec<u8> = (0..256 * 1024).map(|_| rng.gen()).collect();
+
+        let sent = data.clone();
+        let writer = thread::spawn(move || {
+            client.write_all(&sent).unwrap();
+            client.flush().unwrap();
+        });
+
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+    }
+}
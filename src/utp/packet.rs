@@ -0,0 +1,204 @@
+use std::fmt::Display;
+
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 20;
+/// Extension carrying a selective ACK bitmask.
+pub const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum PacketError {
+    TooShort(usize),
+    InvalidType(u8),
+    InvalidVersion(u8),
+    InvalidExtension,
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketError::TooShort(length) => write!(f, "packet of {length} bytes is too short"),
+            PacketError::InvalidType(kind) => write!(f, "invalid packet type {kind}"),
+            PacketError::InvalidVersion(version) => write!(f, "unsupported version {version}"),
+            PacketError::InvalidExtension => write!(f, "truncated extension"),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Packet {
+    pub kind: PacketType,
+    pub connection_id: u16,
+    /// Send time in microseconds, on the sender's clock.
+    pub timestamp: u32,
+    /// Delay the sender measured on the last packet it received.
+    pub timestamp_diff: u32,
+    /// Bytes the sender can still receive.
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Bit `i` acknowledges packet `ack_nr + 2 + i`, least significant bit
+    /// of each byte first.
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(kind: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Packet {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.push(((self.kind as u8) << 4) | VERSION);
+        bytes.push(match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => 0,
+        });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Decodes a packet, skipping extensions other than selective ACKs.
+    pub fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(PacketError::TooShort(bytes.len()));
+        }
+
+        let version = bytes[0] & 0x0f;
+        if version != VERSION {
+            return Err(PacketError::InvalidVersion(version));
+        }
+        let kind =
+            PacketType::from_u8(bytes[0] >> 4).ok_or(PacketError::InvalidType(bytes[0] >> 4))?;
+
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        let mut packet = Packet {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack: None,
+            payload: Vec::new(),
+        };
+
+        let mut extension = bytes[1];
+        let mut offset = HEADER_SIZE;
+        while extension != 0 {
+            let header = bytes
+                .get(offset..offset + 2)
+                .ok_or(PacketError::InvalidExtension)?;
+            let (next, length) = (header[0], header[1] as usize);
+            let data = bytes
+                .get(offset + 2..offset + 2 + length)
+                .ok_or(PacketError::InvalidExtension)?;
+
+            if extension == EXTENSION_SELECTIVE_ACK {
+                packet.selective_ack = Some(data.to_vec());
+            }
+
+            extension = next;
+            offset += 2 + length;
+        }
+
+        packet.payload = bytes[offset..].to_vec();
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut packet = Packet::new(PacketType::Data, 0x1234, 7, 3);
+        packet.timestamp = 1_000_000;
+        packet.timestamp_diff = 250;
+        packet.window = 1 << 20;
+        packet.payload = b"payload".to_vec();
+
+        let encoded = packet.encode();
+        assert_eq!(encoded.len(), HEADER_SIZE + 7);
+        assert_eq!(encoded[0], 0x01);
+        assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+
+        let mut ack = Packet::new(PacketType::State, 1, 2, 3);
+        ack.selective_ack = Some(vec![0b101, 0, 0, 0]);
+        let encoded = ack.encode();
+        assert_eq!(encoded[0], 0x21);
+        assert_eq!(encoded[1], EXTENSION_SELECTIVE_ACK);
+        assert_eq!(Packet::decode(&encoded).unwrap(), ack);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Packet::decode(&[0; 10]), Err(PacketError::TooShort(10)));
+
+        let mut bytes = Packet::new(PacketType::Syn, 1, 1, 0).encode();
+        bytes[0] = 0x42;
+        assert_eq!(Packet::decode(&bytes), Err(PacketError::InvalidVersion(2)));
+        bytes[0] = 0x51;
+        assert_eq!(Packet::decode(&bytes), Err(PacketError::InvalidType(5)));
+
+        // An unknown extension is skipped, a truncated one is not
+        bytes[0] = 0x41;
+        bytes[1] = 9;
+        bytes.extend_from_slice(&[0, 2, 0xaa, 0xbb, b'x']);
+        assert_eq!(Packet::decode(&bytes).unwrap().payload, b"x");
+        bytes.truncate(HEADER_SIZE + 3);
+        assert_eq!(Packet::decode(&bytes), Err(PacketError::InvalidExtension));
+    }
+}
@@ -0,0 +1,395 @@
+use std::fmt::Display;
+
+use crate::{
+    bencode_decoder::{Bencode, DecodeError},
+    geometry::BLOCK_SIZE,
+    torrent::{File, ParseError, Torrent},
+};
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Problem {
+    Undecodable(DecodeError),
+    MissingInfo,
+    InvalidField(String),
+    NonCanonicalEncoding,
+    TrailingData(usize),
+    PieceLengthTooSmall(u32),
+    PieceLengthNotPowerOfTwo(u32),
+    BadMetaVersion(u8),
+    EmptyFileTree,
+    UnsafePath(String),
+    PiecesRootLength {
+        path: String,
+        length: usize,
+    },
+    MissingPieceLayer {
+        path: String,
+    },
+    PieceLayerLength {
+        path: String,
+        length: usize,
+    },
+    PieceLayerCount {
+        path: String,
+        expected: usize,
+        found: usize,
+    },
+    UnknownPieceLayer(Vec<u8>),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Undecodable(error) => write!(f, "metainfo cannot be decoded: {error}"),
+            Problem::MissingInfo => write!(f, "metainfo has no info dictionary"),
+            Problem::InvalidField(field) => write!(f, "{field} field is missing or invalid"),
+            Problem::NonCanonicalEncoding => write!(f, "metainfo is not canonically bencoded"),
+            Problem::TrailingData(len) => write!(f, "{len} bytes of trailing data after metainfo"),
+            Problem::PieceLengthTooSmall(len) => {
+                write!(f, "piece length {len} is below {BLOCK_SIZE} bytes")
+            }
+            Problem::PieceLengthNotPowerOfTwo(len) => {
+                write!(f, "piece length {len} is not a power of two")
+            }
+            Problem::BadMetaVersion(version) => write!(f, "unsupported meta version {version}"),
+            Problem::EmptyFileTree => write!(f, "file tree is empty"),
+            Problem::UnsafePath(path) => write!(f, "unsafe path {path:?}"),
+            Problem::PiecesRootLength { path, length } => {
+                write!(f, "pieces root of {path} is {length} bytes, expected 32")
+            }
+            Problem::MissingPieceLayer { path } => write!(f, "missing piece layer for {path}"),
+            Problem::PieceLayerLength { path, length } => write!(
+                f,
+                "piece layer of {path} is {length} bytes, not a multiple of 32"
+            ),
+            Problem::PieceLayerCount {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "piece layer of {path} has {found} hashes, expected {expected}"
+            ),
+            Problem::UnknownPieceLayer(root) => write!(
+                f,
+                "piece layer {} does not match any file",
+                hex::encode(root)
+            ),
+        }
+    }
+}
+
+impl From<ParseError> for Problem {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::MissingInfo => Problem::MissingInfo,
+            ParseError::InvalidField(field) => Problem::InvalidField(field),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.problems.is_empty() {
+            return writeln!(f, "No problems found");
+        }
+
+        for problem in &self.problems {
+            writeln!(f, "{problem}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes, parses and validates a metainfo file, including checks on the raw
+/// encoding that are lost once the file has been parsed. Files that cannot
+/// be decoded or parsed are reported as such.
+pub fn validate_file(content: Vec<u8>) -> Report {
+    let (metainfo, encoded_len) = match Bencode::try_decode_slice(&content) {
+        Ok(decoded) => decoded,
+        Err(error) => {
+            return Report {
+                problems: vec![Problem::Undecodable(error)],
+            }
+        }
+    };
+
+    let mut problems = Vec::new();
+
+    if !is_canonical(&metainfo) || metainfo.clone().encode_value() != content[..encoded_len] {
+        problems.push(Problem::NonCanonicalEncoding);
+    }
+
+    if encoded_len < content.len() {
+        problems.push(Problem::TrailingData(content.len() - encoded_len));
+    }
+
+    match Torrent::try_parse(&metainfo) {
+        Ok(torrent) => problems.extend(torrent.validate().problems),
+        Err(error) => problems.push(error.into()),
+    }
+
+    Report { problems }
+}
+
+/// Checks that every dictionary has its keys sorted and without duplicates,
+/// as required by BEP 3.
+fn is_canonical(value: &Bencode) -> bool {
+    match value {
+        Bencode::String(_) | Bencode::Integer(_) => true,
+        Bencode::List(list) => list.iter().all(is_canonical),
+        Bencode::Dictionary(dict) => {
+            dict.keys().zip(dict.keys().skip(1)).all(|(a, b)| a < b)
+                && dict.values().all(is_canonical)
+        }
+    }
+}
+
+fn is_safe_component(component: &str) -> bool {
+    !(component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains(['/', '\\', '\0'])
+        || component.as_bytes().get(1) == Some(&b':'))
+}
+
+impl Torrent {
+    pub fn validate(&self) -> Report {
+        let mut problems = Vec::new();
+        let info = &self.info;
+
+        if info.piece_length < BLOCK_SIZE {
+            problems.push(Problem::PieceLengthTooSmall(info.piece_length));
+        }
+
+        if !info.piece_length.is_power_of_two() {
+            problems.push(Problem::PieceLengthNotPowerOfTwo(info.piece_length));
+        }
+
+        if info.meta_version != 2 {
+            problems.push(Problem::BadMetaVersion(info.meta_version));
+        }
+
+        if !is_safe_component(&info.name) {
+            problems.push(Problem::UnsafePath(info.name.clone()));
+        }
+
+        let mut files: Vec<(Vec<String>, File)> = Vec::new();
+        info.file_tree.get_files_with_paths(&[], &mut files);
+
+        if files.is_empty() {
+            problems.push(Problem::EmptyFileTree);
+        }
+
+        for (components, file) in &files {
+            let path = components.join("/");
+
+            if !components.iter().all(|c| is_safe_component(c)) {
+                problems.push(Problem::UnsafePath(path.clone()));
+            }
+
+            if file.length > 0 && file.pieces_root.len() != 32 {
+                problems.push(Problem::PiecesRootLength {
+                    path: path.clone(),
+                    length: file.pieces_root.len(),
+                });
+            }
+
+            if info.piece_length == 0 || file.length <= info.piece_length as u64 {
+                continue;
+            }
+
+            match self.piece_layers.get(&file.pieces_root) {
+                None => problems.push(Problem::MissingPieceLayer { path }),
+                Some(layer) if layer.len() % 32 != 0 => problems.push(Problem::PieceLayerLength {
+                    path,
+                    length: layer.len(),
+                }),
+                Some(layer) => {
+                    let expected = file.length.div_ceil(info.piece_length as u64) as usize;
+                    if layer.len() / 32 != expected {
+                        problems.push(Problem::PieceLayerCount {
+                            path,
+                            expected,
+                            found: layer.len() / 32,
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut unknown_layers: Vec<&Vec<u8>> = self
+            .piece_layers
+            .keys()
+            .filter(|root| !files.iter().any(|(_, file)| &file.pieces_root == *root))
+            .collect();
+        unknown_layers.sort();
+
+        problems.extend(
+            unknown_layers
+                .into_iter()
+                .map(|root| Problem::UnknownPieceLayer(root.clone())),
+        );
+
+        Report { problems }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use super::*;
+    use crate::torrent::FileTree;
+
+    fn read_test_file() -> Vec<u8> {
+        let mut file = fs::File::open("test_folder.torrent").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    fn test_torrent() -> Torrent {
+        let (metainfo, _) = Bencode::decode_value(read_test_file());
+        Torrent::parse(&metainfo)
+    }
+
+    #[test]
+    fn test_missing_piece_layers() {
+        let report = validate_file(read_test_file());
+
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::MissingPieceLayer {
+                    path: "images/LOC_Main_Reading_Room_Highsmith.jpg".to_string()
+                },
+                Problem::MissingPieceLayer {
+                    path: "images/melk-abbey-library.jpg".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_piece_layer_lengths() {
+        let mut torrent = test_torrent();
+        let mut files = Vec::new();
+        torrent.info.file_tree.get_files(&mut files);
+
+        torrent
+            .piece_layers
+            .insert(files[1].pieces_root.clone(), vec![0; 269 * 32]);
+        torrent
+            .piece_layers
+            .insert(files[2].pieces_root.clone(), vec![0; 31]);
+        torrent.piece_layers.insert(vec![1; 32], vec![0; 32]);
+
+        assert_eq!(
+            torrent.validate().problems,
+            vec![
+                Problem::PieceLayerLength {
+                    path: "images/melk-abbey-library.jpg".to_string(),
+                    length: 31
+                },
+                Problem::UnknownPieceLayer(vec![1; 32])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_info_problems() {
+        let mut torrent = test_torrent();
+        torrent.info.piece_length = 3000;
+        torrent.info.meta_version = 1;
+        torrent.info.name = "..".to_string();
+        torrent.info.file_tree = FileTree::Directory(
+            "".to_string(),
+            vec![FileTree::File(
+                "a/b".to_string(),
+                File {
+                    length: 10,
+                    pieces_root: vec![0; 20],
+                },
+            )],
+        );
+
+        assert_eq!(
+            torrent.validate().problems,
+            vec![
+                Problem::PieceLengthTooSmall(3000),
+                Problem::PieceLengthNotPowerOfTwo(3000),
+                Problem::BadMetaVersion(1),
+                Problem::UnsafePath("..".to_string()),
+                Problem::UnsafePath("a/b".to_string()),
+                Problem::PiecesRootLength {
+                    path: "a/b".to_string(),
+                    length: 20
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_file_tree() {
+        let mut torrent = test_torrent();
+        torrent.info.file_tree = FileTree::default();
+
+        assert_eq!(torrent.validate().problems, vec![Problem::EmptyFileTree]);
+    }
+
+    #[test]
+    fn test_non_canonical_encoding() {
+        let report = validate_file(
+            b"d4:infod12:piece lengthi16384e4:name1:a12:meta versioni2e9:file treed1:ad0:d6:lengthi1e11:pieces root32:0123456789abcdef0123456789abcdefeeee8:announce0:e".to_vec(),
+        );
+
+        assert_eq!(report.problems, vec![Problem::NonCanonicalEncoding]);
+    }
+
+    #[test]
+    fn test_malformed_files() {
+        assert_eq!(
+            validate_file(b"d3:fooi1e".to_vec()).problems,
+            vec![Problem::Undecodable(DecodeError::UnexpectedEnd)]
+        );
+        assert_eq!(
+            validate_file(b"d3:fooi1ee".to_vec()).problems,
+            vec![Problem::MissingInfo]
+        );
+        assert_eq!(
+            validate_file(b"li1ee".to_vec()).problems,
+            vec![Problem::InvalidField("metainfo".to_string())]
+        );
+        assert_eq!(
+            validate_file(b"d8:announcei1e4:infodee".to_vec()).problems,
+            vec![Problem::InvalidField("announce".to_string())]
+        );
+        assert_eq!(
+            validate_file(b"d4:infod9:file treed1:ad0:d6:lengthi-1eeeeee".to_vec()).problems,
+            vec![Problem::InvalidField("length".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut content = read_test_file();
+        content.extend_from_slice(b"junk");
+
+        assert!(validate_file(content)
+            .problems
+            .contains(&Problem::TrailingData(4)));
+    }
+}